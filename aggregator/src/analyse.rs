@@ -3,6 +3,7 @@ pub use echo::result::*;
 pub use db_model::analyse::*;
 
 pub mod context;
+pub mod drift;
 pub mod echo;
 pub mod model;
 pub mod persist;
@@ -101,7 +101,8 @@ pub fn fetch_by_follow_up(conn: &mut PgConnection, request_id: &TraceRequestId)
     fetch(conn, parent)
 }
 
-fn find_follow_up_prefix(
+/// Net of the analysis that is waiting for the given follow-up trace
+pub fn find_follow_up_prefix(
     conn: &mut PgConnection,
     id: &TraceRequestId,
 ) -> Result<Ipv6Net, ContextFetchError> {
@@ -124,7 +125,7 @@ fn find_follow_up_prefix(
     }
 }
 
-fn must_v6(net: IpNet) -> Ipv6Net {
+pub fn must_v6(net: IpNet) -> Ipv6Net {
     match net {
         IpNet::V4(net) => panic!("Unexpected IPv4 net {}", net),
         IpNet::V6(net) => net,
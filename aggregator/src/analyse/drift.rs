@@ -0,0 +1,227 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{Context as AnyhowContext, *};
+use chrono::NaiveDateTime;
+use db_model::{
+    analyse::{HitCount, LhrAddr, MeasurementTree, CONFIDENCE_THRESH},
+    persist::{dsl::CidrMethods, DieselErrorFixCause},
+    prefix_tree::{
+        history::{self, HistoryCause, NodeState, StateChange},
+        lock::with_subtree_lock,
+        ContextOps, PrefixTree,
+    },
+};
+use diesel::{
+    dsl::{delete, max},
+    prelude::*,
+    PgConnection,
+};
+use itertools::Itertools;
+use log::warn;
+use tracing::instrument;
+
+use crate::observe;
+
+use super::{context::Context, Interpretation};
+
+/// Share of the fresh LHR hits, in percent, that must go to LHRs that none of the
+/// measurements of a node know for it to count as drifted. A single new LHR next to the known
+/// ones is more likely an added router than a renumbered network.
+const MIN_UNKNOWN_HITS_PCT: HitCount = 50;
+
+/// Hits on unknown LHRs needed at least, such that a few stray responses of a small sample
+/// don't discard all measurements of a node
+const MIN_UNKNOWN_HITS: HitCount = 4;
+
+/// Checks whether an established node, i.e. one that was rated at [CONFIDENCE_THRESH] since it
+/// was last reset, drifted: It did if most hits of the interpretation go to last-hop routers
+/// that none of its measurements know, e.g. because the network was renumbered. Nodes whose
+/// confidence was lowered by decay are still checked, since decay is what schedules them again.
+///
+/// If so, the existing measurements are discarded and the confidence reset, such that the node
+/// re-enters analysis based on the fresh measurements only. LHRs that disappeared are not
+/// detected, since the fresh probes only cover a sample of the node.
+#[instrument(skip_all)]
+pub fn process(
+    conn: &mut PgConnection,
+    context: &mut Context,
+    interpretation: &Interpretation,
+) -> Result<bool> {
+    if context.node().lhr_set_hash.is_nil() {
+        return Ok(false); // no analysis ever found LHRs, so there is nothing to drift from
+    }
+    let fresh_hits = interpretation.lhr_hits();
+    if fresh_hits.is_empty() || !was_established(conn, context.node())? {
+        return Ok(false);
+    }
+
+    let known_lhrs = load_known_lhrs(conn, context)?;
+    let Some(unknown_lhrs) = find_drifted_lhrs(&fresh_hits, &known_lhrs) else {
+        return Ok(false);
+    };
+
+    warn!(
+        "Established prefix {} drifted, re-probing found mostly unknown LHRs {:?}. Analysing it again.",
+        context.log_id(),
+        unknown_lhrs
+    );
+    observe::record_drift();
+    // Usually held already by the probe handler, but not e.g. when replaying archived responses
+    with_subtree_lock(conn, &context.node().net, |conn| {
+        conn.transaction(|conn| reset(conn, context))
+    })
+    .context("resetting drifted prefix")?;
+    context.parent.node.confidence = 0;
+    Ok(true)
+}
+
+/// Unknown LHRs of the fresh hits, if they make up a significant share of them
+fn find_drifted_lhrs(
+    fresh_hits: &HashMap<LhrAddr, HitCount>,
+    known_lhrs: &HashSet<LhrAddr>,
+) -> Option<Vec<LhrAddr>> {
+    let total_hits: HitCount = fresh_hits.values().sum();
+    let (unknown_lhrs, unknown_hits): (Vec<LhrAddr>, Vec<HitCount>) = fresh_hits
+        .iter()
+        .filter(|(addr, _)| !known_lhrs.contains(addr))
+        .sorted()
+        .map(|(addr, hits)| (*addr, *hits))
+        .unzip();
+    let unknown_hits: HitCount = unknown_hits.into_iter().sum();
+
+    let is_significant =
+        unknown_hits >= MIN_UNKNOWN_HITS && unknown_hits * 100 >= total_hits * MIN_UNKNOWN_HITS_PCT;
+    is_significant.then_some(unknown_lhrs)
+}
+
+/// Whether the node was rated at [CONFIDENCE_THRESH] since it was last reset due to drift,
+/// even if decay lowered its confidence again since.
+fn was_established(conn: &mut PgConnection, node: &PrefixTree) -> Result<bool> {
+    use db_model::schema::prefix_tree_history::dsl::*;
+
+    if node.confidence >= CONFIDENCE_THRESH {
+        return Ok(true);
+    }
+    let last_reset: Option<NaiveDateTime> = prefix_tree_history
+        .filter(net.eq6(&node.net))
+        .filter(cause.eq(HistoryCause::Drift))
+        .select(max(created_at))
+        .first(conn)
+        .fix_cause()?;
+    let mut confident = prefix_tree_history
+        .filter(net.eq6(&node.net))
+        .filter(new_confidence.ge(CONFIDENCE_THRESH as i16))
+        .select(id)
+        .into_boxed();
+    if let Some(reset_at) = last_reset {
+        confident = confident.filter(created_at.gt(reset_at));
+    }
+    let any_confident: Option<i64> = confident.first(conn).optional().fix_cause()?;
+    Ok(any_confident.is_some())
+}
+
+fn load_known_lhrs(conn: &mut PgConnection, context: &Context) -> Result<HashSet<LhrAddr>> {
+    use db_model::schema::measurement_tree::dsl::*;
+
+    let trees: Vec<MeasurementTree> = measurement_tree
+        .filter(target_net.subnet_or_eq6(&context.node().net))
+        .select(MeasurementTree::as_select())
+        .load(conn)
+        .fix_cause()?;
+
+    Ok(trees
+        .iter()
+        .flat_map(|it| it.last_hop_routers.items.keys().copied())
+        .collect())
+}
+
+fn reset(conn: &mut PgConnection, context: &Context) -> Result<()> {
+    {
+        use db_model::schema::measurement_tree::dsl::*;
+
+        delete(measurement_tree.filter(target_net.subnet_or_eq6(&context.node().net)))
+            .execute(conn)
+            .fix_cause()?;
+    }
+    {
+        use db_model::schema::prefix_tree::dsl::*;
+
+        diesel::update(context.node())
+            .set(confidence.eq(0))
+            .execute(conn)
+            .fix_cause()?;
+    }
+
+    let node = context.node();
+    let change = StateChange::of(
+        node,
+        NodeState {
+            confidence: 0,
+            ..NodeState::from(node)
+        },
+    );
+    history::record(
+        conn,
+        HistoryCause::Drift,
+        Some(context.analysis.id),
+        &[change],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+    use db_model::test_utils::*;
+
+    use super::*;
+
+    fn gen_hits(hits: &[(&str, HitCount)]) -> HashMap<LhrAddr, HitCount> {
+        hits.iter().map(|(lhr, hits)| (addr(lhr), *hits)).collect()
+    }
+
+    fn gen_known() -> HashSet<LhrAddr> {
+        [addr(TREE_LHR_101)].into()
+    }
+
+    #[test]
+    fn single_new_lhr_is_no_drift() {
+        // given
+        let fresh = gen_hits(&[(TREE_LHR_101, 12), (TREE_LHR_BEEF, 5)]);
+
+        // when
+        let drifted = find_drifted_lhrs(&fresh, &gen_known());
+
+        // then
+        assert_that!(drifted).is_none();
+    }
+
+    #[test]
+    fn mostly_unknown_lhrs_drifted() {
+        // given
+        let fresh = gen_hits(&[
+            (TREE_LHR_101, 4),
+            (TREE_LHR_BEEF, 3),
+            ("2001:db8:beef::21", 2),
+        ]);
+
+        // when
+        let drifted = find_drifted_lhrs(&fresh, &gen_known());
+
+        // then
+        assert_that!(drifted)
+            .is_equal_to(Some(vec![addr(TREE_LHR_BEEF), addr("2001:db8:beef::21")]));
+    }
+
+    #[test]
+    fn few_unknown_hits_are_no_drift() {
+        // given
+        let fresh = gen_hits(&[(TREE_LHR_BEEF, MIN_UNKNOWN_HITS - 1)]);
+
+        // when
+        let drifted = find_drifted_lhrs(&fresh, &gen_known());
+
+        // then
+        assert_that!(drifted).is_none();
+    }
+}
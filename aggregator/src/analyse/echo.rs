@@ -1,3 +1,4 @@
+pub use self::persist::{find_overdue, stop_awaiting_responses};
 pub use self::process::process;
 
 mod persist;
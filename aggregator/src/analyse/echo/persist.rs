@@ -2,32 +2,100 @@ use std::ops::DerefMut;
 
 use anyhow::*;
 
+use diesel::dsl::{now, IntervalDsl};
 use diesel::prelude::*;
 use diesel::PgConnection;
+use ipnet::{IpNet, Ipv6Net};
 use queue_models::probe_request::TraceRequestId;
 use tracing::instrument;
 
-use crate::analyse::context::Context;
+use crate::analyse::context::{must_v6, Context};
 use crate::analyse::persist::UpdateAnalysis;
 use crate::analyse::EchoResult;
 use crate::persist::DieselErrorFixCause;
 
-use crate::schema::split_analysis::pending_follow_up;
+use crate::schema::split_analysis::{
+    echo_responded_by, pending_echo_responses, pending_follow_up, pending_follow_ups,
+};
 
 impl UpdateAnalysis for EchoResult {
+    /// Follow-ups requested by each vantage point are accumulated in the analysis and only
+    /// registered once all of them responded (or stopped being awaited).
+    /// Responses of vantage points that already responded must be filtered before, see
+    /// [crate::analyse::SplitAnalysis::has_echo_response_from].
     #[instrument(skip_all)]
     fn update_analysis(&mut self, conn: &mut PgConnection, context: &mut Context) -> Result<()> {
+        ensure!(
+            !context.analysis.has_echo_response_from(&self.vantage_point),
+            "Echo response from {} was already handled for analysis {}",
+            self.vantage_point,
+            context.analysis.id
+        );
+
         self.deref_mut().update_analysis(conn, context)?;
 
-        if self.needs_follow_up() {
-            let id = TraceRequestId::new();
-            context.analysis.pending_follow_up = Some(id.to_string());
-            diesel::update(&context.analysis)
-                .set(pending_follow_up.eq(id.to_string()))
-                .execute(conn)
-                .fix_cause()?;
+        let analysis = &mut context.analysis;
+        analysis.echo_responded_by.push(self.vantage_point.clone());
+        analysis
+            .pending_follow_ups
+            .add(&self.vantage_point, self.follow_ups.clone());
+        let remaining = diesel::update(&*analysis)
+            .set((
+                pending_echo_responses.eq(pending_echo_responses - 1),
+                echo_responded_by.eq(&analysis.echo_responded_by),
+                pending_follow_ups.eq(&analysis.pending_follow_ups),
+            ))
+            .returning(pending_echo_responses)
+            .get_result(conn)
+            .fix_cause()?;
+        analysis.pending_echo_responses = remaining;
+
+        if !analysis.awaits_echo_responses() {
+            register_follow_up(conn, context)?;
         }
 
         Ok(())
     }
 }
+
+/// Registers a follow-up for the accumulated follow-up targets of an analysis, if any.
+fn register_follow_up(conn: &mut PgConnection, context: &mut Context) -> Result<()> {
+    if context.analysis.pending_follow_ups.is_empty() {
+        return Ok(());
+    }
+    let id = TraceRequestId::new();
+    context.analysis.pending_follow_up = Some(id.to_string());
+    diesel::update(&context.analysis)
+        .set(pending_follow_up.eq(id.to_string()))
+        .execute(conn)
+        .fix_cause()?;
+    Ok(())
+}
+
+/// Nets of analyses that received echo responses from some, but not all, vantage points
+/// and were created more than `timeout_mins` ago.
+pub fn find_overdue(conn: &mut PgConnection, timeout_mins: u32) -> Result<Vec<Ipv6Net>> {
+    use crate::schema::split_analysis::dsl::*;
+
+    let nets: Vec<IpNet> = split_analysis
+        .select(tree_net)
+        .filter(result.is_null())
+        .filter(pending_echo_responses.gt(0))
+        .filter(echo_responded_by.ne(Vec::<String>::new()))
+        .filter(created_at.lt(now - (timeout_mins as i32).minutes()))
+        .distinct()
+        .load(conn)
+        .fix_cause()?;
+    Ok(nets.into_iter().map(must_v6).collect())
+}
+
+/// Stops waiting for the remaining vantage points, completing the analysis with the echo
+/// responses received so far.
+pub fn stop_awaiting_responses(conn: &mut PgConnection, context: &mut Context) -> Result<()> {
+    diesel::update(&context.analysis)
+        .set(pending_echo_responses.eq(0))
+        .execute(conn)
+        .fix_cause()?;
+    context.analysis.pending_echo_responses = 0;
+    register_follow_up(conn, context)
+}
@@ -13,16 +13,26 @@ use queue_models::probe_response::{
 
 #[instrument(name = "analyse echo", skip_all)]
 pub fn process(model: &EchoProbeResponse) -> EchoResult {
-    let mut result = EchoResult::default();
+    let mut result = EchoResult::new(model.vantage_point.clone());
+    if let Some(reason) = &model.incomplete {
+        debug!(
+            "Partial echo response ({:?}), ignoring targets without response",
+            reason
+        );
+    }
     for split in &model.splits {
-        process_split(&mut result, split);
+        process_split(&mut result, split, model.incomplete.is_some());
     }
     result
 }
 
-fn process_split(result: &mut EchoResult, split: &SplitResult) {
+fn process_split(result: &mut EchoResult, split: &SplitResult, incomplete: bool) {
     let mut follow_up_collector = FollowUpCollector::new();
     for responses in &split.responses {
+        if incomplete && responses.key == NoResponse {
+            // these might just not have been probed, or the responses didn't arrive in time
+            continue;
+        }
         process_responses(result, responses, &mut follow_up_collector);
     }
     if let Some(follow_up) = follow_up_collector.into() {
@@ -55,7 +65,7 @@ fn process_responses(
             different_from: None,
         } => {
             follow_up_collector.stage_responsive(targets);
-            result.count_other_responsive(targets);
+            result.count_echo_responsive(targets);
         }
         EchoReply {
             different_from: Some(_),
@@ -1,7 +1,9 @@
 use std::fmt::Display;
-use std::net::Ipv6Addr;
 use std::ops::{Deref, DerefMut};
 
+use db_model::analyse::FollowUpTargets;
+use queue_models::VantagePointId;
+
 use crate::analyse::Interpretation;
 
 #[derive(Debug, Default)]
@@ -26,8 +28,11 @@ impl DerefMut for EchoResult {
 
 
 impl EchoResult {
-    pub fn needs_follow_up(&self) -> bool {
-        !self.follow_ups.is_empty()
+    pub fn new(vantage_point: VantagePointId) -> Self {
+        Self {
+            parent: Interpretation::new(vantage_point),
+            follow_ups: vec![],
+        }
     }
 }
 
@@ -40,8 +45,5 @@ impl Display for EchoResult {
     }
 }
 
-#[derive(Debug)]
-pub struct EchoFollowUp {
-    pub targets: Vec<Ipv6Addr>,
-    pub for_responsive: bool,
-}
+/// Targets of an echo response that should be traced
+pub type EchoFollowUp = FollowUpTargets;
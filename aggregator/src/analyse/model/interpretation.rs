@@ -4,17 +4,33 @@ use std::{collections::HashMap, fmt::Display};
 
 use db_model::analyse::LhrAddr;
 use db_model::analyse::map64::{self, Net64Map};
+use queue_models::{default_vantage_point, VantagePointId};
 
 use crate::analyse::WeirdType;
 use crate::analyse::{HitCount, LhrSource};
 
 /// Interpretation of a probe response
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct Interpretation {
     store: Net64Map<Prefix>,
+    /// Where the interpreted probes were sent from
+    pub vantage_point: VantagePointId,
+}
+
+impl Default for Interpretation {
+    fn default() -> Self {
+        Self::new(default_vantage_point())
+    }
 }
 
 impl Interpretation {
+    pub fn new(vantage_point: VantagePointId) -> Self {
+        Self {
+            store: Net64Map::default(),
+            vantage_point,
+        }
+    }
+
     pub fn register_lhrs(&mut self, targets: &[Ipv6Addr], lhr: LhrAddr, source: LhrSource) {
         for target in targets.iter() {
             self.store[target].register_lhr(lhr, source);
@@ -36,9 +52,13 @@ impl Interpretation {
         }
     }
 
-    pub fn count_other_responsive(&mut self, targets: &[Ipv6Addr]) {
+    /// Counts targets that answered an echo request themselves, remembering them
+    /// for re-use as samples.
+    pub fn count_echo_responsive(&mut self, targets: &[Ipv6Addr]) {
         for target in targets.iter() {
-            self.store[target].responsive_count += 1;
+            let prefix = &mut self.store[target];
+            prefix.responsive_count += 1;
+            prefix.echo_responsive.insert(*target);
         }
     }
 
@@ -48,6 +68,15 @@ impl Interpretation {
         }
     }
 
+    /// Last-hop routers that any of the targets was attributed to, with their hits
+    pub fn lhr_hits(&self) -> HashMap<LhrAddr, HitCount> {
+        let mut hits = HashMap::new();
+        for (addr, lhr) in self.store.iter_values().flat_map(|it| it.last_hop_routers.iter()) {
+            *hits.entry(*addr).or_default() += lhr.hit_count;
+        }
+        hits
+    }
+
     pub fn drain(&mut self) -> map64::Drain<Prefix> {
         self.store.drain()
     }
@@ -67,6 +96,8 @@ pub struct Prefix {
     pub responsive_count: HitCount,
     /// Probes that stayed dark
     pub unresponsive_count: HitCount,
+    /// Targets that answered echo requests from their own address
+    pub echo_responsive: HashSet<Ipv6Addr>,
 }
 
 impl Prefix {
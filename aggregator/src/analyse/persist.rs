@@ -1,20 +1,25 @@
 use anyhow::{Context as AnyhowContext, *};
 
 use db_model::analyse::MeasurementTree;
+use diesel::pg::Pg;
 use diesel::prelude::*;
+use diesel::query_builder::{QueryFragment, QueryId};
 use diesel::sql_types::Array;
 use diesel::sql_types::Cidr;
 use diesel::sql_types::Integer;
 use diesel::sql_types::Jsonb;
+use diesel::sql_types::Timestamp;
 use diesel::PgConnection;
 use ipnet::Ipv6Net;
 use itertools::Itertools;
 use log::trace;
+use queue_models::VantagePointId;
 use log::warn;
 use tracing::instrument;
 use tracing::Span;
 
 use crate::analyse::context::Context;
+use crate::analyse::drift;
 use crate::analyse::Interpretation;
 use crate::analyse::LastHopRouter;
 use crate::analyse::MeasurementForest;
@@ -31,10 +36,15 @@ use crate::schema::measurement_tree::target_net;
 
 use diesel::dsl::*;
 
-pub fn begin_bulk(conn: &mut PgConnection, nets: &[Ipv6Net]) -> Result<()> {
+/// Begins analyses that complete once `echo_responses` echo responses were received, i.e.
+/// one per vantage point that the echo request is sent to.
+pub fn begin_bulk(conn: &mut PgConnection, nets: &[Ipv6Net], echo_responses: i16) -> Result<()> {
     use crate::schema::split_analysis::dsl::*;
 
-    let tuples = nets.iter().map(|net| tree_net.eq6(net)).collect_vec();
+    let tuples = nets
+        .iter()
+        .map(|net| (tree_net.eq6(net), pending_echo_responses.eq(echo_responses)))
+        .collect_vec();
     insert_into(split_analysis)
         .values(tuples)
         .execute(conn)
@@ -49,6 +59,7 @@ pub trait UpdateAnalysis {
 impl UpdateAnalysis for Interpretation {
     #[instrument(skip_all)]
     fn update_analysis(&mut self, conn: &mut PgConnection, context: &mut Context) -> Result<()> {
+        drift::process(conn, context, self).context("checking prefix for drift")?;
         save(conn, &context.analysis, self.drain_to_measurement_forest()?)
     }
 }
@@ -56,8 +67,9 @@ impl UpdateAnalysis for Interpretation {
 impl Interpretation {
     fn drain_to_measurement_forest(&mut self) -> Result<MeasurementForest> {
         let mut forest = MeasurementForest::default();
+        let vantage_point = self.vantage_point.clone();
         for (net, entry) in self.drain() {
-            forest.insert(make_tree(net, entry))?;
+            forest.insert(make_tree(net, entry, &vantage_point))?;
         }
         Ok(forest)
     }
@@ -123,6 +135,25 @@ fn save_merging_into_existing(
     relevant_measurements: Vec<MeasurementTree>,
     local_forest: MeasurementForest,
 ) -> Result<()> {
+    let (inserts, updates) = merge_into_existing(relevant_measurements, local_forest)?;
+
+    if !inserts.is_empty() {
+        do_inserts(conn, inserts)?;
+    }
+
+    if !updates.is_empty() {
+        do_updates(conn, updates)?;
+    }
+
+    Ok(())
+}
+
+/// Merges the local forest into the existing measurements, returning the trees to insert
+/// and the trees to update, respectively
+fn merge_into_existing(
+    relevant_measurements: Vec<MeasurementTree>,
+    local_forest: MeasurementForest,
+) -> Result<(Vec<MeasurementTree>, Vec<MeasurementTree>)> {
     let num_trees = relevant_measurements.len();
     let mut remote_forest = MeasurementForest::with_untouched(relevant_measurements)?;
     trace!("Remote forest has {} trees: {}", num_trees, remote_forest);
@@ -159,16 +190,7 @@ fn save_merging_into_existing(
             }
         }
     }
-
-    if !inserts.is_empty() {
-        do_inserts(conn, inserts)?;
-    }
-
-    if !updates.is_empty() {
-        do_updates(conn, updates)?;
-    }
-
-    Ok(())
+    Ok((inserts, updates))
 }
 
 #[instrument(skip_all, fields(inserts = inserts.len()))]
@@ -186,6 +208,12 @@ fn do_inserts(conn: &mut PgConnection, inserts: Vec<MeasurementTree>) -> Result<
 
 #[instrument(skip_all, fields(updates = updates.len()))]
 fn do_updates(conn: &mut PgConnection, updates: Vec<MeasurementTree>) -> Result<usize> {
+    update_query(updates).execute(conn).fix_cause()
+}
+
+fn update_query(
+    updates: Vec<MeasurementTree>,
+) -> impl RunQueryDsl<PgConnection> + QueryFragment<Pg> + QueryId {
     let query = diesel::sql_query(
         "
         UPDATE measurement_tree mt
@@ -193,37 +221,73 @@ fn do_updates(conn: &mut PgConnection, updates: Vec<MeasurementTree>) -> Result<
             responsive_count = dat.responsive_count,
             unresponsive_count = dat.unresponsive_count,
             last_hop_routers = dat.last_hop_routers,
-            weirdness = dat.weirdness
+            weirdness = dat.weirdness,
+            responsive_targets = dat.responsive_targets,
+            decayed_at = dat.decayed_at
         FROM (
             SELECT
                 UNNEST($1) as target_net,
                 UNNEST($2) as responsive_count, UNNEST($3) as unresponsive_count,
-                UNNEST($4) as last_hop_routers, UNNEST($5) as weirdness
+                UNNEST($4) as last_hop_routers, UNNEST($5) as weirdness,
+                UNNEST($6) as decayed_at, UNNEST($7) as responsive_targets
         ) dat
         WHERE mt.target_net = dat.target_net
     ",
     );
 
-    let query = query
+    query
         .bind::<Array<Cidr>, _>(updates.iter().map(|it| it.target_net).collect_vec())
         .bind::<Array<Integer>, _>(updates.iter().map(|it| it.responsive_count).collect_vec())
         .bind::<Array<Integer>, _>(updates.iter().map(|it| it.unresponsive_count).collect_vec())
         .bind::<Array<Jsonb>, _>(updates.iter().map(|it| it.last_hop_routers.clone()).collect_vec())
-        .bind::<Array<Jsonb>, _>(updates.into_iter().map(|it| it.weirdness).collect_vec())
-        ;
-
-    query.execute(conn).fix_cause()
+        .bind::<Array<Jsonb>, _>(updates.iter().map(|it| it.weirdness.clone()).collect_vec())
+        .bind::<Array<Timestamp>, _>(updates.iter().map(|it| it.decayed_at).collect_vec())
+        .bind::<Array<Jsonb>, _>(updates.into_iter().map(|it| it.responsive_targets).collect_vec())
 }
 
-fn make_tree(net: Ipv6Net, entry: Prefix) -> MeasurementTree {
+fn make_tree(net: Ipv6Net, entry: Prefix, vantage_point: &VantagePointId) -> MeasurementTree {
     let mut tree = MeasurementTree::empty(net);
     for (addr, LastHopRouter { sources, hit_count }) in entry.last_hop_routers.into_iter() {
-        tree.add_lhr_no_sum(addr, sources, hit_count);
+        tree.add_lhr_from_vantage(addr, sources, hit_count, vantage_point);
     }
     for (description, node) in entry.weird.into_iter() {
         tree.add_weird_no_sum(description, node.hit_count);
     }
+    tree.responsive_targets.extend(entry.echo_responsive);
     tree.responsive_count = entry.responsive_count;
     tree.unresponsive_count = entry.unresponsive_count;
     tree
 }
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+    use db_model::test_utils::*;
+
+    use super::*;
+
+    #[test]
+    fn merged_tree_keeps_newer_decay_time_when_persisted() {
+        // given
+        let mut existing = gen_tree_with_lhr_101(TREE_LEFT_NET, 3);
+        let fresh = existing.decayed_at;
+        let stale = fresh - chrono::Duration::days(90);
+        existing.decayed_at = stale;
+        let mut local = gen_tree_with_lhr_101(TREE_LEFT_NET, 2);
+        local.decayed_at = fresh;
+        let mut local_forest = MeasurementForest::default();
+        local_forest.insert(local).unwrap();
+
+        // when
+        let (inserts, updates) = merge_into_existing(vec![existing], local_forest).unwrap();
+        let query = update_query(updates.clone());
+
+        // then
+        assert_that!(inserts).is_empty();
+        assert_that!(updates.iter().map(|it| it.decayed_at).collect_vec())
+            .is_equal_to(vec![fresh]);
+        let sql = diesel::debug_query::<Pg, _>(&query).to_string();
+        assert_that!(sql.contains(&format!("{:?}", fresh))).is_true();
+        assert_that!(sql.contains(&format!("{:?}", stale))).is_false();
+    }
+}
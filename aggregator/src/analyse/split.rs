@@ -1,26 +1,42 @@
+use clap::Args;
 use diesel::{prelude::*, PgConnection, QueryDsl, SelectableHelper};
 use ipnet::Ipv6Net;
 use log::{debug, info, warn};
-use prefix_crab::blocklist::PrefixBlocklist;
+use prefix_crab::{blocklist::PrefixBlocklist, prefix_split::FanOut};
 use thiserror::Error;
 use tracing::instrument;
 
 use crate::{observe, persist::{dsl::CidrMethods, DieselErrorFixCause}};
 use db_model::prefix_tree::ContextOps;
 
-use self::subnet::Subnets;
+use self::{recommend::SplitRecommendation, subnet::Subnets};
 
 use super::{context, MeasurementTree, SplitAnalysisResult};
 
 pub use db_model::analyse::{Confidence, CONFIDENCE_THRESH};
+pub use recommend::{ALGO_VERSION, FIXED_RATIO_ALGO_VERSION, REPLAYABLE_ALGO_VERSIONS};
 
 mod collapse;
 mod confidence;
+pub mod decay;
+mod explain;
 mod merge_redundant;
 mod persist;
+mod ratio_test;
+pub mod reaggregate;
 mod recommend;
 mod subnet;
 
+#[derive(Args, Debug, Clone)]
+#[group(id = "split")]
+pub struct Params {
+    /// Whether to only split prefixes if the measurements from each vantage point on their
+    /// own would also lead to a split. Only relevant if echo requests are sent to multiple
+    /// vantage points (see `ECHO_VANTAGE_POINTS`).
+    #[arg(long, env = "SPLIT_REQUIRE_VANTAGE_AGREEMENT", default_value = "false", action = clap::ArgAction::Set)]
+    pub require_vantage_agreement: bool,
+}
+
 #[derive(Error, Debug)]
 pub enum SplitError {
     #[error("database error loading relevant measurements fpr {base_net} from the database")]
@@ -47,6 +63,8 @@ pub fn process(
     conn: &mut PgConnection,
     request: context::Context,
     blocklist: &PrefixBlocklist,
+    fan_out: &FanOut,
+    params: &Params,
 ) -> SplitResult<()> {
     if !request.node().merge_status.is_eligible_for_split() {
         tracing::warn!("no longer a leaf");
@@ -67,14 +85,15 @@ pub fn process(
     }
 
     let relevant_measurements = load_relevant_measurements(conn, &request.node().net)?;
-    let subnets = Subnets::new(request.node().net, &relevant_measurements)
+    let split_bits = fan_out.bits_for(&request.node().net);
+    let subnets = Subnets::new(request.node().net, split_bits, &relevant_measurements)
         .map_err(|source| SplitError::SplitSubnets { source })?;
 
-    let rec = recommend::recommend(&subnets);
-    let confidence = confidence::rate(request.node().net, &rec);
+    let (rec, confidence) = recommend_and_rate(request.node().net, &subnets, params, ALGO_VERSION);
     let hash = subnets.combined_lhr_set_hash();
+    let explanation = explain::explain(&request.node().net, &subnets, &rec, confidence);
 
-    persist::save_recommendation(conn, &request, &rec, confidence, hash)
+    persist::save_recommendation(conn, &request, &rec, confidence, hash, explanation)
         .map_err(|source| SplitError::SaveRecommendation { source })?;
 
     if confidence >= CONFIDENCE_THRESH {
@@ -133,6 +152,56 @@ pub fn process(
     Ok(())
 }
 
+fn recommend_and_rate(
+    net: Ipv6Net,
+    subnets: &Subnets,
+    params: &Params,
+    algo_version: i32,
+) -> (SplitRecommendation, Confidence) {
+    let mut rec = recommend::recommend_as(subnets, algo_version);
+    if params.require_vantage_agreement {
+        rec = recommend::require_vantage_agreement(subnets, rec, algo_version);
+    }
+    let confidence = confidence::rate(net, &rec);
+    (rec, confidence)
+}
+
+/// Outcome of a split analysis that was not saved, see [evaluate]
+#[derive(Debug)]
+pub struct SplitEvaluation {
+    pub result: SplitAnalysisResult,
+    pub confidence: Confidence,
+}
+
+/// Runs the split analysis of `net` on the given measurements without saving or acting on the
+/// result, as one of the [REPLAYABLE_ALGO_VERSIONS]. This allows comparing changes to the
+/// algorithm against stored results offline.
+pub fn evaluate(
+    net: Ipv6Net,
+    measurements: &[MeasurementTree],
+    fan_out: &FanOut,
+    params: &Params,
+    algo_version: i32,
+) -> anyhow::Result<SplitEvaluation> {
+    anyhow::ensure!(
+        REPLAYABLE_ALGO_VERSIONS.contains(&algo_version),
+        "Algorithm version {} cannot be replayed, only {:?}",
+        algo_version,
+        REPLAYABLE_ALGO_VERSIONS
+    );
+    let subnets = Subnets::new(net, fan_out.bits_for(&net), measurements)?;
+    let (rec, confidence) = recommend_and_rate(net, &subnets, params, algo_version);
+    let explanation = explain::explain(&net, &subnets, &rec, confidence);
+    Ok(SplitEvaluation {
+        result: SplitAnalysisResult {
+            explanation: Some(explanation),
+            algo_version,
+            ..(&rec).into()
+        },
+        confidence,
+    })
+}
+
 #[instrument(skip(conn))]
 fn load_relevant_measurements(
     conn: &mut PgConnection,
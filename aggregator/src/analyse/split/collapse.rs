@@ -10,12 +10,10 @@ use ipnet::Ipv6Net;
 use itertools::Itertools;
 use tracing::instrument;
 
-use crate::analyse::context::Context;
-
 #[instrument(name = "collapse measurements", skip_all)]
 pub fn process(
     conn: &mut PgConnection,
-    request: &Context,
+    request: &impl ContextOps,
     relevant_measurements: Vec<MeasurementTree>,
 ) -> Result<()> {
     let base_net = request.node().net;
@@ -1,25 +1,32 @@
 use ipnet::Ipv6Net;
 use prefix_crab::confidence_threshold;
 
-use super::recommend::{ReProbePriority, SplitRecommendation};
+use super::{
+    ratio_test,
+    recommend::{ReProbePriority, SplitRecommendation},
+};
 use db_model::analyse::*;
 
 pub fn rate(net: Ipv6Net, rec: &SplitRecommendation) -> Confidence {
-    use SplitRecommendation as R;
-
-    match rec {
-        R::YesSplit { priority } => rate_yes(priority, &net),
-        R::NoKeep { priority } => rate_no(priority, &net),
-        R::CannotDetermine { priority } => rate_no(priority, &net),
+    let by_evidence = rate_with_thresh(rec.priority(), thresh(&net, rec));
+    match &rec.priority().ratio_test {
+        // The test alone would be confident with a few hits, but larger prefixes need more
+        // evidence before we can assume that the hits are representative of the whole prefix.
+        Some(test) => ratio_test::confidence(test, rec.should_split()).min(by_evidence),
+        None => by_evidence,
     }
 }
 
-fn rate_yes(prio: &ReProbePriority, net: &Ipv6Net) -> Confidence {
-    rate_with_thresh(prio, confidence_threshold::split_distinct_responses_thresh(net))
-}
+/// Evidence needed for the recommendation to reach [CONFIDENCE_THRESH]
+pub fn thresh(net: &Ipv6Net, rec: &SplitRecommendation) -> u32 {
+    use SplitRecommendation as R;
 
-fn rate_no(prio: &ReProbePriority, net: &Ipv6Net) -> Confidence {
-    rate_with_thresh(prio, confidence_threshold::keep_equivalent_responses_thresh(net))
+    match rec {
+        R::YesSplit { priority: _ } => confidence_threshold::split_distinct_responses_thresh(net),
+        R::NoKeep { priority: _ } | R::CannotDetermine { priority: _ } => {
+            confidence_threshold::keep_equivalent_responses_thresh(net)
+        }
+    }
 }
 
 fn rate_with_thresh(prio: &ReProbePriority, thresh: u32) -> Confidence {
@@ -37,6 +44,7 @@ fn rate_with_thresh(prio: &ReProbePriority, thresh: u32) -> Confidence {
 mod tests {
     use super::*;
     use anyhow::*;
+    use assertor::*;
     use db_model::prefix_tree::PriorityClass::MediumSameMulti;
     use db_model::test_utils::*;
 
@@ -59,6 +67,7 @@ mod tests {
                 priority: ReProbePriority {
                     class: MediumSameMulti,
                     supporting_observations: evidence,
+                    ratio_test: None,
                 },
             };
             let net = Ipv6Net::new(addr(TREE_LHR_BEEF), prefix_size).unwrap();
@@ -74,4 +83,35 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn ratio_test_gated_by_evidence() {
+        // given
+        let test = RatioTest {
+            chi_square: 100.0,
+            degrees_of_freedom: 1,
+            p_value: 1e-20,
+            effect_size: 0.5,
+            hits: 400,
+            required_hits: 130.0,
+            min_expected_hits: 50.0,
+        };
+        let rec = |supporting_observations| SplitRecommendation::YesSplit {
+            priority: ReProbePriority {
+                class: MediumSameMulti,
+                supporting_observations,
+                ratio_test: Some(test),
+            },
+        };
+        let net = Ipv6Net::new(addr(TREE_LHR_BEEF), 48).unwrap();
+        let thresh = confidence_threshold::split_distinct_responses_thresh(&net) as HitCount;
+
+        // when
+        let below_floor = rate(net, &rec(thresh - 1));
+        let at_floor = rate(net, &rec(thresh));
+
+        // then
+        assert_that!(below_floor).is_less_than(CONFIDENCE_THRESH);
+        assert_that!(at_floor).is_at_least(CONFIDENCE_THRESH);
+    }
 }
@@ -0,0 +1,261 @@
+use anyhow::{Context as _, Result};
+use chrono::{Duration, NaiveDateTime, Utc};
+use db_model::{
+    analyse::MeasurementTree,
+    persist::dsl::CidrMethods,
+    persist::DieselErrorFixCause,
+    prefix_tree::{
+        history::{self, HistoryCause, NodeState, StateChange},
+        lock::with_subtree_lock,
+        MergeStatus, PrefixTree,
+    },
+};
+use diesel::{dsl::exists, prelude::*, sql_types::Integer, PgConnection};
+use log::{debug, warn};
+use prefix_crab::prefix_split::FanOut;
+use tracing::instrument;
+
+use super::{confidence, load_relevant_measurements, recommend, subnet::Subnets, Confidence};
+
+#[derive(Debug, Default)]
+pub struct DecayStats {
+    pub decayed_trees: usize,
+    pub lowered_confidences: usize,
+}
+
+/// Halves the counts of all measurement trees that weren't decayed for `half_life`, such that
+/// evidence loses half its weight every `half_life`. Afterwards, the confidence of the affected
+/// leaves is re-rated with the same logic as the split itself, and lowered if it fell. This way,
+/// leaves that weren't probed for a long time are eventually scheduled again.
+/// At most `max_leaves` leaves are handled per call, the others are left to later calls.
+#[instrument(name = "decay", skip_all)]
+pub fn process(
+    conn: &mut PgConnection,
+    half_life: Duration,
+    max_leaves: u32,
+    fan_out: &FanOut,
+) -> Result<DecayStats> {
+    let cutoff = (Utc::now() - half_life).naive_utc();
+    let mut stats = DecayStats::default();
+
+    for leaf in load_leaves_to_decay(conn, cutoff, max_leaves)? {
+        let decayed = with_subtree_lock(conn, &leaf.net, |conn| {
+            conn.transaction(|conn| decay_leaf(conn, &leaf, cutoff, fan_out))
+        });
+        match decayed {
+            Ok((decayed_trees, lowered)) => {
+                stats.decayed_trees += decayed_trees;
+                if lowered {
+                    stats.lowered_confidences += 1;
+                }
+            }
+            Err(e) => warn!("Failed to decay measurements of {} - {:?}", leaf.net, e),
+        }
+    }
+    Ok(stats)
+}
+
+/// Leaves with at least one measurement tree that is due for decay. Since decay moves the trees
+/// past the cutoff, the next call continues with the remaining leaves.
+fn load_leaves_to_decay(
+    conn: &mut PgConnection,
+    cutoff: NaiveDateTime,
+    max_leaves: u32,
+) -> Result<Vec<PrefixTree>> {
+    use db_model::schema::measurement_tree::dsl as mt;
+    use db_model::schema::prefix_tree::dsl::*;
+
+    let a_tree_due = mt::measurement_tree
+        .select(0.into_sql::<Integer>())
+        .filter(mt::target_net.subnet_or_eq(net))
+        .filter(mt::decayed_at.lt(cutoff));
+
+    prefix_tree
+        .filter(merge_status.eq_any([
+            MergeStatus::Leaf,
+            MergeStatus::MinSizeReached,
+            MergeStatus::UnsplitRoot,
+        ]))
+        .filter(exists(a_tree_due))
+        .select(PrefixTree::as_select())
+        .order_by(net)
+        .limit(max_leaves as i64)
+        .load(conn)
+        .fix_cause()
+        .context("loading leaves to decay")
+}
+
+fn decay_leaf(
+    conn: &mut PgConnection,
+    leaf: &PrefixTree,
+    cutoff: NaiveDateTime,
+    fan_out: &FanOut,
+) -> Result<(usize, bool)> {
+    let mut measurements = load_relevant_measurements(conn, &leaf.net)?;
+    let decayed = decay_due(&mut measurements, cutoff);
+    for tree in decayed.iter() {
+        save_decayed(conn, tree)?;
+    }
+    let decayed_trees = decayed.len();
+    measurements.extend(decayed.into_iter().filter(|it| !it.is_empty()));
+
+    match lowered_confidence(leaf, &measurements, fan_out)? {
+        Some(rated) => {
+            debug!(
+                "Lowering confidence of {} from {} to {} after decay.",
+                leaf.net, leaf.confidence, rated
+            );
+            lower_confidence(conn, leaf, rated)?;
+            Ok((decayed_trees, true))
+        }
+        None => Ok((decayed_trees, false)),
+    }
+}
+
+/// Removes the trees that are due from `measurements` and returns them decayed.
+fn decay_due(
+    measurements: &mut Vec<MeasurementTree>,
+    cutoff: NaiveDateTime,
+) -> Vec<MeasurementTree> {
+    let (mut due, kept) = std::mem::take(measurements)
+        .into_iter()
+        .partition::<Vec<_>, _>(|it| it.decayed_at < cutoff);
+    *measurements = kept;
+    for tree in due.iter_mut() {
+        tree.decay();
+    }
+    due
+}
+
+/// The confidence of the leaf re-rated on the (decayed) measurements, if it is lower than before
+fn lowered_confidence(
+    leaf: &PrefixTree,
+    measurements: &[MeasurementTree],
+    fan_out: &FanOut,
+) -> Result<Option<Confidence>> {
+    if !leaf.merge_status.is_eligible_for_split() {
+        return Ok(None); // never analysed, so there is no confidence to lower
+    }
+    let subnets = Subnets::new(leaf.net, fan_out.bits_for(&leaf.net), measurements)?;
+    let rec = recommend::recommend(&subnets);
+    let rated = confidence::rate(leaf.net, &rec);
+    Ok((rated < leaf.confidence).then_some(rated))
+}
+
+fn save_decayed(conn: &mut PgConnection, tree: &MeasurementTree) -> Result<()> {
+    if tree.is_empty() {
+        diesel::delete(tree).execute(conn).fix_cause()?;
+    } else {
+        diesel::update(tree).set(tree).execute(conn).fix_cause()?;
+    }
+    Ok(())
+}
+
+fn lower_confidence(
+    conn: &mut PgConnection,
+    leaf: &PrefixTree,
+    new_confidence: Confidence,
+) -> Result<()> {
+    use db_model::schema::prefix_tree::dsl::*;
+
+    diesel::update(leaf)
+        .set(confidence.eq(new_confidence as i16))
+        .execute(conn)
+        .fix_cause()?;
+
+    let change = StateChange::of(
+        leaf,
+        NodeState {
+            confidence: new_confidence,
+            ..NodeState::from(leaf)
+        },
+    );
+    history::record(conn, HistoryCause::Decay, None, &[change])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+    use db_model::{
+        prefix_tree::{LhrSetHash, PriorityClass},
+        test_utils::*,
+    };
+    use ipnet::IpNet;
+
+    use super::*;
+
+    fn gen_leaf(merge_status: MergeStatus, confidence: Confidence) -> PrefixTree {
+        PrefixTree {
+            net: net(TREE_BASE_NET),
+            created_at: NaiveDateTime::default(),
+            updated_at: NaiveDateTime::default(),
+            merge_status,
+            priority_class: PriorityClass::MediumSameSingle,
+            confidence,
+            lhr_set_hash: LhrSetHash::nil(),
+            asn: 64511,
+        }
+    }
+
+    #[test]
+    fn decay_due_halves_only_old_trees() {
+        // given
+        let cutoff = Utc::now().naive_utc() - Duration::days(180);
+        let mut old = gen_tree_with_lhr_101(TREE_LEFT_NET, 8);
+        old.decayed_at = cutoff - Duration::days(1);
+        let fresh = gen_tree_with_lhr_101(TREE_RIGHT_NET, 8);
+        let mut measurements = vec![old, fresh];
+
+        // when
+        let decayed = decay_due(&mut measurements, cutoff);
+
+        // then
+        assert_that!(decayed).has_length(1);
+        assert_that!(decayed[0].target_net).is_equal_to(IpNet::V6(net(TREE_LEFT_NET)));
+        assert_that!(decayed[0].last_hop_routers.sum_hits()).is_equal_to(4);
+        assert_that!(decayed[0].decayed_at > cutoff).is_true();
+        assert_that!(measurements).has_length(1);
+        assert_that!(measurements[0].last_hop_routers.sum_hits()).is_equal_to(8);
+    }
+
+    #[test]
+    fn lowered_confidence_after_decay() {
+        // given
+        let leaf = gen_leaf(MergeStatus::Leaf, Confidence::MAX);
+        let measurements = vec![gen_tree_with_lhr_101(TREE_LEFT_NET, 2)];
+
+        // when
+        let lowered = lowered_confidence(&leaf, &measurements, &FanOut::default()).unwrap();
+
+        // then
+        assert_that!(lowered).is_some();
+        assert_that!(lowered.unwrap() < Confidence::MAX).is_true();
+    }
+
+    #[test]
+    fn lowered_confidence_never_raises() {
+        // given
+        let leaf = gen_leaf(MergeStatus::Leaf, 0);
+        let measurements = gen_measurements_complex();
+
+        // when
+        let lowered = lowered_confidence(&leaf, &measurements, &FanOut::default()).unwrap();
+
+        // then
+        assert_that!(lowered).is_none();
+    }
+
+    #[test]
+    fn lowered_confidence_not_for_unanalysed() {
+        // given
+        let leaf = gen_leaf(MergeStatus::MinSizeReached, Confidence::MAX);
+        let measurements = vec![gen_tree_with_lhr_101(TREE_LEFT_NET, 2)];
+
+        // when
+        let lowered = lowered_confidence(&leaf, &measurements, &FanOut::default()).unwrap();
+
+        // then
+        assert_that!(lowered).is_none();
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::BTreeMap;
+
+use db_model::analyse::{ComparedItems, HitCount, LhrAddr, SplitExplanation};
+use ipnet::Ipv6Net;
+
+use super::{
+    confidence,
+    recommend::{self, SplitRecommendation},
+    subnet::{Diff, LhrDiff, Subnets},
+    Confidence,
+};
+
+/// Collects the values that [recommend::recommend] and [confidence::rate] based their result on,
+/// such that it can be persisted with the analysis.
+pub fn explain(
+    net: &Ipv6Net,
+    subnets: &Subnets,
+    rec: &SplitRecommendation,
+    confidence: Confidence,
+) -> SplitExplanation {
+    let lhr_diff = subnets.lhr_diff();
+    let (compared, diff) = match lhr_diff {
+        Diff::AllNone => (ComparedItems::Weirdness, subnets.weird_diff().kind()),
+        _ => (ComparedItems::Lhrs, lhr_diff.kind()),
+    };
+
+    let mut ratio_pcts = BTreeMap::new();
+    if let Diff::AllSameMultiple { shared } = &lhr_diff {
+        if shared.len() < recommend::SAME_MANY_LHRS {
+            let addrs = shared.iter().map(|it| it.addr);
+            ratio_pcts = addrs.zip(calc_ratio_pcts(shared)).collect();
+        }
+    }
+
+    let lhr_hits = match lhr_diff {
+        Diff::AllNone => BTreeMap::new(),
+        Diff::AllSameSingle { shared } => hits_by_addr(vec![shared]),
+        Diff::AllSameMultiple { shared } => hits_by_addr(shared),
+        Diff::OverlappingOrDisjoint { shared, distinct } => {
+            hits_by_addr(shared.into_iter().chain(distinct).collect())
+        }
+    };
+
+    SplitExplanation {
+        subnets: subnets.iter().map(|it| it.subnet.network).collect(),
+        compared,
+        diff,
+        lhr_hits,
+        ratio_pcts,
+        ratio_test: rec.priority().ratio_test,
+        confidence_thresh: confidence::thresh(net, rec),
+        confidence,
+    }
+}
+
+/// Share of each shared LHR in the hits of each subnet in percent, in the order of `shared`
+fn calc_ratio_pcts(shared: &[LhrDiff]) -> Vec<Vec<Option<HitCount>>> {
+    let subnet_count = shared.first().map(|it| it.hit_counts.len()).unwrap_or(0);
+    let total_per_subnet: Vec<HitCount> = (0..subnet_count)
+        .map(|i| shared.iter().map(|it| it.hit_counts[i]).sum())
+        .collect();
+
+    shared
+        .iter()
+        .map(|diff| {
+            diff.hit_counts
+                .iter()
+                .zip(total_per_subnet.iter())
+                .map(|(hits, total)| (*total > 0).then(|| (hits * 100).saturating_div(*total)))
+                .collect()
+        })
+        .collect()
+}
+
+fn hits_by_addr(lhrs: Vec<LhrDiff>) -> BTreeMap<LhrAddr, Vec<HitCount>> {
+    lhrs.into_iter()
+        .map(|it| (it.addr, it.hit_counts))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+    use db_model::analyse::DiffKind;
+    use db_model::test_utils::*;
+
+    use super::*;
+
+    #[test]
+    fn same_multi_ratios() {
+        // given
+        let mut measurements = vec![
+            gen_tree_with_lhr_101(TREE_LEFT_NET, 100),
+            gen_tree_with_lhr_101(TREE_RIGHT_NET, 170),
+        ];
+        gen_add_lhr_beef(&mut measurements[0], 100);
+        gen_add_lhr_beef(&mut measurements[1], 104);
+        let base_net = net(TREE_BASE_NET);
+        let subnets = Subnets::new(base_net, 1, &measurements).unwrap();
+        let rec = recommend::recommend(&subnets);
+
+        // when
+        let explanation = explain(&base_net, &subnets, &rec, 42);
+
+        // then
+        assert_that!(explanation.compared).is_equal_to(ComparedItems::Lhrs);
+        assert_that!(explanation.diff).is_equal_to(DiffKind::AllSameMultiple);
+        assert_that!(explanation.lhr_hits.get(&addr(TREE_LHR_101)))
+            .is_equal_to(Some(&vec![100, 170]));
+        assert_that!(explanation.ratio_pcts.get(&addr(TREE_LHR_101)))
+            .is_equal_to(Some(&vec![Some(50), Some(62)]));
+        assert_that!(explanation.ratio_pcts.get(&addr(TREE_LHR_BEEF)))
+            .is_equal_to(Some(&vec![Some(50), Some(37)]));
+        assert_that!(explanation.ratio_test).is_equal_to(rec.priority().ratio_test);
+        assert_that!(explanation.confidence_thresh)
+            .is_equal_to(confidence::thresh(&base_net, &rec));
+        assert_that!(explanation.confidence).is_equal_to(42);
+    }
+}
@@ -1,13 +1,17 @@
-use anyhow::{anyhow, bail, Result};
+use anyhow::{bail, Context as _, Result};
 use db_model::{
-    persist::dsl::CidrMethods,
+    persist::dsl::{masklen, CidrMethods},
     persist::DieselErrorFixCause,
-    prefix_tree::{ContextOps, MergeStatus, PrefixTree, PriorityClass},
+    prefix_tree::{
+        history::{self, HistoryCause},
+        ContextOps, MergeStatus, PrefixTree, PriorityClass,
+    },
 };
 use diesel::{dsl::sql, prelude::*, BoolExpressionMethods, ExpressionMethods, PgConnection};
-use ipnet::Ipv6Net;
+use ipnet::{IpNet, Ipv6Net};
 use itertools::Itertools;
 use log::info;
+use prefix_crab::prefix_split::MAX_BITS_PER_SPLIT;
 use tracing::instrument;
 
 use crate::analyse::context::Context;
@@ -15,81 +19,138 @@ use crate::analyse::context::Context;
 
 #[instrument(name = "merge redundant", skip_all)]
 pub fn process(conn: &mut PgConnection, request: &Context) -> Result<()> {
-    if is_redundant(conn, request)? {
+    if let Some(nets) = find_redundant(conn, request)? {
         info!(
-            "Prefix {} is redundant, merging with sibling",
-            request.node().net
+            "Prefix {} is redundant, merging with {} sibling(s)",
+            request.node().net,
+            nets.siblings.len()
         );
-        merge(conn, request)?;
+        merge(conn, request, &nets)?;
     }
     Ok(())
 }
 
-pub fn is_redundant(conn: &mut PgConnection, request: &Context) -> Result<bool> {
+/// Finds the adjacent nets if the node is redundant, i.e. all its siblings are equivalent leaves
+/// and the parent can be merged into.
+fn find_redundant(conn: &mut PgConnection, request: &Context) -> Result<Option<AdjacentNets>> {
     use db_model::schema::prefix_tree::dsl::*;
 
-    let nets = AdjacentNets::try_from(request.node().net)?;
+    let own_net = request.node().net;
+    let Some(parent) = load_parent(conn, &own_net)? else {
+        return Ok(None);
+    };
+    let nets = AdjacentNets::new(own_net, parent.net)?;
 
     let my_lhr_set_hash = request.node().lhr_set_hash;
-    
-    let found_adjacents: Vec<PrefixTree> = prefix_tree
-        .or_filter(
-            net.eq6(&nets.sibling)
-                .and(priority_class.eq(request.node().priority_class))
-                .and(confidence.ge(100))
-                .and(merge_status.eq(MergeStatus::Leaf)
-                .and(lhr_set_hash.eq(my_lhr_set_hash))),
-        )
-        .or_filter(
-            net.eq6(&nets.parent)
-                .and(merge_status.ne(MergeStatus::Blocked)),
-        )
-        .load(conn)
+    let equivalent_sibling_count: i64 = prefix_tree
+        .filter(net.eq_any(nets.siblings.iter().map(|it| IpNet::V6(*it))))
+        .filter(priority_class.eq(request.node().priority_class))
+        .filter(confidence.ge(100))
+        .filter(merge_status.eq(MergeStatus::Leaf))
+        .filter(lhr_set_hash.eq(my_lhr_set_hash))
+        .count()
+        .get_result(conn)
         .fix_cause()?;
 
-    Ok(found_adjacents.len() == 2) // only OK if sibling & parent exist and are valid
+    if equivalent_sibling_count == nets.siblings.len() as i64 {
+        Ok(Some(nets))
+    } else {
+        Ok(None)
+    }
 }
 
-struct AdjacentNets {
-    own: Ipv6Net,
-    parent: Ipv6Net,
-    sibling: Ipv6Net,
+/// Closest split node above the given net, which is the node that it was split from.
+/// Nodes in between (e.g. merged up ones left over from earlier splits) are skipped.
+fn load_parent(conn: &mut PgConnection, child: &Ipv6Net) -> Result<Option<PrefixTree>> {
+    use db_model::schema::prefix_tree::dsl::*;
+
+    prefix_tree
+        .filter(net.supernet_or_eq6(child))
+        .filter(masklen(net).lt(child.prefix_len() as i64))
+        .filter(merge_status.eq_any([MergeStatus::SplitDown, MergeStatus::SplitRoot]))
+        .order_by(masklen(net).desc())
+        .select(PrefixTree::as_select())
+        .first(conn)
+        .optional()
+        .fix_cause()
+        .with_context(|| format!("loading parent of {}", child))
 }
 
-impl TryFrom<Ipv6Net> for AdjacentNets {
-    type Error = anyhow::Error;
+/// A node with its parent and the other children of the split that created it. With fan-outs of
+/// more than one bit, the parent is not necessarily the direct supernet.
+pub(super) struct AdjacentNets {
+    pub(super) own: Ipv6Net,
+    pub(super) parent: Ipv6Net,
+    pub(super) siblings: Vec<Ipv6Net>,
+}
 
-    fn try_from(value: Ipv6Net) -> Result<Self> {
-        let parent = value.supernet().ok_or_else(|| anyhow!("must not be /1"))?;
-        let sibling = parent
-            .subnets(value.prefix_len())?
-            .filter(move |candidate| candidate != &value)
-            .at_most_one()?
-            .ok_or_else(|| anyhow!("not all subnets should be the same"))?;
+impl AdjacentNets {
+    pub(super) fn new(own: Ipv6Net, parent: Ipv6Net) -> Result<Self> {
+        let split_bits = own.prefix_len().saturating_sub(parent.prefix_len());
+        if !parent.contains(&own) || !(1..=MAX_BITS_PER_SPLIT).contains(&split_bits) {
+            bail!("{} cannot have been split from {}", own, parent);
+        }
+        let siblings = parent
+            .subnets(own.prefix_len())?
+            .filter(|candidate| candidate != &own)
+            .collect_vec();
         Ok(Self {
-            own: value,
+            own,
             parent,
-            sibling,
+            siblings,
         })
     }
+
+    /// Finds the parent among the closest supernets, as far as a single split could reach.
+    pub(super) fn find(own: Ipv6Net, is_parent: impl Fn(&Ipv6Net) -> bool) -> Option<Self> {
+        let mut candidate = own.supernet();
+        for _ in 0..MAX_BITS_PER_SPLIT {
+            let parent = candidate?;
+            if is_parent(&parent) {
+                return Self::new(own, parent).ok();
+            }
+            candidate = parent.supernet();
+        }
+        None
+    }
+
+    pub(super) fn split_bits(&self) -> u8 {
+        self.own.prefix_len() - self.parent.prefix_len()
+    }
+
+    /// Own net and siblings
+    pub(super) fn children(&self) -> Vec<Ipv6Net> {
+        let mut result = self.siblings.clone();
+        result.push(self.own);
+        result
+    }
+
+    /// All nets that change if the children are merged
+    pub(super) fn affected(&self) -> Vec<Ipv6Net> {
+        let mut result = self.children();
+        result.push(self.parent);
+        result
+    }
 }
 
-pub fn merge(conn: &mut PgConnection, request: &Context) -> Result<()> {
+fn merge(conn: &mut PgConnection, request: &Context, nets: &AdjacentNets) -> Result<()> {
     use db_model::schema::prefix_tree::dsl::*;
 
-    let nets = AdjacentNets::try_from(request.node().net)?;
+    let children = nets.children();
+    let affected = nets.affected();
 
     conn.transaction(|conn| {
+        let before = history::load_for_change(conn, &affected)?;
         let n_updated_children = diesel::update(prefix_tree)
             .filter(
                 merge_status
                     .eq(MergeStatus::Leaf)
-                    .and(net.eq6(&nets.own).or(net.eq6(&nets.sibling))),
+                    .and(net.eq_any(children.iter().map(|it| IpNet::V6(*it)))),
             )
             .set(merge_status.eq(MergeStatus::MergedUp))
             .execute(conn)
             .fix_cause()?;
-        if n_updated_children != 2 {
+        if n_updated_children != children.len() {
             bail!(
                 "One of the children didn't exist or wasn't a leaf any more - {}",
                 n_updated_children
@@ -112,6 +173,55 @@ pub fn merge(conn: &mut PgConnection, request: &Context) -> Result<()> {
             bail!("Failed to update parent - {}", n_updated_parent);
         }
 
+        let after = history::load_for_change(conn, &affected)?;
+        history::record(
+            conn,
+            HistoryCause::MergeRedundant,
+            Some(request.analysis.id),
+            &history::changes_between(&before, &after),
+        )?;
         Ok(())
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+    use db_model::test_utils::*;
+
+    use super::*;
+
+    #[test]
+    fn adjacent_multi_bit_split() {
+        // given
+        let parent = net("2001:db8::/32");
+        let own = net("2001:db8:4000::/34");
+
+        // when
+        let nets = AdjacentNets::find(own, |it| it == &parent).unwrap();
+
+        // then
+        assert_that!(nets.parent).is_equal_to(parent);
+        assert_that!(nets.split_bits()).is_equal_to(2);
+        assert_that!(nets.siblings).contains_exactly(vec![
+            net("2001:db8::/34"),
+            net("2001:db8:8000::/34"),
+            net("2001:db8:c000::/34"),
+        ]);
+    }
+
+    #[test]
+    fn adjacent_parent_too_far() {
+        // given
+        let parent = net("2001:db8::/32");
+        let own = net("2001:db8::/48");
+
+        // when
+        let found = AdjacentNets::find(own, |it| it == &parent);
+        let constructed = AdjacentNets::new(own, parent);
+
+        // then
+        assert_that!(found.is_none()).is_true();
+        assert_that!(constructed.is_err()).is_true();
+    }
+}
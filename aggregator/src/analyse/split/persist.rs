@@ -1,6 +1,7 @@
 use anyhow::{Context, Result};
 use diesel::dsl::now;
 use diesel::{prelude::*, PgConnection};
+use ipnet::Ipv6Net;
 use itertools::Itertools;
 use log::warn;
 use prefix_crab::blocklist::PrefixBlocklist;
@@ -10,6 +11,9 @@ use crate::analyse::split::subnet::Subnet;
 use crate::analyse::SplitAnalysis;
 use crate::persist::dsl::CidrMethods;
 use crate::persist::DieselErrorFixCause;
+use db_model::analyse::SplitExplanation;
+use db_model::prefix_tree::control;
+use db_model::prefix_tree::history::{self, HistoryCause, NodeState, StateChange};
 use db_model::prefix_tree::{ContextOps, LhrSetHash, MergeStatus, PrefixTree, PriorityClass};
 
 use super::recommend::{self, ReProbePriority, SplitRecommendation};
@@ -17,19 +21,23 @@ use super::recommend::{self, ReProbePriority, SplitRecommendation};
 use super::subnet::Subnets;
 use super::{context, Confidence, SplitAnalysisResult};
 
-#[instrument(skip(conn, context))]
+#[instrument(skip(conn, context, explanation))]
 pub fn save_recommendation(
     conn: &mut PgConnection,
     context: &context::Context,
     recommendation: &SplitRecommendation,
     confidence: Confidence,
     lhr_set_hash: LhrSetHash,
+    explanation: SplitExplanation,
 ) -> Result<()> {
+    let pinned_class = control::pinned_class(conn, &context.node().net)?;
     SaveRecommendation {
         context,
         recommendation,
         confidence,
         lhr_set_hash,
+        explanation,
+        pinned_class,
     }
     .save(conn)
 }
@@ -39,10 +47,17 @@ struct SaveRecommendation<'a, 'b> {
     pub recommendation: &'b SplitRecommendation,
     pub confidence: Confidence,
     lhr_set_hash: LhrSetHash,
+    explanation: SplitExplanation,
+    /// Set by an operator, takes precedence over the recommendation (which is still saved to the analysis)
+    pinned_class: Option<PriorityClass>,
 }
 
 impl<'a, 'b> SaveRecommendation<'a, 'b> {
     fn save(self, conn: &mut PgConnection) -> Result<()> {
+        conn.transaction(|conn| self.save_in_tx(conn))
+    }
+
+    fn save_in_tx(&self, conn: &mut PgConnection) -> Result<()> {
         match self.save_to_analysis(conn)? {
             1 => {}
             count => warn!(
@@ -59,15 +74,19 @@ impl<'a, 'b> SaveRecommendation<'a, 'b> {
                 &self.context.log_id()
             ),
         }
+        self.save_to_history(conn)?;
         Ok(())
     }
 
     fn save_to_analysis(&self, conn: &mut PgConnection) -> Result<usize> {
         use crate::schema::split_analysis::dsl::*;
 
-        let rec_model: Option<SplitAnalysisResult> = Some(self.recommendation.into());
+        let rec_model = SplitAnalysisResult {
+            explanation: Some(self.explanation.clone()),
+            ..self.recommendation.into()
+        };
         diesel::update(self.analysis())
-            .set((result.eq(rec_model), completed_at.eq(now)))
+            .set((result.eq(Some(rec_model)), completed_at.eq(now)))
             .execute(conn)
             .fix_cause()
             .with_context(|| {
@@ -97,9 +116,31 @@ impl<'a, 'b> SaveRecommendation<'a, 'b> {
             })
     }
 
+    fn save_to_history(&self, conn: &mut PgConnection) -> Result<usize> {
+        let node = self.context.node();
+        let change = StateChange::of(
+            node,
+            NodeState {
+                merge_status: node.merge_status,
+                priority_class: self.priority_class(),
+                confidence: self.confidence,
+            },
+        );
+        history::record(
+            conn,
+            HistoryCause::Analysis,
+            Some(self.analysis().id),
+            &[change],
+        )
+    }
+
+    fn priority_class(&self) -> PriorityClass {
+        self.pinned_class.unwrap_or(self.recommendation.priority().class)
+    }
+
     fn as_prefix_changeset(&self) -> PrefixRecommendationChangeset {
         PrefixRecommendationChangeset {
-            priority_class: self.recommendation.priority().class,
+            priority_class: self.priority_class(),
             confidence: self.confidence as i16,
             lhr_set_hash: self.lhr_set_hash,
         }
@@ -119,6 +160,7 @@ impl From<&SplitRecommendation> for SplitAnalysisResult {
         let ReProbePriority {
             class,
             supporting_observations,
+            ..
         } = *it.priority();
         let should_split = match it {
             SplitRecommendation::YesSplit { priority: _ } => Some(true),
@@ -130,6 +172,7 @@ impl From<&SplitRecommendation> for SplitAnalysisResult {
             evidence: supporting_observations,
             should_split,
             algo_version: recommend::ALGO_VERSION,
+            explanation: None,
         }
     }
 }
@@ -142,8 +185,15 @@ pub fn perform_prefix_split(
     blocklist: &PrefixBlocklist,
 ) -> Result<usize> {
     conn.transaction(|conn| {
+        let mut nets = subnets.iter().map(|it| it.subnet.network).collect_vec();
+        nets.push(context.node().net);
+        let before = history::load_for_change(conn, &nets)?;
+
         insert_split_subnets(conn, subnets, blocklist, context.node())?;
-        mark_parent_obsolete(conn, context.node())
+        let updated = mark_parent_obsolete(conn, context.node())?;
+
+        record_split(conn, &context, &nets, &before)?;
+        Ok::<usize, anyhow::Error>(updated)
     })
     .context("in tx to perform prefix split")
 }
@@ -189,6 +239,21 @@ fn insert_split_subnets(
         .context("inserting new split prefixes")
 }
 
+fn record_split(
+    conn: &mut PgConnection,
+    context: &context::Context,
+    nets: &[Ipv6Net],
+    before: &[PrefixTree],
+) -> Result<usize> {
+    let after = history::load_for_change(conn, nets)?;
+    history::record(
+        conn,
+        HistoryCause::Split,
+        Some(context.analysis.id),
+        &history::changes_between(before, &after),
+    )
+}
+
 fn mark_parent_obsolete(conn: &mut PgConnection, parent: &PrefixTree) -> Result<usize> {
     use crate::schema::prefix_tree::dsl::*;
 
@@ -202,9 +267,27 @@ fn mark_parent_obsolete(conn: &mut PgConnection, parent: &PrefixTree) -> Result<
 pub fn mark_as_blocked(conn: &mut PgConnection, context: &context::Context) -> Result<usize> {
     use crate::schema::prefix_tree::dsl::*;
 
-    diesel::update(context.node())
-        .set(merge_status.eq(MergeStatus::Blocked))
-        .execute(conn)
-        .fix_cause()
-        .context("marking parent node as blocked")
+    conn.transaction(|conn| {
+        let updated = diesel::update(context.node())
+            .set(merge_status.eq(MergeStatus::Blocked))
+            .execute(conn)
+            .fix_cause()
+            .context("marking parent node as blocked")?;
+
+        let node = context.node();
+        let change = StateChange::of(
+            node,
+            NodeState {
+                merge_status: MergeStatus::Blocked,
+                ..NodeState::from(node)
+            },
+        );
+        history::record(
+            conn,
+            HistoryCause::Blocked,
+            Some(context.analysis.id),
+            &[change],
+        )?;
+        Ok(updated)
+    })
 }
@@ -0,0 +1,397 @@
+use std::{collections::BTreeMap, f64::consts::PI, sync::Mutex};
+
+use db_model::analyse::{Confidence, HitCount, RatioTest, CONFIDENCE_THRESH};
+
+use super::subnet::LhrDiff;
+
+// The parameters are the conventional ones from Cohen's power analysis, instead of thresholds
+// tuned to our data: A split needs a significant difference of at least medium size, and we are
+// confident that there is no such difference once the test would detect it with 80% power.
+
+/// Probability of splitting although the ratios are the same
+pub const SIGNIFICANCE_LEVEL: f64 = 0.01;
+
+/// Cramér's V that a difference needs to be relevant, 0.3 is a "medium" effect. Very large
+/// samples would otherwise make any small difference in ratios significant.
+pub const MIN_EFFECT_SIZE: f64 = 0.3;
+
+/// Probability of detecting a difference of [MIN_EFFECT_SIZE] that we require to keep a prefix
+/// at [CONFIDENCE_THRESH]
+pub const POWER: f64 = 0.8;
+
+/// LHRs with fewer hits in total are left out of the test, so that a rarely seen router cannot
+/// decide on the ratio of the entire prefix.
+pub const MIN_LHR_HITS: HitCount = 4;
+
+/// Expected hits that every cell of the table needs for the chi-square approximation to hold.
+/// Below that, the test neither supports a split nor keeping the prefix.
+pub const MIN_EXPECTED_HITS: f64 = 5.0;
+
+/// Runs a chi-square test of homogeneity on the hits of the shared LHRs per subnet. [None] if
+/// there is nothing to compare, i.e. fewer than two LHRs with at least [MIN_LHR_HITS] or
+/// fewer than two subnets with hits.
+pub fn test(shared: &[LhrDiff]) -> Option<RatioTest> {
+    let rows: Vec<&[HitCount]> = shared
+        .iter()
+        .filter(|it| it.total_hit_count() >= MIN_LHR_HITS)
+        .map(|it| it.hit_counts.as_slice())
+        .collect();
+    let subnet_count = rows.first().map(|it| it.len()).unwrap_or(0);
+    let col_totals: Vec<f64> = (0..subnet_count)
+        .map(|i| rows.iter().map(|row| row[i] as f64).sum())
+        .collect();
+    let nonempty_cols = col_totals.iter().filter(|it| **it > 0.0).count();
+    if rows.len() < 2 || nonempty_cols < 2 {
+        return None;
+    }
+
+    let hits: f64 = col_totals.iter().sum();
+    let mut chi_square = 0.0;
+    let mut min_expected_hits = f64::INFINITY;
+    for row in rows.iter() {
+        let row_total: f64 = row.iter().map(|it| *it as f64).sum();
+        for (observed, col_total) in row.iter().zip(col_totals.iter()) {
+            if *col_total == 0.0 {
+                continue;
+            }
+            let expected = row_total * col_total / hits;
+            min_expected_hits = min_expected_hits.min(expected);
+            chi_square += (*observed as f64 - expected).powi(2) / expected;
+        }
+    }
+
+    let degrees_of_freedom = ((rows.len() - 1) * (nonempty_cols - 1)) as u32;
+    let min_dimension = rows.len().min(nonempty_cols) - 1;
+    let noncentrality = required_noncentrality(degrees_of_freedom);
+    Some(RatioTest {
+        chi_square,
+        degrees_of_freedom,
+        p_value: chi_square_sf(chi_square, degrees_of_freedom as f64),
+        effect_size: (chi_square / (hits * min_dimension as f64)).sqrt(),
+        hits: hits as HitCount,
+        required_hits: noncentrality / (MIN_EFFECT_SIZE.powi(2) * min_dimension as f64),
+        min_expected_hits,
+    })
+}
+
+/// Whether the expected hits suffice for the test to tell anything, see [MIN_EXPECTED_HITS]
+pub fn is_valid(test: &RatioTest) -> bool {
+    test.min_expected_hits >= MIN_EXPECTED_HITS
+}
+
+/// Whether the ratios differ significantly and relevantly
+pub fn is_different(test: &RatioTest) -> bool {
+    is_valid(test) && test.p_value < SIGNIFICANCE_LEVEL && test.effect_size >= MIN_EFFECT_SIZE
+}
+
+/// Confidence in a split scales with the p-value on a log scale, such that [SIGNIFICANCE_LEVEL]
+/// corresponds to [CONFIDENCE_THRESH]. Confidence in keeping scales with the hits relative to
+/// those needed to detect a relevant difference with the intended [POWER]. Stays below
+/// [CONFIDENCE_THRESH] if the test is not valid or contradicts the recommendation.
+pub fn confidence(test: &RatioTest, should_split: bool) -> Confidence {
+    let ratio = if should_split {
+        test.p_value.ln() / SIGNIFICANCE_LEVEL.ln()
+    } else {
+        test.hits as f64 / test.required_hits
+    };
+    // float -> int casts saturate, also for infinity if the p-value underflows
+    let confidence = (ratio * CONFIDENCE_THRESH as f64) as Confidence;
+    if is_valid(test) && is_different(test) == should_split {
+        confidence
+    } else {
+        confidence.min(CONFIDENCE_THRESH - 1)
+    }
+}
+
+/// Noncentrality (effect size squared times sample size) at which a chi-square test with
+/// the given degrees of freedom reaches [POWER] at [SIGNIFICANCE_LEVEL]. Cached, since it
+/// only depends on the degrees of freedom and takes a few hundred evaluations to find.
+fn required_noncentrality(degrees_of_freedom: u32) -> f64 {
+    static CACHE: Mutex<BTreeMap<u32, f64>> = Mutex::new(BTreeMap::new());
+
+    // a poisoned lock only means another thread panicked while inserting, the values are fine
+    let mut cache = CACHE.lock().unwrap_or_else(|it| it.into_inner());
+    *cache
+        .entry(degrees_of_freedom)
+        .or_insert_with(|| find_required_noncentrality(degrees_of_freedom))
+}
+
+fn find_required_noncentrality(degrees_of_freedom: u32) -> f64 {
+    let dof = degrees_of_freedom as f64;
+    let critical = bisect(|x| chi_square_sf(x, dof) <= SIGNIFICANCE_LEVEL);
+    bisect(|noncentrality| noncentral_chi_square_sf(critical, dof, noncentrality) >= POWER)
+}
+
+/// Smallest non-negative x for which the monotone `reached` holds, to high precision
+fn bisect(reached: impl Fn(f64) -> bool) -> f64 {
+    let (mut low, mut high) = (0.0, 1.0);
+    while !reached(high) {
+        low = high;
+        high *= 2.0;
+    }
+    for _ in 0..100 {
+        let mid = (low + high) / 2.0;
+        if reached(mid) {
+            high = mid;
+        } else {
+            low = mid;
+        }
+    }
+    high
+}
+
+/// Survival function (1 - CDF) of the chi-square distribution
+fn chi_square_sf(x: f64, dof: f64) -> f64 {
+    if x <= 0.0 {
+        1.0
+    } else {
+        upper_incomplete_gamma(dof / 2.0, x / 2.0)
+    }
+}
+
+/// Survival function of the noncentral chi-square distribution, as Poisson-weighted mixture
+/// of central ones
+fn noncentral_chi_square_sf(x: f64, dof: f64, noncentrality: f64) -> f64 {
+    let half = noncentrality / 2.0;
+    let mut weight = (-half).exp();
+    let mut result = 0.0;
+    let mut j = 0.0;
+    loop {
+        result += weight * chi_square_sf(x, dof + 2.0 * j);
+        j += 1.0;
+        weight *= half / j;
+        if j > half && weight < 1e-12 {
+            return result;
+        }
+    }
+}
+
+const MAX_ITERATIONS: usize = 1000;
+const EPSILON: f64 = 1e-14;
+
+/// Regularised upper incomplete gamma function Q(a, x), see Numerical Recipes 6.2
+fn upper_incomplete_gamma(a: f64, x: f64) -> f64 {
+    let prefactor = (-x + a * x.ln() - ln_gamma(a)).exp();
+    if x < a + 1.0 {
+        // series for the lower function converges quickly here
+        let (mut denominator, mut term) = (a, 1.0 / a);
+        let mut sum = term;
+        for _ in 0..MAX_ITERATIONS {
+            denominator += 1.0;
+            term *= x / denominator;
+            sum += term;
+            if term.abs() < sum.abs() * EPSILON {
+                break;
+            }
+        }
+        (1.0 - sum * prefactor).max(0.0)
+    } else {
+        // continued fraction, modified Lentz's method
+        let tiny = f64::MIN_POSITIVE / EPSILON;
+        let mut b = x + 1.0 - a;
+        let mut c = 1.0 / tiny;
+        let mut d = 1.0 / b;
+        let mut fraction = d;
+        for i in 1..MAX_ITERATIONS {
+            let an = -(i as f64) * (i as f64 - a);
+            b += 2.0;
+            d = an * d + b;
+            if d.abs() < tiny {
+                d = tiny;
+            }
+            c = b + an / c;
+            if c.abs() < tiny {
+                c = tiny;
+            }
+            d = 1.0 / d;
+            let delta = d * c;
+            fraction *= delta;
+            if (delta - 1.0).abs() < EPSILON {
+                break;
+            }
+        }
+        prefactor * fraction
+    }
+}
+
+/// Lanczos approximation of ln(Γ(x)) for x > 0
+fn ln_gamma(x: f64) -> f64 {
+    const G: f64 = 7.0;
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+
+    if x < 0.5 {
+        // reflection formula
+        return (PI / (PI * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+    let x = x - 1.0;
+    let t = x + G + 0.5;
+    let series = COEFFICIENTS
+        .iter()
+        .enumerate()
+        .skip(1)
+        .fold(COEFFICIENTS[0], |acc, (i, c)| acc + c / (x + i as f64));
+    0.5 * (2.0 * PI).ln() + (x + 0.5) * t.ln() - t + series.ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use assertor::*;
+    use db_model::test_utils::*;
+
+    use super::*;
+
+    fn given_lhrs(hits: &[&[HitCount]]) -> Vec<LhrDiff> {
+        [TREE_LHR_101, TREE_LHR_BEEF, "2001:db8:baba::1"]
+            .iter()
+            .zip(hits)
+            .map(|(lhr, hit_counts)| LhrDiff {
+                addr: addr(lhr),
+                sources: HashSet::new(),
+                hit_counts: hit_counts.to_vec(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_nothing_to_compare() {
+        // given
+        let single_lhr = given_lhrs(&[&[20, 30]]);
+        let single_subnet = given_lhrs(&[&[20, 0], &[30, 0]]);
+
+        // when
+        let tests = [test(&single_lhr), test(&single_subnet)];
+
+        // then
+        assert_that!(tests).is_equal_to([None, None]);
+    }
+
+    #[test]
+    fn test_leaves_out_rare_lhrs() {
+        // given
+        let shared = given_lhrs(&[&[20, 30], &[20, 30], &[3, 0]]);
+
+        // when
+        let result = test(&shared).unwrap();
+
+        // then
+        assert_that!(result.degrees_of_freedom).is_equal_to(1);
+        assert_that!(result.hits).is_equal_to(100);
+        assert_that!(result.chi_square).is_less_than(1e-9);
+    }
+
+    #[test]
+    fn test_too_few_expected_hits() {
+        // given
+        let shared = given_lhrs(&[&[40, 8], &[8, 8]]);
+
+        // when
+        let result = test(&shared).unwrap();
+
+        // then
+        // significant and relevant on its own, but expects only 4 hits of beef in the right subnet
+        assert_that!(result.p_value).is_less_than(SIGNIFICANCE_LEVEL);
+        assert_that!(result.effect_size).is_at_least(MIN_EFFECT_SIZE);
+        assert_that!((result.min_expected_hits - 4.0).abs()).is_less_than(1e-9);
+        assert_that!(is_valid(&result)).is_false();
+        assert_that!(is_different(&result)).is_false();
+        assert_that!(confidence(&result, true)).is_less_than(CONFIDENCE_THRESH);
+        assert_that!(confidence(&result, false)).is_less_than(CONFIDENCE_THRESH);
+    }
+
+    #[test]
+    fn clearly_different() {
+        // given
+        let shared = given_lhrs(&[&[80, 16], &[16, 16]]);
+
+        // when
+        let result = test(&shared).unwrap();
+
+        // then
+        assert_that!(is_different(&result)).is_true();
+        assert_that!(confidence(&result, true)).is_at_least(CONFIDENCE_THRESH);
+        assert_that!(confidence(&result, false)).is_less_than(CONFIDENCE_THRESH);
+    }
+
+    #[test]
+    fn significant_but_small_difference() {
+        // given
+        let shared = given_lhrs(&[&[1300, 3100], &[800, 800]]);
+
+        // when
+        let result = test(&shared).unwrap();
+
+        // then
+        assert_that!(result.p_value).is_less_than(SIGNIFICANCE_LEVEL);
+        assert_that!(is_different(&result)).is_false();
+        assert_that!(confidence(&result, false)).is_at_least(CONFIDENCE_THRESH);
+        assert_that!(confidence(&result, true)).is_less_than(CONFIDENCE_THRESH);
+    }
+
+    #[test]
+    fn keep_confidence_grows_with_hits() {
+        // given
+        let few = given_lhrs(&[&[10, 10], &[10, 10]]);
+        let enough = given_lhrs(&[&[40, 40], &[40, 40]]);
+
+        // when
+        let few = test(&few).unwrap();
+        let enough = test(&enough).unwrap();
+
+        // then
+        // about 130 hits are needed to detect a medium effect with one degree of freedom
+        assert_that!(confidence(&few, false)).is_less_than(CONFIDENCE_THRESH);
+        assert_that!(confidence(&enough, false)).is_at_least(CONFIDENCE_THRESH);
+    }
+
+    #[test]
+    fn required_noncentrality_cached() {
+        // given
+        let dof = 3;
+
+        // when
+        let first = required_noncentrality(dof);
+        let cached = required_noncentrality(dof);
+
+        // then
+        assert_that!(cached).is_equal_to(first);
+        assert_that!(cached).is_equal_to(find_required_noncentrality(dof));
+    }
+
+    #[test]
+    fn chi_square_critical_value() {
+        // given
+        let critical_for_5_pct = 3.841_458_820_694_124;
+
+        // when
+        let p_value = chi_square_sf(critical_for_5_pct, 1.0);
+
+        // then
+        assert_that!((p_value - 0.05).abs()).is_less_than(1e-9);
+    }
+
+    #[test]
+    fn required_noncentrality_matches_cohen() {
+        // given
+        let dof = 1;
+
+        // when
+        let noncentrality = required_noncentrality(dof);
+
+        // then
+        // Cohen (1988), Table 7.4.6: n = 1168 for w = 0.1 at a = 0.01, power 0.8
+        let hits_for_small_effect = noncentrality / 0.1f64.powi(2);
+        assert_that!((hits_for_small_effect - 1168.0).abs()).is_less_than(2.0);
+    }
+}
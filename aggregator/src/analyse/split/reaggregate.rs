@@ -0,0 +1,491 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{bail, Context as _, Result};
+use db_model::{
+    persist::dsl::CidrMethods,
+    persist::DieselErrorFixCause,
+    prefix_tree::{
+        self,
+        context::ContextFetchError,
+        history::{self, HistoryCause},
+        lock::with_subtree_lock,
+        ContextOps, MergeStatus, PrefixTree,
+    },
+};
+use diesel::{prelude::*, PgConnection};
+use ipnet::{IpNet, Ipv6Net};
+use itertools::Itertools;
+use log::{debug, info, warn};
+use tracing::instrument;
+
+use crate::analyse::MeasurementTree;
+
+use super::{
+    collapse, confidence, load_relevant_measurements, merge_redundant::AdjacentNets, recommend,
+    subnet::Subnets, CONFIDENCE_THRESH,
+};
+
+/// Walks the prefix tree bottom-up and merges sibling leaves back into their parent if they have
+/// all converged to the same last-hop routers and the measurements of the parent no longer support
+/// the split. Merged parents are considered again on the next level, so that wrong early splits
+/// can be undone over multiple levels in a single pass.
+///
+/// Returns the amount of merges that were performed.
+#[instrument(name = "re-aggregate", skip_all)]
+pub fn process(conn: &mut PgConnection) -> Result<usize> {
+    let candidates = load_candidates(conn)?;
+    let split_nets = load_split_nets(conn)?;
+    Ok(merge_bottom_up(candidates, split_nets, |nets| {
+        with_subtree_lock(conn, &nets.parent, |conn| try_merge(conn, nets))
+    }))
+}
+
+/// Offers each group of settled siblings to `try_merge`, starting at the longest prefixes.
+/// Returns the amount of merges that were performed.
+fn merge_bottom_up(
+    candidates: Vec<PrefixTree>,
+    mut split_nets: HashSet<Ipv6Net>,
+    mut try_merge: impl FnMut(&AdjacentNets) -> Result<Option<PrefixTree>>,
+) -> usize {
+    let mut candidates: HashMap<Ipv6Net, PrefixTree> = candidates
+        .into_iter()
+        .map(|node| (node.net, node))
+        .collect();
+    let mut merged_count = 0usize;
+
+    for prefix_len in (1..=64u8).rev() {
+        let level = candidates
+            .keys()
+            .filter(|it| it.prefix_len() == prefix_len)
+            .copied()
+            .sorted()
+            .collect_vec();
+
+        for own_net in level {
+            // may already have been handled as sibling of another net
+            let Some(own) = candidates.get(&own_net).copied() else {
+                continue;
+            };
+            let Some(nets) = AdjacentNets::find(own_net, |it| split_nets.contains(it)) else {
+                continue;
+            };
+            let siblings = nets
+                .siblings
+                .iter()
+                .map(|it| candidates.get(it).copied())
+                .collect::<Option<Vec<_>>>();
+            let Some(siblings) = siblings else {
+                continue;
+            };
+            if !siblings.iter().all(|sibling| are_candidates(&own, sibling)) {
+                continue;
+            }
+            for child in nets.children() {
+                candidates.remove(&child);
+            }
+
+            match try_merge(&nets) {
+                Ok(Some(merged)) => {
+                    merged_count += 1;
+                    split_nets.remove(&merged.net);
+                    candidates.insert(merged.net, merged);
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Failed to re-aggregate {} - {:?}", nets.parent, e),
+            }
+        }
+    }
+
+    merged_count
+}
+
+fn load_candidates(conn: &mut PgConnection) -> Result<Vec<PrefixTree>> {
+    use db_model::schema::prefix_tree::dsl::*;
+
+    prefix_tree
+        .filter(merge_status.eq_any([MergeStatus::Leaf, MergeStatus::MinSizeReached]))
+        .select(PrefixTree::as_select())
+        .load(conn)
+        .fix_cause()
+        .context("loading leaves for re-aggregation")
+}
+
+/// Nodes that children can have been split from, to find the parent of candidates.
+fn load_split_nets(conn: &mut PgConnection) -> Result<HashSet<Ipv6Net>> {
+    use db_model::schema::prefix_tree::dsl::*;
+
+    let nodes = prefix_tree
+        .filter(merge_status.eq_any([MergeStatus::SplitDown, MergeStatus::SplitRoot]))
+        .select(PrefixTree::as_select())
+        .load(conn)
+        .fix_cause()
+        .context("loading split nodes for re-aggregation")?;
+    Ok(nodes.into_iter().map(|it| it.net).collect())
+}
+
+/// Cheap pre-check without touching the measurements, which are only loaded for siblings that
+/// pass this.
+fn are_candidates(left: &PrefixTree, right: &PrefixTree) -> bool {
+    is_settled(left)
+        && is_settled(right)
+        && !left.lhr_set_hash.is_nil() // nil means unknown
+        && left.lhr_set_hash == right.lhr_set_hash
+}
+
+fn is_settled(node: &PrefixTree) -> bool {
+    match node.merge_status {
+        // never analysed on their own, so we need to rely on the re-computed recommendation
+        MergeStatus::MinSizeReached => true,
+        MergeStatus::Leaf => node.confidence >= CONFIDENCE_THRESH,
+        _ => false,
+    }
+}
+
+/// Re-runs the split recommendation for the parent on the current measurements, and merges
+/// the children if it is now confident that the parent should be kept. Basing this on the
+/// same logic as the split itself ensures that we don't flip-flop between the two.
+fn try_merge(conn: &mut PgConnection, nets: &AdjacentNets) -> Result<Option<PrefixTree>> {
+    let parent = match prefix_tree::context::fetch(conn, &nets.parent) {
+        Ok(it) => it,
+        Err(ContextFetchError::NotInPrefixTree { net: _ }) => return Ok(None),
+        Err(ContextFetchError::DbError(e)) => return Err(e),
+    };
+    let measurements = load_relevant_measurements(conn, &nets.parent)?;
+    let Some(merged) = merged_parent(parent.node(), nets, &measurements)? else {
+        return Ok(None);
+    };
+    conn.transaction(|conn| {
+        merge(conn, nets, &merged)?;
+        collapse::process(conn, &parent, measurements)
+    })?;
+    Ok(Some(merged))
+}
+
+/// The parent as it would look after merging the children, if the measurements support that.
+fn merged_parent(
+    parent: &PrefixTree,
+    nets: &AdjacentNets,
+    measurements: &[MeasurementTree],
+) -> Result<Option<PrefixTree>> {
+    let Some(merged_status) = parent.merge_status.merge() else {
+        debug!("Parent {} is not eligible for merge", parent.net);
+        return Ok(None);
+    };
+
+    let subnets = Subnets::new(nets.parent, nets.split_bits(), measurements)?;
+    let rec = recommend::recommend(&subnets);
+    let confidence = confidence::rate(nets.parent, &rec);
+
+    if rec.should_split() || confidence < CONFIDENCE_THRESH {
+        debug!(
+            "Not re-aggregating {} due to recommendation {:?} at {}% confidence.",
+            parent.net, rec, confidence
+        );
+        return Ok(None);
+    }
+
+    info!(
+        "Re-aggregating {} due to recommendation {:?} at {}% confidence.",
+        parent.net, rec, confidence
+    );
+    Ok(Some(PrefixTree {
+        merge_status: merged_status,
+        priority_class: rec.priority().class,
+        confidence,
+        lhr_set_hash: subnets.combined_lhr_set_hash(),
+        ..*parent
+    }))
+}
+
+fn merge(conn: &mut PgConnection, nets: &AdjacentNets, merged: &PrefixTree) -> Result<()> {
+    use db_model::schema::prefix_tree::dsl::*;
+
+    let children = nets.children();
+    let affected = nets.affected();
+    let before = history::load_for_change(conn, &affected)?;
+
+    let n_updated_children = diesel::update(prefix_tree)
+        .filter(
+            merge_status
+                .eq_any([MergeStatus::Leaf, MergeStatus::MinSizeReached])
+                .and(net.eq_any(children.iter().map(|it| IpNet::V6(*it)))),
+        )
+        .set(merge_status.eq(MergeStatus::MergedUp))
+        .execute(conn)
+        .fix_cause()?;
+    if n_updated_children != children.len() {
+        bail!(
+            "One of the children didn't exist or wasn't a leaf any more - {}",
+            n_updated_children
+        );
+    }
+
+    let n_updated_parent = diesel::update(prefix_tree)
+        .filter(net.eq6(&nets.parent))
+        .set((
+            merge_status.eq(merged.merge_status),
+            priority_class.eq(merged.priority_class),
+            confidence.eq(merged.confidence as i16),
+            lhr_set_hash.eq(merged.lhr_set_hash),
+        ))
+        .execute(conn)
+        .fix_cause()?;
+    if n_updated_parent != 1 {
+        bail!("Failed to update parent - {}", n_updated_parent);
+    }
+
+    let after = history::load_for_change(conn, &affected)?;
+    history::record(
+        conn,
+        HistoryCause::Reaggregate,
+        None,
+        &history::changes_between(&before, &after),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use assertor::*;
+    use chrono::NaiveDateTime;
+    use db_model::{
+        prefix_tree::{LhrSetHash, PriorityClass},
+        test_utils::*,
+    };
+
+    use super::*;
+
+    fn node(net_str: &str, status: MergeStatus, conf: u8, hash: u128) -> PrefixTree {
+        PrefixTree {
+            net: net(net_str),
+            created_at: NaiveDateTime::default(),
+            updated_at: NaiveDateTime::default(),
+            merge_status: status,
+            priority_class: PriorityClass::MediumSameSingle,
+            confidence: conf,
+            lhr_set_hash: LhrSetHash::from_u128(hash),
+            asn: 0,
+        }
+    }
+
+    #[test]
+    fn candidates_same_hash_confident() {
+        // given
+        let left = node("2001:db8::/33", MergeStatus::Leaf, 100, 0xbeef);
+        let right = node("2001:db8:8000::/33", MergeStatus::Leaf, 255, 0xbeef);
+
+        // when
+        let actual = are_candidates(&left, &right);
+
+        // then
+        assert_that!(actual).is_true();
+    }
+
+    #[test]
+    fn not_candidates_different_hash() {
+        // given
+        let left = node("2001:db8::/33", MergeStatus::Leaf, 255, 0xbeef);
+        let right = node("2001:db8:8000::/33", MergeStatus::Leaf, 255, 0x101);
+
+        // when
+        let actual = are_candidates(&left, &right);
+
+        // then
+        assert_that!(actual).is_false();
+    }
+
+    #[test]
+    fn not_candidates_unknown_hash() {
+        // given
+        let left = node("2001:db8::/33", MergeStatus::Leaf, 255, 0);
+        let right = node("2001:db8:8000::/33", MergeStatus::Leaf, 255, 0);
+
+        // when
+        let actual = are_candidates(&left, &right);
+
+        // then
+        assert_that!(actual).is_false();
+    }
+
+    #[test]
+    fn not_candidates_insufficient_confidence() {
+        // given
+        let left = node("2001:db8::/33", MergeStatus::Leaf, 255, 0xbeef);
+        let right = node("2001:db8:8000::/33", MergeStatus::Leaf, 99, 0xbeef);
+
+        // when
+        let actual = are_candidates(&left, &right);
+
+        // then
+        assert_that!(actual).is_false();
+    }
+
+    #[test]
+    fn candidates_min_size_reached() {
+        // given
+        let left = node("2001:db8::/64", MergeStatus::MinSizeReached, 0, 0xbeef);
+        let right = node("2001:db8:0:1::/64", MergeStatus::MinSizeReached, 0, 0xbeef);
+
+        // when
+        let actual = are_candidates(&left, &right);
+
+        // then
+        assert_that!(actual).is_true();
+    }
+
+    #[test]
+    fn merge_bottom_up_cascades() {
+        // given
+        let candidates = vec![
+            node("2001:db8::/64", MergeStatus::MinSizeReached, 0, 0xbeef),
+            node("2001:db8:0:1::/64", MergeStatus::MinSizeReached, 0, 0xbeef),
+            node("2001:db8:0:2::/64", MergeStatus::MinSizeReached, 0, 0xbeef),
+            node("2001:db8:0:3::/64", MergeStatus::MinSizeReached, 0, 0xbeef),
+        ];
+        let split_nets = ["2001:db8::/62", "2001:db8::/63", "2001:db8:0:2::/63"]
+            .into_iter()
+            .map(net)
+            .collect();
+        let mut merged_parents = vec![];
+
+        // when
+        let merged_count = merge_bottom_up(candidates, split_nets, |nets| {
+            merged_parents.push(nets.parent);
+            Ok(Some(node(
+                &nets.parent.to_string(),
+                MergeStatus::Leaf,
+                100,
+                0xbeef,
+            )))
+        });
+
+        // then
+        assert_that!(merged_count).is_equal_to(3);
+        assert_that!(merged_parents).contains_exactly_in_order(vec![
+            net("2001:db8::/63"),
+            net("2001:db8:0:2::/63"),
+            net("2001:db8::/62"),
+        ]);
+    }
+
+    #[test]
+    fn merge_bottom_up_needs_all_siblings() {
+        // given
+        let candidates = vec![node(
+            "2001:db8::/64",
+            MergeStatus::MinSizeReached,
+            0,
+            0xbeef,
+        )];
+        let split_nets = [net("2001:db8::/63")].into_iter().collect();
+        let mut attempts = 0;
+
+        // when
+        let merged_count = merge_bottom_up(candidates, split_nets, |_| {
+            attempts += 1;
+            Ok(None)
+        });
+
+        // then
+        assert_that!(merged_count).is_equal_to(0);
+        assert_that!(attempts).is_equal_to(0);
+    }
+
+    #[test]
+    fn merge_bottom_up_stops_at_failed_merge() {
+        // given
+        let candidates = vec![
+            node("2001:db8::/64", MergeStatus::MinSizeReached, 0, 0xbeef),
+            node("2001:db8:0:1::/64", MergeStatus::MinSizeReached, 0, 0xbeef),
+            node("2001:db8:0:2::/63", MergeStatus::Leaf, 255, 0xbeef),
+        ];
+        let split_nets = [net("2001:db8::/62"), net("2001:db8::/63")]
+            .into_iter()
+            .collect();
+        let mut merged_parents = vec![];
+
+        // when
+        let merged_count = merge_bottom_up(candidates, split_nets, |nets| {
+            merged_parents.push(nets.parent);
+            Err(anyhow!("locked elsewhere"))
+        });
+
+        // then
+        assert_that!(merged_count).is_equal_to(0);
+        assert_that!(merged_parents).contains_exactly(vec![net("2001:db8::/63")]);
+    }
+
+    fn adjacent_64s() -> AdjacentNets {
+        AdjacentNets::new(net("2001:db8::/64"), net("2001:db8::/63")).unwrap()
+    }
+
+    #[test]
+    fn merged_parent_same_lhr() {
+        // given
+        let parent = node("2001:db8::/63", MergeStatus::SplitDown, 0, 0);
+        let measurements = vec![
+            gen_tree_with_lhr_101("2001:db8::/64", 40),
+            gen_tree_with_lhr_101("2001:db8:0:1::/64", 40),
+        ];
+
+        // when
+        let merged = merged_parent(&parent, &adjacent_64s(), &measurements).unwrap();
+
+        // then
+        let merged = merged.expect("merge for equivalent children");
+        assert_that!(merged.net).is_equal_to(parent.net);
+        assert_that!(merged.merge_status).is_equal_to(MergeStatus::Leaf);
+        assert_that!(merged.priority_class).is_equal_to(PriorityClass::MediumSameSingle);
+        assert_that!(merged.confidence).is_at_least(CONFIDENCE_THRESH);
+        assert_that!(merged.lhr_set_hash.is_nil()).is_false();
+    }
+
+    #[test]
+    fn merged_parent_insufficient_evidence() {
+        // given
+        let parent = node("2001:db8::/63", MergeStatus::SplitDown, 0, 0);
+        let measurements = vec![
+            gen_tree_with_lhr_101("2001:db8::/64", 10),
+            gen_tree_with_lhr_101("2001:db8:0:1::/64", 10),
+        ];
+
+        // when
+        let merged = merged_parent(&parent, &adjacent_64s(), &measurements).unwrap();
+
+        // then
+        assert_that!(merged.is_none()).is_true();
+    }
+
+    #[test]
+    fn merged_parent_still_distinct() {
+        // given
+        let parent = node("2001:db8::/63", MergeStatus::SplitDown, 0, 0);
+        let measurements = vec![
+            gen_tree_with_lhr_101("2001:db8::/64", 400),
+            gen_tree_with_lhr_beef("2001:db8:0:1::/64", 400),
+        ];
+
+        // when
+        let merged = merged_parent(&parent, &adjacent_64s(), &measurements).unwrap();
+
+        // then
+        assert_that!(merged.is_none()).is_true();
+    }
+
+    #[test]
+    fn merged_parent_not_split() {
+        // given
+        let parent = node("2001:db8::/63", MergeStatus::Leaf, 0, 0);
+        let measurements = vec![
+            gen_tree_with_lhr_101("2001:db8::/64", 40),
+            gen_tree_with_lhr_101("2001:db8:0:1::/64", 40),
+        ];
+
+        // when
+        let merged = merged_parent(&parent, &adjacent_64s(), &measurements).unwrap();
+
+        // then
+        assert_that!(merged.is_none()).is_true();
+    }
+}
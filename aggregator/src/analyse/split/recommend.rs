@@ -1,19 +1,38 @@
+use itertools::Itertools;
 use log::trace;
 
 use crate::analyse::{HitCount, WeirdItem};
-use db_model::prefix_tree::PriorityClass;
+use db_model::{analyse::RatioTest, prefix_tree::PriorityClass};
 
-use super::subnet::{LhrDiff, Subnets};
+use super::{
+    ratio_test,
+    subnet::{LhrDiff, Subnets},
+};
 
 /// Changes in the split algorithm are versioned to allow us to invalidate results of an older version
 /// if we find out that it is flawed.
-pub const ALGO_VERSION: i32 = 120;
+pub const ALGO_VERSION: i32 = 131;
 
-#[derive(Debug, Eq, PartialEq)]
+/// Last version that compared the ratios of shared LHRs with a fixed threshold instead of a
+/// [ratio_test], kept to replay against
+pub const FIXED_RATIO_ALGO_VERSION: i32 = 120;
+
+/// Versions that [recommend_as] can reproduce
+pub const REPLAYABLE_ALGO_VERSIONS: [i32; 2] = [FIXED_RATIO_ALGO_VERSION, ALGO_VERSION];
+
+/// From this many LHRs shared by all subnets, their ratios are no longer compared
+pub const SAME_MANY_LHRS: usize = 5;
+
+/// Percentage points that the ratios of a shared LHR may differ by between subnets in
+/// [FIXED_RATIO_ALGO_VERSION]. We allow more leeway for the percent difference. Rejecting a split
+/// isn't a huge problem since we usually get many attempts to split but not many to merge/revert.
+const SAME_RATIO_THRESH: HitCount = 15;
+
+#[derive(Debug, PartialEq)]
 pub enum SplitRecommendation {
-    /// Two different subnets have been detected & a split is suggested
+    /// Different subnets have been detected & a split is suggested
     YesSplit { priority: ReProbePriority },
-    /// The two subnets look similar & a split is not suggested.
+    /// The subnets look similar & a split is not suggested.
     NoKeep { priority: ReProbePriority },
     /// An action could not be determined
     CannotDetermine { priority: ReProbePriority },
@@ -33,13 +52,22 @@ impl<'a> SplitRecommendation {
     }
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, PartialEq)]
 pub struct ReProbePriority {
     pub class: PriorityClass,
     pub supporting_observations: HitCount,
+    /// If the ratios of shared LHRs were compared, this determines the confidence
+    pub ratio_test: Option<RatioTest>,
 }
 
 pub fn recommend(subnets: &Subnets) -> SplitRecommendation {
+    recommend_as(subnets, ALGO_VERSION)
+}
+
+/// Recommends as the given version of the algorithm, which must be one of
+/// [REPLAYABLE_ALGO_VERSIONS]
+pub fn recommend_as(subnets: &Subnets, algo_version: i32) -> SplitRecommendation {
+    debug_assert!(REPLAYABLE_ALGO_VERSIONS.contains(&algo_version));
     use super::subnet::Diff as D;
     use PriorityClass as P;
     use SplitRecommendation as R;
@@ -47,14 +75,15 @@ pub fn recommend(subnets: &Subnets) -> SplitRecommendation {
     let diff = subnets.lhr_diff();
     trace!("LHR diff: {:?}", diff);
     match diff {
-        D::BothNone => recommend_without_lhr_data(subnets),
-        D::BothSameSingle { shared } => R::NoKeep {
+        D::AllNone => recommend_without_lhr_data(subnets),
+        D::AllSameSingle { shared } => R::NoKeep {
             priority: ReProbePriority {
                 class: P::MediumSameSingle,
                 supporting_observations: shared.total_hit_count(),
+                ratio_test: None,
             },
         },
-        D::BothSameMultiple { shared } => rate_same_multi(shared),
+        D::AllSameMultiple { shared } => rate_same_multi(shared, algo_version),
         D::OverlappingOrDisjoint { shared, distinct } => R::YesSplit {
             priority: ReProbePriority {
                 class: if shared.is_empty() {
@@ -64,20 +93,45 @@ pub fn recommend(subnets: &Subnets) -> SplitRecommendation {
                 },
                 supporting_observations: sum_deranking_most_popular(shared)
                     + sum_lhr_hits(distinct), // supporting the observation that there is more than one LHR a) where the overall set is the same and b) the sets are distinct
+                ratio_test: None,
             },
         },
     }
 }
 
-fn rate_same_multi(shared: Vec<LhrDiff>) -> SplitRecommendation {
+/// Keeps a split recommendation only if the measurements of each vantage point on their own
+/// also recommend a split. Prefixes that are reached via different paths from different
+/// vantage points (e.g. anycast) then aren't split for that reason alone.
+pub fn require_vantage_agreement(
+    subnets: &Subnets,
+    rec: SplitRecommendation,
+    algo_version: i32,
+) -> SplitRecommendation {
+    let SplitRecommendation::YesSplit { priority } = rec else {
+        return rec;
+    };
+    let vantage_points = subnets.vantage_points();
+    let disagreeing = vantage_points
+        .iter()
+        .filter(|it| !recommend_as(&subnets.for_vantage_point(it), algo_version).should_split())
+        .collect_vec();
+    if vantage_points.len() < 2 || disagreeing.is_empty() {
+        SplitRecommendation::YesSplit { priority }
+    } else {
+        trace!("Vantage points {:?} don't agree to split", disagreeing);
+        SplitRecommendation::CannotDetermine { priority }
+    }
+}
+
+fn rate_same_multi(shared: Vec<LhrDiff>, algo_version: i32) -> SplitRecommendation {
     use PriorityClass as P;
     use SplitRecommendation as R;
 
-    // What same-multi is trying to accomplish is to detect cases where both subnets are divided into parts
+    // What same-multi is trying to accomplish is to detect cases where all subnets are divided into parts
     // that are each served by the same routing infrastructure (no prefix-local routers) and thus have the same
     // LHR set, but would result in significant grouping if split further.
     //
-    // However, if the allocated ratio is the same in both subnets, the ratio would be the same either way.
+    // However, if the allocated ratio is the same in all subnets, the ratio would be the same either way.
     // We have now reduced to a much softer version that really only splits in extreme cases, because there
     // have been so many problems with too aggressive splits, and not really a good case that what we are trying to
     // check for here is even really very relevant at all.
@@ -87,8 +141,8 @@ fn rate_same_multi(shared: Vec<LhrDiff>) -> SplitRecommendation {
     // current data, there is even a somewhat contiguous group of recursive-subnets that has only one of the LHRs
     // and is significantly different from the rest.
 
-    if shared.len() >= 5 {
-        // if we have too many LHRs, a ratio is no longer really meaningful and if both subnets have the same
+    if shared.len() >= SAME_MANY_LHRS {
+        // if we have too many LHRs, a ratio is no longer really meaningful and if all subnets have the same
         // LHR set then most likely they are equivalent.
         return R::NoKeep {
             priority: ReProbePriority {
@@ -97,34 +151,59 @@ fn rate_same_multi(shared: Vec<LhrDiff>) -> SplitRecommendation {
                 // ignoring the five most popular ones is a bit much -> impractical
                 // so just apply a general 75% buff (more LHRs might show up if we keep probing)
                 supporting_observations: sum_lhr_hits(shared).div_euclid(4),
+                ratio_test: None,
             },
         };
     }
 
-    let total_per_subnet: Vec<HitCount> = (0..2usize)
-        .map(|i| shared.iter().map(|it| it.hit_counts[i]).sum())
-        .collect();
+    if algo_version == FIXED_RATIO_ALGO_VERSION {
+        return rate_same_multi_fixed_ratio(shared);
+    }
+
+    // A chi-square test tells us whether the LHRs serve different shares of the subnets. We only split for a
+    // relevant effect size, since with enough hits, even tiny differences in the ratio are significant.
+    let Some(test) = ratio_test::test(&shared) else {
+        return R::NoKeep {
+            priority: ReProbePriority {
+                class: P::MediumSameRatio,
+                supporting_observations: sum_lhr_hits(shared),
+                ratio_test: None,
+            },
+        };
+    };
+    trace!("Ratio test: {:?}", test);
 
-    // We allow more leeway for the percent difference.
-    // Rejecting a split isn't a huge problem since we usually get many attempts to split but not many to merge/revert.
-    let thresh = 15; // % difference is allowed
-
-    let mut ratio_is_same = true;
-    for diff in shared.iter() {
-        let [left, right] = diff.hit_counts;
-        let both_significant = left > 3 && right > 3;
-        let (left, right) = (left * 100, right * 100);
-        let (left, right) = (
-            left.saturating_div(total_per_subnet[0]),
-            right.saturating_div(total_per_subnet[1]),
-        );
-
-        let pct_diff = left.abs_diff(right);
-        if pct_diff > thresh && both_significant { // don't allow 3 or fewer hits to reject the ratio of the entire prefix
-            ratio_is_same = false;
-            break;
+    if ratio_test::is_different(&test) {
+        R::YesSplit {
+            priority: ReProbePriority {
+                class: P::MediumSameMulti,
+                // supporting the observation that there is more than one LHR
+                supporting_observations: sum_deranking_most_popular(shared),
+                ratio_test: Some(test),
+            },
+        }
+    } else {
+        R::NoKeep {
+            priority: ReProbePriority {
+                class: P::MediumSameRatio,
+                // supporting the observation that the ratio is the same
+                supporting_observations: sum_lhr_hits(shared),
+                ratio_test: Some(test),
+            },
         }
     }
+}
+
+fn rate_same_multi_fixed_ratio(shared: Vec<LhrDiff>) -> SplitRecommendation {
+    use PriorityClass as P;
+    use SplitRecommendation as R;
+
+    let ratio_is_same = fixed_ratio_pcts(&shared).into_iter().all(|pcts| {
+        let Some((min_pct, max_pct)) = pcts.into_iter().flatten().minmax().into_option() else {
+            return true;
+        };
+        min_pct.abs_diff(max_pct) <= SAME_RATIO_THRESH.unsigned_abs()
+    });
 
     if ratio_is_same {
         R::NoKeep {
@@ -132,6 +211,7 @@ fn rate_same_multi(shared: Vec<LhrDiff>) -> SplitRecommendation {
                 class: P::MediumSameRatio,
                 // supporting the observation that the ratio is the same
                 supporting_observations: sum_lhr_hits(shared),
+                ratio_test: None,
             },
         }
     } else {
@@ -140,11 +220,32 @@ fn rate_same_multi(shared: Vec<LhrDiff>) -> SplitRecommendation {
                 class: P::MediumSameMulti,
                 // supporting the observation that there is more than one LHR
                 supporting_observations: sum_deranking_most_popular(shared),
+                ratio_test: None,
             },
         }
     }
 }
 
+/// Share of each shared LHR in the hits of each subnet in percent, in the order of `shared`.
+/// Shares with 3 or fewer hits are [None], so that they can't reject the ratio of the entire prefix.
+fn fixed_ratio_pcts(shared: &[LhrDiff]) -> Vec<Vec<Option<HitCount>>> {
+    let subnet_count = shared.first().map(|it| it.hit_counts.len()).unwrap_or(0);
+    let total_per_subnet: Vec<HitCount> = (0..subnet_count)
+        .map(|i| shared.iter().map(|it| it.hit_counts[i]).sum())
+        .collect();
+
+    shared
+        .iter()
+        .map(|diff| {
+            diff.hit_counts
+                .iter()
+                .zip(total_per_subnet.iter())
+                .map(|(hits, total)| (*hits > 3).then(|| (hits * 100).saturating_div(*total)))
+                .collect()
+        })
+        .collect()
+}
+
 fn sum_deranking_most_popular(lhrs: Vec<LhrDiff>) -> HitCount {
     let most_popular_hits = lhrs
         .iter()
@@ -176,24 +277,27 @@ fn recommend_without_lhr_data(subnets: &Subnets) -> SplitRecommendation {
     let diff = subnets.weird_diff();
     trace!("Weirdness diff: {:?}", diff);
     match diff {
-        BothNone => CannotDetermine {
+        AllNone => CannotDetermine {
             priority: ReProbePriority {
                 class: LowUnknown,
                 supporting_observations: subnets.sum_subtrees(|t| t.unresponsive_count),
+                ratio_test: None,
             },
         },
-        BothSameSingle { shared } => NoKeep {
+        AllSameSingle { shared } => NoKeep {
             priority: ReProbePriority {
                 class: LowWeird,
                 supporting_observations: shared.hit_count,
+                ratio_test: None,
             },
         },
-        BothSameMultiple { shared } => CannotDetermine {
+        AllSameMultiple { shared } => CannotDetermine {
             // TODO what should we do in this case, especially if it keeps being like this?
             // e.g. check ratio, or perform more analyses deeper into the tree, or group by /64 and see if the pattern can be split
             priority: ReProbePriority {
                 class: MediumMultiWeird,
                 supporting_observations: sum_weird_hits(shared),
+                ratio_test: None,
             },
         },
         OverlappingOrDisjoint {
@@ -203,6 +307,7 @@ fn recommend_without_lhr_data(subnets: &Subnets) -> SplitRecommendation {
             priority: ReProbePriority {
                 class: MediumMultiWeird,
                 supporting_observations: sum_weird_hits(distinct), // supporting the observation that the weirdness signatures are different
+                ratio_test: None,
             },
         },
     }
@@ -214,10 +319,10 @@ fn sum_weird_hits(weirds: Vec<WeirdItem>) -> HitCount {
 
 #[cfg(test)]
 mod tests {
-    use assertor::{assert_that, EqualityAssertion};
+    use assertor::{assert_that, BooleanAssertion, EqualityAssertion};
 
     use super::{PriorityClass::*, SplitRecommendation::*, *};
-    use crate::analyse::{split::subnet::Subnets, MeasurementTree};
+    use crate::analyse::{split::subnet::Subnets, LhrSource, MeasurementTree};
     use db_model::test_utils::*;
 
     #[test]
@@ -236,12 +341,24 @@ mod tests {
             priority: ReProbePriority {
                 class: MediumSameSingle,
                 supporting_observations: 9,
+                ratio_test: None,
             },
         })
     }
 
     fn when_recommend(measurements: Vec<MeasurementTree>) -> SplitRecommendation {
-        recommend(&Subnets::new(net(TREE_BASE_NET), &measurements).unwrap())
+        recommend(&Subnets::new(net(TREE_BASE_NET), 1, &measurements).unwrap())
+    }
+
+    fn assert_ratio_tested(
+        rec: &SplitRecommendation,
+        class: PriorityClass,
+        supporting_observations: HitCount,
+    ) {
+        let priority = rec.priority();
+        assert_that!(priority.class).is_equal_to(class);
+        assert_that!(priority.supporting_observations).is_equal_to(supporting_observations);
+        assert_that!(priority.ratio_test.is_some()).is_true();
     }
 
     #[test]
@@ -258,15 +375,56 @@ mod tests {
         // when
         let rec = when_recommend(measurements);
 
+        // then
+        // p = 0.14 and V = 0.19, i.e. neither significant nor a relevant difference
+        assert_that!(matches!(rec, NoKeep { .. })).is_true();
+        assert_ratio_tested(&rec, MediumSameRatio, 60); // all
+    }
+
+    #[test]
+    fn same_multi_lhr_different_ratio_fixed_thresh() {
+        // given
+        let mut measurements = vec![
+            gen_tree_with_lhr_101(TREE_LEFT_NET, 13),  // 61.9%
+            gen_tree_with_lhr_101(TREE_RIGHT_NET, 31), // 79.4%
+        ];
+        for measurement in &mut measurements {
+            gen_add_lhr_beef(measurement, 8);
+        }
+        let subnets = Subnets::new(net(TREE_BASE_NET), 1, &measurements).unwrap();
+
+        // when
+        let rec = recommend_as(&subnets, FIXED_RATIO_ALGO_VERSION);
+
         // then
         assert_that!(rec).is_equal_to(YesSplit {
             priority: ReProbePriority {
                 class: MediumSameMulti,
                 supporting_observations: 38, // (13+31)/2 + 2*8
+                ratio_test: None,
             },
         })
     }
 
+    #[test]
+    fn same_multi_lhr_clearly_different_ratio() {
+        // given
+        let mut measurements = vec![
+            gen_tree_with_lhr_101(TREE_LEFT_NET, 80),  // 83.3%
+            gen_tree_with_lhr_101(TREE_RIGHT_NET, 16), // 50%
+        ];
+        for measurement in &mut measurements {
+            gen_add_lhr_beef(measurement, 16);
+        }
+
+        // when
+        let rec = when_recommend(measurements);
+
+        // then
+        assert_that!(matches!(rec, YesSplit { .. })).is_true();
+        assert_ratio_tested(&rec, MediumSameMulti, 80); // 96/2 + 2*16
+    }
+
     #[test]
     fn same_multi_lhr_same_many() {
         // given
@@ -294,6 +452,7 @@ mod tests {
             priority: ReProbePriority {
                 class: MediumSameMany,
                 supporting_observations: 136, // all divided by 4
+                ratio_test: None,
             },
         })
     }
@@ -312,12 +471,8 @@ mod tests {
         let rec = when_recommend(measurements);
 
         // then
-        assert_that!(rec).is_equal_to(NoKeep {
-            priority: ReProbePriority {
-                class: MediumSameRatio,
-                supporting_observations: 474, // all
-            },
-        })
+        assert_that!(matches!(rec, NoKeep { .. })).is_true();
+        assert_ratio_tested(&rec, MediumSameRatio, 474); // all
     }
 
     #[test]
@@ -335,12 +490,8 @@ mod tests {
         let rec = when_recommend(measurements);
 
         // then
-        assert_that!(rec).is_equal_to(NoKeep {
-            priority: ReProbePriority {
-                class: MediumSameRatio,
-                supporting_observations: 404, // all
-            },
-        })
+        assert_that!(matches!(rec, NoKeep { .. })).is_true();
+        assert_ratio_tested(&rec, MediumSameRatio, 404); // all
     }
 
     #[test]
@@ -357,12 +508,8 @@ mod tests {
         let rec = when_recommend(measurements);
 
         // then
-        assert_that!(rec).is_equal_to(NoKeep {
-            priority: ReProbePriority {
-                class: MediumSameRatio,
-                supporting_observations: 497165, // all
-            },
-        })
+        assert_that!(matches!(rec, NoKeep { .. })).is_true();
+        assert_ratio_tested(&rec, MediumSameRatio, 497165); // all
     }
 
     #[test]
@@ -379,12 +526,8 @@ mod tests {
         let rec = when_recommend(measurements);
 
         // then
-        assert_that!(rec).is_equal_to(NoKeep {
-            priority: ReProbePriority {
-                class: MediumSameRatio,
-                supporting_observations: 521165, // all
-            },
-        })
+        assert_that!(matches!(rec, NoKeep { .. })).is_true();
+        assert_ratio_tested(&rec, MediumSameRatio, 521165); // all
     }
 
     #[test]
@@ -402,12 +545,8 @@ mod tests {
         let rec = when_recommend(measurements);
 
         // then
-        assert_that!(rec).is_equal_to(NoKeep {
-            priority: ReProbePriority {
-                class: MediumSameRatio,
-                supporting_observations: 5816, // all
-            },
-        })
+        assert_that!(matches!(rec, NoKeep { .. })).is_true();
+        assert_ratio_tested(&rec, MediumSameRatio, 5816); // all
     }
 
     #[test]
@@ -427,10 +566,74 @@ mod tests {
             priority: ReProbePriority {
                 class: HighOverlapping,
                 supporting_observations: 14, // 5/2 + 12
+                ratio_test: None,
             },
         })
     }
 
+    #[test]
+    fn vantage_points_disagree() {
+        // given
+        let mut measurements = vec![
+            gen_tree_with_lhr_from(TREE_LEFT_NET, TREE_LHR_101, 13, "vienna"),
+            gen_tree_with_lhr_from(TREE_RIGHT_NET, TREE_LHR_101, 31, "vienna"),
+        ];
+        for measurement in &mut measurements {
+            let net_str = measurement.target_net.to_string();
+            let from_berlin = gen_tree_with_lhr_from(&net_str, TREE_LHR_BEEF, 8, "berlin");
+            measurement.merge(&from_berlin).unwrap();
+        }
+        let subnets = Subnets::new(net(TREE_BASE_NET), 1, &measurements).unwrap();
+        // independent of whether the combined ratios would be different enough
+        let priority = ReProbePriority {
+            class: MediumSameMulti,
+            supporting_observations: 38,
+            ratio_test: None,
+        };
+
+        // when
+        let rec = require_vantage_agreement(&subnets, YesSplit { priority }, ALGO_VERSION);
+
+        // then
+        assert_that!(rec).is_equal_to(CannotDetermine {
+            priority: ReProbePriority {
+                class: MediumSameMulti,
+                supporting_observations: 38,
+                ratio_test: None,
+            },
+        })
+    }
+
+    #[test]
+    fn vantage_points_agree() {
+        // given
+        let measurements = vec![
+            gen_tree_with_lhr_from(TREE_LEFT_NET, TREE_LHR_101, 2, "vienna"),
+            gen_tree_with_lhr_from(TREE_RIGHT_NET, TREE_LHR_BEEF, 3, "vienna"),
+            gen_tree_with_lhr_from(TREE_LEFT_NET, TREE_LHR_101, 4, "berlin"),
+            gen_tree_with_lhr_from(TREE_RIGHT_NET_ALT, TREE_LHR_BEEF, 3, "berlin"),
+        ];
+        let subnets = Subnets::new(net(TREE_BASE_NET), 1, &measurements).unwrap();
+
+        // when
+        let rec = require_vantage_agreement(&subnets, recommend(&subnets), ALGO_VERSION);
+
+        // then
+        assert_that!(rec.should_split()).is_equal_to(true);
+    }
+
+    fn gen_tree_with_lhr_from(
+        net_str: &str,
+        lhr: &str,
+        hits: HitCount,
+        vantage_point: &str,
+    ) -> MeasurementTree {
+        let mut tree = MeasurementTree::empty(net(net_str));
+        let sources = [LhrSource::Trace].into();
+        tree.add_lhr_from_vantage(addr(lhr), sources, hits, &vantage_point.to_string());
+        tree
+    }
+
     #[test]
     fn disjoint() {
         // given
@@ -448,6 +651,7 @@ mod tests {
             priority: ReProbePriority {
                 class: HighDisjoint,
                 supporting_observations: 8,
+                ratio_test: None,
             },
         })
     }
@@ -1,18 +1,19 @@
 use std::{
-    array::from_fn,
-    collections::{HashMap, HashSet},
+    collections::{BTreeSet, HashMap, HashSet},
     hash::Hash,
     ops::Deref,
 };
 
 use anyhow::{Context, Result};
 use db_model::{
-    analyse::LhrSource,
+    analyse::{DiffKind, LhrSource},
     prefix_tree::{LhrSetHash, PrefixTree},
 };
 use ipnet::{IpNet, Ipv6Net};
+use itertools::Itertools;
 use log::warn;
 use prefix_crab::prefix_split::{self, SplitSubnet};
+use queue_models::VantagePointId;
 
 use crate::analyse::{HitCount, LhrAddr, LhrItem, WeirdItem, WeirdType};
 
@@ -40,6 +41,22 @@ impl Subnet {
     fn iter_weirds(&self) -> IterWeirds<'_> {
         self.synthetic_tree.weirdness.items.iter()
     }
+
+    /// Copy that only retains the LHR hits observed from the vantage point. Hits that are not
+    /// attributed to any vantage point are retained as well, since they could be from any.
+    fn for_vantage_point(&self, vantage_point: &VantagePointId) -> Self {
+        let mut synthetic_tree = self.synthetic_tree.clone();
+        synthetic_tree.last_hop_routers.items.retain(|_, item| {
+            let hits = item.vantage_points.get(vantage_point).copied().unwrap_or(0);
+            item.hit_count = hits.saturating_add(item.unattributed_hits());
+            item.vantage_points.retain(|it, _| it == vantage_point);
+            item.hit_count > 0
+        });
+        Self {
+            subnet: self.subnet.clone(),
+            synthetic_tree,
+        }
+    }
 }
 
 impl From<SplitSubnet> for Subnet {
@@ -52,34 +69,54 @@ impl From<SplitSubnet> for Subnet {
     }
 }
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Clone)]
 pub struct LhrDiff {
+    pub addr: LhrAddr,
     pub sources: HashSet<LhrSource>,
-    // Important: Multiple places in the code assume the exact length 2 !
-    pub hit_counts: [HitCount; 2],
+    /// One entry per subnet, in the order of [Subnets]
+    pub hit_counts: Vec<HitCount>,
 }
 
 impl LhrDiff {
+    fn new(addr: LhrAddr, subnet_count: usize) -> Self {
+        Self {
+            addr,
+            sources: HashSet::new(),
+            hit_counts: vec![0; subnet_count],
+        }
+    }
+
     fn consume(&mut self, subnet_id: usize, item: LhrItem) {
         self.hit_counts[subnet_id] = item.hit_count;
         self.sources.extend(item.sources);
     }
 
     pub fn total_hit_count(&self) -> HitCount {
-        self.hit_counts[0].saturating_add(self.hit_counts[1])
+        self.hit_counts
+            .iter()
+            .fold(0, |acc, it| acc.saturating_add(*it))
     }
 }
 
 #[derive(Debug)]
 pub struct Subnets {
-    splits: [Subnet; 2],
+    splits: Vec<Subnet>,
 }
 
 impl Subnets {
-    pub fn new(base_net: Ipv6Net, relevant_measurements: &[MeasurementTree]) -> Result<Self> {
-        let split = prefix_split::split(base_net).context("trying to split for split analysis")?;
-        let mut splits: [Subnet; 2] = split.into_subnets().map(From::from);
-        let split_nets: [IpNet; 2] = from_fn(|i| IpNet::V6(splits[i].subnet.network));
+    /// Splits `base_net` by `split_bits` and assigns the measurements to the resulting subnets.
+    pub fn new(
+        base_net: Ipv6Net,
+        split_bits: u8,
+        relevant_measurements: &[MeasurementTree],
+    ) -> Result<Self> {
+        let split = prefix_split::split(base_net, split_bits)
+            .context("trying to split for split analysis")?;
+        let mut splits: Vec<Subnet> = split.into_subnets().into_iter().map(From::from).collect();
+        let split_nets: Vec<IpNet> = splits
+            .iter()
+            .map(|it| IpNet::V6(it.subnet.network))
+            .collect();
         for tree in relevant_measurements {
             let mut unused_tree = Some(tree);
             for (i, candidate_split) in splits.iter_mut().enumerate() {
@@ -93,7 +130,7 @@ impl Subnets {
             }
             if let Some(unused) = unused_tree {
                 warn!(
-                    "Received a tree that didn't fit into either subnet: {:?} - {:?}",
+                    "Received a tree that didn't fit into any subnet: {:?} - {:?}",
                     split_nets, unused,
                 );
             }
@@ -103,10 +140,12 @@ impl Subnets {
 
     pub fn lhr_diff(&self) -> Diff<LhrDiff> {
         let mut lookup: HashMap<&LhrAddr, LhrDiff> = HashMap::new();
-        let mut addr_sets: [HashSet<&LhrAddr>; 2] = Default::default();
+        let mut addr_sets: Vec<HashSet<&LhrAddr>> = vec![HashSet::new(); self.len()];
         for (i, subnet) in self.iter().enumerate() {
             for (addr, data) in subnet.iter_lhrs() {
-                let entry = lookup.entry(addr).or_default();
+                let entry = lookup
+                    .entry(addr)
+                    .or_insert_with(|| LhrDiff::new(*addr, self.len()));
                 entry.consume(i, data.clone());
                 addr_sets[i].insert(addr);
             }
@@ -117,7 +156,7 @@ impl Subnets {
 
     pub fn weird_diff(&self) -> Diff<WeirdItem> {
         let mut lookup: HashMap<&WeirdType, WeirdItem> = HashMap::new();
-        let mut type_sets: [HashSet<&WeirdType>; 2] = Default::default();
+        let mut type_sets: Vec<HashSet<&WeirdType>> = vec![HashSet::new(); self.len()];
 
         for (i, subnet) in self.iter().enumerate() {
             for (addr, data) in subnet.iter_weirds() {
@@ -138,6 +177,24 @@ impl Subnets {
         result
     }
 
+    /// Vantage points that any of the LHRs were observed from
+    pub fn vantage_points(&self) -> BTreeSet<&VantagePointId> {
+        self.iter()
+            .flat_map(|subnet| subnet.iter_lhrs())
+            .flat_map(|(_, item)| item.vantage_points.keys())
+            .collect()
+    }
+
+    /// The subnets as observed from a single vantage point, see [Subnet::for_vantage_point]
+    pub fn for_vantage_point(&self, vantage_point: &VantagePointId) -> Self {
+        Self {
+            splits: self
+                .iter()
+                .map(|it| it.for_vantage_point(vantage_point))
+                .collect(),
+        }
+    }
+
     pub fn combined_lhr_set_hash(&self) -> LhrSetHash {
         let combined = self
             .iter()
@@ -148,31 +205,38 @@ impl Subnets {
 }
 
 impl Deref for Subnets {
-    type Target = [Subnet; 2];
+    type Target = [Subnet];
 
     fn deref(&self) -> &Self::Target {
         &self.splits
     }
 }
 
+/// Comparison of the items found in each subnet. `shared` items were found in all subnets,
+/// `distinct` items only in some of them.
 #[derive(Debug, Eq, PartialEq)]
 pub enum Diff<I> {
-    BothNone,
-    BothSameSingle { shared: I },
-    BothSameMultiple { shared: Vec<I> },
+    AllNone,
+    AllSameSingle { shared: I },
+    AllSameMultiple { shared: Vec<I> },
     OverlappingOrDisjoint { shared: Vec<I>, distinct: Vec<I> },
 }
 
-fn lookup_diff<K, I>(sets: [HashSet<K>; 2], lookup: HashMap<K, I>) -> Diff<I>
+fn lookup_diff<K, I>(sets: Vec<HashSet<K>>, lookup: HashMap<K, I>) -> Diff<I>
 where
-    K: Hash + Eq + PartialEq + std::fmt::Debug,
+    K: Hash + Eq + Ord + std::fmt::Debug,
     I: Clone,
 {
-    let [left_set, right_set] = sets;
-    // If we implement `difference` & `intersection` ourselves in one step, we could skip the
-    // clone() on the key; doesn't seem worth it atm but could improve performance in the future.
-    let distinct: Vec<I> = left_set
-        .symmetric_difference(&right_set)
+    let is_shared = |k: &K| sets.iter().all(|set| set.contains(k));
+    // Keys are visited in subnet order (sorted within each subnet, since sets are unordered),
+    // and only the first occurrence is kept
+    let (shared_keys, distinct_keys): (Vec<&K>, Vec<&K>) = sets
+        .iter()
+        .flat_map(|set| set.iter().sorted())
+        .unique()
+        .partition(|k| is_shared(k));
+    let distinct: Vec<I> = distinct_keys
+        .into_iter()
         .map(|k| {
             lookup
                 .get(k)
@@ -180,8 +244,8 @@ where
                 .clone()
         })
         .collect();
-    let shared: Vec<I> = left_set
-        .intersection(&right_set)
+    let shared: Vec<I> = shared_keys
+        .into_iter()
         .map(|k| {
             lookup
                 .get(k)
@@ -193,12 +257,21 @@ where
 }
 
 impl<I> Diff<I> {
+    pub fn kind(&self) -> DiffKind {
+        match self {
+            Diff::AllNone => DiffKind::AllNone,
+            Diff::AllSameSingle { .. } => DiffKind::AllSameSingle,
+            Diff::AllSameMultiple { .. } => DiffKind::AllSameMultiple,
+            Diff::OverlappingOrDisjoint { .. } => DiffKind::OverlappingOrDisjoint,
+        }
+    }
+
     fn from(shared: Vec<I>, distinct: Vec<I>) -> Self {
         use Diff::*;
 
         if shared.is_empty() {
             if distinct.is_empty() {
-                BothNone
+                AllNone
             } else {
                 OverlappingOrDisjoint {
                     shared: vec![],
@@ -207,14 +280,14 @@ impl<I> Diff<I> {
             }
         } else if distinct.is_empty() {
             if shared.len() == 1 {
-                BothSameSingle {
+                AllSameSingle {
                     shared: shared
                         .into_iter()
                         .next()
                         .expect("vec with length one to have item"),
                 }
             } else {
-                BothSameMultiple { shared }
+                AllSameMultiple { shared }
             }
         } else {
             OverlappingOrDisjoint { shared, distinct }
@@ -239,12 +312,25 @@ mod tests {
         let relevant_measurements = vec![];
 
         // when
-        let subnets = Subnets::new(base_net, &relevant_measurements).unwrap();
+        let subnets = Subnets::new(base_net, 1, &relevant_measurements).unwrap();
 
         // then
         assert_that!(subnets.iter()).has_length(2);
     }
 
+    #[test]
+    fn new_multiple_bits() {
+        // given
+        let base_net = net(TREE_BASE_NET);
+        let relevant_measurements = vec![];
+
+        // when
+        let subnets = Subnets::new(base_net, 2, &relevant_measurements).unwrap();
+
+        // then
+        assert_that!(subnets.iter()).has_length(4);
+    }
+
     #[test]
     fn new_check_merge() {
         // given
@@ -252,10 +338,12 @@ mod tests {
         let relevant_measurements = gen_measurements_complex();
 
         // when
-        let subnets = Subnets::new(base_net, &relevant_measurements).unwrap();
+        let subnets = Subnets::new(base_net, 1, &relevant_measurements).unwrap();
 
         // then
-        let [left, right] = subnets.deref();
+        let [left, right] = subnets.deref() else {
+            panic!("expected exactly two subnets");
+        };
         then_contains_lhr_101(&left, 14);
         then_contains_lhr_beef(&right, 24);
         then_contains_lhr_101(&right, 9);
@@ -269,6 +357,7 @@ mod tests {
             LhrItem {
                 hit_count,
                 sources: vec![LhrSource::UnreachAddr].into_iter().collect(),
+                ..Default::default()
             },
         );
     }
@@ -279,6 +368,7 @@ mod tests {
             LhrItem {
                 hit_count,
                 sources: vec![LhrSource::Trace].into_iter().collect(),
+                ..Default::default()
             },
         );
     }
@@ -298,10 +388,12 @@ mod tests {
         ];
 
         // when
-        let subnets = Subnets::new(base_net, &relevant_measurements).unwrap();
+        let subnets = Subnets::new(base_net, 1, &relevant_measurements).unwrap();
 
         // then
-        let [left, right] = subnets.deref();
+        let [left, right] = subnets.deref() else {
+            panic!("expected exactly two subnets");
+        };
         then_contains_lhr_101(&left, 2);
         then_contains_lhr_beef(&right, 6);
         then_lhr_count(&left, 1);
@@ -309,7 +401,7 @@ mod tests {
     }
 
     #[test]
-    fn diff_from_both_none() {
+    fn diff_from_all_none() {
         // given
         let shared: Vec<i32> = vec![];
         let distinct: Vec<i32> = vec![];
@@ -318,7 +410,7 @@ mod tests {
         let diff = Diff::from(shared, distinct);
 
         // then
-        assert_that!(diff).is_equal_to(Diff::BothNone);
+        assert_that!(diff).is_equal_to(Diff::AllNone);
     }
 
     #[test]
@@ -357,7 +449,7 @@ mod tests {
         let diff = Diff::from(shared.clone(), distinct.clone());
 
         // then
-        assert_that!(diff).is_equal_to(Diff::BothSameSingle { shared: 4 });
+        assert_that!(diff).is_equal_to(Diff::AllSameSingle { shared: 4 });
     }
 
     #[test]
@@ -370,7 +462,7 @@ mod tests {
         let diff = Diff::from(shared.clone(), distinct.clone());
 
         // then
-        assert_that!(diff).is_equal_to(Diff::BothSameMultiple { shared });
+        assert_that!(diff).is_equal_to(Diff::AllSameMultiple { shared });
     }
 
     #[test]
@@ -384,7 +476,7 @@ mod tests {
         lookup.insert(12, "right only");
 
         // when
-        let diff = lookup_diff([left_set, right_set], lookup);
+        let diff = lookup_diff(vec![left_set, right_set], lookup);
 
         // then
         assert_that!(diff).is_equal_to(Diff::OverlappingOrDisjoint {
@@ -392,4 +484,41 @@ mod tests {
             distinct: vec!["left only", "right only"],
         });
     }
+
+    #[test]
+    fn diff_lookup_n_way() {
+        // given
+        let first_set: HashSet<i32> = vec![4, 8].into_iter().collect();
+        let second_set: HashSet<i32> = vec![4, 8].into_iter().collect();
+        let third_set: HashSet<i32> = vec![4].into_iter().collect();
+        let mut lookup = HashMap::new();
+        lookup.insert(4, "in all");
+        lookup.insert(8, "missing in third");
+
+        // when
+        let diff = lookup_diff(vec![first_set, second_set, third_set], lookup);
+
+        // then
+        assert_that!(diff).is_equal_to(Diff::OverlappingOrDisjoint {
+            shared: vec!["in all"],
+            distinct: vec!["missing in third"],
+        });
+    }
+
+    #[test]
+    fn diff_lookup_ordered_by_subnet_then_key() {
+        // given
+        let left_set: HashSet<i32> = vec![30, 2].into_iter().collect();
+        let right_set: HashSet<i32> = vec![16, 2, 1].into_iter().collect();
+        let lookup: HashMap<i32, i32> = [30, 16, 2, 1].into_iter().map(|k| (k, k)).collect();
+
+        // when
+        let diff = lookup_diff(vec![left_set, right_set], lookup);
+
+        // then
+        assert_that!(diff).is_equal_to(Diff::OverlappingOrDisjoint {
+            shared: vec![2],
+            distinct: vec![30, 1, 16],
+        });
+    }
 }
@@ -5,6 +5,7 @@ use diesel::{query_builder::AsChangeset, ExpressionMethods, PgConnection, RunQue
 use queue_models::{
     probe_request::TraceRequestId,
     probe_response::{LastHop, TraceResponse, TraceResponseType, TraceResult as TR},
+    VantagePointId,
 };
 use tracing::instrument;
 
@@ -16,15 +17,22 @@ use super::{context, persist::UpdateAnalysis, Interpretation, LhrSource};
 pub struct TraceResult {
     id: TraceRequestId,
     parent: Interpretation,
+    /// Whether the buddy returned some targets to the queue, so another response follows
+    awaits_remainder: bool,
 }
 
 impl TraceResult {
-    fn new(id: TraceRequestId) -> Self {
+    fn new(id: TraceRequestId, vantage_point: VantagePointId, awaits_remainder: bool) -> Self {
         Self {
             id,
-            parent: Interpretation::default(),
+            parent: Interpretation::new(vantage_point),
+            awaits_remainder,
         }
     }
+
+    pub fn awaits_remainder(&self) -> bool {
+        self.awaits_remainder
+    }
 }
 
 impl Deref for TraceResult {
@@ -42,12 +50,20 @@ impl DerefMut for TraceResult {
 }
 
 pub fn process(model: &TraceResponse) -> TraceResult {
-    let mut result = TraceResult::new(model.id);
+    let mut result = TraceResult::new(
+        model.id,
+        model.vantage_point.clone(),
+        !model.requeued.is_empty(),
+    );
 
     for target in model.results.iter() {
         match target {
             TR::LastResponsiveHop(hop) => register_as_lhr(&mut result, hop),
-            TR::NoResponse { target_addr } => result.count_unresponsive(&[*target_addr]),
+            // In partial responses, these might just not have been traced
+            TR::NoResponse { target_addr } if model.incomplete.is_none() => {
+                result.count_unresponsive(&[*target_addr])
+            }
+            TR::NoResponse { .. } => {}
         }
     }
 
@@ -94,6 +110,10 @@ impl UpdateAnalysis for TraceResult {
         use crate::schema::split_analysis::dsl::*;
 
         self.parent.update_analysis(conn, context)?;
+        if self.awaits_remainder {
+            // The follow-up stays pending, such that the remainder is matched to it as well
+            return Ok(());
+        }
         diesel::update(split_analysis)
             .filter(id.eq(context.analysis.id))
             .filter(pending_follow_up.eq(self.id.to_string()))
@@ -0,0 +1,444 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context as _, Result};
+use db_model::{
+    analyse::SplitAnalysis,
+    persist::{dsl::CidrMethods, DieselErrorFixCause},
+    prefix_tree::{
+        control::{self, PrefixControl},
+        history::{self, HistoryCause, HistoryEntry},
+        lock::with_subtree_lock,
+        MergeStatus, PrefixTree, PriorityClass,
+    },
+};
+use diesel::{dsl::not, prelude::*, PgConnection};
+use ipnet::{IpNet, Ipv6Net};
+use itertools::Itertools;
+use log::{error, info, warn};
+use prefix_crab::loop_with_stop;
+use queue_models::TypeRoutedMessage;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio_util::sync::CancellationToken;
+
+/// Operator commands, sent to the aggregator via the control exchange (e.g. by
+/// `crab-tools prefix-control`). Commands apply to the subtree below (and including) `net`,
+/// unless noted otherwise.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ControlCommand {
+    /// Use this priority class instead of the one recommended by split analyses
+    Pin { net: Ipv6Net, class: PriorityClass },
+    /// Remove a pin, restoring the classes recommended by the latest split analyses (or, if
+    /// there was none, the class before the pin). Other pins still take precedence.
+    Unpin { net: Ipv6Net },
+    /// Move all leaves to [PriorityClass::HighFresh] once, s.t. they are analysed soon
+    Boost { net: Ipv6Net },
+    /// Stop scheduling analyses and acting on the results of running ones, until thawed again
+    Freeze { net: Ipv6Net },
+    Thaw { net: Ipv6Net },
+    /// Turn this single [MergeStatus::Blocked] node back into a leaf. If the net is still
+    /// blocked, it will be blocked again on the next split attempt.
+    Unblock { net: Ipv6Net },
+    /// Analyse this single leaf right away, counting against the next budget of the timer.
+    /// Frozen leaves are not analysed.
+    AnalyseNow { net: Ipv6Net },
+}
+
+impl ControlCommand {
+    pub fn net(&self) -> &Ipv6Net {
+        use ControlCommand as C;
+
+        match self {
+            C::Pin { net, class: _ }
+            | C::Unpin { net }
+            | C::Boost { net }
+            | C::Freeze { net }
+            | C::Thaw { net }
+            | C::Unblock { net }
+            | C::AnalyseNow { net } => net,
+        }
+    }
+}
+
+impl TypeRoutedMessage for ControlCommand {
+    fn routing_key() -> &'static str {
+        "control"
+    }
+}
+
+pub async fn run(
+    control_rx: Receiver<ControlCommand>,
+    analyse_now_tx: Sender<Ipv6Net>,
+    stop_rx: CancellationToken,
+) -> Result<()> {
+    ControlHandler { analyse_now_tx }
+        .run(control_rx, stop_rx)
+        .await
+}
+
+struct ControlHandler {
+    analyse_now_tx: Sender<Ipv6Net>,
+}
+
+impl ControlHandler {
+    async fn run(
+        mut self,
+        mut control_rx: Receiver<ControlCommand>,
+        stop_rx: CancellationToken,
+    ) -> Result<()> {
+        loop_with_stop!(
+            recv "control handler", stop_rx,
+            control_rx => self.handle(it)
+        )
+    }
+
+    async fn handle(&mut self, cmd: ControlCommand) -> Result<()> {
+        info!("Received control command: {:?}", cmd);
+        if let ControlCommand::AnalyseNow { net } = cmd {
+            return self
+                .analyse_now_tx
+                .send(net)
+                .await
+                .context("passing immediate analysis to timer");
+        }
+        // Errors are the operator's concern (e.g. unknown net), don't stop the aggregator
+        let res = crate::persist::connect("aggregator - control")
+            .and_then(|mut conn| apply(&mut conn, &cmd));
+        if let Err(e) = res {
+            error!("Failed to apply control command {:?} due to {:?}", cmd, e);
+        }
+        Ok(())
+    }
+}
+
+/// Applies commands that only affect the database, i.e. all except [ControlCommand::AnalyseNow].
+/// Aggregator instances may be working on the same subtree, so this holds its lock.
+pub fn apply(conn: &mut PgConnection, cmd: &ControlCommand) -> Result<()> {
+    with_subtree_lock(conn, cmd.net(), |conn| apply_locked(conn, cmd))
+}
+
+fn apply_locked(conn: &mut PgConnection, cmd: &ControlCommand) -> Result<()> {
+    use ControlCommand as C;
+
+    conn.transaction(|conn| match cmd {
+        C::Pin { net, class } => {
+            control::set_pinned(conn, net, Some(*class))?;
+            set_class_below(conn, net, *class, &[]).map(|_| ())
+        }
+        C::Unpin { net } => unpin(conn, net),
+        C::Boost { net } => {
+            let boosted = set_class_below(
+                conn,
+                net,
+                PriorityClass::HighFresh,
+                &[MergeStatus::Leaf, MergeStatus::UnsplitRoot],
+            )?;
+            info!("Boosted {} leaves below {}.", boosted, net);
+            Ok(())
+        }
+        C::Freeze { net } => control::set_frozen(conn, net, true),
+        C::Thaw { net } => control::set_frozen(conn, net, false),
+        C::Unblock { net } => unblock(conn, net),
+        C::AnalyseNow { net: _ } => bail!("immediate analyses need to go through the timer"),
+    })
+}
+
+/// Sets the class of all nodes below `root` (optionally, only those with the given statuses),
+/// recording the change in the history.
+fn set_class_below(
+    conn: &mut PgConnection,
+    root: &Ipv6Net,
+    class: PriorityClass,
+    statuses: &[MergeStatus],
+) -> Result<usize> {
+    use db_model::schema::prefix_tree::dsl::*;
+
+    let before: Vec<PrefixTree> = prefix_tree
+        .filter(net.subnet_or_eq6(root))
+        .select(PrefixTree::as_select())
+        .for_update()
+        .load(conn)
+        .fix_cause()?
+        .into_iter()
+        .filter(|it| statuses.is_empty() || statuses.contains(&it.merge_status))
+        .collect();
+
+    let changes = before
+        .iter()
+        .map(|node| {
+            let after = PrefixTree {
+                priority_class: class,
+                ..*node
+            };
+            history::StateChange::of(node, (&after).into())
+        })
+        .collect::<Vec<_>>();
+    let updated = diesel::update(prefix_tree)
+        .filter(net.eq_any(before.iter().map(|it| IpNet::V6(it.net))))
+        .set(priority_class.eq(class))
+        .execute(conn)
+        .fix_cause()?;
+    history::record(conn, HistoryCause::Operator, None, &changes)?;
+    Ok(updated)
+}
+
+fn unpin(conn: &mut PgConnection, root: &Ipv6Net) -> Result<()> {
+    use db_model::schema::prefix_tree::dsl::*;
+
+    let Some(unpinned) = control::pinned_at(conn, root)? else {
+        warn!("Not unpinning {}, since it is not pinned", root);
+        return Ok(());
+    };
+    control::set_pinned(conn, root, None)?;
+    let pins = control::load_pins(conn)?;
+    let recommended = load_recommended_classes(conn, root)?;
+    let before_pin = load_classes_before_pin(conn, root, unpinned)?;
+
+    let before: Vec<PrefixTree> = prefix_tree
+        .filter(net.subnet_or_eq6(root))
+        .select(PrefixTree::as_select())
+        .for_update()
+        .load(conn)
+        .fix_cause()?;
+    let changes = before
+        .iter()
+        .filter_map(|node| {
+            let class = restored_class(&node.net, &pins, &recommended, &before_pin)?;
+            let after = PrefixTree {
+                priority_class: class,
+                ..*node
+            };
+            Some(history::StateChange::of(node, (&after).into()))
+        })
+        .filter(|it| it.old.map(|old| old.priority_class) != Some(it.new.priority_class))
+        .collect::<Vec<_>>();
+
+    let by_class = changes.iter().into_group_map_by(|it| it.new.priority_class);
+    for (class, class_changes) in by_class {
+        diesel::update(prefix_tree)
+            .filter(net.eq_any(class_changes.iter().map(|it| IpNet::V6(it.net))))
+            .set(priority_class.eq(class))
+            .execute(conn)
+            .fix_cause()?;
+    }
+    history::record(conn, HistoryCause::Operator, None, &changes)?;
+    info!(
+        "Unpinned {} from {:?}, restored the class of {} nodes.",
+        root,
+        unpinned,
+        changes.len()
+    );
+    Ok(())
+}
+
+/// Class of a node after a pin above it was removed. Any other pin covering it takes precedence
+/// (the most specific one). Otherwise, it is the class recommended by its latest split analysis,
+/// which is saved even while pinned, or else the class it had before it was pinned.
+/// `None` if neither is known, leaving the class as is.
+fn restored_class(
+    node_net: &Ipv6Net,
+    pins: &[PrefixControl],
+    recommended: &HashMap<Ipv6Net, PriorityClass>,
+    before_pin: &HashMap<Ipv6Net, PriorityClass>,
+) -> Option<PriorityClass> {
+    let pinned = pins
+        .iter()
+        .filter(|it| it.net.contains(node_net))
+        .max_by_key(|it| it.net.prefix_len())
+        .and_then(|it| it.pinned_class);
+    pinned
+        .or_else(|| recommended.get(node_net).copied())
+        .or_else(|| before_pin.get(node_net).copied())
+}
+
+/// Classes recommended by the latest completed split analysis of each node below `root`.
+fn load_recommended_classes(
+    conn: &mut PgConnection,
+    root: &Ipv6Net,
+) -> Result<HashMap<Ipv6Net, PriorityClass>> {
+    use db_model::schema::split_analysis::dsl::*;
+
+    let analyses: Vec<SplitAnalysis> = split_analysis
+        .filter(tree_net.subnet_or_eq6(root))
+        .filter(completed_at.is_not_null())
+        .filter(result.is_not_null())
+        .distinct_on(tree_net)
+        .order_by((tree_net.asc(), completed_at.desc(), id.desc()))
+        .load(conn)
+        .fix_cause()?;
+    Ok(analyses
+        .into_iter()
+        .filter_map(|it| Some((it.tree_net, it.result?.class)))
+        .collect())
+}
+
+/// Classes of the nodes below `root` before they were last set to the `pinned` class by an
+/// operator, i.e. as recorded in the history.
+fn load_classes_before_pin(
+    conn: &mut PgConnection,
+    root: &Ipv6Net,
+    pinned: PriorityClass,
+) -> Result<HashMap<Ipv6Net, PriorityClass>> {
+    use db_model::schema::prefix_tree_history::dsl::*;
+
+    let entries: Vec<HistoryEntry> = prefix_tree_history
+        .filter(net.subnet_or_eq6(root))
+        .filter(cause.eq(HistoryCause::Operator))
+        .filter(new_priority_class.eq(pinned))
+        .filter(old_priority_class.is_not_null())
+        .distinct_on(net)
+        .order_by((net.asc(), created_at.desc(), id.desc()))
+        .select(HistoryEntry::as_select())
+        .load(conn)
+        .fix_cause()?;
+    Ok(entries
+        .into_iter()
+        .filter_map(|it| Some((it.net, it.old_priority_class?)))
+        .collect())
+}
+
+fn unblock(conn: &mut PgConnection, target_net: &Ipv6Net) -> Result<()> {
+    use db_model::schema::prefix_tree::dsl::*;
+
+    let before = history::load_for_change(conn, &[*target_net])?;
+    let Some(node) = before.first() else {
+        bail!("{} is not in the prefix tree", target_net);
+    };
+    if node.merge_status != MergeStatus::Blocked {
+        warn!(
+            "Not unblocking {}, since it is not blocked but {:?}",
+            target_net, node.merge_status
+        );
+        return Ok(());
+    }
+
+    let parent_count: i64 = prefix_tree
+        .filter(net.supernet_or_eq6(target_net))
+        .filter(not(net.eq6(target_net)))
+        .count()
+        .get_result(conn)
+        .fix_cause()?;
+    let new_status = if parent_count == 0 {
+        MergeStatus::UnsplitRoot
+    } else {
+        MergeStatus::new(target_net.prefix_len())
+    };
+
+    diesel::update(node)
+        .set((
+            merge_status.eq(new_status),
+            priority_class.eq(PriorityClass::HighFresh),
+        ))
+        .execute(conn)
+        .fix_cause()?;
+    let after = history::load_for_change(conn, &[*target_net])?;
+    history::record(
+        conn,
+        HistoryCause::Operator,
+        None,
+        &history::changes_between(&before, &after),
+    )?;
+    info!("Unblocked {} as {:?}.", target_net, new_status);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+    use chrono::NaiveDateTime;
+    use db_model::test_utils::*;
+
+    use super::*;
+
+    fn pin(net_str: &str, class: PriorityClass) -> PrefixControl {
+        PrefixControl {
+            net: net(net_str),
+            created_at: NaiveDateTime::default(),
+            updated_at: NaiveDateTime::default(),
+            pinned_class: Some(class),
+            frozen: false,
+        }
+    }
+
+    fn classes(entries: &[(&str, PriorityClass)]) -> HashMap<Ipv6Net, PriorityClass> {
+        entries
+            .iter()
+            .map(|(net_str, class)| (net(net_str), *class))
+            .collect()
+    }
+
+    #[test]
+    fn restored_class_prefers_recommendation() {
+        // given
+        let recommended = classes(&[(TREE_LEFT_NET, PriorityClass::MediumSameMulti)]);
+        let before_pin = classes(&[(TREE_LEFT_NET, PriorityClass::LowWeird)]);
+
+        // when
+        let class = restored_class(&net(TREE_LEFT_NET), &[], &recommended, &before_pin);
+
+        // then
+        assert_that!(class).is_equal_to(Some(PriorityClass::MediumSameMulti));
+    }
+
+    #[test]
+    fn restored_class_before_pin_if_never_analysed() {
+        // given
+        let recommended = classes(&[(TREE_RIGHT_NET, PriorityClass::MediumSameMulti)]);
+        let before_pin = classes(&[(TREE_LEFT_NET, PriorityClass::LowWeird)]);
+
+        // when
+        let left = restored_class(&net(TREE_LEFT_NET), &[], &recommended, &before_pin);
+        let unknown = restored_class(&net(TREE_UNRELATED_NET), &[], &recommended, &before_pin);
+
+        // then
+        assert_that!(left).is_equal_to(Some(PriorityClass::LowWeird));
+        assert_that!(unknown).is_equal_to(None);
+    }
+
+    #[test]
+    fn restored_class_other_pins_take_precedence() {
+        // given
+        let pins = vec![
+            pin(TREE_BASE_NET, PriorityClass::LowWeird),
+            pin(TREE_LEFT_NET, PriorityClass::HighFresh),
+        ];
+        let recommended = classes(&[
+            (TREE_LEFT_NET, PriorityClass::MediumSameMulti),
+            (TREE_RIGHT_NET, PriorityClass::MediumSameMulti),
+        ]);
+
+        // when
+        let left = restored_class(&net(TREE_LEFT_NET), &pins, &recommended, &HashMap::new());
+        let right = restored_class(&net(TREE_RIGHT_NET), &pins, &recommended, &HashMap::new());
+        let unrelated = restored_class(
+            &net(TREE_UNRELATED_NET),
+            &pins,
+            &recommended,
+            &HashMap::new(),
+        );
+
+        // then
+        assert_that!(left).is_equal_to(Some(PriorityClass::HighFresh));
+        assert_that!(right).is_equal_to(Some(PriorityClass::LowWeird));
+        assert_that!(unrelated).is_equal_to(None);
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        // given
+        let cmd = ControlCommand::Pin {
+            net: net(TREE_BASE_NET),
+            class: PriorityClass::LowWeird,
+        };
+
+        // when
+        let json = serde_json::to_string(&cmd).unwrap();
+        let parsed: ControlCommand = serde_json::from_str(&json).unwrap();
+
+        // then
+        assert_that!(json).is_equal_to(
+            r#"{"Pin":{"net":"2001:db8::/32","class":"LowWeird"}}"#.to_string(),
+        );
+        assert_that!(parsed).is_equal_to(cmd);
+    }
+}
@@ -1,27 +1,51 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use anyhow::*;
 use clap::Args;
-use db_model::prefix_tree::context::ContextFetchError;
+use db_model::prefix_tree::{
+    context::ContextFetchError,
+    lock::{LockTimeout, SubtreeLock},
+};
 use diesel::prelude::*;
-use log::{error, info, trace};
-use tokio::sync::mpsc::{Receiver, Sender};
+use ipnet::Ipv6Net;
+use log::{error, info, trace, warn};
+use tokio::{
+    select,
+    sync::mpsc::{Receiver, Sender},
+    time::interval,
+};
 
 use prefix_crab::{
-    blocklist::{self, PrefixBlocklist}, drop_if_permanent, error::IsPermanent, helpers::rabbit::ack_sender::CanAck,
+    blocklist::{self, PrefixBlocklist}, error::is_permanent_as, helpers::rabbit::ack_sender::CanAck,
+    prefix_split::FanOut,
 };
 use queue_models::probe_response::ProbeResponse;
 
 use crate::{analyse, schedule::FollowUpRequest};
 mod archive;
+pub mod as_rate;
 mod echo;
 mod trace;
 
+/// How often to check for analyses whose echo responses are overdue
+const OVERDUE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Args)]
 #[group(id = "handleprobe")]
 pub struct Params {
     #[clap(flatten)]
     pub blocklist: blocklist::Params,
+
+    #[clap(flatten)]
+    pub as_rate: as_rate::Params,
+
+    #[clap(flatten)]
+    pub split: analyse::split::Params,
+
+    /// How long to wait for the echo responses of all vantage points, in minutes. Analyses
+    /// that received some but not all of them are then completed with the responses received.
+    #[arg(long, env = "ECHO_RESPONSE_TIMEOUT_MINS", default_value = "30")]
+    pub echo_response_timeout_mins: u32,
 }
 
 #[derive(Debug)]
@@ -29,6 +53,8 @@ pub struct TaskRequest {
     pub model: ProbeResponse,
     pub received_at: Instant,
     pub delivery_tag: u64,
+    /// Set if the message should be returned to the queue instead of being acked
+    pub requeue: bool,
 }
 
 impl CanAck for TaskRequest {
@@ -37,19 +63,35 @@ impl CanAck for TaskRequest {
     }
 }
 
+/// A request that permanently failed processing. It is published to the dead-letter queue
+/// (which also takes care of the ack), so that it can be replayed once the cause is fixed.
+/// If publishing fails, it is requeued instead.
+#[derive(Debug)]
+pub struct DeadLetter {
+    pub request: TaskRequest,
+    pub error: Error,
+}
+
 pub async fn run(
     task_rx: Receiver<TaskRequest>,
     ack_tx: Sender<TaskRequest>,
+    dead_letter_tx: Sender<DeadLetter>,
     follow_up_tx: Sender<FollowUpRequest>,
     params: Params,
+    fan_out: FanOut,
 ) -> Result<()> {
     let conn = crate::persist::connect("aggregator - probe handler")?;
     let blocklist = blocklist::read(params.blocklist)?;
     let handler = ProbeHandler {
         conn,
         ack_tx,
+        dead_letter_tx,
         follow_up_tx,
         blocklist,
+        fan_out,
+        as_rate: params.as_rate,
+        split: params.split,
+        echo_response_timeout_mins: params.echo_response_timeout_mins,
     };
 
     info!("Probe handler is ready to receive work!");
@@ -59,19 +101,34 @@ pub async fn run(
 struct ProbeHandler {
     conn: PgConnection,
     ack_tx: Sender<TaskRequest>,
+    dead_letter_tx: Sender<DeadLetter>,
     follow_up_tx: Sender<FollowUpRequest>,
     blocklist: PrefixBlocklist,
+    fan_out: FanOut,
+    as_rate: as_rate::Params,
+    split: analyse::split::Params,
+    echo_response_timeout_mins: u32,
 }
 
 impl ProbeHandler {
     async fn run(mut self, mut task_rx: Receiver<TaskRequest>) -> Result<()> {
+        let mut overdue_trigger = interval(OVERDUE_CHECK_INTERVAL);
         loop {
-            if let Some(req) = task_rx.recv().await {
-                trace!("Received something: {:?}", req);
-                self.handle_recv(req).await.context("handling probe responses")?;
-            } else {
-                info!("Probe handler shutting down.");
-                return Ok(());
+            select! {
+                received = task_rx.recv() => {
+                    if let Some(req) = received {
+                        trace!("Received something: {:?}", req);
+                        self.handle_recv(req).await.context("handling probe responses")?;
+                    } else {
+                        info!("Probe handler shutting down.");
+                        return Ok(());
+                    }
+                }
+                _ = overdue_trigger.tick() => {
+                    if let Err(e) = self.complete_overdue_echoes().await {
+                        error!("Failed to complete analyses with overdue echo responses: {:?}", e);
+                    }
+                }
             }
         }
     }
@@ -79,28 +136,79 @@ impl ProbeHandler {
     async fn handle_recv(&mut self, req: TaskRequest) -> Result<()> {
         match self.handle_one(&req).await {
             Result::Ok(_) => self.ack_tx.send(req).await.context("sending ack"),
-            Err(e) => match process_error(e, &req) {
-                Result::Ok(_) => {
-                    self.ack_tx.send(req).await.context("sending err ack")?;
-                    Ok(())
-                }
-                any => any,
-            },
+            Err(e) if is_permanent(&e) => {
+                error!(
+                    "Permanent error handling a request, sending it to DLQ: {:?} - {:?}",
+                    req, e
+                );
+                let dead_letter = DeadLetter {
+                    request: req,
+                    error: e,
+                };
+                self.dead_letter_tx
+                    .send(dead_letter)
+                    .await
+                    .context("sending to DLQ")
+            }
+            Err(e) if is_lock_timeout(&e) => {
+                warn!(
+                    "Subtree is busy, returning the request to the queue: {:?} - {:?}",
+                    req, e
+                );
+                let requeue = TaskRequest {
+                    requeue: true,
+                    ..req
+                };
+                self.ack_tx.send(requeue).await.context("sending nack")
+            }
+            Err(e) => {
+                // Not acked, so it will be re-delivered after restart
+                error!("Failed to handle request: {:?} - shutting down.", req);
+                Err(e)
+            }
         }
     }
 
     async fn handle_one(&mut self, req: &TaskRequest) -> Result<()> {
-        match &req.model {
+        // Other aggregator instances may be handling responses in the same subtree
+        let lock = match self.find_target_net(&req.model)? {
+            Some(net) => Some(SubtreeLock::acquire_async(&mut self.conn, &net).await?),
+            None => None,
+        };
+
+        let result = match &req.model {
             ProbeResponse::Echo(model) => self.handle_echo(model).await,
             ProbeResponse::Trace(model) => self.handle_trace(model),
+        };
+
+        if let Some(lock) = lock {
+            lock.release_or_log(&mut self.conn);
+        }
+        result
+    }
+
+    fn find_target_net(&mut self, model: &ProbeResponse) -> Result<Option<Ipv6Net>> {
+        match model {
+            ProbeResponse::Echo(model) => Ok(Some(model.target_net)),
+            ProbeResponse::Trace(model) => {
+                match analyse::context::find_follow_up_prefix(&mut self.conn, &model.id) {
+                    Result::Ok(net) => Ok(Some(net)),
+                    // reported by the handler
+                    Err(analyse::context::ContextFetchError::NoMatchingAnalysis { id: _ }) => {
+                        Ok(None)
+                    }
+                    Err(e) => Err(e.into()),
+                }
+            }
         }
     }
 }
 
-fn process_error(e: Error, req: &TaskRequest) -> Result<()> {
-    drop_if_permanent!(e <- ContextFetchError);
-    drop_if_permanent!(e <- analyse::context::ContextFetchError);
-    // TODO Could be handled with DLQ
-    error!("Failed to handle request: {:?} - shutting down.", req);
-    Err(e)
-}
\ No newline at end of file
+fn is_permanent(e: &Error) -> bool {
+    is_permanent_as::<ContextFetchError>(e) || is_permanent_as::<analyse::context::ContextFetchError>(e)
+}
+
+/// Another instance held the subtree for too long, which will likely resolve itself
+fn is_lock_timeout(e: &Error) -> bool {
+    e.downcast_ref::<LockTimeout>().is_some()
+}
@@ -0,0 +1,228 @@
+use anyhow::{Context, Result};
+use clap::Args;
+use db_model::prefix_tree::{AsNumber, AsRateBudget};
+use diesel::prelude::*;
+use log::debug;
+use queue_models::probe_response::{EchoProbeResponse, ResponseKey};
+use tracing::instrument;
+
+use crate::persist::DieselErrorFixCause;
+
+/// Adapts the per-AS budget of the analysis timer to observed ICMP rate limiting, using
+/// additive increase and multiplicative decrease (like TCP congestion control). A sudden rise
+/// of the share of unanswered probes compared to the moving average of the AS is taken as a
+/// sign of rate limiting.
+#[derive(Args, Clone, Debug)]
+#[group(id = "asrate")]
+pub struct Params {
+    /// Whether to adapt the per-AS budget to observed responses, or to use the same fixed
+    /// budget (ANALYSIS_TIMER_MAX_PREFIX_PER_AS) for every AS
+    #[arg(long, env = "AS_RATE_DO_ADAPT", default_value = "true", action = clap::ArgAction::Set)]
+    as_rate_do_adapt: bool,
+
+    /// How much to raise the scale of the budget of an AS per echo response without rate limiting
+    #[arg(long, env = "AS_RATE_INCREASE_STEP", default_value = "0.02")]
+    as_rate_increase_step: f32,
+
+    /// Factor to multiply the scale of the budget of an AS with if rate limiting is suspected
+    #[arg(long, env = "AS_RATE_DECREASE_FACTOR", default_value = "0.5")]
+    as_rate_decrease_factor: f32,
+
+    /// How much higher than usual the unresponsive share of a response has to be to suspect
+    /// rate limiting
+    #[arg(long, env = "AS_RATE_SPIKE_MARGIN", default_value = "0.25")]
+    as_rate_spike_margin: f32,
+
+    /// Weight of a new response in the moving average of the unresponsive share
+    #[arg(long, env = "AS_RATE_SMOOTHING", default_value = "0.1")]
+    as_rate_smoothing: f32,
+
+    /// Bounds for the scale of the per-AS budget
+    #[arg(long, env = "AS_RATE_MIN_SCALE", default_value = "0.05")]
+    as_rate_min_scale: f32,
+
+    #[arg(long, env = "AS_RATE_MAX_SCALE", default_value = "20.0")]
+    as_rate_max_scale: f32,
+}
+
+/// Responses with fewer probes than this are too noisy to base a decision on
+const MIN_PROBES_PER_OBSERVATION: usize = 16;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Observation {
+    probe_count: usize,
+    unresponsive_count: usize,
+}
+
+impl Observation {
+    fn new(res: &EchoProbeResponse) -> Self {
+        let mut obs = Observation {
+            probe_count: 0,
+            unresponsive_count: 0,
+        };
+        for responses in res.splits.iter().flat_map(|it| &it.responses) {
+            obs.probe_count += responses.len();
+            if responses.key == ResponseKey::NoResponse {
+                obs.unresponsive_count += responses.len();
+            }
+        }
+        obs
+    }
+
+    fn unresponsive_ratio(&self) -> f32 {
+        self.unresponsive_count as f32 / self.probe_count as f32
+    }
+}
+
+fn initial(asn: AsNumber) -> AsRateBudget {
+    AsRateBudget {
+        asn,
+        scale: 1.0,
+        unresponsive_ratio: 0.0,
+        observation_count: 0,
+    }
+}
+
+fn apply(budget: &mut AsRateBudget, obs: Observation, params: &Params) {
+    let ratio = obs.unresponsive_ratio();
+    if budget.observation_count == 0 {
+        budget.unresponsive_ratio = ratio; // nothing to compare to yet
+    } else {
+        if ratio > budget.unresponsive_ratio + params.as_rate_spike_margin {
+            budget.scale *= params.as_rate_decrease_factor;
+        } else {
+            budget.scale += params.as_rate_increase_step;
+        }
+        budget.unresponsive_ratio +=
+            params.as_rate_smoothing * (ratio - budget.unresponsive_ratio);
+    }
+    budget.scale = budget
+        .scale
+        .clamp(params.as_rate_min_scale, params.as_rate_max_scale);
+    budget.observation_count += 1;
+}
+
+/// Updates the learned budget of the AS that the echo response belongs to.
+#[instrument(name = "observe AS rate", skip(conn, res, params))]
+pub fn observe(
+    conn: &mut PgConnection,
+    asn: AsNumber,
+    res: &EchoProbeResponse,
+    params: &Params,
+) -> Result<()> {
+    use crate::schema::as_rate_budget::dsl;
+
+    if !params.as_rate_do_adapt || res.incomplete.is_some() {
+        // Targets without response in partial results need not be unresponsive
+        return Ok(());
+    }
+    let obs = Observation::new(res);
+    if obs.probe_count < MIN_PROBES_PER_OBSERVATION {
+        return Ok(());
+    }
+
+    conn.transaction(|conn| {
+        let mut budget = dsl::as_rate_budget
+            .filter(dsl::asn.eq(asn))
+            .select(AsRateBudget::as_select())
+            .for_update()
+            .first(conn)
+            .optional()
+            .fix_cause()?
+            .unwrap_or_else(|| initial(asn));
+        apply(&mut budget, obs, params);
+        debug!("AS{} rate budget is now {:?} after {:?}", asn, budget, obs);
+
+        diesel::insert_into(dsl::as_rate_budget)
+            .values(&budget)
+            .on_conflict(dsl::asn)
+            .do_update()
+            .set(&budget)
+            .execute(conn)
+            .fix_cause()?;
+        Ok::<(), anyhow::Error>(())
+    })
+    .with_context(|| format!("updating rate budget of AS{}", asn))
+}
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[clap(flatten)]
+        params: Params,
+    }
+
+    fn params() -> Params {
+        TestCli::parse_from(["test"]).params
+    }
+
+    fn obs(unresponsive_count: usize) -> Observation {
+        Observation {
+            probe_count: 100,
+            unresponsive_count,
+        }
+    }
+
+    #[test]
+    fn first_observation_sets_baseline() {
+        // given
+        let mut budget = initial(1);
+
+        // when
+        apply(&mut budget, obs(40), &params());
+
+        // then
+        assert_that!(budget.scale).is_equal_to(1.0);
+        assert_that!(budget.unresponsive_ratio).is_equal_to(0.4);
+        assert_that!(budget.observation_count).is_equal_to(1);
+    }
+
+    #[test]
+    fn stable_responses_increase() {
+        // given
+        let mut budget = initial(1);
+        apply(&mut budget, obs(40), &params());
+
+        // when
+        apply(&mut budget, obs(50), &params());
+
+        // then
+        assert_that!(budget.scale).is_greater_than(1.0);
+        assert_that!(budget.unresponsive_ratio).is_greater_than(0.4);
+    }
+
+    #[test]
+    fn spike_decreases() {
+        // given
+        let mut budget = initial(1);
+        apply(&mut budget, obs(10), &params());
+
+        // when
+        apply(&mut budget, obs(90), &params());
+
+        // then
+        assert_that!(budget.scale).is_equal_to(0.5);
+    }
+
+    #[test]
+    fn repeated_spikes_stop_at_min_scale() {
+        // given
+        let mut budget = initial(1);
+        apply(&mut budget, obs(0), &params());
+
+        // when
+        // fewer spikes than it takes the moving average to consider them usual
+        for _ in 0..10 {
+            apply(&mut budget, obs(100), &params());
+        }
+
+        // then
+        assert_that!(budget.scale).is_equal_to(0.05);
+    }
+}
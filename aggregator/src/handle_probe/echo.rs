@@ -1,7 +1,7 @@
 use std::time::Instant;
 
 use anyhow::*;
-use db_model::prefix_tree::{self, ContextOps};
+use db_model::prefix_tree::{self, control, lock::SubtreeLock, ContextOps};
 use diesel::PgConnection;
 use ipnet::Ipv6Net;
 use log::{info, warn};
@@ -9,62 +9,231 @@ use queue_models::probe_response::EchoProbeResponse;
 use tracing::instrument;
 
 use crate::{
-    analyse::{self, context, persist::UpdateAnalysis, split, EchoResult},
+    analyse::{self, context, persist::UpdateAnalysis, split},
     observe,
     schedule::FollowUpRequest,
 };
 
-use super::{archive, ProbeHandler};
+use super::{archive, as_rate, is_lock_timeout, is_permanent, ProbeHandler};
 
 impl ProbeHandler {
     #[instrument(skip(self, res), fields(net = %res.target_net))]
     pub(super) async fn handle_echo(&mut self, res: &EchoProbeResponse) -> Result<()> {
         archive::process(&mut self.conn, &res.target_net, res);
 
-        let (interpretation, context) = interpret_and_save(&mut self.conn, res.target_net, res)?;
-
-        observe::record_echo_analysis(interpretation.needs_follow_up());
-        if interpretation.needs_follow_up() {
-            if let Some(id) = &context.analysis.pending_follow_up {
-                let start = Instant::now();
-                let model = FollowUpRequest {
-                    id: id.parse().context("Invalid TypeID stored in node")?,
-                    prefix_tree: *context.node(),
-                    follow_ups: interpretation.follow_ups,
-                };
-                info!("Requesting follow-up {} for {}.", model.id, res.target_net);
-                self.follow_up_tx
-                    .send(model)
-                    .await
-                    .context("sending follow-up")?;
-                tracing::info!("Follow-up {} waited {:?} to schedule", id, start.elapsed());
-            } else {
-                warn!("Interpretation needs follow-up but it wasn't registered in the node");
+        let mut context = fetch_context(&mut self.conn, res.target_net)?;
+        if context.analysis.has_echo_response_from(&res.vantage_point) {
+            warn!(
+                "Ignoring repeated echo response for {} from {}.",
+                res.target_net, res.vantage_point
+            );
+            return Ok(());
+        }
+
+        if let Err(e) = as_rate::observe(&mut self.conn, context.node().asn, res, &self.as_rate) {
+            warn!("Failed to adapt AS rate budget, continuing: {:?}", e);
+        }
+
+        analyse::echo::process(res)
+            .update_analysis(&mut self.conn, &mut context)
+            .context("while saving analysis data")?;
+
+        if context.analysis.awaits_echo_responses() {
+            info!(
+                "Echo response for {} from {}, awaiting {} more vantage points.",
+                res.target_net, res.vantage_point, context.analysis.pending_echo_responses
+            );
+            return Ok(());
+        }
+        self.conclude_echo(context).await
+    }
+
+    /// Completes analyses whose remaining vantage points did not respond in time with the
+    /// echo responses that were received.
+    pub(super) async fn complete_overdue_echoes(&mut self) -> Result<()> {
+        let overdue = analyse::echo::find_overdue(&mut self.conn, self.echo_response_timeout_mins)
+            .context("finding analyses with overdue echo responses")?;
+        for net in overdue {
+            let lock = match SubtreeLock::acquire_async(&mut self.conn, &net).await {
+                Result::Ok(lock) => lock,
+                Err(e) if is_lock_timeout(&e) => {
+                    warn!("Retrying overdue {} on the next check: {}", net, e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let result = self.complete_overdue_echo(net).await;
+            lock.release_or_log(&mut self.conn);
+            result?;
+        }
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    async fn complete_overdue_echo(&mut self, net: Ipv6Net) -> Result<()> {
+        let mut context = match fetch_context(&mut self.conn, net) {
+            Result::Ok(context) => context,
+            Err(e) if is_permanent(&e) => {
+                info!("Analysis of {} is no longer active: {}", net, e);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        if !context.analysis.awaits_echo_responses() {
+            return Ok(()); // the last response arrived in the meantime
+        }
+        warn!(
+            "{} vantage points did not respond in time for {}, continuing with the responses of {:?}.",
+            context.analysis.pending_echo_responses, net, context.analysis.echo_responded_by
+        );
+        analyse::echo::stop_awaiting_responses(&mut self.conn, &mut context)?;
+        self.conclude_echo(context).await
+    }
+
+    /// Follows up or splits once no more echo responses are awaited
+    async fn conclude_echo(&mut self, context: context::Context) -> Result<()> {
+        let net = context.node().net;
+        let is_frozen = control::is_frozen(&mut self.conn, &net)?;
+        match next_step(
+            context.analysis.awaits_echo_responses(),
+            is_frozen,
+            !context.analysis.pending_follow_ups.is_empty(),
+        ) {
+            NextStep::AwaitVantagePoints => {
+                warn!(
+                    "Not concluding {}, since it still awaits echo responses.",
+                    net
+                );
+            }
+            NextStep::HoldFrozen => {
+                info!(
+                    "{} was frozen by an operator, keeping the measurements but not acting on them.",
+                    net
+                );
+            }
+            NextStep::FollowUp => {
+                observe::record_echo_analysis(true);
+                self.request_follow_up(&context).await?;
+            }
+            NextStep::Split => {
+                observe::record_echo_analysis(false);
+                info!("No further follow-up necessary, scheduling split analysis.");
+                split::process(
+                    &mut self.conn,
+                    context,
+                    &self.blocklist,
+                    &self.fan_out,
+                    &self.split,
+                )?;
             }
-        } else {
-            info!("No further follow-up necessary, scheduling split analysis.");
-            split::process(&mut self.conn, context, &self.blocklist)?;
         }
+        Ok(())
+    }
 
+    /// Traces the follow-up targets of all vantage points from the one that requested most
+    async fn request_follow_up(&mut self, context: &context::Context) -> Result<()> {
+        let Some(id) = &context.analysis.pending_follow_up else {
+            warn!("Interpretation needs follow-up but it wasn't registered in the node");
+            return Ok(());
+        };
+        let start = Instant::now();
+        let pending = &context.analysis.pending_follow_ups;
+        let model = FollowUpRequest {
+            id: id.parse().context("Invalid TypeID stored in node")?,
+            prefix_tree: *context.node(),
+            follow_ups: pending.merged(),
+            vantage_point: pending.main_vantage_point().cloned(),
+        };
+        info!(
+            "Requesting follow-up {} for {} from {:?}.",
+            model.id,
+            context.node().net,
+            model.vantage_point
+        );
+        self.follow_up_tx
+            .send(model)
+            .await
+            .context("sending follow-up")?;
+        tracing::info!("Follow-up {} waited {:?} to schedule", id, start.elapsed());
         Ok(())
     }
 }
 
-fn interpret_and_save(
-    conn: &mut PgConnection,
-    target_net: Ipv6Net,
-    model: &EchoProbeResponse,
-) -> Result<(EchoResult, context::Context)> {
+/// What to do with an echo response once its measurements are saved
+#[derive(Debug, PartialEq, Eq)]
+enum NextStep {
+    /// Other vantage points still need to respond
+    AwaitVantagePoints,
+    /// The subtree was frozen after the analysis was scheduled. Neither follow up nor change the
+    /// tree; the analysis is retried once thawed.
+    HoldFrozen,
+    FollowUp,
+    Split,
+}
+
+fn next_step(awaits_echo_responses: bool, is_frozen: bool, needs_follow_up: bool) -> NextStep {
+    if awaits_echo_responses {
+        NextStep::AwaitVantagePoints
+    } else if is_frozen {
+        NextStep::HoldFrozen
+    } else if needs_follow_up {
+        NextStep::FollowUp
+    } else {
+        NextStep::Split
+    }
+}
+
+fn fetch_context(conn: &mut PgConnection, target_net: Ipv6Net) -> Result<context::Context> {
     let tree_context =
         prefix_tree::context::fetch(conn, &target_net).context("fetching tree context")?;
-    let mut context =
-        analyse::context::fetch(conn, tree_context).context("fetch context for probe handling")?;
+    analyse::context::fetch(conn, tree_context).context("fetch context for probe handling")
+}
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+
+    use super::*;
 
-    let mut interpretation = analyse::echo::process(model);
+    #[test]
+    fn next_step_frozen_holds() {
+        // given
+        let is_frozen = true;
 
-    interpretation
-        .update_analysis(conn, &mut context)
-        .context("while saving analysis data")?;
+        // when
+        let with_follow_up = next_step(false, is_frozen, true);
+        let without_follow_up = next_step(false, is_frozen, false);
 
-    Ok((interpretation, context))
+        // then
+        assert_that!(with_follow_up).is_equal_to(NextStep::HoldFrozen);
+        assert_that!(without_follow_up).is_equal_to(NextStep::HoldFrozen);
+    }
+
+    #[test]
+    fn next_step_awaits_vantage_points_even_if_frozen() {
+        // given
+        let awaits_echo_responses = true;
+
+        // when
+        let frozen = next_step(awaits_echo_responses, true, false);
+        let thawed = next_step(awaits_echo_responses, false, true);
+
+        // then
+        assert_that!(frozen).is_equal_to(NextStep::AwaitVantagePoints);
+        assert_that!(thawed).is_equal_to(NextStep::AwaitVantagePoints);
+    }
+
+    #[test]
+    fn next_step_thawed_progresses() {
+        // given
+        let is_frozen = false;
+
+        // when
+        let with_follow_up = next_step(false, is_frozen, true);
+        let without_follow_up = next_step(false, is_frozen, false);
+
+        // then
+        assert_that!(with_follow_up).is_equal_to(NextStep::FollowUp);
+        assert_that!(without_follow_up).is_equal_to(NextStep::Split);
+    }
 }
@@ -1,5 +1,5 @@
 use anyhow::*;
-use log::warn;
+use log::{info, warn};
 use queue_models::probe_response::TraceResponse;
 use tracing::{instrument, Span};
 
@@ -7,7 +7,7 @@ use crate::analyse::context::ContextFetchError;
 use crate::analyse::persist::UpdateAnalysis;
 
 use crate::analyse;
-use db_model::prefix_tree::ContextOps;
+use db_model::prefix_tree::{control, ContextOps};
 
 use super::{archive, ProbeHandler};
 
@@ -29,6 +29,30 @@ impl ProbeHandler {
         let mut interpretation = analyse::trace::process(res);
         interpretation.update_analysis(&mut self.conn, &mut context)?;
 
-        analyse::split::process(&mut self.conn, context, &self.blocklist).map_err(|e| anyhow!(e))
+        if interpretation.awaits_remainder() {
+            info!(
+                "{} targets of {} were returned to the queue, splitting once they are traced.",
+                res.requeued.len(),
+                res.id
+            );
+            return Ok(());
+        }
+
+        if control::is_frozen(&mut self.conn, &context.node().net)? {
+            info!(
+                "{} was frozen by an operator, keeping the trace but not acting on it.",
+                context.node().net
+            );
+            return Ok(());
+        }
+
+        analyse::split::process(
+            &mut self.conn,
+            context,
+            &self.blocklist,
+            &self.fan_out,
+            &self.split,
+        )
+        .map_err(|e| anyhow!(e))
     }
 }
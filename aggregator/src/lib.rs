@@ -0,0 +1,17 @@
+// FQNs are needed in some Diesel macros, make them easier to read
+pub use db_model::persist;
+pub use db_model::{schema, sql_types};
+
+use prefix_crab::helpers::stop::flatten;
+
+/// Analysis of incoming data in combination with existing knowledge
+pub mod analyse;
+/// Operator commands to steer the aggregator at runtime
+pub mod control;
+/// Business logic for handling incoming probes
+pub mod handle_probe;
+pub mod observe;
+/// RabbitMQ-specific logic (producers, consumers), encapsulated using in-memory senders/receivers
+pub mod rabbit;
+/// Scheduling new analyses based on priority and capacity
+pub mod schedule;
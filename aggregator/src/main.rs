@@ -2,25 +2,14 @@ use anyhow::Result;
 use clap::Parser;
 
 use prefix_crab::helpers::{bootstrap, logging};
+use prefix_crab::prefix_split;
 
 use futures::executor;
 use prefix_crab::helpers::stop::{self, flatten};
 use tokio::sync::mpsc;
 use tokio::try_join;
 
-// FQNs are needed in some Diesel macros, make them easier to read
-pub use db_model::persist;
-pub use db_model::{schema, sql_types};
-
-/// Analysis of incoming data in combination with existing knowledge
-mod analyse;
-/// Business logic for handling incoming probes
-mod handle_probe;
-mod observe;
-/// RabbitMQ-specific logic (producers, consumers), encapsulated using in-memory senders/receivers
-mod rabbit;
-/// Scheduling new analyses based on priority and capacity
-mod schedule;
+use aggregator::{control, handle_probe, observe, persist, rabbit, schedule};
 
 #[derive(Parser)]
 #[command(author, version, about)]
@@ -40,6 +29,9 @@ struct Cli {
     #[clap(flatten)]
     handle_probe: handle_probe::Params,
 
+    #[clap(flatten)]
+    prefix_split: prefix_split::Params,
+
     #[clap(flatten)]
     observe: observe::Params,
 }
@@ -52,8 +44,11 @@ fn do_run(cli: Cli) -> Result<()> {
     // bounded s.t. we don't keep consuming new work items when we block for some reason
     let (result_tx, result_rx) = mpsc::channel(8); // buffer should be lower than the prefetch-count on the RMQ channel (otherwise it won't be used up)
     let (ack_tx, ack_rx) = mpsc::channel(128);
+    let (dead_letter_tx, dead_letter_rx) = mpsc::channel(16);
     let (probe_tx, probe_rx) = mpsc::channel(256);
     let (follow_up_tx, follow_up_rx) = mpsc::channel(512);
+    let (control_tx, control_rx) = mpsc::channel(16);
+    let (analyse_now_tx, analyse_now_rx) = mpsc::channel(16);
 
     persist::initialize(&cli.persist)?;
     let observe_guard = observe::initialize(cli.observe)?;
@@ -61,9 +56,11 @@ fn do_run(cli: Cli) -> Result<()> {
     // This task is shut down by the RabbitMQ receiver closing the channel
     let probe_handle = tokio::spawn(handle_probe::run(
         result_rx,
-        ack_tx,
+        ack_tx.clone(),
+        dead_letter_tx,
         follow_up_tx,
         cli.handle_probe,
+        cli.prefix_split.prefix_split_fan_out.clone(),
     ));
 
     let sig_handler = stop::new();
@@ -73,17 +70,29 @@ fn do_run(cli: Cli) -> Result<()> {
     let schedule_handle = tokio::spawn(schedule::run(
         probe_tx,
         follow_up_rx,
+        analyse_now_rx,
         stop_rx.clone(),
         cli.schedule,
+        cli.prefix_split.prefix_split_fan_out,
     ));
 
+    let control_handle = tokio::spawn(control::run(control_rx, analyse_now_tx, stop_rx.clone()));
+
     let rabbit_handle = tokio::spawn(rabbit::run(
-        result_tx, ack_rx, probe_rx, stop_rx, cli.rabbit,
+        result_tx,
+        ack_tx,
+        ack_rx,
+        dead_letter_rx,
+        probe_rx,
+        control_tx,
+        stop_rx,
+        cli.rabbit,
     ));
 
     let res = executor::block_on(async {
         try_join!(
             flatten(schedule_handle),
+            flatten(control_handle),
             flatten(rabbit_handle),
             flatten(probe_handle)
         )?;
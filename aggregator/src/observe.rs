@@ -36,6 +36,22 @@ lazy_static! {
         .u64_counter("prefix_crab_split_decision_count_v2") // v1 missed instance indicators
         .with_description("Count of split decisions")
         .init();
+    static ref REAGGREGATE_MERGE_COUNT: Counter<u64> = METER
+        .u64_counter("prefix_crab_reaggregate_merge_count")
+        .with_description("Count of split prefixes merged back up by re-aggregation")
+        .init();
+    static ref DECAYED_TREE_COUNT: Counter<u64> = METER
+        .u64_counter("prefix_crab_decayed_tree_count")
+        .with_description("Count of measurement trees whose counts were halved by decay")
+        .init();
+    static ref DECAY_LOWERED_COUNT: Counter<u64> = METER
+        .u64_counter("prefix_crab_decay_lowered_count")
+        .with_description("Count of prefixes whose confidence was lowered due to decay")
+        .init();
+    static ref DRIFT_COUNT: Counter<u64> = METER
+        .u64_counter("prefix_crab_drift_count")
+        .with_description("Count of converged prefixes whose topology changed on re-probing")
+        .init();
 }
 
 #[derive(Args, Clone)]
@@ -137,3 +153,16 @@ pub fn record_split_decision(prio: PriorityClass, action_performed: bool, would_
         ],
     )
 }
+
+pub fn record_reaggregate_merges(count: u64) {
+    REAGGREGATE_MERGE_COUNT.add(count, &[])
+}
+
+pub fn record_decay(decayed_trees: u64, lowered_confidences: u64) {
+    DECAYED_TREE_COUNT.add(decayed_trees, &[]);
+    DECAY_LOWERED_COUNT.add(lowered_confidences, &[])
+}
+
+pub fn record_drift() {
+    DRIFT_COUNT.add(1, &[])
+}
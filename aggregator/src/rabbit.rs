@@ -1,4 +1,5 @@
-use crate::handle_probe::TaskRequest;
+use crate::control::ControlCommand;
+use crate::handle_probe::{DeadLetter, TaskRequest};
 use anyhow::*;
 use clap::Args;
 use log::debug;
@@ -11,6 +12,8 @@ use tokio::select;
 use tokio::sync::mpsc;
 use tokio_util::sync::CancellationToken;
 
+mod control;
+mod dead_letter;
 mod receive;
 mod send;
 
@@ -36,6 +39,19 @@ pub struct Params {
     #[arg(long, default_value = "prefix-crab.probe-request")]
     out_exchange_name: String,
 
+    /// Name of the queue to move responses to that permanently failed processing.
+    /// They can be re-published from there using `crab-tools dlq-replay`.
+    #[arg(long, env = "DEAD_LETTER_QUEUE_NAME", default_value = "prefix-crab.probe-response.dead-letter")]
+    dead_letter_queue_name: String,
+
+    /// Name of the exchange that operator commands are published to, e.g. by `crab-tools prefix-control`.
+    #[arg(long, env = "CONTROL_EXCHANGE_NAME", default_value = "prefix-crab.control")]
+    control_exchange_name: String,
+
+    /// Name of the queue to receive operator commands from.
+    #[arg(long, env = "CONTROL_QUEUE_NAME", default_value = "prefix-crab.control.aggregate")]
+    control_queue_name: String,
+
     /// Whether to pretty print JSON in RabbitMQ responses.
     #[arg(long, env = "PRETTY_PRINT")]
     pretty_print: bool,
@@ -49,13 +65,31 @@ impl Params {
 
 pub async fn run(
     work_tx: mpsc::Sender<TaskRequest>,
+    ack_tx: mpsc::Sender<TaskRequest>,
     ack_rx: mpsc::Receiver<TaskRequest>,
+    dead_letter_rx: mpsc::Receiver<DeadLetter>,
     probe_rx: mpsc::Receiver<ProbeRequest>,
+    control_tx: mpsc::Sender<ControlCommand>,
     stop_rx: CancellationToken,
     params: Params,
 ) -> Result<()> {
     let handle = prepare(&params).await?;
     let receiver = receive::run(&handle, &params, work_tx, stop_rx.clone(), ack_rx);
+    let dead_letter_handle = handle.fork().await?;
+    let dead_letter_sender = dead_letter::run(
+        &dead_letter_handle,
+        dead_letter_rx,
+        ack_tx,
+        params.dead_letter_queue_name.clone(),
+        stop_rx.clone(),
+    );
+    let control_handle = handle.fork().await?;
+    let control_receiver = control::run(
+        &control_handle,
+        params.control_queue_name.clone(),
+        control_tx,
+        stop_rx.clone(),
+    );
     let probe_sender = send::run(
         &handle,
         probe_rx,
@@ -66,6 +100,8 @@ pub async fn run(
     let res = select! {
         exit_res = receiver => exit_res,
         exit_res = probe_sender => exit_res,
+        exit_res = dead_letter_sender => exit_res,
+        exit_res = control_receiver => exit_res,
     };
     debug!("RabbitMQ handler is shutting down. Triggering clean stop.");
     stop::trigger();
@@ -85,6 +121,20 @@ async fn prepare(params: &Params) -> Result<RabbitHandle> {
 
     configure
         .declare_exchange(&params.out_exchange_name, "direct")
+        .await?
+        .declare_queue(&params.dead_letter_queue_name)
+        .await?;
+
+    configure
+        .declare_exchange(&params.control_exchange_name, "direct")
+        .await?
+        .declare_queue(&params.control_queue_name)
+        .await?
+        .bind_queue_routing(
+            &params.control_queue_name,
+            &params.control_exchange_name,
+            ControlCommand::routing_key(),
+        )
         .await?;
 
     Ok(handle)
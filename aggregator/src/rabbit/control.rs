@@ -0,0 +1,53 @@
+use amqprs::channel::BasicAckArguments;
+use amqprs::Deliver;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use prefix_crab::helpers::rabbit::receive::{JsonReceiver, MessageHandler};
+use prefix_crab::helpers::rabbit::RabbitHandle;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::control::ControlCommand;
+
+pub async fn run(
+    handle: &RabbitHandle,
+    queue_name: String,
+    control_tx: mpsc::Sender<ControlCommand>,
+    stop_rx: CancellationToken,
+) -> Result<()> {
+    JsonReceiver {
+        handle,
+        queue_name,
+        msg_handler: ControlHandler { handle, control_tx },
+    }
+    .run(stop_rx)
+    .await
+    .context("while receiving control commands")
+}
+
+struct ControlHandler<'han> {
+    handle: &'han RabbitHandle,
+    control_tx: mpsc::Sender<ControlCommand>,
+}
+
+#[async_trait]
+impl MessageHandler for ControlHandler<'_> {
+    type Model = ControlCommand;
+
+    async fn handle_msg<'de>(&self, model: Self::Model, deliver: Deliver) -> Result<()> {
+        self.control_tx
+            .send(model)
+            .await
+            .context("while passing control command")?;
+        // Commands are rare and cheap to re-send, so there is no need to wait for them to be applied
+        self.handle
+            .chan()
+            .basic_ack(BasicAckArguments::new(deliver.delivery_tag(), false))
+            .await
+            .context("during control command ack")
+    }
+
+    fn consumer_tag() -> String {
+        "aggregator control receiver".to_string()
+    }
+}
@@ -0,0 +1,130 @@
+use std::fmt::Debug;
+
+use amqprs::channel::BasicPublishArguments;
+use anyhow::{Context, Result};
+use log::{info, warn};
+use prefix_crab::helpers::rabbit::{dead_letter, RabbitHandle};
+use prefix_crab::loop_with_stop;
+use queue_models::probe_response::ProbeResponse;
+use queue_models::RoutedMessage;
+use serde::Serialize;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio_util::sync::CancellationToken;
+
+use crate::handle_probe::{DeadLetter, TaskRequest};
+
+struct DeadLetterSender<'han> {
+    queue_name: String,
+    handle: &'han RabbitHandle,
+    ack_tx: Sender<TaskRequest>,
+}
+
+pub async fn run(
+    handle: &RabbitHandle,
+    work_rx: Receiver<DeadLetter>,
+    ack_tx: Sender<TaskRequest>,
+    queue_name: String,
+    stop_rx: CancellationToken,
+) -> Result<()> {
+    DeadLetterSender {
+        queue_name,
+        handle,
+        ack_tx,
+    }
+    .run(work_rx, stop_rx)
+    .await
+    .with_context(|| "while sending dead letters")
+}
+
+impl DeadLetterSender<'_> {
+    async fn run(
+        mut self,
+        mut work_rx: Receiver<DeadLetter>,
+        stop_rx: CancellationToken,
+    ) -> Result<()> {
+        loop_with_stop!(
+            recv "dead letter sender", stop_rx,
+            work_rx => self.do_send(it)
+        )
+    }
+
+    async fn do_send(&mut self, msg: DeadLetter) -> Result<()> {
+        match self.publish(&msg).await {
+            Ok(_) => {
+                info!(
+                    "Moved failed {} response to {}.",
+                    msg.request.model.routing_key(),
+                    self.queue_name
+                );
+                // Only ack after publish, so that it will be re-delivered if publishing fails
+                self.ack_tx
+                    .send(msg.request)
+                    .await
+                    .context("sending ack for dead letter")
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to publish dead letter, returning it to the queue: {:?} - {:?}",
+                    msg, e
+                );
+                let mut request = msg.request;
+                request.requeue = true;
+                self.ack_tx
+                    .send(request)
+                    .await
+                    .context("sending nack for dead letter")
+            }
+        }
+    }
+
+    async fn publish(&self, msg: &DeadLetter) -> Result<()> {
+        let routing_key = msg.request.model.routing_key();
+        // Publish to the default exchange, which routes directly to the queue with this name
+        let args = BasicPublishArguments::new("", &self.queue_name);
+        let props = dead_letter::to_properties(routing_key, &msg.error)?;
+        let bin = to_body(&msg.request.model)?;
+        self.handle
+            .chan()
+            .basic_publish(props, bin, args)
+            .await
+            .with_context(|| "during publish")?;
+        Ok(())
+    }
+}
+
+/// Body must be the same as originally received, so that it can be replayed
+fn to_body(model: &ProbeResponse) -> Result<Vec<u8>> {
+    match model {
+        ProbeResponse::Echo(inner) => to_bin(inner),
+        ProbeResponse::Trace(inner) => to_bin(inner),
+    }
+}
+
+fn to_bin(msg: &(impl Serialize + Debug)) -> Result<Vec<u8>> {
+    serde_json::to_vec(&msg).with_context(|| format!("during serialisation of {:?}", msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+    use queue_models::probe_response::EchoProbeResponse;
+
+    use super::*;
+
+    #[test]
+    fn body_is_replayable() -> Result<()> {
+        // given
+        let received =
+            r#"{"target_net":"2001:db8::/32","subnet_prefix_len":34,"sent_ttl":64,"splits":[]}"#;
+        let model: EchoProbeResponse = serde_json::from_str(received)?;
+
+        // when
+        let body = to_body(&model.into())?;
+
+        // then
+        let replayed: EchoProbeResponse = serde_json::from_slice(&body)?;
+        assert_that!(replayed.target_net.to_string()).is_equal_to("2001:db8::/32".to_string());
+        assert_that!(replayed.subnet_prefix_len).is_equal_to(34);
+        Ok(())
+    }
+}
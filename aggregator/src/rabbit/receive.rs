@@ -90,6 +90,7 @@ where
             model: model.into(),
             received_at: Instant::now(),
             delivery_tag: deliver.delivery_tag(),
+            requeue: false,
         };
         self.work_sender
             .send(request)
@@ -143,7 +144,9 @@ impl AckRouter<'_, '_> {
             work.received_at.elapsed()
         );
         match work.model {
+            R::Echo(_) if work.requeue => self.echo_ack.do_requeue(work).await,
             R::Echo(_) => self.echo_ack.do_ack(work).await,
+            R::Trace(_) if work.requeue => self.trace_ack.do_requeue(work).await,
             R::Trace(_) => self.trace_ack.do_ack(work).await,
         }
     }
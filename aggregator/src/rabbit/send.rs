@@ -7,7 +7,6 @@ use log::warn;
 use prefix_crab::helpers::rabbit::RabbitHandle;
 use prefix_crab::loop_with_stop;
 use queue_models::probe_request::ProbeRequest;
-use queue_models::RoutedMessage;
 use serde::Serialize;
 use tokio::sync::mpsc::Receiver;
 use tokio_util::sync::CancellationToken;
@@ -56,7 +55,11 @@ impl RabbitSender<'_> {
     }
 
     async fn publish(&self, msg: ProbeRequest) -> Result<()> {
-        let args = BasicPublishArguments::new(&self.exchange_name, msg.routing_key());
+        let routing_key = match &msg {
+            ProbeRequest::Echo(inner) => inner.vantage_routing_key(),
+            ProbeRequest::Trace(inner) => inner.vantage_routing_key(),
+        };
+        let args = BasicPublishArguments::new(&self.exchange_name, &routing_key);
         let bin = match msg {
             ProbeRequest::Echo(inner) => self.to_bin(&inner),
             ProbeRequest::Trace(inner) => self.to_bin(&inner),
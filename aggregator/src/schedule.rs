@@ -1,6 +1,8 @@
 use anyhow::*;
 use clap::Args;
-use queue_models::probe_request::ProbeRequest;
+use ipnet::Ipv6Net;
+use prefix_crab::prefix_split::FanOut;
+use queue_models::{probe_request::ProbeRequest, VantagePointId};
 use tokio::{
     sync::mpsc::{Receiver, Sender},
     try_join,
@@ -10,11 +12,15 @@ use tokio_util::sync::CancellationToken;
 use crate::flatten;
 
 mod analysis_timer;
+mod decay_timer;
 mod follow_up;
+mod reaggregate_timer;
 
 pub use follow_up::FollowUpRequest;
 
-#[derive(Args, Debug)]
+use analysis_timer::SamplingMode;
+
+#[derive(Args, Debug, Clone)]
 #[group(id = "schedule")]
 pub struct Params {
     /// How often to trigger a new analysis batch, in seconds
@@ -31,20 +37,94 @@ pub struct Params {
     #[arg(long, env = "ANALYSIS_TIMER_MAX_PREFIX_PER_AS", default_value = "223")]
     analysis_timer_max_prefix_per_as: usize,
 
+    /// How many converged prefixes (at maximum confidence) to re-probe per timer interval,
+    /// to detect changes such as renumbering. Taken from the prefix budget, zero to disable.
+    #[arg(long, env = "ANALYSIS_TIMER_REVALIDATION_BUDGET", default_value = "1")]
+    analysis_timer_revalidation_budget: u32,
+
+    /// How long a converged prefix must not have been analysed to be re-probed, in hours (default = 30d)
+    #[arg(long, env = "ANALYSIS_REVALIDATION_MIN_AGE_HOURS", default_value = "720")]
+    analysis_revalidation_min_age_hours: u32,
+
+    /// Which addresses the prober should probe in each subnet of scheduled analyses.
+    /// `previously-responsive` re-uses addresses that answered in earlier measurements,
+    /// and `hitlist` requires the prober to be configured with a hitlist.
+    #[arg(long, env = "ANALYSIS_SAMPLING_MODE", value_enum, default_value = "uniform")]
+    analysis_sampling_mode: SamplingMode,
+
+    /// How many addresses per subnet of scheduled analyses to take from the hitlist
+    /// imported by seed-guard, if it knows any in the subnet. These are probed first; the
+    /// remaining addresses are chosen according to the sampling mode (with `hitlist`, from the
+    /// prober's `HITLIST_FILE`). Set to zero to not use the imported hitlist.
+    #[arg(long, env = "ANALYSIS_HITLIST_SAMPLES_PER_SUBNET", default_value = "8")]
+    analysis_hitlist_samples_per_subnet: u16,
+
+    /// Vantage points to send each echo request to, comma-separated, which must match the
+    /// `VANTAGE_POINT`s of the zmap-buddies. Analyses wait for the responses of all of them.
+    /// If none are given, each echo request is sent once, to any vantage point.
+    #[arg(long, env = "ECHO_VANTAGE_POINTS", value_delimiter = ',')]
+    echo_vantage_points: Vec<VantagePointId>,
+
     /// Whether to run the regular prefix schedule, or not (disabling the entire feedback system eventually)
     #[arg(long, env = "AGG_DO_SCHEDULE", default_value = "true", action = clap::ArgAction::Set)]
     do_schedule: bool,
+
+    /// How often to walk the prefix tree and merge split prefixes back up
+    /// if their children turned out to be equivalent, in seconds (default = 6h)
+    #[arg(long, env = "REAGGREGATE_INTERVAL_SECS", default_value = "21600")]
+    reaggregate_interval_secs: u64,
+
+    /// Whether to run the regular re-aggregation pass, or not (splits are then final)
+    #[arg(long, env = "AGG_DO_REAGGREGATE", default_value = "true", action = clap::ArgAction::Set)]
+    do_reaggregate: bool,
+
+    /// How often to check for measurements that are due for decay, in seconds (default = 1d)
+    #[arg(long, env = "DECAY_INTERVAL_SECS", default_value = "86400")]
+    decay_interval_secs: u64,
+
+    /// After how many days measurements lose half of their weight, such that prefixes that
+    /// weren't probed for a long time lose confidence and are scheduled again
+    #[arg(long, env = "DECAY_HALF_LIFE_DAYS", default_value = "180")]
+    decay_half_life_days: u32,
+
+    /// How many leaves to decay at most per check, such that a backlog of due measurements
+    /// (e.g. after enabling decay) is worked off over multiple checks
+    #[arg(long, env = "DECAY_LEAVES_PER_TICK", default_value = "50000")]
+    decay_leaves_per_tick: u32,
+
+    /// Whether to run the regular decay pass, or not (measurements then keep their weight forever)
+    #[arg(long, env = "AGG_DO_DECAY", default_value = "true", action = clap::ArgAction::Set)]
+    do_decay: bool,
 }
 
 pub async fn run(
     probe_tx: Sender<ProbeRequest>,
     follow_up_rx: Receiver<FollowUpRequest>,
+    analyse_now_rx: Receiver<Ipv6Net>,
     stop_rx: CancellationToken,
     params: Params,
+    fan_out: FanOut,
 ) -> Result<()> {
     let follow_up_handle = tokio::spawn(follow_up::run(probe_tx.clone(), follow_up_rx));
-    let timer_handle = tokio::spawn(analysis_timer::run(probe_tx, stop_rx, params));
+    let reaggregate_handle = tokio::spawn(reaggregate_timer::run(stop_rx.clone(), params.clone()));
+    let decay_handle = tokio::spawn(decay_timer::run(
+        stop_rx.clone(),
+        params.clone(),
+        fan_out.clone(),
+    ));
+    let timer_handle = tokio::spawn(analysis_timer::run(
+        probe_tx,
+        analyse_now_rx,
+        stop_rx,
+        params,
+        fan_out,
+    ));
 
-    try_join!(flatten(follow_up_handle), flatten(timer_handle))?;
+    try_join!(
+        flatten(follow_up_handle),
+        flatten(timer_handle),
+        flatten(reaggregate_handle),
+        flatten(decay_handle)
+    )?;
     Ok(())
 }
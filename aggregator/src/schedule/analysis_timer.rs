@@ -2,18 +2,23 @@ use std::time::Duration;
 
 use crate::{analyse, observe, schedule::analysis_timer::class_budget::SelectedPrefix};
 
-use self::{as_budget::AsBudgets, class_budget::ClassBudget};
+use self::{
+    as_budget::AsBudgets,
+    class_budget::{Budget, RevalidationBudget},
+};
 
 use super::Params;
 use anyhow::*;
-use db_model::prefix_tree::{AsNumber, PriorityClass};
+use db_model::prefix_tree::{self, history, lock::with_subtree_lock, AsNumber, PriorityClass};
 use diesel::PgConnection;
+use ipnet::Ipv6Net;
 use log::{debug, error, info, warn};
-use prefix_crab::loop_with_stop;
+use prefix_crab::prefix_split::FanOut;
 use queue_models::probe_request::{EchoProbeRequest, ProbeRequest};
 use strum::IntoEnumIterator;
 use tokio::{
-    sync::mpsc::Sender,
+    select,
+    sync::mpsc::{Receiver, Sender},
     time::{interval, Instant},
 };
 use tokio_util::sync::CancellationToken;
@@ -21,34 +26,161 @@ use tracing::instrument;
 
 mod as_budget;
 mod class_budget;
+mod sampling;
+
+pub use sampling::SamplingMode;
 
 pub async fn run(
     probe_tx: Sender<ProbeRequest>,
+    analyse_now_rx: Receiver<Ipv6Net>,
     stop_rx: CancellationToken,
     params: Params,
+    fan_out: FanOut,
 ) -> Result<()> {
-    Timer { probe_tx, params }.run(stop_rx).await
+    Timer {
+        probe_tx,
+        params,
+        fan_out,
+        analysed_now_asns: vec![],
+    }
+    .run(analyse_now_rx, stop_rx)
+    .await
 }
 
 struct Timer {
     probe_tx: Sender<ProbeRequest>,
     params: Params,
+    fan_out: FanOut,
+    /// ASNs of immediate analyses since the last tick, which count against its budget
+    analysed_now_asns: Vec<AsNumber>,
 }
 
 impl Timer {
-    async fn run(mut self, stop_rx: CancellationToken) -> Result<()> {
-        if !self.params.do_schedule {
-            warn!("Regular scheduling is disabled, not doing that.");
-            return Ok(());
+    async fn run(
+        mut self,
+        mut analyse_now_rx: Receiver<Ipv6Net>,
+        stop_rx: CancellationToken,
+    ) -> Result<()> {
+        let do_schedule = self.params.do_schedule;
+        if !do_schedule {
+            warn!("Regular scheduling is disabled, only handling immediate analyses.");
+        } else {
+            info!("Analysis timer is ready for work.");
         }
-        info!("Analysis timer is ready for work.");
         let mut trigger = interval(Duration::from_secs(
             self.params.analysis_timer_interval_secs,
         ));
-        loop_with_stop!(
-            "analysis timer", stop_rx,
-            trigger.tick() => self.tick() as void_async
+        loop {
+            select! {
+                biased; // Stop should take prio
+                _ = stop_rx.cancelled() => {
+                    log::trace!("Cancellation signal received by analysis timer.");
+                    return Ok(());
+                }
+                Some(target_net) = analyse_now_rx.recv() => self.analyse_now(target_net).await,
+                _ = trigger.tick(), if do_schedule => self.tick().await,
+            }
+        }
+    }
+
+    async fn analyse_now(&mut self, target_net: Ipv6Net) {
+        if let Err(e) = self.do_analyse_now(target_net).await {
+            error!("Failed to start immediate analysis of {} due to {:?}", target_net, e);
+        }
+    }
+
+    /// Bypasses the budgets of the current interval, since an operator explicitly asked for
+    /// this, but counts against the budgets of the next tick.
+    #[instrument(skip(self))]
+    async fn do_analyse_now(&mut self, target_net: Ipv6Net) -> Result<()> {
+        let mut conn = crate::persist::connect("aggregator - analysis timer")?;
+        let begun = with_subtree_lock(&mut conn, &target_net, |conn| {
+            let node = prefix_tree::context::fetch(conn, &target_net)?.node;
+            if !node.merge_status.is_eligible_for_split() {
+                warn!(
+                    "Not analysing {} right away, since it is not a leaf but {:?}",
+                    target_net, node.merge_status
+                );
+                return Ok(None);
+            }
+            if prefix_tree::control::is_frozen(conn, &target_net)? {
+                warn!(
+                    "Not analysing {} right away, since it is frozen",
+                    target_net
+                );
+                return Ok(None);
+            }
+            analyse::persist::begin_bulk(conn, &[target_net], self.expected_echo_responses())
+                .context("saving analysis to begin")?;
+            Ok(Some(node.asn))
+        })?;
+        let Some(asn) = begun else {
+            return Ok(());
+        };
+        self.analysed_now_asns.push(asn);
+        for req in self.echo_requests(&mut conn, target_net)? {
+            self.probe_tx
+                .send(ProbeRequest::Echo(req))
+                .await
+                .context("sending immediate probe request")?;
+        }
+        info!("Immediate analysis of {} scheduled.", target_net);
+        Ok(())
+    }
+
+    /// One response per vantage point, or a single one if any vantage point may respond
+    fn expected_echo_responses(&self) -> i16 {
+        self.params.echo_vantage_points.len().clamp(1, i16::MAX as usize) as i16
+    }
+
+    /// One request per configured vantage point, sharing the same samples
+    fn echo_requests(
+        &self,
+        conn: &mut PgConnection,
+        target_net: Ipv6Net,
+    ) -> Result<Vec<EchoProbeRequest>> {
+        let req = self.echo_request(conn, target_net)?;
+        if self.params.echo_vantage_points.is_empty() {
+            return Ok(vec![req]);
+        }
+        Ok(self
+            .params
+            .echo_vantage_points
+            .iter()
+            .map(|vantage_point| EchoProbeRequest {
+                vantage_point: Some(vantage_point.clone()),
+                ..req.clone()
+            })
+            .collect())
+    }
+
+    fn echo_request(
+        &self,
+        conn: &mut PgConnection,
+        target_net: Ipv6Net,
+    ) -> Result<EchoProbeRequest> {
+        let split_bits = self.fan_out.bits_for(&target_net);
+        let strategy = sampling::strategy_for(
+            conn,
+            self.params.analysis_sampling_mode,
+            &target_net,
+            split_bits,
+        )
+        .with_context(|| format!("choosing samples for {}", target_net))?;
+        let hitlist = sampling::load_hitlist(
+            conn,
+            &target_net,
+            split_bits,
+            self.params.analysis_hitlist_samples_per_subnet,
         )
+        .with_context(|| format!("loading hitlist for {}", target_net))?;
+        Ok(EchoProbeRequest {
+            target_net,
+            split_bits,
+            sampling: strategy,
+            hitlist,
+            vantage_point: None,
+        })
     }
 
     async fn tick(&mut self) {
@@ -60,8 +192,31 @@ impl Timer {
     #[instrument(skip(self))]
     async fn do_tick(&mut self) -> Result<()> {
         let mut conn = crate::persist::connect("aggregator - analysis timer")?;
-        let mut as_budgets = as_budget::allocate(&self.params);
-        let budgets = class_budget::allocate(&mut conn, self.params.analysis_timer_prefix_budget)?;
+        let analysed_now = std::mem::take(&mut self.analysed_now_asns);
+        let mut as_budgets = as_budget::allocate(&mut conn, &self.params)?;
+        for asn in analysed_now.iter() {
+            as_budgets.try_consume(*asn);
+        }
+        let prefix_budget = self
+            .params
+            .analysis_timer_prefix_budget
+            .saturating_sub(analysed_now.len() as u32);
+
+        let start = Instant::now();
+        let mut prefix_count = 0u32;
+        let revalidation = RevalidationBudget::new(
+            self.params.analysis_timer_revalidation_budget,
+            prefix_budget,
+            self.params.analysis_revalidation_min_age_hours,
+        );
+        if revalidation.allocated > 0 {
+            prefix_count += self
+                .process_budget(&mut conn, revalidation, &mut as_budgets)
+                .await
+                .context("processing revalidation")?;
+        }
+
+        let budgets = class_budget::allocate(&mut conn, prefix_budget - prefix_count)?;
 
         for prio in PriorityClass::iter() {
             observe::record_budget(
@@ -76,12 +231,10 @@ impl Timer {
             return Ok(());
         }
 
-        let start = Instant::now();
-        let mut prefix_count = 0u32;
         for budget in budgets {
             let class = budget.class;
             prefix_count += self
-                .process_class(&mut conn, budget, &mut as_budgets)
+                .process_budget(&mut conn, budget, &mut as_budgets)
                 .await
                 .with_context(|| format!("processing class {:?}", class))?;
         }
@@ -100,14 +253,14 @@ impl Timer {
         Ok(())
     }
 
-    #[instrument(skip_all, fields(class = ?budget.class))]
-    async fn process_class(
+    #[instrument(skip_all, fields(budget = ?budget))]
+    async fn process_budget(
         &self,
         conn: &mut PgConnection,
-        budget: ClassBudget,
+        budget: impl Budget,
         as_budgets: &mut AsBudgets,
     ) -> Result<u32> {
-        let mut available_allocation = budget.allocated;
+        let mut available_allocation = budget.allocated();
         let mut suppressed_count = 0u32;
         for _ in 0..5 {
             suppressed_count = 0; // reset since this represents slots that remain open _only_ due to AS rate limit, and not just "no prefixes available"
@@ -124,14 +277,16 @@ impl Timer {
                 }
             }
 
-            analyse::persist::begin_bulk(conn, &admitted_prefixes)
+            let begun_prefixes = self
+                .begin_analyses(conn, admitted_prefixes)
                 .context("saving analyses to begin")?;
 
-            for target_net in admitted_prefixes {
-                let req = EchoProbeRequest { target_net };
-                if self.probe_tx.send(ProbeRequest::Echo(req)).await.is_err() {
-                    info!("Receiver closed probe channel, assume shutdown.");
-                    return Ok(budget.allocated - available_allocation);
+            for target_net in begun_prefixes {
+                for req in self.echo_requests(conn, target_net)? {
+                    if self.probe_tx.send(ProbeRequest::Echo(req)).await.is_err() {
+                        info!("Receiver closed probe channel, assume shutdown.");
+                        return Ok(budget.allocated() - available_allocation);
+                    }
                 }
             }
 
@@ -143,10 +298,34 @@ impl Timer {
         }
         if suppressed_count > 0 {
             debug!(
-                "Unable to fill class {:?} even after 5 retries, {} slots still suppressed",
-                budget.class, suppressed_count
+                "Unable to fill {:?} even after 5 retries, {} slots still suppressed",
+                budget, suppressed_count
             );
         }
-        Ok(budget.allocated - available_allocation)
+        Ok(budget.allocated() - available_allocation)
+    }
+
+    /// Begins analyses of the nets that are still leaves. Another aggregator instance may have
+    /// split or merged them since they were selected, so each one is checked while holding its
+    /// subtree lock.
+    fn begin_analyses(&self, conn: &mut PgConnection, nets: Vec<Ipv6Net>) -> Result<Vec<Ipv6Net>> {
+        let mut begun = Vec::with_capacity(nets.len());
+        for net in nets {
+            let is_begun = with_subtree_lock(conn, &net, |conn| {
+                let status = history::load_for_change(conn, &[net])?
+                    .first()
+                    .map(|it| it.merge_status);
+                if !status.is_some_and(|it| it.is_eligible_for_split()) {
+                    debug!("Not analysing {}, since it changed to {:?}", net, status);
+                    return Ok(false);
+                }
+                analyse::persist::begin_bulk(conn, &[net], self.expected_echo_responses())?;
+                Ok(true)
+            })?;
+            if is_begun {
+                begun.push(net);
+            }
+        }
+        Ok(begun)
     }
 }
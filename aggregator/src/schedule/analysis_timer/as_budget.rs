@@ -1,17 +1,42 @@
+use anyhow::Result;
 use db_model::prefix_tree::AsNumber;
+use diesel::prelude::*;
 use nohash_hasher::IntMap;
 use tracing::instrument;
 
+use crate::persist::DieselErrorFixCause;
 use crate::schedule::Params;
 
-#[instrument(name = "allocate AS budgets", skip(params))]
-pub fn allocate(params: &Params) -> AsBudgets {
-    AsBudgets::new(params.analysis_timer_max_prefix_per_as)
+/// Allocates the per-AS budgets, scaling the default allocation with what was learned about each
+/// AS from previous responses (see `handle_probe::as_rate`).
+#[instrument(name = "allocate AS budgets", skip(conn, params))]
+pub fn allocate(conn: &mut PgConnection, params: &Params) -> Result<AsBudgets> {
+    use crate::schema::as_rate_budget::dsl::*;
+
+    let scales: Vec<(AsNumber, f32)> = as_rate_budget
+        .select((asn, scale))
+        .load(conn)
+        .fix_cause()?;
+    let default_allocation = params.analysis_timer_max_prefix_per_as;
+    let allocation_overrides = scales
+        .into_iter()
+        .map(|(it, factor)| (it, scale_allocation(default_allocation, factor)))
+        .collect();
+    Ok(AsBudgets {
+        allocation_overrides,
+        ..AsBudgets::new(default_allocation)
+    })
+}
+
+fn scale_allocation(default_allocation: usize, factor: f32) -> usize {
+    // at least one, otherwise the AS would never be probed again, and could never recover
+    ((default_allocation as f32 * factor).round() as usize).max(1)
 }
 
 #[derive(Default)]
 pub struct AsBudgets {
     allocation_per_as: usize,
+    allocation_overrides: IntMap<AsNumber, usize>,
     pub consumed_per_as: IntMap<AsNumber, usize>,
     exhausted_asns: Vec<AsNumber>,
 }
@@ -24,13 +49,21 @@ impl AsBudgets {
         }
     }
 
+    fn allocation_for(&self, asn: AsNumber) -> usize {
+        *self
+            .allocation_overrides
+            .get(&asn)
+            .unwrap_or(&self.allocation_per_as)
+    }
+
     pub fn try_consume(&mut self, asn: AsNumber) -> bool {
+        let allocation = self.allocation_for(asn);
         let consumed = self.consumed_per_as.entry(asn).or_default();
-        if consumed >= &mut self.allocation_per_as {
+        if *consumed >= allocation {
             false
         } else {
             *consumed += 1;
-            if consumed == &mut self.allocation_per_as {
+            if *consumed == allocation {
                 self.exhausted_asns.push(asn)
             }
             true
@@ -45,3 +78,46 @@ impl AsBudgets {
         &self.exhausted_asns
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+
+    use super::*;
+
+    #[test]
+    fn consume_with_override() {
+        // given
+        let mut budgets = AsBudgets {
+            allocation_overrides: [(1, 1)].into_iter().collect(),
+            ..AsBudgets::new(2)
+        };
+
+        // when
+        let first_scaled = budgets.try_consume(1);
+        let second_scaled = budgets.try_consume(1);
+        let first_default = budgets.try_consume(2);
+        let second_default = budgets.try_consume(2);
+
+        // then
+        assert_that!(first_scaled).is_true();
+        assert_that!(second_scaled).is_false();
+        assert_that!(first_default).is_true();
+        assert_that!(second_default).is_true();
+        assert_that!(budgets.get_exhausted_asns().to_vec()).contains_exactly(vec![1, 2]);
+    }
+
+    #[test]
+    fn scale_never_zero() {
+        // given
+        let default_allocation = 223;
+
+        // when
+        let doubled = scale_allocation(default_allocation, 2.0);
+        let tiny = scale_allocation(default_allocation, 0.001);
+
+        // then
+        assert_that!(doubled).is_equal_to(446);
+        assert_that!(tiny).is_equal_to(1);
+    }
+}
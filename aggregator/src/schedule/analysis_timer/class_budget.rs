@@ -8,10 +8,12 @@ use prefix_crab::helpers::ip::ExpectV6;
 use rand::Rng;
 use tracing::instrument;
 use std::collections::{btree_map, BTreeMap};
+use std::fmt::Debug;
 
 use diesel::prelude::*;
 use diesel::{PgConnection, QueryDsl};
 
+use crate::persist::dsl::CidrMethods;
 use crate::persist::DieselErrorFixCause;
 use db_model::prefix_tree::{AsNumber, MergeStatus, PriorityClass};
 
@@ -68,6 +70,7 @@ macro_rules! leaf_where_no_analysis {
     (let $var_name:ident = it) => {
         use crate::schema::prefix_tree::dsl::*;
         use crate::schema::split_analysis::dsl as ana;
+        use crate::schema::prefix_control::dsl as ctl;
 
         let a_pending_analysis = ana::split_analysis
             .select(0.into_sql::<Integer>())
@@ -75,13 +78,45 @@ macro_rules! leaf_where_no_analysis {
             .filter(ana::completed_at.is_null())
             .filter(not(ana::created_at.lt(now - 4.hours()))); // retry unfinished analyses
 
+        let a_freeze_above = ctl::prefix_control
+            .select(0.into_sql::<Integer>())
+            .filter(ctl::frozen.eq(true))
+            .filter(ctl::net.supernet_or_eq(net));
+
         let $var_name = prefix_tree
             .filter(not(exists(a_pending_analysis)))
+            .filter(not(exists(a_freeze_above))) // frozen by operator
             .filter(merge_status.eq_any(&[MergeStatus::Leaf, MergeStatus::UnsplitRoot]))
             .filter(not(confidence.eq(255)));
     };
 }
 
+/// Leaves that converged, i.e. reached maximum confidence, and were not analysed for the given
+/// number of hours, as candidates for revalidation.
+macro_rules! converged_leaf_where_no_recent_analysis {
+    (let $var_name:ident = it, min_age_hours = $min_age_hours:expr) => {
+        use crate::schema::prefix_tree::dsl::*;
+        use crate::schema::split_analysis::dsl as ana;
+        use crate::schema::prefix_control::dsl as ctl;
+
+        let a_recent_analysis = ana::split_analysis
+            .select(0.into_sql::<Integer>())
+            .filter(ana::tree_net.eq(net))
+            .filter(ana::created_at.gt(now - ($min_age_hours as i32).hours()));
+
+        let a_freeze_above = ctl::prefix_control
+            .select(0.into_sql::<Integer>())
+            .filter(ctl::frozen.eq(true))
+            .filter(ctl::net.supernet_or_eq(net));
+
+        let $var_name = prefix_tree
+            .filter(not(exists(a_recent_analysis)))
+            .filter(not(exists(a_freeze_above))) // frozen by operator
+            .filter(merge_status.eq_any(&[MergeStatus::Leaf, MergeStatus::UnsplitRoot]))
+            .filter(confidence.eq(255));
+    };
+}
+
 fn count_available_per_class(conn: &mut PgConnection) -> Result<BTreeMap<PriorityClass, u64>> {
     leaf_where_no_analysis!(let base = it);
     let tuples = base
@@ -189,17 +224,33 @@ impl Iterator for BudgetsIntoIter {
     }
 }
 
+/// A number of prefixes to analyse, and how to find them
+pub trait Budget: Debug {
+    fn allocated(&self) -> u32;
+
+    fn select_prefixes(
+        &self,
+        conn: &mut PgConnection,
+        as_budgets: &AsBudgets,
+    ) -> Result<Vec<SelectedPrefix>>;
+}
+
+#[derive(Debug)]
 pub struct ClassBudget {
     pub class: PriorityClass,
     pub allocated: u32,
 }
 
-impl ClassBudget {
-    pub fn select_prefixes(
+impl Budget for ClassBudget {
+    fn allocated(&self) -> u32 {
+        self.allocated
+    }
+
+    fn select_prefixes(
         &self,
         conn: &mut PgConnection,
         as_budgets: &AsBudgets,
-    ) -> Result<impl Iterator<Item = SelectedPrefix>> {
+    ) -> Result<Vec<SelectedPrefix>> {
         leaf_where_no_analysis!(let base = it);
 
         let mut raw_nets = base
@@ -217,7 +268,52 @@ impl ClassBudget {
         // TODO actually select prefixes at random and not just in undefined/physical order
         // e.g. consider https://www.postgresql.org/docs/current/tsm-system-rows.html
 
-        Ok(raw_nets.into_iter().map_into())
+        Ok(raw_nets.into_iter().map_into().collect())
+    }
+}
+
+/// Converged leaves are never selected by the class budgets, since there is nothing left to
+/// decide. This budget re-probes them nonetheless once in a while to detect changes in the
+/// network, see [crate::analyse::drift].
+#[derive(Debug)]
+pub struct RevalidationBudget {
+    pub allocated: u32,
+    pub min_age_hours: u32,
+}
+
+impl RevalidationBudget {
+    /// Revalidation is taken from the prefix budget, so it gets at most all of it.
+    pub fn new(configured: u32, prefix_budget: u32, min_age_hours: u32) -> Self {
+        Self {
+            allocated: configured.min(prefix_budget),
+            min_age_hours,
+        }
+    }
+}
+
+impl Budget for RevalidationBudget {
+    fn allocated(&self) -> u32 {
+        self.allocated
+    }
+
+    fn select_prefixes(
+        &self,
+        conn: &mut PgConnection,
+        as_budgets: &AsBudgets,
+    ) -> Result<Vec<SelectedPrefix>> {
+        converged_leaf_where_no_recent_analysis!(let base = it, min_age_hours = self.min_age_hours);
+
+        let mut raw_nets = base
+            .select((net, asn))
+            .limit(self.allocated as i64)
+            .into_boxed();
+
+        if as_budgets.has_exhausted_asns() {
+            raw_nets = raw_nets.filter(asn.ne_all(as_budgets.get_exhausted_asns()));
+        }
+
+        let raw_nets: Vec<(IpNet, AsNumber)> = raw_nets.load(conn).fix_cause()?;
+        Ok(raw_nets.into_iter().map_into().collect())
     }
 }
 
@@ -309,4 +405,31 @@ mod tests {
         assert_that!(budgets.available_per_class.get(&PriorityClass::HighFresh)).is_none();
         assert_that!(budgets.available_per_class.get(&PriorityClass::LowUnknown)).is_some();
     }
+
+    #[test]
+    fn revalidation_within_prefix_budget() {
+        // given
+        let (configured, min_age_hours) = (5, 720);
+
+        // when
+        let plenty = RevalidationBudget::new(configured, 20, min_age_hours);
+        let scarce = RevalidationBudget::new(configured, 2, min_age_hours);
+
+        // then
+        assert_that!(plenty.allocated()).is_equal_to(5);
+        assert_that!(scarce.allocated()).is_equal_to(2);
+        assert_that!(scarce.min_age_hours).is_equal_to(720);
+    }
+
+    #[test]
+    fn revalidation_disabled() {
+        // given
+        let configured = 0;
+
+        // when
+        let budget = RevalidationBudget::new(configured, 20, 720);
+
+        // then
+        assert_that!(budget.allocated()).is_equal_to(0);
+    }
 }
@@ -0,0 +1,220 @@
+use std::net::Ipv6Addr;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use db_model::analyse::ResponsiveTargets;
+use db_model::hitlist::to_addrs;
+use diesel::prelude::*;
+use diesel::sql_types::{Array, BigInt, Cidr, Integer, Jsonb};
+use ipnet::{IpNet, Ipv6Net};
+use prefix_crab::helpers::ip::ExpectV6;
+use prefix_crab::prefix_split::{self, PrefixSplit, SAMPLES_PER_SUBNET};
+use queue_models::probe_request::SamplingStrategy;
+
+use crate::persist::DieselErrorFixCause;
+
+/// Which [SamplingStrategy] to request for scheduled analyses
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum SamplingMode {
+    Uniform,
+    LowByte,
+    FirstHost,
+    Hitlist,
+    PreviouslyResponsive,
+}
+
+pub fn strategy_for(
+    conn: &mut PgConnection,
+    mode: SamplingMode,
+    target_net: &Ipv6Net,
+    split_bits: u8,
+) -> Result<SamplingStrategy> {
+    Ok(match mode {
+        SamplingMode::Uniform => SamplingStrategy::Uniform,
+        SamplingMode::LowByte => SamplingStrategy::LowByte,
+        SamplingMode::FirstHost => SamplingStrategy::FirstHost,
+        SamplingMode::Hitlist => SamplingStrategy::Hitlist,
+        SamplingMode::PreviouslyResponsive => SamplingStrategy::PreviouslyResponsive {
+            addresses: load_responsive_targets(conn, target_net, split_bits)?,
+        },
+    })
+}
+
+/// Addresses that previously answered echo requests in the net, at most [SAMPLES_PER_SUBNET]
+/// for each subnet it will be split into. The most responsive measurement nodes of each subnet
+/// are preferred. If there are none, the prober falls back to uniform sampling.
+fn load_responsive_targets(
+    conn: &mut PgConnection,
+    target_net: &Ipv6Net,
+    split_bits: u8,
+) -> Result<Vec<Ipv6Addr>> {
+    #[derive(QueryableByName)]
+    struct Row {
+        #[diesel(sql_type = Jsonb)]
+        responsive_targets: ResponsiveTargets,
+    }
+
+    let split = prefix_split::split(*target_net, split_bits)?;
+    // Nodes with responses usually know at least one target, so this many nodes per subnet suffice
+    let rows: Vec<Row> = diesel::sql_query(
+        "
+        SELECT responsive_targets FROM (
+            SELECT responsive_targets, ROW_NUMBER() OVER (
+                PARTITION BY set_masklen(target_net, $2)
+                ORDER BY responsive_count DESC, target_net
+            ) AS subnet_rank
+            FROM measurement_tree
+            WHERE target_net <<= $1 AND responsive_count > 0
+        ) ranked
+        WHERE subnet_rank <= $3
+    ",
+    )
+    .bind::<Cidr, _>(IpNet::V6(*target_net))
+    .bind::<Integer, _>(split.subnet_prefix_len as i32)
+    .bind::<BigInt, _>(SAMPLES_PER_SUBNET as i64)
+    .load(conn)
+    .fix_cause()?;
+    let addrs = rows.into_iter().flat_map(|it| it.responsive_targets.items);
+    Ok(spread_over_subnets(
+        &split,
+        addrs,
+        SAMPLES_PER_SUBNET as usize,
+    ))
+}
+
+/// Hitlist addresses for each subnet the net will be split into, at most `per_subnet` each.
+/// Rows are ranked within each subnet in a single query, so that addresses are spread over all
+/// of them.
+pub fn load_hitlist(
+    conn: &mut PgConnection,
+    target_net: &Ipv6Net,
+    split_bits: u8,
+    per_subnet: u16,
+) -> Result<Vec<Ipv6Addr>> {
+    #[derive(QueryableByName)]
+    struct Row {
+        #[diesel(sql_type = Cidr)]
+        net: IpNet,
+        #[diesel(sql_type = Array<BigInt>)]
+        interface_ids: Vec<i64>,
+    }
+
+    if per_subnet == 0 {
+        return Ok(vec![]);
+    }
+    let split = prefix_split::split(*target_net, split_bits)?;
+    // Each row has at least one address, so this many rows per subnet suffice
+    let rows: Vec<Row> = diesel::sql_query(
+        "
+        SELECT net, interface_ids FROM (
+            SELECT net, interface_ids, ROW_NUMBER() OVER (
+                PARTITION BY set_masklen(net, $2) ORDER BY net
+            ) AS subnet_rank
+            FROM hitlist
+            WHERE net <<= $1
+        ) ranked
+        WHERE subnet_rank <= $3
+        ORDER BY net
+    ",
+    )
+    .bind::<Cidr, _>(IpNet::V6(*target_net))
+    .bind::<Integer, _>(split.subnet_prefix_len as i32)
+    .bind::<BigInt, _>(per_subnet as i64)
+    .load(conn)
+    .fix_cause()?;
+    let addrs = rows
+        .iter()
+        .flat_map(|row| to_addrs(&row.net.expect_v6(), &row.interface_ids));
+    Ok(spread_over_subnets(&split, addrs, per_subnet as usize))
+}
+
+/// At most `per_subnet` of the addresses for each subnet of the split, in subnet order.
+/// Addresses outside of the split are dropped.
+fn spread_over_subnets(
+    split: &PrefixSplit,
+    addrs: impl IntoIterator<Item = Ipv6Addr>,
+    per_subnet: usize,
+) -> Vec<Ipv6Addr> {
+    let mut by_subnet: Vec<Vec<Ipv6Addr>> = split.iter().map(|_| vec![]).collect();
+    for addr in addrs {
+        let Some(index) = split.iter().position(|it| it.network.contains(&addr)) else {
+            continue;
+        };
+        let subnet_addrs = &mut by_subnet[index];
+        if subnet_addrs.len() < per_subnet && !subnet_addrs.contains(&addr) {
+            subnet_addrs.push(addr);
+        }
+    }
+    by_subnet.into_iter().flatten().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+    use db_model::test_utils::*;
+
+    use super::*;
+
+    #[test]
+    fn spread_caps_each_subnet() -> Result<()> {
+        // given
+        let split = prefix_split::split(net("2001:db8::/32"), 1)?;
+        let addrs = vec![
+            addr("2001:db8::1"),
+            addr("2001:db8::2"),
+            addr("2001:db8::3"),
+            addr("2001:db8:8000::1"),
+        ];
+
+        // when
+        let spread = spread_over_subnets(&split, addrs, 2);
+
+        // then
+        assert_that!(spread).contains_exactly_in_order(vec![
+            addr("2001:db8::1"),
+            addr("2001:db8::2"),
+            addr("2001:db8:8000::1"),
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn spread_orders_by_subnet() -> Result<()> {
+        // given
+        let split = prefix_split::split(net("2001:db8::/32"), 2)?;
+        let addrs = vec![
+            addr("2001:db8:c000::1"),
+            addr("2001:db8:4000::1"),
+            addr("2001:db8::1"),
+        ];
+
+        // when
+        let spread = spread_over_subnets(&split, addrs, 16);
+
+        // then
+        assert_that!(spread).contains_exactly_in_order(vec![
+            addr("2001:db8::1"),
+            addr("2001:db8:4000::1"),
+            addr("2001:db8:c000::1"),
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn spread_drops_foreign_and_duplicate() -> Result<()> {
+        // given
+        let split = prefix_split::split(net("2001:db8::/32"), 1)?;
+        let addrs = vec![
+            addr("2001:db9::1"),
+            addr("2001:db8::1"),
+            addr("2001:db8::1"),
+        ];
+
+        // when
+        let spread = spread_over_subnets(&split, addrs, 16);
+
+        // then
+        assert_that!(spread).contains_exactly_in_order(vec![addr("2001:db8::1")]);
+        Ok(())
+    }
+}
@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+use anyhow::*;
+use log::{error, info, warn};
+use prefix_crab::{loop_with_stop, prefix_split::FanOut};
+use tokio::time::{interval, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+
+use crate::{analyse::split::decay, observe};
+
+use super::Params;
+
+pub async fn run(stop_rx: CancellationToken, params: Params, fan_out: FanOut) -> Result<()> {
+    if !params.do_decay {
+        warn!("Decay is disabled, measurements will keep their weight forever.");
+        return Ok(());
+    }
+    info!(
+        "Decay scheduled every {}s, with a half-life of {} days.",
+        params.decay_interval_secs, params.decay_half_life_days
+    );
+    let half_life = chrono::Duration::days(params.decay_half_life_days as i64);
+    let max_leaves = params.decay_leaves_per_tick;
+    let mut trigger = interval(Duration::from_secs(params.decay_interval_secs));
+    loop_with_stop!(
+        "decay timer", stop_rx,
+        trigger.tick() => tick(half_life, max_leaves, (&fan_out)) as simple
+    )
+}
+
+fn tick(half_life: chrono::Duration, max_leaves: u32, fan_out: &FanOut) {
+    if let Err(e) = do_tick(half_life, max_leaves, fan_out) {
+        error!("Failed to perform scheduled decay due to {:?}", e);
+    }
+}
+
+#[instrument(skip(fan_out))]
+fn do_tick(half_life: chrono::Duration, max_leaves: u32, fan_out: &FanOut) -> Result<()> {
+    let mut conn = crate::persist::connect("aggregator - decay timer")?;
+    let start = Instant::now();
+
+    let stats = decay::process(&mut conn, half_life, max_leaves, fan_out)?;
+    observe::record_decay(stats.decayed_trees as u64, stats.lowered_confidences as u64);
+
+    info!(
+        "Decay halved {} measurement trees and lowered the confidence of {} prefixes in {}ms.",
+        stats.decayed_trees,
+        stats.lowered_confidences,
+        start.elapsed().as_millis(),
+    );
+    Ok(())
+}
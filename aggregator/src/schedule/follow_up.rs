@@ -3,7 +3,10 @@ use std::net::Ipv6Addr;
 use anyhow::*;
 use db_model::prefix_tree::PrefixTree;
 use log::{debug, info};
-use queue_models::probe_request::{ProbeRequest, TraceRequest, TraceRequestId};
+use queue_models::{
+    probe_request::{ProbeRequest, TraceRequest, TraceRequestId},
+    VantagePointId,
+};
 use rand::prelude::*;
 use tokio::sync::mpsc::{Receiver, Sender};
 
@@ -14,6 +17,8 @@ pub struct FollowUpRequest {
     pub id: TraceRequestId,
     pub prefix_tree: PrefixTree,
     pub follow_ups: Vec<EchoFollowUp>,
+    /// Vantage point to trace from, see [TraceRequest::vantage_point]
+    pub vantage_point: Option<VantagePointId>,
 }
 
 pub async fn run(
@@ -34,6 +39,7 @@ pub async fn run(
 #[allow(clippy::if_same_then_else)] // same expr, different reason/meaning
 fn flatten_request(req: FollowUpRequest) -> ProbeRequest {
     let id = req.id;
+    let vantage_point = req.vantage_point.clone();
     let targets = if req.prefix_tree.confidence > 100 && thread_rng().gen_ratio(2, 3) {
         vec![] // if we have full confidence already, skip follow-ups in 2/3 of cases
     } else if req.prefix_tree.confidence > 60 && thread_rng().gen_ratio(1, 5) {
@@ -46,7 +52,12 @@ fn flatten_request(req: FollowUpRequest) -> ProbeRequest {
         targets.len()
     );
 
-    ProbeRequest::Trace(TraceRequest { id, targets })
+    ProbeRequest::Trace(TraceRequest {
+        id,
+        targets,
+        vantage_point,
+        is_remainder: false,
+    })
 }
 
 fn make_actual_targets(req: FollowUpRequest) -> Vec<Ipv6Addr> {
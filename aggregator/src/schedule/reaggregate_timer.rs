@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+use anyhow::*;
+use log::{error, info, warn};
+use prefix_crab::loop_with_stop;
+use tokio::time::{interval, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+
+use crate::{analyse::split::reaggregate, observe};
+
+use super::Params;
+
+pub async fn run(stop_rx: CancellationToken, params: Params) -> Result<()> {
+    if !params.do_reaggregate {
+        warn!("Re-aggregation is disabled, split prefixes will never be merged back.");
+        return Ok(());
+    }
+    info!(
+        "Re-aggregation scheduled every {}s.",
+        params.reaggregate_interval_secs
+    );
+    let mut trigger = interval(Duration::from_secs(params.reaggregate_interval_secs));
+    loop_with_stop!(
+        "re-aggregate timer", stop_rx,
+        trigger.tick() => tick() as simple
+    )
+}
+
+fn tick() {
+    if let Err(e) = do_tick() {
+        error!("Failed to perform scheduled re-aggregation due to {:?}", e);
+    }
+}
+
+#[instrument]
+fn do_tick() -> Result<()> {
+    let mut conn = crate::persist::connect("aggregator - re-aggregate timer")?;
+    let start = Instant::now();
+
+    let merged_count = reaggregate::process(&mut conn)?;
+    observe::record_reaggregate_merges(merged_count as u64);
+
+    info!(
+        "Re-aggregation merged {} prefixes in {}ms.",
+        merged_count,
+        start.elapsed().as_millis(),
+    );
+    Ok(())
+}
@@ -2,23 +2,39 @@ use anyhow::Result;
 use clap::Subcommand;
 use log::debug;
 
+mod archive_replay;
+mod dlq_replay;
 mod edge_analyse;
+mod export;
 mod hit_count;
+mod prefix_control;
 mod prefix_inspect;
 mod prefix_scan;
 mod rate_calculate;
+mod split_replay;
+mod topology_generate;
+mod topology_score;
 mod tree_compare;
+mod tree_diff;
 mod uniform_merge;
 
 pub fn handle(cmd: Commands) -> Result<()> {
     let command_result = match cmd {
         Commands::PrefixScan(data) => prefix_scan::handle(data),
         Commands::PrefixInspect(data) => prefix_inspect::handle(data),
+        Commands::PrefixControl(data) => prefix_control::handle(data),
         Commands::RateCalculate(data) => rate_calculate::handle(data),
         Commands::EdgeAnalyse(data) => edge_analyse::handle(data),
         Commands::HitCount(data) => hit_count::handle(data),
         Commands::TreeCompare(data) => tree_compare::handle(data),
+        Commands::TreeDiff(data) => tree_diff::handle(data),
         Commands::UniformMerge(data) => uniform_merge::handle(data),
+        Commands::DlqReplay(data) => dlq_replay::handle(data),
+        Commands::ArchiveReplay(data) => archive_replay::handle(data),
+        Commands::SplitReplay(data) => split_replay::handle(data),
+        Commands::TopologyGenerate(data) => topology_generate::handle(data),
+        Commands::TopologyScore(data) => topology_score::handle(data),
+        Commands::Export(data) => export::handle(data),
     };
     debug!("Finished command execution. Result: {:?}", command_result);
     command_result
@@ -28,9 +44,17 @@ pub fn handle(cmd: Commands) -> Result<()> {
 pub enum Commands {
     PrefixScan(prefix_scan::Params),
     PrefixInspect(prefix_inspect::Params),
+    PrefixControl(prefix_control::Params),
     RateCalculate(rate_calculate::Params),
     EdgeAnalyse(edge_analyse::Params),   // evaluation E
     HitCount(hit_count::Params),         // evaluation A
     TreeCompare(tree_compare::Params),   // evaluation F
+    TreeDiff(tree_diff::Params),
     UniformMerge(uniform_merge::Params), // evaluation G
+    DlqReplay(dlq_replay::Params),
+    ArchiveReplay(archive_replay::Params),
+    SplitReplay(split_replay::Params),
+    TopologyGenerate(topology_generate::Params),
+    TopologyScore(topology_score::Params),
+    Export(export::Params),
 }
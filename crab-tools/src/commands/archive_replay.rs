@@ -0,0 +1,397 @@
+use aggregator::analyse::{
+    self,
+    context::{self, ContextFetchError},
+    persist::UpdateAnalysis,
+    split,
+};
+use anyhow::*;
+use clap::Args;
+use db_model::{
+    persist::{self, dsl::CidrMethods, DieselErrorFixCause},
+    prefix_tree::{self, AsNumber, ContextOps, MergeStatus, PrefixTree},
+};
+use diesel::{pg::PgRowByRowLoadingMode, prelude::*, PgConnection};
+use futures::executor;
+use ipnet::{IpNet, Ipv6Net};
+use itertools::Itertools;
+use log::{debug, info, warn};
+use prefix_crab::{
+    blocklist::{self, PrefixBlocklist},
+    helpers::ip::ExpectV6,
+    prefix_split::{self, FanOut},
+};
+use queue_models::probe_response::{EchoProbeResponse, TraceResponse};
+use serde_json::Value;
+
+/// Re-processes the response archive of one database through the analysis pipeline of the
+/// aggregator, saving the results to another (usually fresh) database. This allows evaluating
+/// changes to the analysis against historical data without probing again.
+///
+/// Note that the replay can only follow the original probing decisions. If the replayed
+/// tree diverges from the original one (e.g. a prefix is split differently), responses for
+/// prefixes that are not a leaf in the replayed tree are skipped.
+#[derive(Args, Clone)]
+pub struct Params {
+    /// Database to write the replayed analyses to (migrations are applied automatically)
+    #[clap(flatten)]
+    persist: persist::Params,
+
+    /// Database to read the response archive from
+    #[arg(long, env = "DATABASE_URL_ARCHIVE")]
+    db_url_archive: String,
+
+    #[clap(flatten)]
+    blocklist: blocklist::Params,
+
+    /// Should match the original aggregator, since the archived responses were split with it
+    #[clap(flatten)]
+    prefix_split: prefix_split::Params,
+
+    #[clap(flatten)]
+    split: split::Params,
+
+    /// Copy root nodes from the archive database before replaying, needed for an empty database
+    #[arg(long, default_value = "true", action = clap::ArgAction::Set)]
+    seed_roots: bool,
+
+    /// How many archive entries to replay at most (all if not given)
+    #[arg(long)]
+    limit: Option<i64>,
+}
+
+impl Params {
+    fn archive_db(&self) -> persist::Params {
+        persist::Params::new(self.db_url_archive.to_owned())
+    }
+}
+
+pub fn handle(params: Params) -> Result<()> {
+    let replay_handle = tokio::task::spawn_blocking(move || run(params));
+    let stats = executor::block_on(replay_handle)??;
+
+    info!("Replay finished: {:?}", stats);
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct ReplayStats {
+    echo: u64,
+    trace: u64,
+    skipped: u64,
+    failed: u64,
+}
+
+fn run(params: Params) -> Result<ReplayStats> {
+    persist::initialize(&params.persist)?;
+    let mut conn = persist::connect("crab-tools - archive-replay")?;
+    let mut archive_conn =
+        persist::connect_manual("crab-tools - archive-replay - source", &params.archive_db())?;
+    let blocklist = blocklist::read(params.blocklist.clone())?;
+
+    if params.seed_roots {
+        seed_roots(&mut archive_conn, &mut conn)?;
+    }
+
+    let mut replay = Replay {
+        conn,
+        blocklist,
+        fan_out: params.prefix_split.prefix_split_fan_out.clone(),
+        split: params.split.clone(),
+        stats: ReplayStats::default(),
+    };
+
+    let entries = {
+        use db_model::schema::response_archive::dsl::*;
+
+        let query = response_archive
+            .select((path, data))
+            .order_by((created_at.asc(), id.asc()))
+            .limit(params.limit.unwrap_or(i64::MAX));
+        query
+            .load_iter::<(IpNet, Value), PgRowByRowLoadingMode>(&mut archive_conn)
+            .fix_cause()?
+    };
+
+    for entry in entries {
+        let (archived_path, archived_data) = entry.context("iterating response archive")?;
+        replay.process(archived_path.expect_v6(), archived_data);
+
+        let stats = &replay.stats;
+        if (stats.echo + stats.trace + stats.skipped + stats.failed) % 1000 == 0 {
+            info!(" ... Replay status: {:?}", replay.stats);
+        }
+    }
+
+    Ok(replay.stats)
+}
+
+fn seed_roots(archive_conn: &mut PgConnection, conn: &mut PgConnection) -> Result<()> {
+    use db_model::schema::prefix_tree::dsl::*;
+
+    let roots: Vec<(IpNet, AsNumber)> = prefix_tree
+        .filter(merge_status.eq_any([MergeStatus::UnsplitRoot, MergeStatus::SplitRoot]))
+        .select((net, asn))
+        .load(archive_conn)
+        .fix_cause()?;
+
+    let tuples = roots
+        .iter()
+        .map(|(root_net, root_asn)| {
+            (
+                net.eq6(root_net.expect_v6()),
+                merge_status.eq(MergeStatus::UnsplitRoot),
+                asn.eq(*root_asn),
+            )
+        })
+        .collect_vec();
+
+    let mut inserted = 0;
+    for chunk in tuples.chunks(1000) {
+        inserted += diesel::insert_into(prefix_tree)
+            .values(chunk)
+            .on_conflict_do_nothing()
+            .execute(conn)
+            .fix_cause()?;
+    }
+    info!("Seeded {} of {} root nodes.", inserted, roots.len());
+    Ok(())
+}
+
+/// Archived responses don't record their type, so we need to try both
+#[derive(Debug)]
+enum Archived {
+    Echo(EchoProbeResponse),
+    Trace(TraceResponse),
+}
+
+fn parse_archived(data: Value) -> Result<Archived> {
+    if let Result::Ok(echo) = serde_json::from_value::<EchoProbeResponse>(data.clone()) {
+        Ok(Archived::Echo(echo))
+    } else if let Result::Ok(trace) = serde_json::from_value::<TraceResponse>(data) {
+        Ok(Archived::Trace(trace))
+    } else {
+        Err(anyhow!("Unparseable response archive entry"))
+    }
+}
+
+/// Echo responses are only replayed for nodes that the original aggregator would have
+/// analysed, i.e. leaves of the replayed tree
+fn is_replayable(node: &PrefixTree) -> bool {
+    node.merge_status.is_eligible_for_split()
+}
+
+struct Replay {
+    conn: PgConnection,
+    blocklist: PrefixBlocklist,
+    fan_out: FanOut,
+    split: split::Params,
+    stats: ReplayStats,
+}
+
+impl Replay {
+    fn process(&mut self, archived_path: Ipv6Net, archived_data: Value) {
+        let res = match parse_archived(archived_data) {
+            Result::Ok(Archived::Echo(echo)) => self.process_echo(echo),
+            Result::Ok(Archived::Trace(trace)) => self.process_trace(archived_path, trace),
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = res {
+            warn!(" !!! Error replaying {}: {:?}. Continuing.", archived_path, e);
+            self.stats.failed += 1;
+        }
+    }
+
+    fn process_echo(&mut self, res: EchoProbeResponse) -> Result<()> {
+        let Some(mut context) = self.fetch_or_begin(&res.target_net)? else {
+            self.stats.skipped += 1;
+            return Ok(());
+        };
+
+        analyse::echo::process(&res)
+            .update_analysis(&mut self.conn, &mut context)
+            .context("while saving analysis data")?;
+
+        let needs_follow_up = !context.analysis.pending_follow_ups.is_empty();
+        if !needs_follow_up && !context.analysis.awaits_echo_responses() {
+            // Otherwise, the split is handled with the trace response that follows
+            split::process(
+                &mut self.conn,
+                context,
+                &self.blocklist,
+                &self.fan_out,
+                &self.split,
+            )?;
+        }
+        self.stats.echo += 1;
+        Ok(())
+    }
+
+    fn fetch_or_begin(&mut self, net: &Ipv6Net) -> Result<Option<context::Context>> {
+        let tree_context = match prefix_tree::context::fetch(&mut self.conn, net) {
+            Err(prefix_tree::context::ContextFetchError::NotInPrefixTree { net: _ }) => {
+                debug!("Skipping {} since it is not in the replayed tree", net);
+                return Ok(None);
+            }
+            other => other?,
+        };
+        if !is_replayable(tree_context.node()) {
+            debug!("Skipping {} since it is not a leaf in the replayed tree", net);
+            return Ok(None);
+        }
+
+        // The original scheduler created the analysis before sending the echo request
+        match context::fetch(&mut self.conn, tree_context) {
+            Err(ContextFetchError::NoActiveAnalysis { net: _ }) => {
+                analyse::persist::begin_bulk(&mut self.conn, &[*net], 1)?;
+                let tree_context = prefix_tree::context::fetch(&mut self.conn, net)?;
+                Ok(Some(context::fetch(&mut self.conn, tree_context)?))
+            }
+            other => Ok(Some(other?)),
+        }
+    }
+
+    fn process_trace(&mut self, archived_path: Ipv6Net, mut res: TraceResponse) -> Result<()> {
+        let Some(mut context) = self.fetch_pending(&archived_path)? else {
+            self.stats.skipped += 1;
+            return Ok(());
+        };
+
+        // The replayed echo response generated a new follow-up ID, so the archived one won't match
+        res.id = context
+            .analysis
+            .pending_follow_up
+            .as_ref()
+            .expect("fetch_pending to only return analyses with pending follow-up")
+            .parse()
+            .context("Invalid TypeID stored in analysis")?;
+
+        let mut interpretation = analyse::trace::process(&res);
+        interpretation.update_analysis(&mut self.conn, &mut context)?;
+        self.stats.trace += 1;
+        if interpretation.awaits_remainder() {
+            // Splitting happens once the archived response for the remainder is replayed
+            return Ok(());
+        }
+
+        split::process(
+            &mut self.conn,
+            context,
+            &self.blocklist,
+            &self.fan_out,
+            &self.split,
+        )?;
+        Ok(())
+    }
+
+    fn fetch_pending(&mut self, net: &Ipv6Net) -> Result<Option<context::Context>> {
+        let tree_context = match prefix_tree::context::fetch(&mut self.conn, net) {
+            Err(prefix_tree::context::ContextFetchError::NotInPrefixTree { net: _ }) => {
+                return Ok(None)
+            }
+            other => other?,
+        };
+        let context = match context::fetch(&mut self.conn, tree_context) {
+            Err(ContextFetchError::NoActiveAnalysis { net: _ }) => return Ok(None),
+            other => other?,
+        };
+        if context.analysis.pending_follow_up.is_none() {
+            debug!("Skipping trace for {} since no follow-up is pending", net);
+            return Ok(None);
+        }
+        Ok(Some(context))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+    use chrono::NaiveDateTime;
+    use db_model::{
+        prefix_tree::{LhrSetHash, PriorityClass},
+        test_utils::*,
+    };
+    use queue_models::probe_request::TraceRequestId;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn parse_echo() -> Result<()> {
+        // given
+        let data = json!({
+            "target_net": "2001:db8::/32",
+            "subnet_prefix_len": 33,
+            "sent_ttl": 64,
+            "splits": [],
+        });
+
+        // when
+        let parsed = parse_archived(data)?;
+
+        // then
+        let Archived::Echo(echo) = parsed else {
+            bail!("expected echo, got {:?}", parsed);
+        };
+        assert_that!(echo.target_net).is_equal_to(net(TREE_BASE_NET));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_trace() -> Result<()> {
+        // given
+        let trace = TraceResponse {
+            id: TraceRequestId::new(),
+            results: vec![],
+            vantage_point: "vienna".to_string(),
+            incomplete: None,
+            requeued: vec![],
+        };
+        let data = serde_json::to_value(&trace)?;
+
+        // when
+        let parsed = parse_archived(data)?;
+
+        // then
+        let Archived::Trace(parsed_trace) = parsed else {
+            bail!("expected trace, got {:?}", parsed);
+        };
+        assert_that!(parsed_trace.id).is_equal_to(trace.id);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_garbage() {
+        // given
+        let data = json!({ "answer": 42 });
+
+        // when
+        let parsed = parse_archived(data);
+
+        // then
+        assert_that!(parsed.is_err()).is_true();
+    }
+
+    #[test]
+    fn replay_only_leaves() {
+        // given
+        let node = |merge_status| PrefixTree {
+            net: net(TREE_BASE_NET),
+            created_at: NaiveDateTime::default(),
+            updated_at: NaiveDateTime::default(),
+            merge_status,
+            priority_class: PriorityClass::HighFresh,
+            confidence: 0,
+            lhr_set_hash: LhrSetHash::from_u128(0),
+            asn: 0,
+        };
+
+        // when
+        let leaf = is_replayable(&node(MergeStatus::Leaf));
+        let split = is_replayable(&node(MergeStatus::SplitDown));
+
+        // then
+        assert_that!(leaf).is_true();
+        assert_that!(split).is_false();
+    }
+}
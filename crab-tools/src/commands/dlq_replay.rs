@@ -0,0 +1,164 @@
+use amqprs::channel::{BasicAckArguments, BasicGetArguments, BasicPublishArguments};
+use amqprs::BasicProperties;
+use anyhow::*;
+use clap::Args;
+use futures::executor;
+use log::{info, warn};
+
+use prefix_crab::helpers::rabbit::dead_letter;
+use prefix_crab::helpers::rabbit::{ConfigureRabbit, RabbitHandle};
+
+use crate::rabbit;
+
+/// Re-publishes probe responses that the aggregator moved to the dead-letter queue,
+/// e.g. after the cause of the failure has been fixed.
+#[derive(Args, Clone)]
+pub struct Params {
+    #[clap(flatten)]
+    rabbit: rabbit::Params,
+
+    /// Name of the dead-letter queue to read from
+    #[arg(long, default_value = "prefix-crab.probe-response.dead-letter")]
+    dead_letter_queue_name: String,
+
+    /// Name of the queue to park messages in that cannot be replayed, e.g. because they lack
+    /// the original routing key
+    #[arg(long, default_value = "prefix-crab.probe-response.dead-letter.parked")]
+    parking_queue_name: String,
+
+    /// Name of the exchange to re-publish the responses to
+    #[arg(long, default_value = "prefix-crab.probe-response")]
+    response_exchange_name: String,
+
+    /// How many messages to replay at most (all if not given)
+    #[arg(long)]
+    limit: Option<usize>,
+
+    /// Only print the messages and their errors, leaving them in the queue
+    #[arg(long)]
+    dry_run: bool,
+}
+
+pub fn handle(params: Params) -> Result<()> {
+    let replay_handle = tokio::spawn(run(params));
+    let replayed = executor::block_on(replay_handle)??;
+
+    info!("Replayed {} dead-lettered responses.", replayed);
+    Ok(())
+}
+
+async fn run(params: Params) -> Result<usize> {
+    let handle = RabbitHandle::connect(params.rabbit.amqp_uri.as_str(), "crab-tools").await?;
+    ConfigureRabbit::new(&handle)
+        .declare_queue(&params.parking_queue_name)
+        .await?;
+    let mut replayed = 0usize;
+
+    while params.limit.map(|it| replayed < it).unwrap_or(true) {
+        let args = BasicGetArguments::new(&params.dead_letter_queue_name)
+            .no_ack(false)
+            .finish();
+        let (get_ok, props, body) = match handle.chan().basic_get(args).await? {
+            Some(it) => it,
+            None => break, // queue is empty
+        };
+        let delivery_tag = get_ok.delivery_tag();
+
+        print_dead_letter(&props, &body);
+        if params.dry_run {
+            // Not acked, will be returned to the queue once we disconnect
+            replayed += 1;
+            continue;
+        }
+
+        let (props, publish_args) = match replay_target(&props) {
+            Replay::Republish { routing_key } => {
+                replayed += 1;
+                let args = BasicPublishArguments::new(&params.response_exchange_name, &routing_key);
+                (BasicProperties::default(), args)
+            }
+            Replay::Park { reason } => {
+                warn!(
+                    "Parking message in {} that cannot be replayed: {:?}",
+                    params.parking_queue_name, reason
+                );
+                // Publish to the default exchange, which routes directly to the queue with this name
+                let args = BasicPublishArguments::new("", &params.parking_queue_name);
+                (props, args)
+            }
+        };
+        handle
+            .chan()
+            .basic_publish(props, body, publish_args)
+            .await
+            .with_context(|| "during publish")?;
+        handle
+            .chan()
+            .basic_ack(BasicAckArguments::new(delivery_tag, false))
+            .await
+            .with_context(|| "during ack")?;
+    }
+
+    Ok(replayed)
+}
+
+#[derive(Debug)]
+enum Replay {
+    Republish { routing_key: String },
+    Park { reason: Error },
+}
+
+/// Messages are only acked after they have been published somewhere else, so that none
+/// stay locked in the dead-letter queue for the whole session
+fn replay_target(props: &BasicProperties) -> Replay {
+    match dead_letter::original_routing_key(props) {
+        Result::Ok(routing_key) => Replay::Republish { routing_key },
+        Err(reason) => Replay::Park { reason },
+    }
+}
+
+fn print_dead_letter(props: &BasicProperties, body: &[u8]) {
+    let header = |key| dead_letter::get_str(props, key).unwrap_or_else(|| "?".to_string());
+    println!(
+        " --- {} response failed at {}",
+        header(dead_letter::ROUTING_KEY_HEADER),
+        header(dead_letter::FAILED_AT_HEADER),
+    );
+    for cause in header(dead_letter::ERROR_CHAIN_HEADER).lines() {
+        println!("   ! {}", cause);
+    }
+    println!("   > {}", String::from_utf8_lossy(body));
+}
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+
+    use super::*;
+
+    #[test]
+    fn replay_dead_letter() -> Result<()> {
+        // given
+        let props = dead_letter::to_properties("echo", &anyhow!("database on fire"))?;
+
+        // when
+        let target = replay_target(&props);
+
+        // then
+        let is_echo = matches!(target, Replay::Republish { routing_key } if routing_key == "echo");
+        assert_that!(is_echo).is_true();
+        Ok(())
+    }
+
+    #[test]
+    fn park_without_routing_key() {
+        // given
+        let props = BasicProperties::default();
+
+        // when
+        let target = replay_target(&props);
+
+        // then
+        assert_that!(matches!(target, Replay::Park { .. })).is_true();
+    }
+}
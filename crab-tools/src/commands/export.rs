@@ -0,0 +1,609 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufWriter, ErrorKind, Read, Write},
+    net::Ipv6Addr,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use anyhow::*;
+use clap::{Args, ValueEnum};
+use db_model::{
+    analyse::{Confidence, HitCount, MeasurementTree, WeirdType},
+    persist::{self, dsl::CidrMethods, DieselErrorFixCause},
+    prefix_tree::{AsNumber, LhrSetHash, MergeStatus, PrefixTree, PriorityClass, LEAF_MERGE_STATUSES},
+};
+use diesel::{pg::PgRowByRowLoadingMode, prelude::*};
+use ipnet::Ipv6Net;
+use itertools::Itertools;
+use log::info;
+use serde::{Deserialize, Serialize};
+
+/// Exports the leaves of the prefix tree, i.e. the inferred subnet boundaries, together with
+/// the merged measurements of each leaf.
+///
+/// The binary format is big-endian and starts with the magic `PCLF` and a version byte (1).
+/// Each leaf is then encoded as follows:
+///  - network address (16 bytes), prefix length (u8), ASN (i64)
+///  - merge status, priority class (u8 each, index in declaration order), confidence (u8)
+///  - LHR set hash (16 bytes), responsive count, unresponsive count (i32 each)
+///  - number of last-hop routers (u16), followed by address (16 bytes) and hit count (i32) each
+///  - number of weirdness types (u8), followed by type (u8, index in declaration order)
+///    and hit count (i32) each
+#[derive(Args, Clone)]
+pub struct Params {
+    #[clap(flatten)]
+    persist: persist::Params,
+
+    out_file: PathBuf,
+
+    #[arg(long, value_enum, default_value = "csv")]
+    format: ExportFormat,
+
+    /// Only export leaves within this prefix (all if not given)
+    #[arg(long)]
+    target_prefix: Option<Ipv6Net>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ExportFormat {
+    Csv,
+    Jsonl,
+    Binary,
+}
+
+pub fn handle(params: Params) -> Result<()> {
+    let out_file = File::create_new(&params.out_file)
+        .with_context(|| format!("creating export file {:?}", params.out_file))?;
+    let mut writer = LeafWriter::new(params.format, BufWriter::new(out_file))?;
+
+    let mut leaf_conn = persist::connect_manual("crab-tools - export - leaves", &params.persist)?;
+    let mut tree_conn = persist::connect_manual("crab-tools - export - trees", &params.persist)?;
+    let root = match params.target_prefix {
+        Some(it) => it,
+        None => "::/0".parse().expect("::/0 to be a valid prefix"),
+    };
+
+    let leaves = {
+        use db_model::schema::prefix_tree::dsl::*;
+
+        prefix_tree
+            .filter(net.subnet_or_eq6(&root))
+            .filter(merge_status.eq_any(LEAF_MERGE_STATUSES))
+            .order_by(net.asc())
+            .select(PrefixTree::as_select())
+            .load_iter::<PrefixTree, PgRowByRowLoadingMode>(&mut leaf_conn)
+            .fix_cause()?
+    };
+    let mut trees = {
+        use db_model::schema::measurement_tree::dsl::*;
+
+        measurement_tree
+            .filter(target_net.subnet_or_eq6(&root))
+            .order_by(target_net.asc())
+            .select(MeasurementTree::as_select())
+            .load_iter::<MeasurementTree, PgRowByRowLoadingMode>(&mut tree_conn)
+            .fix_cause()?
+    };
+
+    // Both are ordered by network, so the measurements of a leaf directly follow the leaf itself
+    let mut exported = 0usize;
+    let mut pending = next_tree(&mut trees)?;
+    for leaf in leaves {
+        let leaf = leaf.context("iterating leaves")?;
+        let mut merged = MeasurementTree::empty(leaf.net);
+
+        while let Some(tree) = pending.take() {
+            let tree_net = tree.try_net_into_v6()?;
+            if leaf.net.contains(&tree_net) {
+                merged.merge(&tree)?;
+            } else if tree_net > leaf.net {
+                pending = Some(tree); // belongs to a later leaf
+                break;
+            } // else: not below any leaf, e.g. residue of a merge
+            pending = next_tree(&mut trees)?;
+        }
+
+        writer.write(&leaf, &merged)?;
+        exported += 1;
+        if exported % 10000 == 0 {
+            info!(" ... Exported {} leaves", exported);
+        }
+    }
+
+    writer.finish()?;
+    info!("Exported {} leaves to {:?}.", exported, params.out_file);
+    Ok(())
+}
+
+fn next_tree(
+    trees: &mut impl Iterator<Item = QueryResult<MeasurementTree>>,
+) -> Result<Option<MeasurementTree>> {
+    trees.next().transpose().context("iterating measurements")
+}
+
+/// A single exported leaf, as written to JSONL and read back from any of the formats
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(super) struct JsonRecord {
+    pub(super) net: Ipv6Net,
+    pub(super) asn: AsNumber,
+    pub(super) merge_status: MergeStatus,
+    pub(super) priority_class: PriorityClass,
+    pub(super) confidence: Confidence,
+    pub(super) lhr_set_hash: String,
+    pub(super) responsive_count: HitCount,
+    pub(super) unresponsive_count: HitCount,
+    pub(super) last_hop_routers: BTreeMap<Ipv6Addr, HitCount>,
+    pub(super) weirdness: BTreeMap<String, HitCount>,
+}
+
+impl JsonRecord {
+    fn new(leaf: &PrefixTree, merged: &MeasurementTree) -> Self {
+        Self {
+            net: leaf.net,
+            asn: leaf.asn,
+            merge_status: leaf.merge_status,
+            priority_class: leaf.priority_class,
+            confidence: leaf.confidence,
+            lhr_set_hash: leaf.lhr_set_hash.to_string(),
+            responsive_count: merged.responsive_count,
+            unresponsive_count: merged.unresponsive_count,
+            last_hop_routers: merged
+                .last_hop_routers
+                .items
+                .iter()
+                .map(|(addr, item)| (*addr, item.hit_count))
+                .collect(),
+            weirdness: merged
+                .weirdness
+                .items
+                .iter()
+                .map(|(kind, item)| (format!("{:?}", kind), item.hit_count))
+                .collect(),
+        }
+    }
+}
+
+/// Flat version of [JsonRecord], since CSV cannot represent nested collections.
+/// Collections are encoded as `key=hits` pairs separated by `;`.
+#[derive(Serialize, Deserialize)]
+struct CsvRecord {
+    net: Ipv6Net,
+    asn: AsNumber,
+    merge_status: MergeStatus,
+    priority_class: PriorityClass,
+    confidence: Confidence,
+    lhr_set_hash: String,
+    responsive_count: HitCount,
+    unresponsive_count: HitCount,
+    last_hop_routers: String,
+    weirdness: String,
+}
+
+impl From<JsonRecord> for CsvRecord {
+    fn from(value: JsonRecord) -> Self {
+        fn flatten<K: std::fmt::Display>(map: BTreeMap<K, HitCount>) -> String {
+            map.into_iter()
+                .map(|(key, hits)| format!("{}={}", key, hits))
+                .join(";")
+        }
+
+        Self {
+            net: value.net,
+            asn: value.asn,
+            merge_status: value.merge_status,
+            priority_class: value.priority_class,
+            confidence: value.confidence,
+            lhr_set_hash: value.lhr_set_hash,
+            responsive_count: value.responsive_count,
+            unresponsive_count: value.unresponsive_count,
+            last_hop_routers: flatten(value.last_hop_routers),
+            weirdness: flatten(value.weirdness),
+        }
+    }
+}
+
+impl TryFrom<CsvRecord> for JsonRecord {
+    type Error = Error;
+
+    fn try_from(value: CsvRecord) -> Result<Self> {
+        fn unflatten<K>(flat: &str) -> Result<BTreeMap<K, HitCount>>
+        where
+            K: FromStr + Ord,
+            K::Err: std::error::Error + Send + Sync + 'static,
+        {
+            flat.split(';')
+                .filter(|it| !it.is_empty())
+                .map(|pair| {
+                    let (key, hits) = pair
+                        .split_once('=')
+                        .with_context(|| format!("expected key=hits, got {:?}", pair))?;
+                    Ok((key.parse()?, hits.parse()?))
+                })
+                .collect()
+        }
+
+        Ok(Self {
+            net: value.net,
+            asn: value.asn,
+            merge_status: value.merge_status,
+            priority_class: value.priority_class,
+            confidence: value.confidence,
+            lhr_set_hash: value.lhr_set_hash,
+            responsive_count: value.responsive_count,
+            unresponsive_count: value.unresponsive_count,
+            last_hop_routers: unflatten(&value.last_hop_routers)?,
+            weirdness: unflatten(&value.weirdness)?,
+        })
+    }
+}
+
+enum LeafWriter<W: Write> {
+    Csv(Box<csv::Writer<W>>),
+    Jsonl(W),
+    Binary(W),
+}
+
+const BINARY_MAGIC: &[u8; 4] = b"PCLF";
+const BINARY_VERSION: u8 = 1;
+
+impl<W: Write> LeafWriter<W> {
+    fn new(format: ExportFormat, out: W) -> Result<Self> {
+        Ok(match format {
+            ExportFormat::Csv => Self::Csv(Box::new(csv::Writer::from_writer(out))),
+            ExportFormat::Jsonl => Self::Jsonl(out),
+            ExportFormat::Binary => {
+                let mut out = out;
+                out.write_all(BINARY_MAGIC)?;
+                out.write_all(&[BINARY_VERSION])?;
+                Self::Binary(out)
+            }
+        })
+    }
+
+    fn write(&mut self, leaf: &PrefixTree, merged: &MeasurementTree) -> Result<()> {
+        match self {
+            Self::Csv(writer) => writer.serialize(CsvRecord::from(JsonRecord::new(leaf, merged)))?,
+            Self::Jsonl(out) => {
+                serde_json::to_writer(&mut *out, &JsonRecord::new(leaf, merged))?;
+                out.write_all(b"\n")?;
+            }
+            Self::Binary(out) => write_binary(out, leaf, merged)?,
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<W> {
+        let mut out = match self {
+            Self::Csv(writer) => writer
+                .into_inner()
+                .map_err(|e| anyhow!("flushing CSV: {}", e.error()))?,
+            Self::Jsonl(out) | Self::Binary(out) => out,
+        };
+        out.flush()?;
+        Ok(out)
+    }
+}
+
+/// Reads back an export in any of the formats, which is detected from its content.
+pub(super) fn read_leaves(mut input: impl BufRead) -> Result<Vec<JsonRecord>> {
+    let start = input.fill_buf()?;
+    if start.starts_with(BINARY_MAGIC) {
+        read_binary(input)
+    } else if start.first() == Some(&b'{') {
+        read_jsonl(input)
+    } else {
+        csv::Reader::from_reader(input)
+            .deserialize::<CsvRecord>()
+            .enumerate()
+            .map(|(index, record)| {
+                let record = record.with_context(|| format!("parsing CSV record {}", index + 1))?;
+                JsonRecord::try_from(record).with_context(|| format!("in CSV record {}", index + 1))
+            })
+            .collect()
+    }
+}
+
+fn read_jsonl(input: impl BufRead) -> Result<Vec<JsonRecord>> {
+    let mut records = vec![];
+    for (index, line) in input.lines().enumerate() {
+        let line = line.context("reading JSONL")?;
+        if line.is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line)
+            .with_context(|| format!("parsing line {} of JSONL", index + 1))?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn write_binary(out: &mut impl Write, leaf: &PrefixTree, merged: &MeasurementTree) -> Result<()> {
+    out.write_all(&leaf.net.network().octets())?;
+    out.write_all(&[leaf.net.prefix_len()])?;
+    out.write_all(&leaf.asn.to_be_bytes())?;
+    out.write_all(&[
+        merge_status_code(leaf.merge_status),
+        priority_class_code(leaf.priority_class),
+        leaf.confidence,
+    ])?;
+    out.write_all(leaf.lhr_set_hash.as_bytes())?;
+    out.write_all(&merged.responsive_count.to_be_bytes())?;
+    out.write_all(&merged.unresponsive_count.to_be_bytes())?;
+
+    let lhrs = merged.last_hop_routers.items.iter().sorted_by_key(|(addr, _)| **addr);
+    let lhr_count: u16 = lhrs.len().try_into().context("too many LHRs for binary format")?;
+    out.write_all(&lhr_count.to_be_bytes())?;
+    for (addr, item) in lhrs {
+        out.write_all(&addr.octets())?;
+        out.write_all(&item.hit_count.to_be_bytes())?;
+    }
+
+    let weirdness = &merged.weirdness.items;
+    out.write_all(&[weirdness.len() as u8])?; // there are less than 256 types
+    for (kind, item) in weirdness.iter().sorted_by_key(|(kind, _)| weird_type_code(kind)) {
+        out.write_all(&[weird_type_code(kind)])?;
+        out.write_all(&item.hit_count.to_be_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_binary(mut input: impl Read) -> Result<Vec<JsonRecord>> {
+    let header: [u8; 5] = read_array(&mut input)?;
+    if &header[..4] != BINARY_MAGIC || header[4] != BINARY_VERSION {
+        bail!("Not a binary export of version {}", BINARY_VERSION);
+    }
+
+    let mut records = vec![];
+    loop {
+        let mut network = [0u8; 16];
+        // EOF is only expected at the start of a leaf
+        match input.read(&mut network[..1]) {
+            Result::Ok(0) => return Ok(records),
+            Result::Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+        input.read_exact(&mut network[1..])?;
+        let record = read_binary_leaf(&mut input, network)
+            .with_context(|| format!("reading leaf {} of binary export", records.len() + 1))?;
+        records.push(record);
+    }
+}
+
+fn read_binary_leaf(input: &mut impl Read, network: [u8; 16]) -> Result<JsonRecord> {
+    let [prefix_len] = read_array(input)?;
+    let asn = AsNumber::from_be_bytes(read_array(input)?);
+    let [merge_status, priority_class, confidence] = read_array(input)?;
+    let lhr_set_hash = LhrSetHash::from_bytes(read_array(input)?);
+    let responsive_count = HitCount::from_be_bytes(read_array(input)?);
+    let unresponsive_count = HitCount::from_be_bytes(read_array(input)?);
+
+    let lhr_count = u16::from_be_bytes(read_array(input)?);
+    let mut last_hop_routers = BTreeMap::new();
+    for _ in 0..lhr_count {
+        let addr = Ipv6Addr::from(read_array::<16>(input)?);
+        last_hop_routers.insert(addr, HitCount::from_be_bytes(read_array(input)?));
+    }
+
+    let [weird_count] = read_array(input)?;
+    let mut weirdness = BTreeMap::new();
+    for _ in 0..weird_count {
+        let [code] = read_array(input)?;
+        let kind = format!("{:?}", weird_type_from_code(code)?);
+        weirdness.insert(kind, HitCount::from_be_bytes(read_array(input)?));
+    }
+
+    Ok(JsonRecord {
+        net: Ipv6Net::new(Ipv6Addr::from(network), prefix_len)?,
+        asn,
+        merge_status: merge_status_from_code(merge_status)?,
+        priority_class: priority_class_from_code(priority_class)?,
+        confidence,
+        lhr_set_hash: lhr_set_hash.to_string(),
+        responsive_count,
+        unresponsive_count,
+        last_hop_routers,
+        weirdness,
+    })
+}
+
+fn read_array<const N: usize>(input: &mut impl Read) -> Result<[u8; N]> {
+    let mut buf = [0u8; N];
+    input
+        .read_exact(&mut buf)
+        .context("truncated binary export")?;
+    Ok(buf)
+}
+
+fn merge_status_code(status: MergeStatus) -> u8 {
+    use MergeStatus as S;
+    match status {
+        S::Leaf => 0,
+        S::MinSizeReached => 1,
+        S::SplitDown => 2,
+        S::MergedUp => 3,
+        S::UnsplitRoot => 4,
+        S::SplitRoot => 5,
+        S::Blocked => 6,
+    }
+}
+
+fn priority_class_code(class: PriorityClass) -> u8 {
+    use PriorityClass as P;
+    match class {
+        P::HighFresh => 0,
+        P::HighOverlapping => 1,
+        P::HighDisjoint => 2,
+        P::MediumSameMulti => 3,
+        P::MediumSameRatio => 4,
+        P::MediumSameMany => 5,
+        P::MediumSameSingle => 6,
+        P::MediumMultiWeird => 7,
+        P::LowWeird => 8,
+        P::LowUnknown => 9,
+    }
+}
+
+fn weird_type_code(kind: &WeirdType) -> u8 {
+    use WeirdType as W;
+    match kind {
+        W::DestUnreachOther => 0,
+        W::DestUnreachRejectRoute => 1,
+        W::DestUnreachFailedEgress => 2,
+        W::DifferentEchoReplySource => 3,
+        W::EchoReplyInTrace => 4,
+        W::UnexpectedIcmpType => 5,
+        W::TtlExceededForEcho => 6,
+    }
+}
+
+fn merge_status_from_code(code: u8) -> Result<MergeStatus> {
+    use MergeStatus as S;
+    Ok(match code {
+        0 => S::Leaf,
+        1 => S::MinSizeReached,
+        2 => S::SplitDown,
+        3 => S::MergedUp,
+        4 => S::UnsplitRoot,
+        5 => S::SplitRoot,
+        6 => S::Blocked,
+        _ => bail!("Unknown merge status code {}", code),
+    })
+}
+
+fn priority_class_from_code(code: u8) -> Result<PriorityClass> {
+    use PriorityClass as P;
+    Ok(match code {
+        0 => P::HighFresh,
+        1 => P::HighOverlapping,
+        2 => P::HighDisjoint,
+        3 => P::MediumSameMulti,
+        4 => P::MediumSameRatio,
+        5 => P::MediumSameMany,
+        6 => P::MediumSameSingle,
+        7 => P::MediumMultiWeird,
+        8 => P::LowWeird,
+        9 => P::LowUnknown,
+        _ => bail!("Unknown priority class code {}", code),
+    })
+}
+
+fn weird_type_from_code(code: u8) -> Result<WeirdType> {
+    use WeirdType as W;
+    Ok(match code {
+        0 => W::DestUnreachOther,
+        1 => W::DestUnreachRejectRoute,
+        2 => W::DestUnreachFailedEgress,
+        3 => W::DifferentEchoReplySource,
+        4 => W::EchoReplyInTrace,
+        5 => W::UnexpectedIcmpType,
+        6 => W::TtlExceededForEcho,
+        _ => bail!("Unknown weirdness type code {}", code),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+    use chrono::NaiveDateTime;
+    use db_model::test_utils::*;
+
+    use super::*;
+
+    fn gen_leaf() -> (PrefixTree, MeasurementTree) {
+        let leaf = PrefixTree {
+            net: net(TREE_BASE_NET),
+            created_at: NaiveDateTime::default(),
+            updated_at: NaiveDateTime::default(),
+            merge_status: MergeStatus::Leaf,
+            priority_class: PriorityClass::MediumSameMulti,
+            confidence: 142,
+            lhr_set_hash: LhrSetHash::from_u128(0xbeef),
+            asn: 64511,
+        };
+        let mut merged = gen_tree_with_lhr_101(TREE_LEFT_NET, 7);
+        gen_add_lhr_beef(&mut merged, 3);
+        merged.add_weird_no_sum(WeirdType::EchoReplyInTrace, 2);
+        merged.responsive_count = 12;
+        merged.unresponsive_count = 4;
+        (leaf, merged)
+    }
+
+    fn when_exported(format: ExportFormat) -> Result<Vec<u8>> {
+        let (leaf, merged) = gen_leaf();
+        let mut writer = LeafWriter::new(format, vec![])?;
+        writer.write(&leaf, &merged)?;
+        writer.write(&leaf, &MeasurementTree::empty(leaf.net))?;
+        writer.finish()
+    }
+
+    fn then_roundtrips(exported: Vec<u8>) -> Result<()> {
+        let (leaf, merged) = gen_leaf();
+        let read = read_leaves(exported.as_slice())?;
+        assert_that!(read).contains_exactly_in_order(vec![
+            JsonRecord::new(&leaf, &merged),
+            JsonRecord::new(&leaf, &MeasurementTree::empty(leaf.net)),
+        ]);
+        Ok(())
+    }
+
+    #[test]
+    fn jsonl_roundtrip() -> Result<()> {
+        // given, when
+        let exported = when_exported(ExportFormat::Jsonl)?;
+
+        // then
+        let text = String::from_utf8(exported.clone())?;
+        assert_that!(text.lines().count()).is_equal_to(2);
+        let lhrs = r#""last_hop_routers":{"2001:db8:101::1":7,"2001:db8:beef::20":3}"#;
+        assert_that!(text.contains(lhrs)).is_true();
+        then_roundtrips(exported)
+    }
+
+    #[test]
+    fn csv_roundtrip() -> Result<()> {
+        // given, when
+        let exported = when_exported(ExportFormat::Csv)?;
+
+        // then
+        let text = String::from_utf8(exported.clone())?;
+        let lines = text.lines().collect_vec();
+        assert_that!(lines.len()).is_equal_to(3);
+        assert_that!(lines[0]).is_equal_to(
+            "net,asn,merge_status,priority_class,confidence,lhr_set_hash,responsive_count,\
+            unresponsive_count,last_hop_routers,weirdness",
+        );
+        let counts = ",12,4,2001:db8:101::1=7;2001:db8:beef::20=3,EchoReplyInTrace=2";
+        assert_that!(lines[1].ends_with(counts)).is_true();
+        then_roundtrips(exported)
+    }
+
+    #[test]
+    fn binary_roundtrip() -> Result<()> {
+        // given, when
+        let exported = when_exported(ExportFormat::Binary)?;
+
+        // then
+        assert_that!(&exported[..5]).is_equal_to(&b"PCLF\x01"[..]);
+        let fixed_len = 16 + 1 + 8 + 3 + 16 + 4 + 4 + 2 + 1;
+        let first_len = fixed_len + 2 * (16 + 4) + (1 + 4);
+        assert_that!(exported.len()).is_equal_to(5 + first_len + fixed_len);
+        then_roundtrips(exported)
+    }
+
+    #[test]
+    fn binary_truncated() -> Result<()> {
+        // given
+        let mut exported = when_exported(ExportFormat::Binary)?;
+        exported.pop();
+
+        // when
+        let read = read_leaves(exported.as_slice());
+
+        // then
+        assert_that!(read.is_err()).is_true();
+        Ok(())
+    }
+}
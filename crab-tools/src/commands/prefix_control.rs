@@ -0,0 +1,103 @@
+use amqprs::channel::BasicPublishArguments;
+use amqprs::BasicProperties;
+use aggregator::control::ControlCommand;
+use anyhow::*;
+use clap::{Args, Subcommand};
+use db_model::prefix_tree::PriorityClass;
+use futures::executor;
+use ipnet::Ipv6Net;
+use log::info;
+use prefix_crab::helpers::rabbit::RabbitHandle;
+use queue_models::RoutedMessage;
+
+use crate::rabbit;
+
+/// Steers the aggregator at runtime by sending it a command via the control exchange.
+/// The aggregator logs the outcome; changes to the tree are recorded in its history.
+#[derive(Args, Clone)]
+pub struct Params {
+    #[clap(flatten)]
+    rabbit: rabbit::Params,
+
+    #[command(subcommand)]
+    action: Action,
+}
+
+#[derive(Subcommand, Clone)]
+enum Action {
+    /// Keep the priority class of a subtree fixed, regardless of analysis results
+    Pin {
+        net: Ipv6Net,
+        /// Class name as in the database, e.g. `HighFresh` or `LowUnknown`
+        #[arg(value_parser = parse_class)]
+        class: PriorityClass,
+    },
+    /// Let analyses determine the priority class of a subtree again
+    Unpin { net: Ipv6Net },
+    /// Move all leaves of a subtree to the highest priority class once
+    Boost { net: Ipv6Net },
+    /// Stop scheduling analyses in a subtree
+    Freeze { net: Ipv6Net },
+    /// Resume scheduling analyses in a frozen subtree
+    Thaw { net: Ipv6Net },
+    /// Turn a single blocked node back into a leaf
+    Unblock { net: Ipv6Net },
+    /// Analyse a single leaf right away (counts against the next budget of the analysis timer)
+    Analyse { net: Ipv6Net },
+}
+
+impl From<Action> for ControlCommand {
+    fn from(value: Action) -> Self {
+        use Action as A;
+        use ControlCommand as C;
+
+        match value {
+            A::Pin { net, class } => C::Pin { net, class },
+            A::Unpin { net } => C::Unpin { net },
+            A::Boost { net } => C::Boost { net },
+            A::Freeze { net } => C::Freeze { net },
+            A::Thaw { net } => C::Thaw { net },
+            A::Unblock { net } => C::Unblock { net },
+            A::Analyse { net } => C::AnalyseNow { net },
+        }
+    }
+}
+
+fn parse_class(raw: &str) -> Result<PriorityClass> {
+    serde_json::from_value(serde_json::Value::String(raw.to_string()))
+        .with_context(|| format!("unknown priority class {}", raw))
+}
+
+pub fn handle(params: Params) -> Result<()> {
+    send(&params.rabbit, params.action.into())
+}
+
+pub fn send(rabbit: &rabbit::Params, cmd: ControlCommand) -> Result<()> {
+    let sender = ControlSender {
+        exchange_name: rabbit.control_exchange_name.to_string(),
+    };
+    let rabbit_handle = tokio::spawn(sender.run(rabbit.amqp_uri.to_string(), cmd.clone()));
+
+    executor::block_on(rabbit_handle)??;
+
+    info!("Sent {:?} to the aggregator.", cmd);
+    Ok(())
+}
+
+struct ControlSender {
+    exchange_name: String,
+}
+
+impl ControlSender {
+    async fn run(self, amqp_uri: String, cmd: ControlCommand) -> Result<()> {
+        let handle = RabbitHandle::connect(amqp_uri.as_str(), "crab-tools").await?;
+        let args = BasicPublishArguments::new(&self.exchange_name, cmd.routing_key());
+        let bin = serde_json::to_vec_pretty(&cmd)
+            .with_context(|| format!("during serialisation of {:?}", cmd))?;
+        handle
+            .chan()
+            .basic_publish(BasicProperties::default(), bin, args)
+            .await
+            .with_context(|| "during publish")
+    }
+}
@@ -1,7 +1,7 @@
 use db_model::{
     analyse::{
         subnet::{Subnet, Subnets},
-        MeasurementTree,
+        MeasurementTree, SplitAnalysis, SplitExplanation,
     },
     persist::{
         self,
@@ -15,7 +15,9 @@ use diesel::{
     OptionalExtension, PgConnection, QueryDsl, RunQueryDsl, SelectableHelper,
 };
 use itertools::Itertools;
-use prefix_crab::prefix_split::{NetIndex, SplitSubnet};
+use prefix_crab::prefix_split::{
+    NetIndex, SplitSubnet, DEFAULT_BITS_PER_SPLIT, MAX_BITS_PER_SPLIT,
+};
 use std::time::Instant;
 use std::{io::Write, ops::Deref};
 
@@ -101,6 +103,10 @@ pub fn print_prefix(net: Ipv6Net) -> Result {
         load_start.elapsed(),
     );
 
+    if let Some(analysis) = load_latest_analysis(&mut conn, &net)? {
+        buf = print_analysis(buf, &analysis)?;
+    }
+
     buf = buf.flush_section()?;
 
     if net.prefix_len() >= 64 {
@@ -116,7 +122,9 @@ pub fn print_prefix(net: Ipv6Net) -> Result {
         }
         buf = print_subnet(buf, &fake_subnet, root)?;
     } else {
-        let subnets = Subnets::new(net, measurements).map_err(pfxerr!(SubnetSplit))?;
+        let split_bits = load_split_bits(&mut conn, &net)?;
+        let subnets =
+            Subnets::new(net, split_bits, measurements).map_err(pfxerr!(SubnetSplit))?;
         for subnet in subnets.iter() {
             buf = print_subnet(buf, subnet, root)?;
         }
@@ -171,6 +179,91 @@ fn print_subnet(
     buf.flush_section()
 }
 
+/// Part of the header section, since the sections after it must map to the subnets
+fn print_analysis(
+    mut buf: PrintedPrefixBuilder,
+    analysis: &SplitAnalysis,
+) -> StdResult<PrintedPrefixBuilder, Error> {
+    let Some(result) = &analysis.result else {
+        return Ok(buf);
+    };
+    let verdict = match result.should_split {
+        Some(true) => "✂ split",
+        Some(false) => "🤝 keep",
+        None => "🤷 undetermined",
+    };
+    writepfx!(
+        &mut buf,
+        "🧪 Latest analysis #{} ({:?}): {} {:?} with {} evidence (algo v{})",
+        analysis.id,
+        analysis.completed_at,
+        verdict,
+        result.class,
+        result.evidence,
+        result.algo_version,
+    );
+
+    match &result.explanation {
+        Some(explanation) => print_explanation(buf, explanation),
+        None => {
+            writepfx!(&mut buf, " No explanation recorded for this analysis.");
+            Ok(buf)
+        }
+    }
+}
+
+fn print_explanation(
+    mut buf: PrintedPrefixBuilder,
+    explanation: &SplitExplanation,
+) -> StdResult<PrintedPrefixBuilder, Error> {
+    writepfx!(
+        &mut buf,
+        " 🔎 {:?} diff of {:?} => {}% confidence ({} evidence needed for 100%)",
+        explanation.diff,
+        explanation.compared,
+        explanation.confidence,
+        explanation.confidence_thresh,
+    );
+    writepfx!(
+        &mut buf,
+        " Subnets: {}",
+        explanation.subnets.iter().join(" | ")
+    );
+    for (addr, hits) in explanation.lhr_hits.iter() {
+        let ratios = match explanation.ratio_pcts.get(addr) {
+            Some(pcts) => {
+                let mut pcts = pcts.iter().map(|pct| match pct {
+                    Some(it) => format!("{}%", it),
+                    None => "-".to_string(),
+                });
+                format!(", ratios {}", pcts.join(" | "))
+            }
+            None => "".to_string(),
+        };
+        writepfx!(
+            &mut buf,
+            "  🚏 {} - hits {}{}",
+            addr,
+            hits.iter().join(" | "),
+            ratios
+        );
+    }
+    if let Some(test) = &explanation.ratio_test {
+        writepfx!(
+            &mut buf,
+            " 📐 χ²={:.2} with {} DoF => p={:.2e}, Cramér's V={:.3} ({} of {:.0} hits to rule out a relevant difference, min. {:.1} expected per cell)",
+            test.chi_square,
+            test.degrees_of_freedom,
+            test.p_value,
+            test.effect_size,
+            test.hits,
+            test.required_hits,
+            test.min_expected_hits,
+        );
+    }
+    Ok(buf)
+}
+
 fn load_tree(conn: &mut PgConnection, target: &Ipv6Net) -> StdResult<PrefixTree, Error> {
     use db_model::schema::prefix_tree::dsl::*;
 
@@ -235,6 +328,40 @@ fn load_root(conn: &mut PgConnection, leaf: &PrefixTree) -> StdResult<Option<Pre
         .map_err(pfxerr!(LoadClosestRoot))
 }
 
+/// Bits that the net was split by if it has children, so that the subnets match the tree.
+fn load_split_bits(conn: &mut PgConnection, parent: &Ipv6Net) -> StdResult<u8, Error> {
+    use db_model::schema::prefix_tree::dsl::*;
+
+    let closest_child_len: Option<i64> = prefix_tree
+        .filter(net.subnet_or_eq6(parent))
+        .filter(masklen(net).gt(parent.prefix_len() as i64))
+        .select(masklen(net))
+        .order(masklen(net).asc())
+        .first(conn)
+        .optional()
+        .fix_cause()
+        .map_err(pfxerr!(LoadTree))?;
+    Ok(closest_child_len
+        .map(|len| (len as u8 - parent.prefix_len()).min(MAX_BITS_PER_SPLIT))
+        .unwrap_or(DEFAULT_BITS_PER_SPLIT))
+}
+
+fn load_latest_analysis(
+    conn: &mut PgConnection,
+    target: &Ipv6Net,
+) -> StdResult<Option<SplitAnalysis>, Error> {
+    use db_model::schema::split_analysis::dsl::*;
+
+    split_analysis
+        .filter(tree_net.eq6(target))
+        .filter(result.is_not_null())
+        .order(completed_at.desc())
+        .first(conn)
+        .optional()
+        .fix_cause()
+        .map_err(pfxerr!(LoadAnalysis))
+}
+
 fn load_relevant_measurements(
     conn: &mut PgConnection,
     base_net: &Ipv6Net,
@@ -82,6 +82,8 @@ pub enum Error {
     LoadClosestRoot { desc: String },
     #[error("Loading AS info for {net}: {desc}")]
     LoadAsInfo { desc: String, net: Ipv6Net },
+    #[error("Loading latest analysis: {desc}")]
+    LoadAnalysis { desc: String },
     #[error("Loading measurements: {desc}")]
     LoadMeasurements { desc: String },
     #[error("Splitting into subnets: {desc}")]
@@ -0,0 +1,400 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::IpAddr,
+};
+
+use aggregator::analyse::split::{self, SplitEvaluation};
+use anyhow::*;
+use clap::Args;
+use db_model::{
+    analyse::{forest::MeasurementForest, MeasurementTree, SplitAnalysis, CONFIDENCE_THRESH},
+    persist::{self, dsl::CidrMethods, DieselErrorFixCause},
+    prefix_tree::{MergeStatus, PriorityClass},
+};
+use diesel::{prelude::*, PgConnection};
+use ipnet::{IpNet, Ipv6Net};
+use itertools::Itertools;
+use log::info;
+use prefix_crab::{helpers::ip::ExpectV6, prefix_split};
+
+/// Re-runs the split decision at every analysed node within a prefix on the measurements
+/// stored in the database, and compares the outcome with the latest stored analysis result.
+/// Nothing is saved, so changes to the split algorithm can be evaluated within minutes.
+/// Leaves without a stored result are replayed as well and reported separately.
+///
+/// The current measurements are used, which may include responses that were received after
+/// the stored analysis. Expect some differences even without changes to the algorithm.
+#[derive(Args, Clone)]
+pub struct Params {
+    #[clap(flatten)]
+    persist: persist::Params,
+
+    target_prefix: Ipv6Net,
+
+    /// Should match the aggregator, such that the subnets match the stored analyses
+    #[clap(flatten)]
+    prefix_split: prefix_split::Params,
+
+    #[clap(flatten)]
+    split: split::Params,
+
+    /// Version of the split algorithm to replay, see `split::REPLAYABLE_ALGO_VERSIONS`
+    #[arg(long, default_value_t = split::ALGO_VERSION)]
+    algo_version: i32,
+
+    /// Only compare with stored results of this algorithm version (all if not given). Leaves
+    /// with results of other versions are then reported as without stored result.
+    #[arg(long)]
+    stored_algo_version: Option<i32>,
+
+    /// Print each node whose decision or priority class changed
+    #[arg(long, default_value = "false")]
+    list_changes: bool,
+}
+
+pub fn handle(params: Params) -> Result<()> {
+    persist::initialize(&params.persist)?;
+    let mut conn = persist::connect("crab-tools - split-replay")?;
+
+    ensure!(
+        split::REPLAYABLE_ALGO_VERSIONS.contains(&params.algo_version),
+        "Algorithm version {} cannot be replayed, only {:?}",
+        params.algo_version,
+        split::REPLAYABLE_ALGO_VERSIONS
+    );
+
+    let trees = load_measurements(&mut conn, &params.target_prefix)?;
+    let stored = load_stored_results(&mut conn, &params)?;
+    let stored_nets: HashSet<Ipv6Net> = stored.iter().map(|it| it.tree_net).collect();
+    let unanalysed = load_leaves(&mut conn, &params.target_prefix)?
+        .into_iter()
+        .filter(|it| !stored_nets.contains(it))
+        .collect_vec();
+    info!(
+        "Loaded {} measurement trees, {} analysed nodes and {} leaves without stored result.",
+        trees.len(),
+        stored.len(),
+        unanalysed.len(),
+    );
+
+    let mut report = Report::new(params.algo_version);
+    let replay = |net: Ipv6Net| -> Result<SplitEvaluation> {
+        let node_trees = trees_within(&trees, &net);
+        ensure!(!node_trees.is_empty(), "no measurements");
+        split::evaluate(
+            net,
+            node_trees,
+            &params.prefix_split.prefix_split_fan_out,
+            &params.split,
+            params.algo_version,
+        )
+    };
+    for analysis in stored {
+        match replay(analysis.tree_net) {
+            Result::Ok(replayed) => report.add(&analysis, &replayed, params.list_changes),
+            Err(e) => report.skip(analysis.tree_net, e),
+        }
+    }
+    for net in unanalysed {
+        match replay(net) {
+            Result::Ok(replayed) => report.add_unanalysed(net, &replayed, params.list_changes),
+            Err(e) => report.skip(net, e),
+        }
+    }
+
+    report.print();
+    Ok(())
+}
+
+/// Sorted by network address, such that the trees within a prefix are contiguous
+fn load_measurements(conn: &mut PgConnection, root: &Ipv6Net) -> Result<Vec<MeasurementTree>> {
+    use db_model::schema::measurement_tree::dsl::*;
+
+    let raw_trees: Vec<MeasurementTree> = measurement_tree
+        .filter(target_net.subnet_or_eq6(root))
+        .select(MeasurementTree::as_select())
+        .load(conn)
+        .fix_cause()?;
+    let forest = MeasurementForest::with_untouched(raw_trees)?;
+
+    Ok(forest
+        .into_iter()
+        .map(|it| it.tree)
+        .sorted_by_key(|it| (it.target_net.network(), it.target_net.prefix_len()))
+        .collect())
+}
+
+/// Leaves that split analyses are performed on
+fn load_leaves(conn: &mut PgConnection, root: &Ipv6Net) -> Result<Vec<Ipv6Net>> {
+    use db_model::schema::prefix_tree::dsl::*;
+
+    let raw_nets: Vec<IpNet> = prefix_tree
+        .filter(net.subnet_or_eq6(root))
+        .filter(merge_status.eq_any([MergeStatus::Leaf, MergeStatus::UnsplitRoot]))
+        .select(net)
+        .load(conn)
+        .fix_cause()?;
+
+    Ok(raw_nets.into_iter().map(|it| it.expect_v6()).collect_vec())
+}
+
+/// Trees in `trees` (sorted as by [load_measurements]) that are within `net`
+fn trees_within<'a>(trees: &'a [MeasurementTree], net: &Ipv6Net) -> &'a [MeasurementTree] {
+    let start = trees.partition_point(|it| it.target_net.network() < IpAddr::V6(net.network()));
+    let end = trees.partition_point(|it| it.target_net.network() <= IpAddr::V6(net.broadcast()));
+    let candidates = &trees[start..end];
+    // A tree of a supernet that starts at the same address would be sorted first
+    let supernets = candidates
+        .iter()
+        .take_while(|it| it.target_net.prefix_len() < net.prefix_len())
+        .count();
+    &candidates[supernets..]
+}
+
+/// The latest completed analysis with a result for each node
+fn load_stored_results(conn: &mut PgConnection, params: &Params) -> Result<Vec<SplitAnalysis>> {
+    use db_model::schema::split_analysis::dsl::*;
+
+    let analyses: Vec<SplitAnalysis> = split_analysis
+        .filter(tree_net.subnet_or_eq6(&params.target_prefix))
+        .filter(result.is_not_null())
+        .order_by((tree_net.asc(), completed_at.desc()))
+        .load(conn)
+        .fix_cause()?;
+
+    let is_stored_version = |it: &SplitAnalysis| match (&it.result, params.stored_algo_version) {
+        (Some(res), Some(version)) => res.algo_version == version,
+        (_, None) => true,
+        (None, _) => false,
+    };
+    Ok(analyses
+        .into_iter()
+        .filter(is_stored_version)
+        .dedup_by(|a, b| a.tree_net == b.tree_net)
+        .collect())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum Decision {
+    Split,
+    Keep,
+    Undetermined,
+}
+
+impl From<Option<bool>> for Decision {
+    fn from(value: Option<bool>) -> Self {
+        match value {
+            Some(true) => Self::Split,
+            Some(false) => Self::Keep,
+            None => Self::Undetermined,
+        }
+    }
+}
+
+const DECISIONS: [Decision; 3] = [Decision::Split, Decision::Keep, Decision::Undetermined];
+
+struct Report {
+    algo_version: i32,
+    /// (stored, replayed) -> count
+    decisions: BTreeMap<(Decision, Decision), usize>,
+    class_shifts: BTreeMap<(PriorityClass, PriorityClass), usize>,
+    stored_algo_versions: BTreeMap<i32, usize>,
+    /// Decisions for leaves without stored result
+    unanalysed: BTreeMap<Decision, usize>,
+    replayed_confident: usize,
+    evaluated: usize,
+    skipped: Vec<(Ipv6Net, Error)>,
+}
+
+impl Report {
+    fn new(algo_version: i32) -> Self {
+        Self {
+            algo_version,
+            decisions: BTreeMap::new(),
+            class_shifts: BTreeMap::new(),
+            stored_algo_versions: BTreeMap::new(),
+            unanalysed: BTreeMap::new(),
+            replayed_confident: 0,
+            evaluated: 0,
+            skipped: vec![],
+        }
+    }
+
+    fn skip(&mut self, net: Ipv6Net, reason: Error) {
+        self.skipped.push((net, reason));
+    }
+
+    fn add_unanalysed(&mut self, net: Ipv6Net, replayed: &SplitEvaluation, list_changes: bool) {
+        let decision = replayed.result.should_split.into();
+        *self.unanalysed.entry(decision).or_default() += 1;
+
+        if list_changes {
+            println!(
+                "  + {}: not analysed -> {:?} {:?} at {}%",
+                net, decision, replayed.result.class, replayed.confidence,
+            );
+        }
+    }
+
+    fn add(&mut self, stored: &SplitAnalysis, replayed: &SplitEvaluation, list_changes: bool) {
+        let stored_result = stored
+            .result
+            .as_ref()
+            .expect("only analyses with result loaded");
+        let replayed_result = &replayed.result;
+        let decisions = (
+            stored_result.should_split.into(),
+            replayed_result.should_split.into(),
+        );
+        let classes = (stored_result.class, replayed_result.class);
+
+        self.evaluated += 1;
+        *self.decisions.entry(decisions).or_default() += 1;
+        *self
+            .stored_algo_versions
+            .entry(stored_result.algo_version)
+            .or_default() += 1;
+        if classes.0 != classes.1 {
+            *self.class_shifts.entry(classes).or_default() += 1;
+        }
+        if replayed.confidence >= CONFIDENCE_THRESH {
+            self.replayed_confident += 1;
+        }
+
+        if list_changes && (decisions.0 != decisions.1 || classes.0 != classes.1) {
+            println!(
+                "  ~ {}: {:?} {:?} (v{}) -> {:?} {:?} at {}% (v{})",
+                stored.tree_net,
+                decisions.0,
+                classes.0,
+                stored_result.algo_version,
+                decisions.1,
+                classes.1,
+                replayed.confidence,
+                replayed_result.algo_version,
+            );
+        }
+    }
+
+    fn print(&self) {
+        println!(
+            "Replayed {} analysed nodes with algorithm v{} ({} at sufficient confidence), skipped {}.",
+            self.evaluated,
+            self.algo_version,
+            self.replayed_confident,
+            self.skipped.len(),
+        );
+        let versions = self
+            .stored_algo_versions
+            .iter()
+            .map(|(version, count)| format!("v{}: {}", version, count))
+            .join(", ");
+        println!("Stored results by algorithm version: {}", versions);
+
+        println!(
+            "\nStored \\ Replayed {:>14}{:>14}{:>14}",
+            "Split", "Keep", "Undetermined"
+        );
+        for stored in DECISIONS {
+            let counts = DECISIONS
+                .iter()
+                .map(|replayed| {
+                    let count = self.decisions.get(&(stored, *replayed)).unwrap_or(&0);
+                    format!("{:>14}", count)
+                })
+                .join("");
+            println!("{:<18}{}", format!("{:?}", stored), counts);
+        }
+        let agreeing: usize = DECISIONS
+            .iter()
+            .filter_map(|it| self.decisions.get(&(*it, *it)))
+            .sum();
+        if let Some(agreement) = (agreeing * 100).checked_div(self.evaluated) {
+            println!("Agreement: {}%", agreement);
+        }
+
+        println!("\nClass shifts:");
+        for ((old_class, new_class), count) in self.class_shifts.iter() {
+            println!("  {:?} -> {:?}: {}", old_class, new_class, count);
+        }
+
+        let unanalysed = DECISIONS
+            .iter()
+            .map(|it| format!("{:?}: {}", it, self.unanalysed.get(it).unwrap_or(&0)))
+            .join(", ");
+        println!("\nLeaves without stored result: {}", unanalysed);
+
+        if !self.skipped.is_empty() {
+            println!("\nSkipped:");
+            for (net, reason) in self.skipped.iter() {
+                println!("  ! {}: {:#}", net, reason);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+    use db_model::{analyse::SplitAnalysisResult, test_utils::*};
+
+    use super::*;
+
+    #[test]
+    fn trees_within_excludes_supernets_and_neighbours() {
+        // given
+        let trees = [
+            "2001:db8::/32",
+            "2001:db8::/33",
+            "2001:db8::/48",
+            "2001:db8:8000::/33",
+            "2001:db9::/32",
+        ]
+        .map(|it| MeasurementTree::empty(net(it)));
+
+        // when
+        let within = trees_within(&trees, &net("2001:db8::/33"));
+
+        // then
+        let nets = within.iter().map(|it| it.target_net.expect_v6()).collect_vec();
+        assert_that!(nets).is_equal_to(vec![net("2001:db8::/33"), net("2001:db8::/48")]);
+    }
+
+    #[test]
+    fn trees_within_nothing_measured() {
+        // given
+        let trees = [MeasurementTree::empty(net(TREE_LEFT_NET))];
+
+        // when
+        let within = trees_within(&trees, &net(TREE_RIGHT_NET));
+
+        // then
+        assert_that!(within.is_empty()).is_true();
+    }
+
+    #[test]
+    fn report_counts_unanalysed_separately() {
+        // given
+        let mut report = Report::new(split::ALGO_VERSION);
+        let replayed = SplitEvaluation {
+            result: SplitAnalysisResult {
+                class: PriorityClass::HighDisjoint,
+                evidence: 8,
+                should_split: Some(true),
+                algo_version: split::ALGO_VERSION,
+                explanation: None,
+            },
+            confidence: CONFIDENCE_THRESH,
+        };
+
+        // when
+        report.add_unanalysed(net(TREE_LEFT_NET), &replayed, false);
+        report.skip(net(TREE_RIGHT_NET), anyhow!("no measurements"));
+
+        // then
+        assert_that!(report.unanalysed.get(&Decision::Split)).is_equal_to(Some(&1));
+        assert_that!(report.evaluated).is_equal_to(0);
+        assert_that!(report.decisions.is_empty()).is_true();
+        assert_that!(report.skipped.len()).is_equal_to(1);
+    }
+}
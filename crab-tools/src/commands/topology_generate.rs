@@ -0,0 +1,190 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    net::Ipv6Addr,
+    path::PathBuf,
+};
+
+use anyhow::*;
+use clap::Args;
+use ipnet::Ipv6Net;
+use itertools::Itertools;
+use log::info;
+use prefix_crab::{
+    prefix_split::MAX_PREFIX_LEN,
+    simulation::{SimulatedBehaviour, TopologyEntry, WeightedRouter},
+};
+use rand::{rngs::StdRng, seq::IteratorRandom, Rng, SeedableRng};
+
+/// Generates a synthetic ground-truth topology of nested allocations, in the format used by the
+/// simulated probers (SIMULATION_TOPOLOGY_FILE). After running the whole pipeline against it,
+/// the resulting tree can be rated using `topology-score`.
+#[derive(Args, Clone)]
+pub struct Params {
+    /// Prefix to generate allocations in, this should be a root in the prefix tree
+    base_net: Ipv6Net,
+
+    out_file: PathBuf,
+
+    /// Seed for the random generator, the same seed always yields the same topology
+    #[arg(long, default_value = "1")]
+    seed: u64,
+
+    /// How many levels of more-specific allocations to nest at most
+    #[arg(long, default_value = "4")]
+    max_depth: u8,
+
+    /// Chance of an allocation to contain more-specific allocations
+    #[arg(long, default_value = "0.6")]
+    nest_probability: f64,
+
+    /// Chance of a more-specific allocation to be routed the same as its parent,
+    /// i.e. the ground truth does not need a split there
+    #[arg(long, default_value = "0.2")]
+    equivalent_probability: f64,
+
+    /// Chance of an allocation to be entirely unresponsive
+    #[arg(long, default_value = "0.15")]
+    unresponsive_probability: f64,
+
+    /// Chance of an allocation to be served by multiple last-hop routers
+    #[arg(long, default_value = "0.3")]
+    multi_lhr_probability: f64,
+
+    /// Chance of the last-hop routers of an allocation to rate-limit ICMP errors
+    #[arg(long, default_value = "0.2")]
+    rate_limit_probability: f64,
+
+    /// Highest share of lost probes to assign to an allocation
+    #[arg(long, default_value = "0.1")]
+    max_loss_rate: f64,
+
+    /// Prefix that (fictional) last-hop router addresses are taken from
+    #[arg(long, default_value = "2001:db8:ffff::/48")]
+    router_net: Ipv6Net,
+}
+
+pub fn handle(params: Params) -> Result<()> {
+    let mut generator = Generator {
+        rng: StdRng::seed_from_u64(params.seed),
+        next_router: 1,
+        entries: vec![],
+        params: params.clone(),
+    };
+    let root = generator.make_entry(params.base_net, None);
+    generator.nest(&root, 1);
+    generator.entries.push(root);
+
+    let file = File::create(&params.out_file)
+        .with_context(|| format!("creating topology file {:?}", params.out_file))?;
+    let mut writer = BufWriter::new(file);
+    writeln!(
+        writer,
+        "# Synthetic topology for {} (seed {}, generated by crab-tools topology-generate)",
+        params.base_net, params.seed
+    )?;
+    for entry in generator.entries.iter().sorted_by_key(|it| it.net) {
+        writeln!(writer, "{}", entry)?;
+    }
+    writer.flush()?;
+
+    info!(
+        "Generated {} allocations using {} last-hop routers into {:?}.",
+        generator.entries.len(),
+        generator.next_router - 1,
+        params.out_file
+    );
+    Ok(())
+}
+
+struct Generator {
+    rng: StdRng,
+    next_router: u128,
+    entries: Vec<TopologyEntry>,
+    params: Params,
+}
+
+impl Generator {
+    fn nest(&mut self, parent: &TopologyEntry, depth: u8) {
+        let parent_len = parent.net.prefix_len();
+        if depth > self.params.max_depth
+            || parent_len >= MAX_PREFIX_LEN
+            || !self.rng.gen_bool(self.params.nest_probability)
+        {
+            return;
+        }
+        let child_len = (parent_len + self.rng.gen_range(1..=8)).min(MAX_PREFIX_LEN);
+        let child_count = self.rng.gen_range(1..=3);
+        let children = parent
+            .net
+            .subnets(child_len)
+            .expect("child len to be valid")
+            .choose_multiple(&mut self.rng, child_count);
+        for child_net in children {
+            let child = if self.rng.gen_bool(self.params.equivalent_probability) {
+                TopologyEntry {
+                    net: child_net,
+                    ..parent.clone()
+                }
+            } else {
+                self.make_entry(child_net, Some(parent))
+            };
+            self.nest(&child, depth + 1);
+            self.entries.push(child);
+        }
+    }
+
+    fn make_entry(&mut self, net: Ipv6Net, parent: Option<&TopologyEntry>) -> TopologyEntry {
+        let behaviour = self.make_behaviour();
+        let router_count = if self.rng.gen_bool(self.params.multi_lhr_probability) {
+            self.rng.gen_range(2..=4)
+        } else {
+            1
+        };
+        let last_hops = (0..router_count)
+            .map(|_| WeightedRouter {
+                addr: self.make_router(),
+                weight: self.rng.gen_range(1..=4),
+            })
+            .collect_vec();
+        // More-specifics are usually behind their parent's routers; stay within default max TTL
+        let min_hops = parent.map(|it| it.hops).unwrap_or(4).min(14);
+        let rate_limit = if self.rng.gen_bool(self.params.rate_limit_probability) {
+            Some(self.rng.gen_range(5..=50))
+        } else {
+            None
+        };
+        TopologyEntry {
+            net,
+            last_hops,
+            hops: self.rng.gen_range(min_hops..=min_hops + 1),
+            behaviour,
+            loss_rate: self.rng.gen_range(0.0..=self.params.max_loss_rate),
+            rate_limit,
+        }
+    }
+
+    fn make_behaviour(&mut self) -> SimulatedBehaviour {
+        use SimulatedBehaviour as B;
+
+        if self.rng.gen_bool(self.params.unresponsive_probability) {
+            return B::NoResponse;
+        }
+        match self.rng.gen_range(0..10) {
+            0..=3 => B::EchoReply,
+            4..=8 => B::DestinationUnreachable {
+                code: *[0u8, 1, 3, 4, 5, 6]
+                    .iter()
+                    .choose(&mut self.rng)
+                    .expect("non-empty codes"),
+            },
+            _ => B::TimeExceeded,
+        }
+    }
+
+    fn make_router(&mut self) -> Ipv6Addr {
+        let base = u128::from(self.params.router_net.network());
+        self.next_router += 1;
+        Ipv6Addr::from(base + self.next_router - 1)
+    }
+}
@@ -0,0 +1,234 @@
+use std::{collections::HashSet, path::PathBuf};
+
+use anyhow::*;
+use clap::Args;
+use db_model::{
+    persist::{self, dsl::CidrMethods, DieselErrorFixCause},
+    prefix_tree::LEAF_MERGE_STATUSES,
+};
+use diesel::prelude::*;
+use ipnet::{IpNet, Ipv6Net};
+use itertools::Itertools;
+use log::{debug, info};
+use prefix_crab::{helpers::ip::ExpectV6, prefix_split, simulation};
+
+/// Scores the leaves of the prefix tree against the ground truth of a simulated topology,
+/// e.g. one generated by `topology-generate`. The ground truth are the least specific
+/// prefixes whose addresses all share the same last-hop routers.
+#[derive(Args, Clone)]
+pub struct Params {
+    #[clap(flatten)]
+    persist: persist::Params,
+
+    /// Topology that the probers were simulating
+    topology_file: PathBuf,
+
+    /// Prefix to score, usually the base prefix the topology was generated for
+    target_prefix: Ipv6Net,
+
+    /// Should match the aggregator, since the ground truth can only contain nets that
+    /// its splits can reach
+    #[clap(flatten)]
+    prefix_split: prefix_split::Params,
+}
+
+pub fn handle(params: Params) -> Result<()> {
+    let topology = simulation::Params {
+        simulation_topology_file: Some(params.topology_file.clone()),
+    }
+    .read_topology()?
+    .expect("topology file to be set");
+
+    let truth = topology.ideal_leaves(
+        params.target_prefix,
+        &params.prefix_split.prefix_split_fan_out,
+    );
+    let actual = select_leaves(&params)?;
+    info!(
+        "Comparing {} actual leaves to {} ground-truth leaves.",
+        actual.len(),
+        truth.len()
+    );
+
+    let score = Score::calculate(&truth, &actual);
+    println!("{}", score);
+    Ok(())
+}
+
+fn select_leaves(params: &Params) -> Result<Vec<Ipv6Net>> {
+    use db_model::schema::prefix_tree::dsl::*;
+    let mut conn = persist::connect_manual("crab-tools - topology-score", &params.persist)?;
+
+    let raw_nets: Vec<IpNet> = prefix_tree
+        .filter(net.subnet_or_eq6(&params.target_prefix))
+        .filter(merge_status.eq_any(LEAF_MERGE_STATUSES))
+        .select(net)
+        .load(&mut conn)
+        .fix_cause()?;
+
+    Ok(raw_nets.into_iter().map(|it| it.expect_v6()).collect_vec())
+}
+
+#[derive(Debug, Default)]
+struct Score {
+    truth_count: usize,
+    actual_count: usize,
+    /// Actual leaves that exactly match a ground-truth leaf
+    exact: usize,
+    /// Actual leaves that are more specific than needed
+    over_split: usize,
+    /// Actual leaves that cover multiple ground-truth leaves, i.e. should have been split
+    under_split: usize,
+    /// Actual leaves outside of the ground truth, e.g. if the target is not a root
+    unrelated: usize,
+}
+
+impl Score {
+    fn calculate(truth: &[Ipv6Net], actual: &[Ipv6Net]) -> Self {
+        let truth_set: HashSet<&Ipv6Net> = truth.iter().collect();
+        let mut score = Score {
+            truth_count: truth.len(),
+            actual_count: actual.len(),
+            ..Default::default()
+        };
+        for leaf in actual {
+            if truth_set.contains(leaf) {
+                score.exact += 1;
+            } else if truth.iter().any(|it| it.contains(leaf)) {
+                debug!("Over-split: {}", leaf);
+                score.over_split += 1;
+            } else if truth.iter().any(|it| leaf.contains(it)) {
+                debug!("Under-split: {}", leaf);
+                score.under_split += 1;
+            } else {
+                score.unrelated += 1;
+            }
+        }
+        score
+    }
+
+    /// Share of actual boundaries (leaves) that are also in the ground truth
+    fn precision(&self) -> f64 {
+        ratio(self.exact, self.actual_count)
+    }
+
+    /// Share of ground-truth boundaries (leaves) that were found
+    fn recall(&self) -> f64 {
+        ratio(self.exact, self.truth_count)
+    }
+}
+
+fn ratio(part: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 / total as f64
+    }
+}
+
+impl std::fmt::Display for Score {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Ground-truth leaves: {}", self.truth_count)?;
+        writeln!(f, "Actual leaves:       {}", self.actual_count)?;
+        writeln!(f, " - exact match:      {}", self.exact)?;
+        writeln!(f, " - over-split:       {}", self.over_split)?;
+        writeln!(f, " - under-split:      {}", self.under_split)?;
+        writeln!(f, " - unrelated:        {}", self.unrelated)?;
+        writeln!(f, "Boundary precision:  {:.3}", self.precision())?;
+        write!(f, "Boundary recall:     {:.3}", self.recall())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+    use prefix_crab::test_utils::net;
+
+    use super::*;
+
+    fn nets(inputs: &[&str]) -> Vec<Ipv6Net> {
+        inputs.iter().map(|it| net(it)).collect_vec()
+    }
+
+    #[test]
+    fn exact_match() {
+        // given
+        let truth = nets(&["2001:db8::/33", "2001:db8:8000::/33"]);
+
+        // when
+        let score = Score::calculate(&truth, &truth);
+
+        // then
+        assert_that!(score.exact).is_equal_to(2);
+        assert_that!(score.over_split + score.under_split + score.unrelated).is_equal_to(0);
+        assert_that!(score.precision()).is_equal_to(1.0);
+        assert_that!(score.recall()).is_equal_to(1.0);
+    }
+
+    #[test]
+    fn leaf_split_too_far() {
+        // given
+        let truth = nets(&["2001:db8::/33", "2001:db8:8000::/33"]);
+        let actual = nets(&["2001:db8::/33", "2001:db8:8000::/34", "2001:db8:c000::/34"]);
+
+        // when
+        let score = Score::calculate(&truth, &actual);
+
+        // then
+        assert_that!(score.exact).is_equal_to(1);
+        assert_that!(score.over_split).is_equal_to(2);
+        assert_that!(score.precision()).is_equal_to(1.0 / 3.0);
+        assert_that!(score.recall()).is_equal_to(0.5);
+    }
+
+    #[test]
+    fn leaves_merged_too_coarsely() {
+        // given
+        let truth = nets(&["2001:db8::/33", "2001:db8:8000::/34", "2001:db8:c000::/34"]);
+        let actual = nets(&["2001:db8::/33", "2001:db8:8000::/33"]);
+
+        // when
+        let score = Score::calculate(&truth, &actual);
+
+        // then
+        assert_that!(score.exact).is_equal_to(1);
+        assert_that!(score.under_split).is_equal_to(1);
+        assert_that!(score.precision()).is_equal_to(0.5);
+        assert_that!(score.recall()).is_equal_to(1.0 / 3.0);
+    }
+
+    #[test]
+    fn unrelated_leaves() {
+        // given
+        let truth = nets(&["2001:db8::/32"]);
+        let actual = nets(&["2001:db9::/32", "2001:dba::/48"]);
+
+        // when
+        let score = Score::calculate(&truth, &actual);
+
+        // then
+        assert_that!(score.unrelated).is_equal_to(2);
+        assert_that!(score.exact + score.over_split + score.under_split).is_equal_to(0);
+        assert_that!(score.precision()).is_equal_to(0.0);
+        assert_that!(score.recall()).is_equal_to(0.0);
+    }
+
+    #[test]
+    fn empty_inputs() {
+        // given
+        let truth = nets(&["2001:db8::/32"]);
+
+        // when
+        let no_actual = Score::calculate(&truth, &[]);
+        let no_truth = Score::calculate(&[], &truth);
+        let neither = Score::calculate(&[], &[]);
+
+        // then
+        assert_that!(no_actual.precision()).is_equal_to(0.0);
+        assert_that!(no_actual.recall()).is_equal_to(0.0);
+        assert_that!(no_truth.unrelated).is_equal_to(1);
+        assert_that!(no_truth.recall()).is_equal_to(0.0);
+        assert_that!(neither.precision()).is_equal_to(0.0);
+        assert_that!(neither.recall()).is_equal_to(0.0);
+    }
+}
@@ -0,0 +1,446 @@
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use anyhow::*;
+use clap::{Args, ValueEnum};
+use db_model::{
+    persist::{self, dsl::CidrMethods, DieselErrorFixCause},
+    prefix_tree::{AsNumber, LhrSetHash, PrefixTree, PriorityClass, LEAF_MERGE_STATUSES},
+};
+use diesel::prelude::*;
+use ipnet::Ipv6Net;
+use itertools::{EitherOrBoth, Itertools};
+use log::info;
+use serde::Serialize;
+
+use super::export::{read_leaves, JsonRecord};
+
+/// Compares the leaves of two prefix tree snapshots, e.g. from two scan runs a few months apart.
+///
+/// Reports leaf boundaries that were added or removed, leaves whose LHR set or priority class
+/// changed, as well as a summary of these per AS. Leaves are attributed to the AS of the
+/// snapshot they are present in, and to the newer AS if present in both.
+#[derive(Args, Clone)]
+pub struct Params {
+    /// Older snapshot, either a PostgreSQL URL or a file created by `export` (in any format)
+    old: Snapshot,
+
+    /// Newer snapshot, in the same formats as the older one
+    new: Snapshot,
+
+    #[arg(long, value_enum, default_value = "human")]
+    format: DiffFormat,
+
+    /// Write the report to this file instead of stdout
+    #[arg(long)]
+    out_file: Option<PathBuf>,
+
+    /// Only compare leaves within this prefix (all if not given)
+    #[arg(long)]
+    target_prefix: Option<Ipv6Net>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum DiffFormat {
+    Human,
+    Json,
+}
+
+#[derive(Clone, Debug)]
+enum Snapshot {
+    Database(persist::Params),
+    Export(PathBuf),
+}
+
+impl FromStr for Snapshot {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let snapshot = if s.starts_with("postgres://") || s.starts_with("postgresql://") {
+            Self::Database(persist::Params::new(s.to_owned()))
+        } else {
+            Self::Export(s.into())
+        };
+        Result::Ok(snapshot)
+    }
+}
+
+impl Snapshot {
+    fn load(&self, root: &Ipv6Net) -> Result<BTreeMap<Ipv6Net, Leaf>> {
+        match self {
+            Self::Database(db_params) => load_database(db_params, root),
+            Self::Export(path) => load_export(path, root),
+        }
+    }
+}
+
+pub fn handle(params: Params) -> Result<()> {
+    let root = match params.target_prefix {
+        Some(it) => it,
+        None => "::/0".parse().expect("::/0 to be a valid prefix"),
+    };
+
+    let old = params.old.load(&root).context("loading old snapshot")?;
+    let new = params.new.load(&root).context("loading new snapshot")?;
+    info!("Loaded {} old and {} new leaves.", old.len(), new.len());
+
+    let diff = TreeDiff::new(&old, &new);
+
+    let mut out: Box<dyn Write> = match &params.out_file {
+        Some(path) => Box::new(BufWriter::new(
+            File::create_new(path).with_context(|| format!("creating report file {:?}", path))?,
+        )),
+        None => Box::new(io::stdout().lock()),
+    };
+    match params.format {
+        DiffFormat::Human => diff.write_human(&mut out)?,
+        DiffFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, &diff)?;
+            writeln!(out)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// The parts of a leaf relevant for comparison
+#[derive(Debug, Clone)]
+struct Leaf {
+    net: Ipv6Net,
+    asn: AsNumber,
+    priority_class: PriorityClass,
+    lhr_set_hash: String,
+}
+
+impl From<PrefixTree> for Leaf {
+    fn from(value: PrefixTree) -> Self {
+        Self {
+            net: value.net,
+            asn: value.asn,
+            priority_class: value.priority_class,
+            lhr_set_hash: value.lhr_set_hash.to_string(),
+        }
+    }
+}
+
+impl From<JsonRecord> for Leaf {
+    fn from(value: JsonRecord) -> Self {
+        Self {
+            net: value.net,
+            asn: value.asn,
+            priority_class: value.priority_class,
+            lhr_set_hash: value.lhr_set_hash,
+        }
+    }
+}
+
+impl Leaf {
+    fn to_ref(&self) -> LeafRef {
+        LeafRef {
+            net: self.net,
+            asn: self.asn,
+        }
+    }
+
+    fn has_known_lhrs(&self) -> bool {
+        self.lhr_set_hash != LhrSetHash::nil().to_string()
+    }
+}
+
+fn load_database(db_params: &persist::Params, root: &Ipv6Net) -> Result<BTreeMap<Ipv6Net, Leaf>> {
+    use db_model::schema::prefix_tree::dsl::*;
+    let mut conn = persist::connect_manual("crab-tools - tree-diff", db_params)?;
+
+    let leaves: Vec<PrefixTree> = prefix_tree
+        .filter(net.subnet_or_eq6(root))
+        .filter(merge_status.eq_any(LEAF_MERGE_STATUSES))
+        .select(PrefixTree::as_select())
+        .load(&mut conn)
+        .fix_cause()?;
+
+    Ok(leaves
+        .into_iter()
+        .map(|leaf| (leaf.net, leaf.into()))
+        .collect())
+}
+
+fn load_export(path: &PathBuf, root: &Ipv6Net) -> Result<BTreeMap<Ipv6Net, Leaf>> {
+    let file = File::open(path).with_context(|| format!("opening export {:?}", path))?;
+    let records =
+        read_leaves(BufReader::new(file)).with_context(|| format!("reading export {:?}", path))?;
+
+    Ok(records
+        .into_iter()
+        .filter(|record| root.contains(&record.net))
+        .map(|record| (record.net, record.into()))
+        .collect())
+}
+
+#[derive(Serialize, Default)]
+struct TreeDiff {
+    old_leaves: usize,
+    new_leaves: usize,
+    added: Vec<LeafRef>,
+    removed: Vec<LeafRef>,
+    /// Only leaves with known LHRs in both snapshots are compared
+    lhrs_changed: Vec<LhrChange>,
+    class_shifts: Vec<ClassShift>,
+    per_as: BTreeMap<AsNumber, AsSummary>,
+}
+
+#[derive(Serialize)]
+struct LeafRef {
+    net: Ipv6Net,
+    asn: AsNumber,
+}
+
+#[derive(Serialize)]
+struct LhrChange {
+    net: Ipv6Net,
+    asn: AsNumber,
+    old_lhr_set_hash: String,
+    new_lhr_set_hash: String,
+}
+
+#[derive(Serialize)]
+struct ClassShift {
+    net: Ipv6Net,
+    asn: AsNumber,
+    old_class: PriorityClass,
+    new_class: PriorityClass,
+}
+
+#[derive(Serialize, Default)]
+struct AsSummary {
+    old_leaves: usize,
+    new_leaves: usize,
+    added: usize,
+    removed: usize,
+    lhrs_changed: usize,
+    class_shifts: usize,
+}
+
+impl TreeDiff {
+    fn new(old: &BTreeMap<Ipv6Net, Leaf>, new: &BTreeMap<Ipv6Net, Leaf>) -> Self {
+        let mut diff = Self {
+            old_leaves: old.len(),
+            new_leaves: new.len(),
+            ..Default::default()
+        };
+
+        // Both are ordered by network, so a single pass suffices
+        for item in old
+            .values()
+            .merge_join_by(new.values(), |o, n| o.net.cmp(&n.net))
+        {
+            match item {
+                EitherOrBoth::Left(removed) => {
+                    let summary = diff.per_as.entry(removed.asn).or_default();
+                    summary.old_leaves += 1;
+                    summary.removed += 1;
+                    diff.removed.push(removed.to_ref());
+                }
+                EitherOrBoth::Right(added) => {
+                    let summary = diff.per_as.entry(added.asn).or_default();
+                    summary.new_leaves += 1;
+                    summary.added += 1;
+                    diff.added.push(added.to_ref());
+                }
+                EitherOrBoth::Both(old, new) => diff.compare_kept(old, new),
+            }
+        }
+        diff
+    }
+
+    fn compare_kept(&mut self, old: &Leaf, new: &Leaf) {
+        self.per_as.entry(old.asn).or_default().old_leaves += 1;
+        let summary = self.per_as.entry(new.asn).or_default();
+        summary.new_leaves += 1;
+
+        if old.has_known_lhrs() && new.has_known_lhrs() && old.lhr_set_hash != new.lhr_set_hash {
+            summary.lhrs_changed += 1;
+            self.lhrs_changed.push(LhrChange {
+                net: new.net,
+                asn: new.asn,
+                old_lhr_set_hash: old.lhr_set_hash.clone(),
+                new_lhr_set_hash: new.lhr_set_hash.clone(),
+            });
+        }
+        if old.priority_class != new.priority_class {
+            summary.class_shifts += 1;
+            self.class_shifts.push(ClassShift {
+                net: new.net,
+                asn: new.asn,
+                old_class: old.priority_class,
+                new_class: new.priority_class,
+            });
+        }
+    }
+
+    fn write_human(&self, out: &mut impl Write) -> Result<()> {
+        writeln!(out, "Leaves: {} -> {}", self.old_leaves, self.new_leaves)?;
+        writeln!(out, "Added leaf boundaries: {}", self.added.len())?;
+        writeln!(out, "Removed leaf boundaries: {}", self.removed.len())?;
+        writeln!(out, "Changed LHR sets: {}", self.lhrs_changed.len())?;
+        writeln!(out, "Class shifts: {}", self.class_shifts.len())?;
+        let shift_counts = self
+            .class_shifts
+            .iter()
+            .counts_by(|it| (it.old_class, it.new_class));
+        for ((old_class, new_class), count) in shift_counts.into_iter().sorted() {
+            writeln!(out, "  {:?} -> {:?}: {}", old_class, new_class, count)?;
+        }
+
+        writeln!(out, "\nPer AS:")?;
+        for (asn, summary) in self.per_as.iter() {
+            writeln!(
+                out,
+                "  AS{}: {} -> {} leaves, +{} -{}, {} LHR sets changed, {} class shifts",
+                asn,
+                summary.old_leaves,
+                summary.new_leaves,
+                summary.added,
+                summary.removed,
+                summary.lhrs_changed,
+                summary.class_shifts,
+            )?;
+        }
+
+        writeln!(out, "\nAdded:")?;
+        for leaf in self.added.iter() {
+            writeln!(out, "  + {} (AS{})", leaf.net, leaf.asn)?;
+        }
+        writeln!(out, "\nRemoved:")?;
+        for leaf in self.removed.iter() {
+            writeln!(out, "  - {} (AS{})", leaf.net, leaf.asn)?;
+        }
+        writeln!(out, "\nChanged LHR sets:")?;
+        for change in self.lhrs_changed.iter() {
+            writeln!(
+                out,
+                "  ~ {} (AS{}): {} -> {}",
+                change.net, change.asn, change.old_lhr_set_hash, change.new_lhr_set_hash
+            )?;
+        }
+        writeln!(out, "\nClass shifts:")?;
+        for shift in self.class_shifts.iter() {
+            writeln!(
+                out,
+                "  ~ {} (AS{}): {:?} -> {:?}",
+                shift.net, shift.asn, shift.old_class, shift.new_class
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+    use db_model::test_utils::*;
+
+    use super::*;
+
+    fn gen_leaf(net_str: &str, asn: AsNumber, class: PriorityClass, hash: LhrSetHash) -> Leaf {
+        Leaf {
+            net: net(net_str),
+            asn,
+            priority_class: class,
+            lhr_set_hash: hash.to_string(),
+        }
+    }
+
+    fn gen_snapshot(leaves: Vec<Leaf>) -> BTreeMap<Ipv6Net, Leaf> {
+        leaves.into_iter().map(|it| (it.net, it)).collect()
+    }
+
+    #[test]
+    fn added_and_removed() {
+        // given
+        let hash = LhrSetHash::from_u128(0xbeef);
+        let old = gen_snapshot(vec![
+            gen_leaf(TREE_LEFT_NET, 1, PriorityClass::LowUnknown, hash),
+            gen_leaf(TREE_RIGHT_NET, 1, PriorityClass::LowUnknown, hash),
+        ]);
+        let new = gen_snapshot(vec![
+            gen_leaf(TREE_RIGHT_NET, 1, PriorityClass::LowUnknown, hash),
+            gen_leaf(TREE_UNRELATED_NET, 2, PriorityClass::HighFresh, hash),
+        ]);
+
+        // when
+        let diff = TreeDiff::new(&old, &new);
+
+        // then
+        assert_that!(diff.added.iter().map(|it| it.net).collect_vec())
+            .contains_exactly(vec![net(TREE_UNRELATED_NET)]);
+        assert_that!(diff.removed.iter().map(|it| it.net).collect_vec())
+            .contains_exactly(vec![net(TREE_LEFT_NET)]);
+        assert_that!(diff.lhrs_changed.is_empty()).is_true();
+        assert_that!(diff.class_shifts.is_empty()).is_true();
+        let as1 = &diff.per_as[&1];
+        assert_that!((as1.old_leaves, as1.new_leaves, as1.added, as1.removed))
+            .is_equal_to((2, 1, 0, 1));
+        let as2 = &diff.per_as[&2];
+        assert_that!((as2.old_leaves, as2.new_leaves, as2.added, as2.removed))
+            .is_equal_to((0, 1, 1, 0));
+    }
+
+    #[test]
+    fn lhrs_changed_only_if_known_in_both() {
+        // given
+        let (hash_a, hash_b) = (LhrSetHash::from_u128(0xa), LhrSetHash::from_u128(0xb));
+        let class = PriorityClass::MediumSameSingle;
+        let old = gen_snapshot(vec![
+            gen_leaf(TREE_LEFT_NET, 1, class, hash_a),
+            gen_leaf(TREE_RIGHT_NET, 1, class, LhrSetHash::nil()),
+            gen_leaf(TREE_RIGHT_NET_ALT, 1, class, hash_a),
+        ]);
+        let new = gen_snapshot(vec![
+            gen_leaf(TREE_LEFT_NET, 1, class, hash_b),
+            gen_leaf(TREE_RIGHT_NET, 1, class, hash_b),
+            gen_leaf(TREE_RIGHT_NET_ALT, 1, class, hash_a),
+        ]);
+
+        // when
+        let diff = TreeDiff::new(&old, &new);
+
+        // then
+        assert_that!(diff.lhrs_changed.iter().map(|it| it.net).collect_vec())
+            .contains_exactly(vec![net(TREE_LEFT_NET)]);
+        let change = &diff.lhrs_changed[0];
+        assert_that!(change.old_lhr_set_hash).is_equal_to(hash_a.to_string());
+        assert_that!(change.new_lhr_set_hash).is_equal_to(hash_b.to_string());
+        assert_that!(diff.per_as[&1].lhrs_changed).is_equal_to(1);
+        assert_that!(diff.added.is_empty()).is_true();
+        assert_that!(diff.removed.is_empty()).is_true();
+    }
+
+    #[test]
+    fn class_shift_attributed_to_new_as() {
+        // given
+        let hash = LhrSetHash::from_u128(0xbeef);
+        let (old_class, new_class) = (PriorityClass::HighFresh, PriorityClass::LowWeird);
+        let old = gen_snapshot(vec![gen_leaf(TREE_LEFT_NET, 1, old_class, hash)]);
+        let new = gen_snapshot(vec![gen_leaf(TREE_LEFT_NET, 2, new_class, hash)]);
+
+        // when
+        let diff = TreeDiff::new(&old, &new);
+
+        // then
+        let shift = &diff.class_shifts[..];
+        assert_that!(shift.len()).is_equal_to(1);
+        assert_that!(shift[0].asn).is_equal_to(2);
+        assert_that!(shift[0].old_class).is_equal_to(old_class);
+        assert_that!(shift[0].new_class).is_equal_to(new_class);
+        assert_that!(diff.per_as[&1].old_leaves).is_equal_to(1);
+        assert_that!(diff.per_as[&1].class_shifts).is_equal_to(0);
+        assert_that!(diff.per_as[&2].new_leaves).is_equal_to(1);
+        assert_that!(diff.per_as[&2].class_shifts).is_equal_to(1);
+    }
+}
@@ -14,6 +14,10 @@ pub struct Params {
     #[arg(long, default_value = "prefix-crab.probe-request")]
     pub request_exchange_name: String,
 
+    /// Name of the exchange to publish operator commands for the aggregator to
+    #[arg(long, env = "CONTROL_EXCHANGE_NAME", default_value = "prefix-crab.control")]
+    pub control_exchange_name: String,
+
     /// Whether to pretty print JSON in RabbitMQ responses.
     #[arg(long, env = "PRETTY_PRINT")]
     pretty_print: bool,
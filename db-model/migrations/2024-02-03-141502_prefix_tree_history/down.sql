@@ -0,0 +1,2 @@
+DROP TABLE prefix_tree_history;
+DROP TYPE prefix_history_cause;
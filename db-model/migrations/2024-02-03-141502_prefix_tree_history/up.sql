@@ -0,0 +1,30 @@
+CREATE TYPE prefix_history_cause AS ENUM (
+    'baseline', 'seeded', 'removed', 'analysis', 'split', 'blocked', 'merge_redundant', 'reaggregate'
+);
+
+-- append-only, so no foreign keys: entries must outlive the nodes and analyses they refer to
+CREATE TABLE prefix_tree_history
+(
+    id                 bigserial PRIMARY KEY NOT NULL,
+    net                cidr                  NOT NULL,
+    created_at         timestamp             NOT NULL DEFAULT (CURRENT_TIMESTAMP AT TIME ZONE 'UTC'),
+    cause              prefix_history_cause  NOT NULL,
+    split_analysis_id  bigint                NULL DEFAULT NULL,
+    -- old_* are NULL if the node did not exist before, new_* are the last state for 'removed'
+    old_merge_status   prefix_merge_status   NULL,
+    new_merge_status   prefix_merge_status   NOT NULL,
+    old_priority_class prefix_priority_class NULL,
+    new_priority_class prefix_priority_class NOT NULL,
+    old_confidence     smallint              NULL,
+    new_confidence     smallint              NOT NULL
+);
+
+CREATE INDEX prefix_tree_history_gist_idx ON prefix_tree_history USING gist (net inet_ops);
+CREATE INDEX prefix_tree_history_net_created_idx ON prefix_tree_history (net, created_at);
+CREATE INDEX prefix_tree_history_split_analysis_idx ON prefix_tree_history (split_analysis_id)
+    WHERE split_analysis_id IS NOT NULL;
+
+-- Nothing is known about changes before this point, so take the current state as starting point
+INSERT INTO prefix_tree_history (net, cause, new_merge_status, new_priority_class, new_confidence)
+SELECT net, 'baseline', merge_status, priority_class, confidence
+FROM prefix_tree;
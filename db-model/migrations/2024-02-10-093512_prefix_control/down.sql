@@ -0,0 +1,2 @@
+DROP TABLE prefix_control;
+-- removing a value from an enum type is not supported in Postgres.
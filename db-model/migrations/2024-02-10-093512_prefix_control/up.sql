@@ -0,0 +1,15 @@
+-- Operator overrides for subtrees of the prefix tree, see crab-tools prefix-control
+CREATE TABLE prefix_control
+(
+    net          cidr PRIMARY KEY      NOT NULL,
+    created_at   timestamp             NOT NULL DEFAULT (CURRENT_TIMESTAMP AT TIME ZONE 'UTC'),
+    updated_at   timestamp             NOT NULL DEFAULT (CURRENT_TIMESTAMP AT TIME ZONE 'UTC'),
+    pinned_class prefix_priority_class NULL DEFAULT NULL,
+    frozen       boolean               NOT NULL DEFAULT false
+);
+
+CREATE INDEX prefix_control_gist_idx ON prefix_control USING gist (net inet_ops);
+
+SELECT diesel_manage_updated_at('prefix_control');
+
+ALTER TYPE prefix_history_cause ADD VALUE IF NOT EXISTS 'operator';
@@ -0,0 +1 @@
+DROP TABLE as_rate_budget;
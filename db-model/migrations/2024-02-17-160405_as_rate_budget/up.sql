@@ -0,0 +1,14 @@
+-- Learned per-AS rate budget, see aggregator handle_probe::as_rate
+CREATE TABLE as_rate_budget
+(
+    asn                int8      PRIMARY KEY NOT NULL,
+    created_at         timestamp NOT NULL DEFAULT (CURRENT_TIMESTAMP AT TIME ZONE 'UTC'),
+    updated_at         timestamp NOT NULL DEFAULT (CURRENT_TIMESTAMP AT TIME ZONE 'UTC'),
+    -- factor applied to the default prefixes per AS and schedule
+    scale              real      NOT NULL DEFAULT 1.0,
+    -- moving average of the share of probes without response
+    unresponsive_ratio real      NOT NULL DEFAULT 0.0,
+    observation_count  int8      NOT NULL DEFAULT 0
+);
+
+SELECT diesel_manage_updated_at('as_rate_budget');
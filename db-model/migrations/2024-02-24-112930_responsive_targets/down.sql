@@ -0,0 +1 @@
+ALTER TABLE measurement_tree DROP COLUMN responsive_targets;
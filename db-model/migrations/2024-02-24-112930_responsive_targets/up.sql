@@ -0,0 +1,3 @@
+-- A few addresses that answered echo requests, to be re-used as samples
+ALTER TABLE measurement_tree
+    ADD COLUMN responsive_targets jsonb NOT NULL DEFAULT '{"items": []}';
@@ -0,0 +1,8 @@
+-- Known-responsive addresses imported from a hitlist by seed-guard
+CREATE TABLE hitlist
+(
+    -- always a /64
+    net           cidr   PRIMARY KEY NOT NULL,
+    -- lower 64 bits of the addresses in this /64, ascending
+    interface_ids int8[] NOT NULL
+);
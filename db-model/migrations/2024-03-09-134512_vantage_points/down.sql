@@ -0,0 +1 @@
+ALTER TABLE split_analysis DROP COLUMN pending_echo_responses;
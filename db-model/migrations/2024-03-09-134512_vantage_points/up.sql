@@ -0,0 +1,4 @@
+-- Echo responses (one per vantage point) that an analysis still waits for before
+-- requesting follow-ups or deciding on a split
+ALTER TABLE split_analysis
+    ADD COLUMN pending_echo_responses int2 NOT NULL DEFAULT 1;
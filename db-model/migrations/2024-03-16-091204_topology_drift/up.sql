@@ -0,0 +1 @@
+ALTER TYPE prefix_history_cause ADD VALUE IF NOT EXISTS 'drift';
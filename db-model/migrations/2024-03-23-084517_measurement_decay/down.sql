@@ -0,0 +1,5 @@
+DROP INDEX measurement_tree_decayed_at_idx;
+
+ALTER TABLE measurement_tree DROP COLUMN decayed_at;
+
+-- removing a value from an enum type is not supported in Postgres.
@@ -0,0 +1,10 @@
+-- When the counts of a measurement tree were last halved, see aggregator decay timer.
+-- Existing trees start from their last update, since that's when their evidence was gathered.
+ALTER TABLE measurement_tree
+    ADD COLUMN decayed_at timestamp NOT NULL DEFAULT (CURRENT_TIMESTAMP AT TIME ZONE 'UTC');
+
+UPDATE measurement_tree SET decayed_at = updated_at;
+
+CREATE INDEX measurement_tree_decayed_at_idx ON measurement_tree (decayed_at);
+
+ALTER TYPE prefix_history_cause ADD VALUE IF NOT EXISTS 'decay';
@@ -0,0 +1,2 @@
+ALTER TABLE split_analysis DROP COLUMN pending_follow_ups;
+ALTER TABLE split_analysis DROP COLUMN echo_responded_by;
@@ -0,0 +1,8 @@
+-- Vantage points that already responded to the echo request of an analysis, s.t. redelivered
+-- responses are not counted twice
+ALTER TABLE split_analysis
+    ADD COLUMN echo_responded_by text[] NOT NULL DEFAULT '{}';
+
+-- Follow-up targets collected from the echo responses of all vantage points so far
+ALTER TABLE split_analysis
+    ADD COLUMN pending_follow_ups jsonb NOT NULL DEFAULT '{}';
@@ -1,6 +1,12 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashSet};
+use std::net::Ipv6Addr;
+
 use chrono::NaiveDateTime;
-use diesel::prelude::*;
+use diesel::{prelude::*, sql_types::Jsonb, AsExpression, FromSqlRow};
 use ipnet::Ipv6Net;
+use itertools::Itertools;
+use queue_models::VantagePointId;
 use serde::{Deserialize, Serialize};
 
 use crate::{
@@ -8,7 +14,7 @@ use crate::{
     prefix_tree::{PrefixTree, PriorityClass},
 };
 
-use super::HitCount;
+use super::{Confidence, HitCount, LhrAddr};
 
 #[derive(Queryable, Identifiable, Associations, PartialEq, Debug, Clone)]
 #[diesel(table_name = crate::schema::split_analysis)]
@@ -24,15 +30,248 @@ pub struct SplitAnalysis {
     pub completed_at: Option<NaiveDateTime>,
     pub pending_follow_up: Option<String>, // Actually TraceRequestId
     pub result: Option<SplitAnalysisResult>,
+    /// Echo responses that are still awaited, one per vantage point the request was sent to
+    pub pending_echo_responses: i16,
+    /// Vantage points whose echo responses were already handled
+    pub echo_responded_by: Vec<VantagePointId>,
+    /// Follow-ups requested by the echo responses so far, sent once all of them arrived
+    pub pending_follow_ups: PendingFollowUps,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct SplitAnalysisResult {
     // Important: JSONB field, must stay compatible!
     pub class: PriorityClass,
     pub evidence: HitCount,
     pub should_split: Option<bool>, // missing = we don't know
     pub algo_version: i32,
+    /// Missing for results saved before explanations were recorded
+    #[serde(default)]
+    pub explanation: Option<SplitExplanation>,
+}
+
+/// The inputs that a split recommendation was derived from, to allow auditing it later.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SplitExplanation {
+    // Important: JSONB field, must stay compatible!
+    /// The compared subnets, in the order of all per-subnet values below
+    pub subnets: Vec<Ipv6Net>,
+    /// Weirdness is only compared if no LHRs were observed in any subnet
+    pub compared: ComparedItems,
+    pub diff: DiffKind,
+    /// Hits of each LHR, one entry per subnet
+    pub lhr_hits: BTreeMap<LhrAddr, Vec<HitCount>>,
+    /// Share of each LHR in the hits of each subnet in percent, only if ratios were compared.
+    /// Missing for subnets without hits.
+    pub ratio_pcts: BTreeMap<LhrAddr, Vec<Option<HitCount>>>,
+    /// Only if ratios were compared, in which case it also determines the confidence, capped by
+    /// `confidence_thresh`
+    #[serde(default)]
+    pub ratio_test: Option<RatioTest>,
+    /// Evidence needed for a confidence of [super::CONFIDENCE_THRESH], also if rated by `ratio_test`
+    pub confidence_thresh: u32,
+    pub confidence: Confidence,
+}
+
+/// Chi-square test of homogeneity on the hits of the LHRs shared by all subnets (rows) per
+/// subnet (columns), i.e. whether the LHRs serve the same share of each subnet.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct RatioTest {
+    // Important: JSONB field, must stay compatible!
+    pub chi_square: f64,
+    pub degrees_of_freedom: u32,
+    pub p_value: f64,
+    /// Cramér's V, from 0 (same ratios) to 1 (each LHR serves only one subnet)
+    pub effect_size: f64,
+    pub hits: HitCount,
+    /// Hits needed to detect a relevant effect size with the intended power
+    pub required_hits: f64,
+    /// Smallest expected hit count of any cell, the test is only valid if it is large enough.
+    /// Zero for tests stored before this was recorded.
+    #[serde(default)]
+    pub min_expected_hits: f64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ComparedItems {
+    Lhrs,
+    Weirdness,
+}
+
+/// How the items found in the subnets relate to each other
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum DiffKind {
+    AllNone,
+    AllSameSingle,
+    AllSameMultiple,
+    OverlappingOrDisjoint,
+}
+
+impl SplitAnalysis {
+    /// Whether further vantage points are still expected to respond to the echo request
+    pub fn awaits_echo_responses(&self) -> bool {
+        self.pending_echo_responses > 0
+    }
+
+    /// Whether the echo response of this vantage point was already handled, i.e. a response
+    /// was delivered again
+    pub fn has_echo_response_from(&self, vantage_point: &VantagePointId) -> bool {
+        self.echo_responded_by.contains(vantage_point)
+    }
 }
 
 configure_jsonb_serde!(SplitAnalysisResult);
+
+/// Follow-up targets requested by the echo responses of an analysis, per vantage point.
+#[derive(
+    FromSqlRow, AsExpression, Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq,
+)]
+#[diesel(sql_type = Jsonb)]
+pub struct PendingFollowUps {
+    // Important: JSONB field, must stay compatible!
+    #[serde(default)]
+    pub by_vantage_point: BTreeMap<VantagePointId, Vec<FollowUpTargets>>,
+}
+
+/// Targets that are followed up (or skipped) together
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FollowUpTargets {
+    pub targets: Vec<Ipv6Addr>,
+    pub for_responsive: bool,
+}
+
+impl PendingFollowUps {
+    pub fn add(&mut self, vantage_point: &VantagePointId, follow_ups: Vec<FollowUpTargets>) {
+        if follow_ups.is_empty() {
+            return;
+        }
+        self.by_vantage_point
+            .entry(vantage_point.clone())
+            .or_default()
+            .extend(follow_ups);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_vantage_point.is_empty()
+    }
+
+    /// The vantage point that requested the most targets, which should trace all of them.
+    /// Ties are broken by the name of the vantage point.
+    pub fn main_vantage_point(&self) -> Option<&VantagePointId> {
+        self.by_vantage_point
+            .iter()
+            .min_by_key(|(_, follow_ups)| {
+                Reverse(follow_ups.iter().map(|it| it.targets.len()).sum::<usize>())
+            })
+            .map(|(vantage_point, _)| vantage_point)
+    }
+
+    /// Follow-ups of all vantage points, with targets requested by multiple of them only
+    /// included once
+    pub fn merged(&self) -> Vec<FollowUpTargets> {
+        let mut seen = HashSet::new();
+        self.by_vantage_point
+            .values()
+            .flatten()
+            .filter_map(|it| {
+                let targets = it
+                    .targets
+                    .iter()
+                    .filter(|target| seen.insert(**target))
+                    .copied()
+                    .collect_vec();
+                (!targets.is_empty()).then_some(FollowUpTargets {
+                    targets,
+                    for_responsive: it.for_responsive,
+                })
+            })
+            .collect()
+    }
+}
+
+configure_jsonb_serde!(PendingFollowUps);
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn result_without_explanation_stays_readable() {
+        // given
+        let json =
+            r#"{"class":"MediumSameRatio","evidence":42,"should_split":false,"algo_version":120}"#;
+
+        // when
+        let result: SplitAnalysisResult = serde_json::from_str(json).unwrap();
+
+        // then
+        assert_that!(result.evidence).is_equal_to(42);
+        assert_that!(result.explanation).is_equal_to(None);
+    }
+
+    fn follow_up(targets: &[&str]) -> FollowUpTargets {
+        FollowUpTargets {
+            targets: targets.iter().map(|it| addr(it)).collect(),
+            for_responsive: false,
+        }
+    }
+
+    #[test]
+    fn pending_follow_ups_empty_from_db_default() {
+        // given
+        let json = "{}";
+
+        // when
+        let parsed: PendingFollowUps = serde_json::from_str(json).unwrap();
+
+        // then
+        assert_that!(parsed.is_empty()).is_true();
+        assert_that!(parsed.main_vantage_point()).is_equal_to(None);
+    }
+
+    #[test]
+    fn pending_follow_ups_accumulate_across_vantage_points() {
+        // given
+        let mut pending = PendingFollowUps::default();
+        let (east, west) = ("east".to_string(), "west".to_string());
+
+        // when
+        pending.add(&west, vec![follow_up(&["2001:db8::1", "2001:db8::2"])]);
+        pending.add(&east, vec![]);
+        pending.add(
+            &east,
+            vec![follow_up(&["2001:db8::2"]), follow_up(&["2001:db8::3"])],
+        );
+
+        // then
+        assert_that!(pending.by_vantage_point.keys().collect_vec())
+            .contains_exactly_in_order(vec![&east, &west]);
+        assert_that!(pending.merged()).contains_exactly_in_order(vec![
+            follow_up(&["2001:db8::2"]),
+            follow_up(&["2001:db8::3"]),
+            follow_up(&["2001:db8::1"]),
+        ]);
+    }
+
+    #[test]
+    fn pending_follow_ups_main_vantage_point() {
+        // given
+        let mut pending = PendingFollowUps::default();
+        let (east, west, north) = ("east".to_string(), "west".to_string(), "north".to_string());
+        pending.add(&west, vec![follow_up(&["2001:db8::1", "2001:db8::2"])]);
+        pending.add(
+            &east,
+            vec![follow_up(&["2001:db8::2"]), follow_up(&["2001:db8::3"])],
+        );
+        pending.add(&north, vec![follow_up(&["2001:db8::4"])]);
+
+        // when
+        let main = pending.main_vantage_point();
+
+        // then
+        assert_that!(main).is_equal_to(Some(&east));
+    }
+}
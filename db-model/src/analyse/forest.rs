@@ -118,20 +118,18 @@ impl MeasurementForest {
         match net.prefix_len().cmp(&map64::PREFIX_LEN) {
             Greater => bail!("trees with prefixes longer than /64 may not be planted here: {}. best regards, the lorax.", net),
             Equal => {
-                let was_there_before = self.trees64.contains_net(&net);
-                let entry = self.trees64.entry_by_net_or(
-                    &net, ModifiableTree::empty
-                );
-                entry.touched = if was_there_before {
+                if self.trees64.contains_net(&net) {
+                    let entry = self.trees64.entry_by_net_or(
+                        &net, ModifiableTree::empty
+                    );
                     if should_touch {
-                        ModificationType::Updated
-                    } else {
-                        entry.touched
+                        entry.touched = ModificationType::Updated;
                     }
+                    entry.consume_merge(tree, should_touch)?;
                 } else {
-                    touched_no_conflict
-                };
-                entry.consume_merge(tree, should_touch)?;
+                    // not merged into an empty tree, which would reset e.g. the decay time to now
+                    self.trees64.insert(&net, ModifiableTree { tree, touched: touched_no_conflict });
+                }
             }, 
             Less => self.merged_trees.push(ModifiableTree { tree, touched: touched_no_conflict }),
         };
@@ -164,6 +164,11 @@ impl<V> Net64Map<V> {
         self.entry_by_net(net).or_insert_with(|| new_fn(*net))
     }
 
+    /// Replaces any value previously stored for the net
+    pub fn insert(&mut self, net: &Ipv6Net, value: V) {
+        self.per_net.insert(to_key(net), value);
+    }
+
     fn entry_by_addr(&mut self, addr: &Ipv6Addr) -> hash_map::Entry<'_, u64, V> {
         self.per_net.entry(addr_to_key(addr))
     }
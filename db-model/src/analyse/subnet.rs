@@ -1,5 +1,4 @@
 use std::{
-    array::from_fn,
     collections::{HashMap, HashSet},
     hash::Hash,
     ops::Deref,
@@ -7,6 +6,7 @@ use std::{
 
 use anyhow::{Context, Result};
 use ipnet::{IpNet, Ipv6Net};
+use itertools::Itertools;
 use log::warn;
 use prefix_crab::prefix_split::{self, SplitSubnet};
 
@@ -59,14 +59,23 @@ impl From<SplitSubnet> for Subnet {
 
 #[derive(Debug)]
 pub struct Subnets {
-    splits: [Subnet; 2],
+    splits: Vec<Subnet>,
 }
 
 impl Subnets {
-    pub fn new(base_net: Ipv6Net, relevant_measurements: Vec<MeasurementTree>) -> Result<Self> {
-        let split = prefix_split::split(base_net).context("trying to split for split analysis")?;
-        let mut splits: [Subnet; 2] = split.into_subnets().map(From::from);
-        let split_nets: [IpNet; 2] = from_fn(|i| IpNet::V6(splits[i].subnet.network));
+    /// Splits `base_net` by `split_bits` and assigns the measurements to the resulting subnets.
+    pub fn new(
+        base_net: Ipv6Net,
+        split_bits: u8,
+        relevant_measurements: Vec<MeasurementTree>,
+    ) -> Result<Self> {
+        let split = prefix_split::split(base_net, split_bits)
+            .context("trying to split for split analysis")?;
+        let mut splits: Vec<Subnet> = split.into_subnets().into_iter().map(From::from).collect();
+        let split_nets: Vec<IpNet> = splits
+            .iter()
+            .map(|it| IpNet::V6(it.subnet.network))
+            .collect();
         for tree in relevant_measurements {
             let mut unused_tree = Some(tree);
             for (i, candidate_split) in splits.iter_mut().enumerate() {
@@ -78,7 +87,7 @@ impl Subnets {
             }
             if let Some(unused) = unused_tree {
                 warn!(
-                    "Received a tree that didn't fit into either subnet: {:?} - {:?}",
+                    "Received a tree that didn't fit into any subnet: {:?} - {:?}",
                     split_nets, unused,
                 );
             }
@@ -88,7 +97,7 @@ impl Subnets {
 
     pub fn lhr_diff(&self) -> Diff<LhrItem> {
         let mut lookup: HashMap<&LhrAddr, LhrItem> = HashMap::new();
-        let mut addr_sets: [HashSet<&LhrAddr>; 2] = Default::default();
+        let mut addr_sets: Vec<HashSet<&LhrAddr>> = vec![HashSet::new(); self.len()];
         for (i, subnet) in self.iter().enumerate() {
             for (addr, data) in subnet.iter_lhrs() {
                 let entry = lookup.entry(addr).or_default();
@@ -102,7 +111,7 @@ impl Subnets {
 
     pub fn weird_diff(&self) -> Diff<WeirdItem> {
         let mut lookup: HashMap<&WeirdType, WeirdItem> = HashMap::new();
-        let mut type_sets: [HashSet<&WeirdType>; 2] = Default::default();
+        let mut type_sets: Vec<HashSet<&WeirdType>> = vec![HashSet::new(); self.len()];
 
         for (i, subnet) in self.iter().enumerate() {
             for (addr, data) in subnet.iter_weirds() {
@@ -125,31 +134,38 @@ impl Subnets {
 }
 
 impl Deref for Subnets {
-    type Target = [Subnet; 2];
+    type Target = [Subnet];
 
     fn deref(&self) -> &Self::Target {
         &self.splits
     }
 }
 
+/// Comparison of the items found in each subnet. `shared` items were found in all subnets,
+/// `distinct` items only in some of them.
 #[derive(Debug, Eq, PartialEq)]
 pub enum Diff<I> {
-    BothNone,
-    BothSameSingle { shared: I },
-    BothSameMultiple { shared: Vec<I> },
+    AllNone,
+    AllSameSingle { shared: I },
+    AllSameMultiple { shared: Vec<I> },
     OverlappingOrDisjoint { shared: Vec<I>, distinct: Vec<I> },
 }
 
-fn lookup_diff<K, I>(sets: [HashSet<K>; 2], lookup: HashMap<K, I>) -> Diff<I>
+fn lookup_diff<K, I>(sets: Vec<HashSet<K>>, lookup: HashMap<K, I>) -> Diff<I>
 where
-    K: Hash + Eq + PartialEq + std::fmt::Debug,
+    K: Hash + Eq + Ord + std::fmt::Debug,
     I: Clone,
 {
-    let [left_set, right_set] = sets;
-    // If we implement `difference` & `intersection` ourselves in one step, we could skip the
-    // clone() on the key; doesn't seem worth it atm but could improve performance in the future.
-    let distinct: Vec<I> = left_set
-        .symmetric_difference(&right_set)
+    let is_shared = |k: &K| sets.iter().all(|set| set.contains(k));
+    // Keys are visited in subnet order (sorted within each subnet, since sets are unordered),
+    // and only the first occurrence is kept
+    let (shared_keys, distinct_keys): (Vec<&K>, Vec<&K>) = sets
+        .iter()
+        .flat_map(|set| set.iter().sorted())
+        .unique()
+        .partition(|k| is_shared(k));
+    let distinct: Vec<I> = distinct_keys
+        .into_iter()
         .map(|k| {
             lookup
                 .get(k)
@@ -157,8 +173,8 @@ where
                 .clone()
         })
         .collect();
-    let shared: Vec<I> = left_set
-        .intersection(&right_set)
+    let shared: Vec<I> = shared_keys
+        .into_iter()
         .map(|k| {
             lookup
                 .get(k)
@@ -175,7 +191,7 @@ impl<I> Diff<I> {
 
         if shared.is_empty() {
             if distinct.is_empty() {
-                BothNone
+                AllNone
             } else {
                 OverlappingOrDisjoint {
                     shared: vec![],
@@ -184,14 +200,14 @@ impl<I> Diff<I> {
             }
         } else if distinct.is_empty() {
             if shared.len() == 1 {
-                BothSameSingle {
+                AllSameSingle {
                     shared: shared
                         .into_iter()
                         .next()
                         .expect("vec with length one to have item"),
                 }
             } else {
-                BothSameMultiple { shared }
+                AllSameMultiple { shared }
             }
         } else {
             OverlappingOrDisjoint { shared, distinct }
@@ -216,7 +232,7 @@ mod tests {
         let relevant_measurements = vec![];
 
         // when
-        let subnets = Subnets::new(base_net, relevant_measurements).unwrap();
+        let subnets = Subnets::new(base_net, 1, relevant_measurements).unwrap();
 
         // then
         assert_that!(subnets.iter()).has_length(2);
@@ -229,10 +245,12 @@ mod tests {
         let relevant_measurements = gen_measurements_complex();
 
         // when
-        let subnets = Subnets::new(base_net, relevant_measurements).unwrap();
+        let subnets = Subnets::new(base_net, 1, relevant_measurements).unwrap();
 
         // then
-        let [left, right] = subnets.deref();
+        let [left, right] = subnets.deref() else {
+            panic!("expected exactly two subnets");
+        };
         then_contains_lhr_101(&left, 14);
         then_contains_lhr_beef(&right, 24);
         then_contains_lhr_101(&right, 9);
@@ -246,6 +264,7 @@ mod tests {
             LhrItem {
                 hit_count,
                 sources: vec![LhrSource::UnreachAddr].into_iter().collect(),
+                ..Default::default()
             },
         );
     }
@@ -256,6 +275,7 @@ mod tests {
             LhrItem {
                 hit_count,
                 sources: vec![LhrSource::Trace].into_iter().collect(),
+                ..Default::default()
             },
         );
     }
@@ -275,10 +295,12 @@ mod tests {
         ];
 
         // when
-        let subnets = Subnets::new(base_net, relevant_measurements).unwrap();
+        let subnets = Subnets::new(base_net, 1, relevant_measurements).unwrap();
 
         // then
-        let [left, right] = subnets.deref();
+        let [left, right] = subnets.deref() else {
+            panic!("expected exactly two subnets");
+        };
         then_contains_lhr_101(&left, 2);
         then_contains_lhr_beef(&right, 6);
         then_lhr_count(&left, 1);
@@ -286,7 +308,7 @@ mod tests {
     }
 
     #[test]
-    fn diff_from_both_none() {
+    fn diff_from_all_none() {
         // given
         let shared: Vec<i32> = vec![];
         let distinct: Vec<i32> = vec![];
@@ -295,7 +317,7 @@ mod tests {
         let diff = Diff::from(shared, distinct);
 
         // then
-        assert_that!(diff).is_equal_to(Diff::BothNone);
+        assert_that!(diff).is_equal_to(Diff::AllNone);
     }
 
     #[test]
@@ -334,7 +356,7 @@ mod tests {
         let diff = Diff::from(shared.clone(), distinct.clone());
 
         // then
-        assert_that!(diff).is_equal_to(Diff::BothSameSingle { shared: 4 });
+        assert_that!(diff).is_equal_to(Diff::AllSameSingle { shared: 4 });
     }
 
     #[test]
@@ -347,7 +369,7 @@ mod tests {
         let diff = Diff::from(shared.clone(), distinct.clone());
 
         // then
-        assert_that!(diff).is_equal_to(Diff::BothSameMultiple { shared });
+        assert_that!(diff).is_equal_to(Diff::AllSameMultiple { shared });
     }
 
     #[test]
@@ -361,7 +383,7 @@ mod tests {
         lookup.insert(12, "right only");
 
         // when
-        let diff = lookup_diff([left_set, right_set], lookup);
+        let diff = lookup_diff(vec![left_set, right_set], lookup);
 
         // then
         assert_that!(diff).is_equal_to(Diff::OverlappingOrDisjoint {
@@ -1,5 +1,5 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeSet, HashMap, HashSet},
     net::Ipv6Addr,
     ops::IndexMut,
 };
@@ -8,7 +8,7 @@ use anyhow::{bail, Result};
 use chrono::{NaiveDateTime, Utc};
 use diesel::{prelude::*, sql_types::Jsonb, AsExpression, FromSqlRow};
 use ipnet::{IpNet, Ipv6Net};
-use queue_models::probe_response::DestUnreachKind;
+use queue_models::{probe_response::DestUnreachKind, VantagePointId};
 use serde::{Deserialize, Serialize};
 
 use crate::{
@@ -31,6 +31,9 @@ pub struct MeasurementTree {
     pub unresponsive_count: i32,
     pub last_hop_routers: LhrData,
     pub weirdness: WeirdData,
+    pub responsive_targets: ResponsiveTargets,
+    /// When the counts were last halved by [Self::decay], or when the tree was created
+    pub decayed_at: NaiveDateTime,
 }
 
 impl MeasurementTree {
@@ -43,6 +46,8 @@ impl MeasurementTree {
             unresponsive_count: 0,
             last_hop_routers: LhrData::default(),
             weirdness: WeirdData::default(),
+            responsive_targets: ResponsiveTargets::default(),
+            decayed_at: Utc::now().naive_utc(),
         }
     }
 
@@ -65,6 +70,9 @@ impl MeasurementTree {
             );
         }
         self.updated_at = Utc::now().naive_utc();
+        // the bulk of the evidence is usually the fresh one that is merged into older trees,
+        // which shouldn't lose half its weight right away
+        self.decayed_at = self.decayed_at.max(other.decayed_at);
         self.responsive_count = self.responsive_count.saturating_add(other.responsive_count);
         self.unresponsive_count = self
             .unresponsive_count
@@ -72,15 +80,52 @@ impl MeasurementTree {
         self.last_hop_routers
             .consume_merge(other.last_hop_routers.clone());
         self.weirdness.consume_merge(other.weirdness.clone());
+        self.responsive_targets
+            .consume_merge(other.responsive_targets.clone());
         Ok(())
     }
 
+    /// Halves all counts (rounding down), such that older evidence weighs less than fresh
+    /// evidence. LHRs and weirdness without remaining hits are removed.
+    pub fn decay(&mut self) {
+        self.decayed_at = Utc::now().naive_utc();
+        self.responsive_count /= 2;
+        self.unresponsive_count /= 2;
+        self.last_hop_routers.items.retain(|_, item| {
+            item.decay();
+            item.hit_count > 0
+        });
+        self.weirdness.items.retain(|_, item| {
+            item.hit_count /= 2;
+            item.hit_count > 0
+        });
+    }
+
     pub fn add_lhr_no_sum(&mut self, addr: Ipv6Addr, sources: HashSet<LhrSource>, hits: HitCount) {
         self.last_hop_routers.items.insert(
             addr,
             LhrItem {
                 sources,
                 hit_count: hits,
+                vantage_points: HashMap::new(),
+            },
+        );
+    }
+
+    /// Like [Self::add_lhr_no_sum], but attributes all hits to a vantage point.
+    pub fn add_lhr_from_vantage(
+        &mut self,
+        addr: Ipv6Addr,
+        sources: HashSet<LhrSource>,
+        hits: HitCount,
+        vantage_point: &VantagePointId,
+    ) {
+        self.last_hop_routers.items.insert(
+            addr,
+            LhrItem {
+                sources,
+                hit_count: hits,
+                vantage_points: HashMap::from([(vantage_point.clone(), hits)]),
             },
         );
     }
@@ -159,12 +204,69 @@ pub struct LhrItem {
     // i.e. add only optional fields or provide defaults!
     pub sources: HashSet<LhrSource>,
     pub hit_count: HitCount,
+    /// Share of [Self::hit_count] observed from each vantage point. Hits from before
+    /// vantage points were recorded are not attributed to any of them.
+    #[serde(default)]
+    pub vantage_points: HashMap<VantagePointId, HitCount>,
 }
 
 impl LhrItem {
     pub fn consume_merge(&mut self, other: Self) {
         self.sources.extend(other.sources);
         self.hit_count = self.hit_count.saturating_add(other.hit_count);
+        for (vantage_point, hits) in other.vantage_points {
+            let entry = self.vantage_points.entry(vantage_point).or_default();
+            *entry = entry.saturating_add(hits);
+        }
+    }
+
+    /// Halves the hits, rounding down. The hits of the vantage points are derived from the
+    /// halved total (keeping the attributed share), such that rounding each of them down
+    /// doesn't leave hits unattributed.
+    fn decay(&mut self) {
+        let old_hits = self.hit_count;
+        self.hit_count /= 2;
+        let attributed: HitCount = self.vantage_points.values().sum();
+        if attributed <= 0 || old_hits <= 0 {
+            self.vantage_points.clear();
+            return;
+        }
+        let attributed_after =
+            attributed.min(old_hits) as i64 * self.hit_count as i64 / old_hits as i64;
+        let attributed = attributed as i64;
+
+        // Largest remainder method, ties broken by the name of the vantage point
+        let mut scaled: Vec<_> = self
+            .vantage_points
+            .iter()
+            .map(|(vantage_point, hits)| {
+                let exact = *hits as i64 * attributed_after;
+                (
+                    vantage_point.clone(),
+                    exact / attributed,
+                    exact % attributed,
+                )
+            })
+            .collect();
+        let floored: i64 = scaled.iter().map(|(_, hits, _)| hits).sum();
+        let missing = (attributed_after - floored) as usize;
+        scaled.sort_by(|(a_name, _, a_rem), (b_name, _, b_rem)| {
+            b_rem.cmp(a_rem).then_with(|| a_name.cmp(b_name))
+        });
+        for (_, hits, _) in scaled.iter_mut().take(missing) {
+            *hits += 1;
+        }
+        self.vantage_points = scaled
+            .into_iter()
+            .filter(|(_, hits, _)| *hits > 0)
+            .map(|(vantage_point, hits, _)| (vantage_point, hits as HitCount))
+            .collect();
+    }
+
+    /// Hits that are not attributed to any vantage point
+    pub fn unattributed_hits(&self) -> HitCount {
+        let attributed: HitCount = self.vantage_points.values().sum();
+        self.hit_count.saturating_sub(attributed).max(0)
     }
 }
 
@@ -178,7 +280,7 @@ pub struct WeirdData {
     pub items: HashMap<WeirdType, WeirdItem>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum WeirdType {
     DestUnreachOther,
     DestUnreachRejectRoute,
@@ -213,6 +315,34 @@ impl WeirdItem {
 
 crate::persist::configure_jsonb_serde!(WeirdData);
 
+/// Addresses in a [MeasurementTree] that answered echo requests. Only a few are kept, as
+/// these are just re-used as samples for future measurements.
+#[derive(FromSqlRow, AsExpression, Serialize, Deserialize, Debug, Default, Clone)]
+#[diesel(sql_type = Jsonb)]
+pub struct ResponsiveTargets {
+    // IMPORTANT: Type must stay backwards-compatible with previously-written JSON,
+    // i.e. add only optional fields or provide defaults!
+    pub items: BTreeSet<Ipv6Addr>,
+}
+
+impl ResponsiveTargets {
+    pub const MAX_ITEMS: usize = 16;
+
+    /// Adds addresses up to [Self::MAX_ITEMS], keeping the lowest ones if there are more.
+    pub fn extend(&mut self, addrs: impl IntoIterator<Item = Ipv6Addr>) {
+        self.items.extend(addrs);
+        while self.items.len() > Self::MAX_ITEMS {
+            self.items.pop_last();
+        }
+    }
+
+    fn consume_merge(&mut self, other: Self) {
+        self.extend(other.items);
+    }
+}
+
+crate::persist::configure_jsonb_serde!(ResponsiveTargets);
+
 impl IndexMut<&Ipv6Net> for Net64Map<MeasurementTree> {
     fn index_mut(&mut self, idx: &Ipv6Net) -> &mut Self::Output {
         self.entry_by_net_or(idx, MeasurementTree::empty)
@@ -227,7 +357,9 @@ impl IndexMut<&Ipv6Addr> for Net64Map<MeasurementTree> {
 
 #[cfg(test)]
 mod tests {
-    use assertor::{assert_that, EqualityAssertion, MapAssertion, ResultAssertion};
+    use assertor::{
+        assert_that, BooleanAssertion, EqualityAssertion, MapAssertion, ResultAssertion,
+    };
     use ipnet::Ipv6Net;
     use std::{net::Ipv6Addr, str::FromStr};
 
@@ -297,6 +429,108 @@ mod tests {
         assert_that!(parent_tree.last_hop_routers.items).has_length(1);
     }
 
+    #[test]
+    fn merge_lhr_vantage_points() {
+        // given
+        let (mut parent_tree, mut sub_tree) = given_trees();
+        let sources: HashSet<LhrSource> = [LhrSource::Trace].into();
+        let (vienna, berlin) = ("vienna".to_string(), "berlin".to_string());
+
+        parent_tree.add_lhr_no_sum(given_some_addr(), sources.clone(), 3);
+        sub_tree.add_lhr_from_vantage(given_some_addr(), sources.clone(), 4, &vienna);
+        let mut other_sub_tree = sub_tree.clone();
+        other_sub_tree.add_lhr_from_vantage(given_some_addr(), sources, 2, &berlin);
+
+        // when
+        parent_tree.merge(&sub_tree).unwrap();
+        parent_tree.merge(&other_sub_tree).unwrap();
+
+        // then
+        let item = &parent_tree.last_hop_routers.items[&given_some_addr()];
+        assert_that!(item.hit_count).is_equal_to(9);
+        assert_that!(item.vantage_points).contains_entry(vienna, 4);
+        assert_that!(item.vantage_points).contains_entry(berlin, 2);
+        assert_that!(item.unattributed_hits()).is_equal_to(3);
+    }
+
+    #[test]
+    fn decay_halves_counts() {
+        // given
+        let (mut tree, _) = given_trees();
+        let vienna = "vienna".to_string();
+        tree.responsive_count = 9;
+        tree.unresponsive_count = 4;
+        tree.add_lhr_from_vantage(given_some_addr(), [LhrSource::Trace].into(), 5, &vienna);
+        tree.add_lhr_no_sum(given_another_addr(), [LhrSource::Trace].into(), 1);
+        tree.add_weird_no_sum(WeirdType::TtlExceededForEcho, 1);
+
+        // when
+        tree.decay();
+
+        // then
+        assert_that!(tree.responsive_count).is_equal_to(4);
+        assert_that!(tree.unresponsive_count).is_equal_to(2);
+        assert_that!(tree.last_hop_routers.items).has_length(1);
+        let item = &tree.last_hop_routers.items[&given_some_addr()];
+        assert_that!(item.hit_count).is_equal_to(2);
+        assert_that!(item.vantage_points).contains_entry(vienna, 2);
+        assert_that!(tree.weirdness.items).has_length(0);
+    }
+
+    #[test]
+    fn decay_attributes_all_halved_hits() {
+        // given
+        let (mut tree, _) = given_trees();
+        let (vienna, berlin) = ("vienna".to_string(), "berlin".to_string());
+        let mut lhr = gen_lhr(2, &[LhrSource::Trace]);
+        lhr.vantage_points = HashMap::from([(vienna, 1), (berlin.clone(), 1)]);
+        tree.last_hop_routers.items.insert(given_some_addr(), lhr);
+
+        // when
+        tree.decay();
+
+        // then
+        let item = &tree.last_hop_routers.items[&given_some_addr()];
+        assert_that!(item.hit_count).is_equal_to(1);
+        assert_that!(item.vantage_points).contains_entry(berlin, 1);
+        assert_that!(item.vantage_points).has_length(1);
+        assert_that!(item.unattributed_hits()).is_equal_to(0);
+    }
+
+    #[test]
+    fn decay_keeps_unattributed_share() {
+        // given
+        let (mut tree, _) = given_trees();
+        let vienna = "vienna".to_string();
+        let mut lhr = gen_lhr(9, &[LhrSource::Trace]);
+        lhr.vantage_points = HashMap::from([(vienna.clone(), 5)]);
+        tree.last_hop_routers.items.insert(given_some_addr(), lhr);
+
+        // when
+        tree.decay();
+
+        // then
+        let item = &tree.last_hop_routers.items[&given_some_addr()];
+        assert_that!(item.hit_count).is_equal_to(4);
+        assert_that!(item.vantage_points).contains_entry(vienna, 2);
+        assert_that!(item.unattributed_hits()).is_equal_to(2);
+    }
+
+    #[test]
+    fn merge_keeps_newer_decayed_at() {
+        // given
+        let (mut parent_tree, mut sub_tree) = given_trees();
+        let fresh = parent_tree.decayed_at;
+        parent_tree.decayed_at = fresh - chrono::Duration::days(90);
+        sub_tree.decayed_at = fresh;
+
+        // when
+        parent_tree.merge(&sub_tree).unwrap();
+
+        // then
+        assert_that!(parent_tree.decayed_at).is_equal_to(fresh);
+    }
+
     fn gen_lhr(hit_count: HitCount, sources: &[LhrSource]) -> LhrItem {
         let mut item = LhrItem::default();
         item.hit_count = hit_count;
@@ -328,6 +562,26 @@ mod tests {
         assert_that!(parent_tree.weirdness.items).has_length(1);
     }
 
+    #[test]
+    fn merge_responsive_targets_capped() {
+        // given
+        let (mut parent_tree, mut sub_tree) = given_trees();
+
+        parent_tree.responsive_targets.extend([given_some_addr()]);
+        let base = u128::from(given_some_addr()) & !0xffff;
+        sub_tree
+            .responsive_targets
+            .extend((0..ResponsiveTargets::MAX_ITEMS as u128).map(|it| Ipv6Addr::from(base | it)));
+
+        // when
+        parent_tree.merge(&sub_tree).unwrap();
+
+        // then
+        let items = &parent_tree.responsive_targets.items;
+        assert_that!(items.len()).is_equal_to(ResponsiveTargets::MAX_ITEMS);
+        assert_that!(items.contains(&given_some_addr())).is_false();
+    }
+
     #[test]
     fn no_merge_unrelated_addrs() {
         // given
@@ -0,0 +1,66 @@
+use std::net::Ipv6Addr;
+
+use ipnet::Ipv6Net;
+use itertools::Itertools;
+
+/// Rows of the `hitlist` table are /64s, storing only the interface identifiers
+/// (lower 64 bits) of the addresses to keep the table compact.
+pub const HITLIST_PREFIX_LEN: u8 = 64;
+
+/// Groups addresses by their /64, in the representation of the `hitlist` table.
+/// Addresses must be sorted ascending for each /64 to be returned only once.
+pub fn group_by_slash64<'a>(
+    addrs: impl IntoIterator<Item = &'a Ipv6Addr>,
+) -> Vec<(Ipv6Net, Vec<i64>)> {
+    addrs
+        .into_iter()
+        .group_by(|it| u128::from(**it) >> 64)
+        .into_iter()
+        .map(|(upper, group)| {
+            let net = Ipv6Net::new(Ipv6Addr::from(upper << 64), HITLIST_PREFIX_LEN)
+                .expect("hitlist prefix length to be valid");
+            let interface_ids = group.map(|it| u128::from(*it) as u64 as i64).collect();
+            (net, interface_ids)
+        })
+        .collect()
+}
+
+/// Inverse of [group_by_slash64] for a single row.
+pub fn to_addrs(net: &Ipv6Net, interface_ids: &[i64]) -> Vec<Ipv6Addr> {
+    let upper = u128::from(net.network());
+    interface_ids
+        .iter()
+        .map(|it| Ipv6Addr::from(upper | (*it as u64 as u128)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn group_and_back() {
+        // given
+        let addrs = vec![
+            addr("2001:db8::1"),
+            addr("2001:db8::ffff:ffff:ffff:ffff"),
+            addr("2001:db8:0:1::53"),
+        ];
+
+        // when
+        let grouped = group_by_slash64(&addrs);
+
+        // then
+        assert_that!(grouped).has_length(2);
+        let (first_net, first_ids) = &grouped[0];
+        assert_that!(*first_net).is_equal_to(net("2001:db8::/64"));
+        assert_that!(to_addrs(first_net, first_ids))
+            .contains_exactly_in_order(vec![addrs[0], addrs[1]]);
+        let (second_net, second_ids) = &grouped[1];
+        assert_that!(*second_net).is_equal_to(net("2001:db8:0:1::/64"));
+        assert_that!(to_addrs(second_net, second_ids)).contains_exactly(vec![addrs[2]]);
+    }
+}
@@ -1,4 +1,5 @@
 pub mod analyse;
+pub mod hitlist;
 pub mod persist;
 pub mod prefix_tree;
 pub mod schema;
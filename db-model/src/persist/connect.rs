@@ -40,6 +40,12 @@ pub fn connect(app_name: &str) -> Result<PgConnection> {
 }
 
 pub fn connect_manual(app_name: &str, params: &Params) -> Result<PgConnection> {
+    let url = connection_url(app_name, params)?;
+    PgConnection::establish(url.as_str()).with_context(|| "while connecting to Postgres")
+}
+
+/// Connection URL including the application name, e.g. for use with a connection pool
+pub fn connection_url(app_name: &str, params: &Params) -> Result<Url> {
     let mut url = Url::parse(&params.database_url)?;
     url.query_pairs_mut()
         .append_pair("application_name", app_name);
@@ -48,6 +54,5 @@ pub fn connect_manual(app_name: &str, params: &Params) -> Result<PgConnection> {
     // The subtle difference is that spaces are encoded as + in the latter and %20 in the former
     let query_percent_encoded = url.query().unwrap_or("").replace('+', "%20");
     url.set_query(Some(&query_percent_encoded));
-
-    PgConnection::establish(url.as_str()).with_context(|| "while connecting to Postgres")
+    Ok(url)
 }
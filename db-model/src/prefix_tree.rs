@@ -3,3 +3,6 @@ pub use context::{Context, ContextOps};
 
 pub mod model;
 pub mod context;
+pub mod history;
+pub mod lock;
+pub mod control;
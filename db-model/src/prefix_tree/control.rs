@@ -0,0 +1,127 @@
+use anyhow::{Context, Result};
+use chrono::NaiveDateTime;
+use diesel::{dsl::exists, prelude::*};
+use ipnet::Ipv6Net;
+
+use crate::persist::dsl::{masklen, CidrMethods};
+use crate::persist::DieselErrorFixCause;
+use crate::prefix_tree::PriorityClass;
+
+/// Operator overrides for the subtree below (and including) a net. Overrides of more specific
+/// nets take precedence.
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = crate::schema::prefix_control)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(primary_key(net))]
+pub struct PrefixControl {
+    #[diesel(deserialize_as = crate::persist::Ipv6NetLoader)]
+    pub net: Ipv6Net,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+
+    /// Priority class to use instead of the one recommended by split analyses
+    pub pinned_class: Option<PriorityClass>,
+    /// Whether no analyses should be scheduled in this subtree
+    pub frozen: bool,
+}
+
+/// Class that nodes at the given net are pinned to, if any.
+pub fn pinned_class(conn: &mut PgConnection, target_net: &Ipv6Net) -> Result<Option<PriorityClass>> {
+    use crate::schema::prefix_control::dsl::*;
+
+    let found: Option<Option<PriorityClass>> = prefix_control
+        .filter(net.supernet_or_eq6(target_net))
+        .filter(pinned_class.is_not_null())
+        .order_by(masklen(net).desc())
+        .select(pinned_class)
+        .first(conn)
+        .optional()
+        .fix_cause()
+        .with_context(|| format!("loading pinned class for {}", target_net))?;
+    Ok(found.flatten())
+}
+
+/// Class that is pinned at exactly this net, ignoring pins of supernets (unlike [pinned_class]).
+pub fn pinned_at(conn: &mut PgConnection, target_net: &Ipv6Net) -> Result<Option<PriorityClass>> {
+    use crate::schema::prefix_control::dsl::*;
+
+    let found: Option<Option<PriorityClass>> = prefix_control
+        .filter(net.eq6(target_net))
+        .select(pinned_class)
+        .first(conn)
+        .optional()
+        .fix_cause()
+        .with_context(|| format!("loading pin of {}", target_net))?;
+    Ok(found.flatten())
+}
+
+/// All controls that pin a class, anywhere in the tree.
+pub fn load_pins(conn: &mut PgConnection) -> Result<Vec<PrefixControl>> {
+    use crate::schema::prefix_control::dsl::*;
+
+    prefix_control
+        .filter(pinned_class.is_not_null())
+        .select(PrefixControl::as_select())
+        .load(conn)
+        .fix_cause()
+        .context("loading pins")
+}
+
+/// Whether the net is in a subtree that was frozen, i.e. no analyses should progress in it.
+pub fn is_frozen(conn: &mut PgConnection, target_net: &Ipv6Net) -> Result<bool> {
+    use crate::schema::prefix_control::dsl::*;
+
+    diesel::select(exists(
+        prefix_control
+            .filter(net.supernet_or_eq6(target_net))
+            .filter(frozen.eq(true)),
+    ))
+    .get_result(conn)
+    .fix_cause()
+    .with_context(|| format!("checking whether {} is frozen", target_net))
+}
+
+/// Pins the subtree to a class, or removes the pin if `None`.
+pub fn set_pinned(
+    conn: &mut PgConnection,
+    target_net: &Ipv6Net,
+    class: Option<PriorityClass>,
+) -> Result<()> {
+    use crate::schema::prefix_control::dsl::*;
+
+    diesel::insert_into(prefix_control)
+        .values((net.eq6(target_net), pinned_class.eq(class)))
+        .on_conflict(net)
+        .do_update()
+        .set(pinned_class.eq(class))
+        .execute(conn)
+        .fix_cause()
+        .with_context(|| format!("setting pinned class of {}", target_net))?;
+    delete_if_unused(conn, target_net)
+}
+
+pub fn set_frozen(conn: &mut PgConnection, target_net: &Ipv6Net, is_frozen: bool) -> Result<()> {
+    use crate::schema::prefix_control::dsl::*;
+
+    diesel::insert_into(prefix_control)
+        .values((net.eq6(target_net), frozen.eq(is_frozen)))
+        .on_conflict(net)
+        .do_update()
+        .set(frozen.eq(is_frozen))
+        .execute(conn)
+        .fix_cause()
+        .with_context(|| format!("setting frozen state of {}", target_net))?;
+    delete_if_unused(conn, target_net)
+}
+
+fn delete_if_unused(conn: &mut PgConnection, target_net: &Ipv6Net) -> Result<()> {
+    use crate::schema::prefix_control::dsl::*;
+
+    diesel::delete(prefix_control)
+        .filter(net.eq6(target_net))
+        .filter(pinned_class.is_null())
+        .filter(frozen.eq(false))
+        .execute(conn)
+        .fix_cause()?;
+    Ok(())
+}
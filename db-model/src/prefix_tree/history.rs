@@ -0,0 +1,350 @@
+use anyhow::{Context, Result};
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+use ipnet::{IpNet, Ipv6Net};
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::analyse::Confidence;
+use crate::persist::dsl::CidrMethods;
+use crate::persist::DieselErrorFixCause;
+use crate::prefix_tree::{MergeStatus, PrefixTree, PriorityClass};
+
+/// Why the state of a node changed, i.e. which operation wrote a history entry.
+#[derive(diesel_derive_enum::DbEnum, Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[ExistingTypePath = "crate::sql_types::PrefixHistoryCause"]
+pub enum HistoryCause {
+    // Important: Used in the database, do not change incompatibly!
+    /// State at the time history was introduced; nothing is known about earlier changes.
+    Baseline,
+    /// Root node created because its prefix was announced.
+    Seeded,
+    /// Root node (or any node below it) deleted because its prefix is no longer announced.
+    /// The new state is the last state of the node.
+    Removed,
+    /// A split analysis re-rated the node (priority class & confidence).
+    Analysis,
+    /// The node was split, or was created (or revived) as child of a split.
+    Split,
+    /// The node was found to be blocked when trying to split it.
+    Blocked,
+    /// The node was merged with its redundant sibling, or is the parent they were merged into.
+    MergeRedundant,
+    /// Same as [HistoryCause::MergeRedundant], but caused by the periodic re-aggregation pass.
+    Reaggregate,
+    /// Changed by an operator via the control channel, see [crate::prefix_tree::control].
+    Operator,
+    /// Re-probing an established node found mostly last-hop routers its measurements didn't know.
+    /// The measurements were discarded and the confidence reset, so it is analysed again.
+    Drift,
+    /// The measurements of the node were decayed, lowering its confidence.
+    Decay,
+}
+
+/// The part of the state of a node that is tracked in the history.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub struct NodeState {
+    pub merge_status: MergeStatus,
+    pub priority_class: PriorityClass,
+    pub confidence: Confidence,
+}
+
+impl From<&PrefixTree> for NodeState {
+    fn from(value: &PrefixTree) -> Self {
+        Self {
+            merge_status: value.merge_status,
+            priority_class: value.priority_class,
+            confidence: value.confidence,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StateChange {
+    pub net: Ipv6Net,
+    /// `None` if the node did not exist before
+    pub old: Option<NodeState>,
+    pub new: NodeState,
+}
+
+impl StateChange {
+    pub fn of(old: &PrefixTree, new: NodeState) -> Self {
+        Self {
+            net: old.net,
+            old: Some(old.into()),
+            new,
+        }
+    }
+
+    pub fn created(net: Ipv6Net, new: NodeState) -> Self {
+        Self {
+            net,
+            old: None,
+            new,
+        }
+    }
+
+    fn is_noop(&self) -> bool {
+        self.old == Some(self.new)
+    }
+}
+
+/// Changes from the `before` to the `after` state of the same nodes, e.g. as loaded with
+/// [load_for_change]. Nodes missing in `before` are treated as created.
+pub fn changes_between(before: &[PrefixTree], after: &[PrefixTree]) -> Vec<StateChange> {
+    after
+        .iter()
+        .map(|new| match before.iter().find(|old| old.net == new.net) {
+            Some(old) => StateChange::of(old, new.into()),
+            None => StateChange::created(new.net, new.into()),
+        })
+        .collect_vec()
+}
+
+/// Appends the given changes to the history, skipping those that don't change anything.
+/// Should be called in the same transaction as the change itself.
+pub fn record(
+    conn: &mut PgConnection,
+    reason: HistoryCause,
+    analysis_id: Option<i64>,
+    changes: &[StateChange],
+) -> Result<usize> {
+    use crate::schema::prefix_tree_history::dsl::*;
+
+    let tuples = changes
+        .iter()
+        // removals don't change the state itself
+        .filter(|it| !it.is_noop() || reason == HistoryCause::Removed)
+        .map(|it| {
+            (
+                net.eq6(&it.net),
+                cause.eq(reason),
+                split_analysis_id.eq(analysis_id),
+                old_merge_status.eq(it.old.map(|old| old.merge_status)),
+                new_merge_status.eq(it.new.merge_status),
+                old_priority_class.eq(it.old.map(|old| old.priority_class)),
+                new_priority_class.eq(it.new.priority_class),
+                old_confidence.eq(it.old.map(|old| old.confidence as i16)),
+                new_confidence.eq(it.new.confidence as i16),
+            )
+        })
+        .collect_vec();
+    if tuples.is_empty() {
+        return Ok(0);
+    }
+
+    diesel::insert_into(prefix_tree_history)
+        .values(tuples)
+        .execute(conn)
+        .fix_cause()
+        .with_context(|| format!("recording {:?} in prefix tree history", reason))
+}
+
+/// Loads the current state of the given nodes (if they exist) to record changes against,
+/// locking them until the end of the transaction.
+pub fn load_for_change(conn: &mut PgConnection, nets: &[Ipv6Net]) -> Result<Vec<PrefixTree>> {
+    use crate::schema::prefix_tree::dsl::*;
+
+    prefix_tree
+        .filter(net.eq_any(nets.iter().map(|it| IpNet::V6(*it))))
+        .select(PrefixTree::as_select())
+        .for_update()
+        .load(conn)
+        .fix_cause()
+        .context("loading nodes to record history")
+}
+
+#[derive(Queryable, Selectable, Debug, Clone)]
+#[diesel(table_name = crate::schema::prefix_tree_history)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct HistoryEntry {
+    pub id: i64,
+    #[diesel(deserialize_as = crate::persist::Ipv6NetLoader)]
+    pub net: Ipv6Net,
+    pub created_at: NaiveDateTime,
+    pub cause: HistoryCause,
+    pub split_analysis_id: Option<i64>,
+    pub old_merge_status: Option<MergeStatus>,
+    pub new_merge_status: MergeStatus,
+    pub old_priority_class: Option<PriorityClass>,
+    pub new_priority_class: PriorityClass,
+    pub old_confidence: Option<i16>,
+    pub new_confidence: i16,
+}
+
+impl HistoryEntry {
+    pub fn old_state(&self) -> Option<NodeState> {
+        Some(NodeState {
+            merge_status: self.old_merge_status?,
+            priority_class: self.old_priority_class?,
+            confidence: to_confidence(self.old_confidence?),
+        })
+    }
+
+    pub fn new_state(&self) -> NodeState {
+        NodeState {
+            merge_status: self.new_merge_status,
+            priority_class: self.new_priority_class,
+            confidence: to_confidence(self.new_confidence),
+        }
+    }
+}
+
+fn to_confidence(raw: i16) -> Confidence {
+    raw.clamp(0, Confidence::MAX as i16) as Confidence
+}
+
+/// The `limit` most recent changes of a single node, oldest first.
+pub fn of_net(
+    conn: &mut PgConnection,
+    target_net: &Ipv6Net,
+    limit: i64,
+) -> Result<Vec<HistoryEntry>> {
+    use crate::schema::prefix_tree_history::dsl::*;
+
+    let mut entries = prefix_tree_history
+        .filter(net.eq6(target_net))
+        .order_by((created_at.desc(), id.desc()))
+        .limit(limit)
+        .select(HistoryEntry::as_select())
+        .load(conn)
+        .fix_cause()
+        .with_context(|| format!("loading history of {}", target_net))?;
+    entries.reverse();
+    Ok(entries)
+}
+
+/// Reconstructs the tree within `root` (including itself) as it was at the given time, by
+/// returning the latest entry of each node up to then, ordered by net. The state of each node
+/// at that time is [HistoryEntry::new_state]. Like in the tree itself, nodes that were split
+/// or merged up are included. The tree cannot be reconstructed before the time the history was
+/// introduced, since the [HistoryCause::Baseline] entries are dated then.
+/// Nodes that were removed by then are not included.
+pub fn as_of(
+    conn: &mut PgConnection,
+    root: &Ipv6Net,
+    at: NaiveDateTime,
+) -> Result<Vec<HistoryEntry>> {
+    use crate::schema::prefix_tree_history::dsl::*;
+
+    prefix_tree_history
+        .filter(net.subnet_or_eq6(root))
+        .filter(created_at.le(at))
+        .distinct_on(net)
+        .order_by((net.asc(), created_at.desc(), id.desc()))
+        .select(HistoryEntry::as_select())
+        .load(conn)
+        .fix_cause()
+        .with_context(|| format!("reconstructing tree of {} as of {}", root, at))
+        .map(|entries: Vec<HistoryEntry>| {
+            entries
+                .into_iter()
+                .filter(|it| it.cause != HistoryCause::Removed)
+                .collect_vec()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+
+    use crate::test_utils::*;
+
+    use super::*;
+
+    fn state(merge_status: MergeStatus, confidence: Confidence) -> NodeState {
+        NodeState {
+            merge_status,
+            priority_class: PriorityClass::HighFresh,
+            confidence,
+        }
+    }
+
+    #[test]
+    fn noop_if_unchanged() {
+        // given
+        let old = state(MergeStatus::Leaf, 42);
+
+        // when
+        let same = StateChange {
+            net: net(TREE_LEFT_NET),
+            old: Some(old),
+            new: old,
+        };
+        let split = StateChange {
+            new: state(MergeStatus::SplitDown, 42),
+            ..same
+        };
+        let created = StateChange::created(net(TREE_LEFT_NET), old);
+
+        // then
+        assert_that!(same.is_noop()).is_true();
+        assert_that!(split.is_noop()).is_false();
+        assert_that!(created.is_noop()).is_false();
+    }
+
+    #[test]
+    fn changes_between_created_and_updated() {
+        // given
+        let parent = PrefixTree {
+            net: net(TREE_BASE_NET),
+            created_at: NaiveDateTime::default(),
+            updated_at: NaiveDateTime::default(),
+            merge_status: MergeStatus::Leaf,
+            priority_class: PriorityClass::MediumSameMulti,
+            confidence: 110,
+            lhr_set_hash: Default::default(),
+            asn: 0,
+        };
+        let child = PrefixTree {
+            net: net(TREE_LEFT_NET),
+            merge_status: MergeStatus::MinSizeReached,
+            priority_class: PriorityClass::HighFresh,
+            confidence: 0,
+            ..parent
+        };
+        let split_parent = PrefixTree {
+            merge_status: MergeStatus::SplitDown,
+            ..parent
+        };
+
+        // when
+        let changes = changes_between(&[parent], &[split_parent, child]);
+
+        // then
+        assert_that!(changes).contains_exactly_in_order(vec![
+            StateChange::of(&parent, (&split_parent).into()),
+            StateChange::created(child.net, (&child).into()),
+        ]);
+    }
+
+    #[test]
+    fn entry_states() {
+        // given
+        let entry = HistoryEntry {
+            id: 1,
+            net: net(TREE_LEFT_NET),
+            created_at: NaiveDateTime::default(),
+            cause: HistoryCause::Split,
+            split_analysis_id: Some(7),
+            old_merge_status: Some(MergeStatus::Leaf),
+            new_merge_status: MergeStatus::SplitDown,
+            old_priority_class: Some(PriorityClass::HighFresh),
+            new_priority_class: PriorityClass::HighFresh,
+            old_confidence: Some(300),
+            new_confidence: 12,
+        };
+
+        // when
+        let old = entry.old_state();
+        let created = HistoryEntry {
+            old_merge_status: None,
+            ..entry.clone()
+        };
+
+        // then
+        assert_that!(old).is_equal_to(Some(state(MergeStatus::Leaf, 255)));
+        assert_that!(entry.new_state()).is_equal_to(state(MergeStatus::SplitDown, 12));
+        assert_that!(created.old_state()).is_none();
+    }
+}
@@ -0,0 +1,315 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use diesel::{
+    prelude::*,
+    sql_types::{BigInt, Bool, Cidr},
+};
+use ipnet::{IpNet, Ipv6Net};
+use itertools::Itertools;
+use log::{error, warn};
+use thiserror::Error;
+
+use crate::persist::DieselErrorFixCause;
+
+/// How long [SubtreeLock::acquire] waits for other sessions to release a subtree
+pub const ACQUIRE_TIMEOUT: Duration = Duration::from_secs(300);
+
+const RETRY_DELAY_MIN: Duration = Duration::from_millis(10);
+const RETRY_DELAY_MAX: Duration = Duration::from_secs(1);
+
+/// Postgres advisory lock on the subtrees that a net overlaps, such that multiple aggregator
+/// instances don't modify the same part of the tree concurrently (e.g. splitting a node while
+/// another instance merges it with its siblings).
+///
+/// A subtree is identified by its topmost node. A net within the tree locks the subtree that
+/// covers it, a net above the tree locks all subtrees below it, and nets outside the tree lock
+/// only themselves. The lock is held by the connection's session, so it is also released if the
+/// connection is closed, e.g. because the process crashed. The same session may acquire a lock
+/// it already holds, it is released once it was released as often.
+#[must_use = "the lock is only released by release() or by closing the connection"]
+#[derive(Debug)]
+pub struct SubtreeLock {
+    net: Ipv6Net,
+    /// Sorted, such that sessions acquire overlapping sets of keys in the same order
+    keys: Vec<i64>,
+}
+
+/// Another session held (some of) the subtrees of `net` for longer than [ACQUIRE_TIMEOUT].
+/// The work can be retried later, e.g. by returning the message that caused it to the queue.
+#[derive(Error, Debug)]
+#[error("subtrees of {net} still locked by another session after {waited:?}")]
+pub struct LockTimeout {
+    pub net: Ipv6Net,
+    waited: Duration,
+}
+
+impl SubtreeLock {
+    /// Waits until the subtrees of `net` are no longer locked by another session, for at most
+    /// [ACQUIRE_TIMEOUT], failing with [LockTimeout] otherwise. Either all of them are locked or
+    /// none, so that sessions waiting for each other's subtrees can't deadlock.
+    ///
+    /// This blocks the thread while waiting, so async code should use [Self::acquire_async].
+    pub fn acquire(conn: &mut PgConnection, net: &Ipv6Net) -> Result<Self> {
+        let start = Instant::now();
+        let mut delay = RETRY_DELAY_MIN;
+        loop {
+            if let Some(lock) = Self::try_acquire(conn, net)? {
+                return Ok(lock);
+            }
+            check_timeout(start, net)?;
+            thread::sleep(delay);
+            delay = (delay * 2).min(RETRY_DELAY_MAX);
+        }
+    }
+
+    /// Like [Self::acquire], but yields to the runtime instead of blocking while waiting
+    pub async fn acquire_async(conn: &mut PgConnection, net: &Ipv6Net) -> Result<Self> {
+        let start = Instant::now();
+        let mut delay = RETRY_DELAY_MIN;
+        loop {
+            if let Some(lock) = Self::try_acquire(conn, net)? {
+                return Ok(lock);
+            }
+            check_timeout(start, net)?;
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(RETRY_DELAY_MAX);
+        }
+    }
+
+    /// Locks the subtrees of `net` if no other session holds any of them. Since the tree may
+    /// have changed before the lock was held (e.g. a root was seeded above `net`), the roots
+    /// are looked up again afterwards, and nothing is locked if they differ.
+    fn try_acquire(conn: &mut PgConnection, net: &Ipv6Net) -> Result<Option<Self>> {
+        let roots = find_overlapping_roots(conn, net)?;
+        let lock = Self {
+            net: *net,
+            keys: lock_keys(net, &roots),
+        };
+        if !lock.try_lock_all(conn)? {
+            return Ok(None);
+        }
+        match find_overlapping_roots(conn, net) {
+            Ok(roots) if lock_keys(net, &roots) == lock.keys => Ok(Some(lock)),
+            Ok(_) => {
+                lock.release(conn)?;
+                Ok(None)
+            }
+            Err(e) => {
+                lock.release_or_log(conn);
+                Err(e)
+            }
+        }
+    }
+
+    fn try_lock_all(&self, conn: &mut PgConnection) -> Result<bool> {
+        #[derive(QueryableByName)]
+        struct Locked {
+            #[diesel(sql_type = Bool)]
+            locked: bool,
+        }
+
+        for (i, key) in self.keys.iter().enumerate() {
+            let result: Locked = diesel::sql_query("SELECT pg_try_advisory_lock($1) AS locked")
+                .bind::<BigInt, _>(*key)
+                .get_result(conn)
+                .fix_cause()
+                .with_context(|| format!("acquiring lock for subtree of {}", self.net))?;
+            if !result.locked {
+                unlock_all(conn, &self.keys[..i])
+                    .with_context(|| format!("backing off from lock of {}", self.net))?;
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn release(self, conn: &mut PgConnection) -> Result<()> {
+        unlock_all(conn, &self.keys)
+            .with_context(|| format!("releasing lock for subtree of {}", self.net))
+    }
+
+    /// Releases the lock, logging errors instead of returning them, such that they don't replace
+    /// the result of the work done while holding it. If the connection broke, Postgres has
+    /// released the lock anyway.
+    pub fn release_or_log(self, conn: &mut PgConnection) {
+        let net = self.net;
+        if let Err(e) = self.release(conn) {
+            error!("Failed to release lock for subtree of {}: {:?}", net, e);
+        }
+    }
+}
+
+/// Runs `f` while holding the [SubtreeLock] of `net`
+pub fn with_subtree_lock<T>(
+    conn: &mut PgConnection,
+    net: &Ipv6Net,
+    f: impl FnOnce(&mut PgConnection) -> Result<T>,
+) -> Result<T> {
+    let lock = SubtreeLock::acquire(conn, net)?;
+    let result = f(conn);
+    lock.release_or_log(conn);
+    result
+}
+
+fn check_timeout(start: Instant, net: &Ipv6Net) -> Result<()> {
+    let waited = start.elapsed();
+    if waited >= ACQUIRE_TIMEOUT {
+        return Err(LockTimeout { net: *net, waited }.into());
+    }
+    Ok(())
+}
+
+fn unlock_all(conn: &mut PgConnection, keys: &[i64]) -> Result<()> {
+    #[derive(QueryableByName)]
+    struct Unlocked {
+        #[diesel(sql_type = Bool)]
+        unlocked: bool,
+    }
+
+    for key in keys.iter().rev() {
+        let result: Unlocked = diesel::sql_query("SELECT pg_advisory_unlock($1) AS unlocked")
+            .bind::<BigInt, _>(*key)
+            .get_result(conn)
+            .fix_cause()?;
+        if !result.unlocked {
+            warn!("Lock with key {} was not held on release.", key);
+        }
+    }
+    Ok(())
+}
+
+/// Topmost nodes of the tree that cover `target_net` or are within it
+fn find_overlapping_roots(conn: &mut PgConnection, target_net: &Ipv6Net) -> Result<Vec<Ipv6Net>> {
+    #[derive(QueryableByName)]
+    struct Row {
+        #[diesel(sql_type = Cidr)]
+        net: IpNet,
+    }
+
+    let rows: Vec<Row> = diesel::sql_query(
+        "
+        SELECT node.net FROM prefix_tree node
+        WHERE (node.net >>= $1 OR node.net << $1)
+        AND NOT EXISTS (SELECT 1 FROM prefix_tree parent WHERE parent.net >> node.net)
+    ",
+    )
+    .bind::<Cidr, _>(IpNet::V6(*target_net))
+    .load(conn)
+    .fix_cause()
+    .with_context(|| format!("finding subtree roots for {}", target_net))?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|it| match it.net {
+            IpNet::V6(it) => Some(it),
+            IpNet::V4(_) => None,
+        })
+        .collect())
+}
+
+/// Keys to lock for `net`, given the roots of the subtrees that it overlaps
+fn lock_keys(net: &Ipv6Net, overlapping_roots: &[Ipv6Net]) -> Vec<i64> {
+    if overlapping_roots.is_empty() {
+        return vec![lock_key(net)];
+    }
+    overlapping_roots
+        .iter()
+        .map(lock_key)
+        .sorted()
+        .dedup()
+        .collect()
+}
+
+/// Must be stable across versions and instances, so no [std::hash::Hash] here. Collisions
+/// are harmless, they only cause unrelated subtrees to wait for each other.
+fn lock_key(root: &Ipv6Net) -> i64 {
+    let bits = u128::from(root.network());
+    let folded = (bits >> 64) as u64 ^ (bits as u64) ^ root.prefix_len() as u64;
+    folded as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+
+    use super::*;
+    use crate::test_utils::*;
+
+    #[test]
+    fn lock_key_differs_by_prefix_len() {
+        // given
+        let base = net(TREE_BASE_NET);
+        let left = Ipv6Net::new(base.network(), base.prefix_len() + 1).unwrap();
+
+        // when
+        let keys = (lock_key(&base), lock_key(&left));
+
+        // then
+        assert_that!(keys.0).is_not_equal_to(keys.1);
+    }
+
+    #[test]
+    fn overlapping_nets_conflict() {
+        // given
+        let root = net(TREE_BASE_NET);
+        let other_root = net("2001:db9::/32");
+        let supernet = net("2001:db8::/15");
+
+        // when
+        let within = lock_keys(&net(TREE_LEFT_NET), &[root]);
+        let at_root = lock_keys(&root, &[root]);
+        let above = lock_keys(&supernet, &[other_root, root]);
+
+        // then
+        assert_that!(within).is_equal_to(vec![lock_key(&root)]);
+        assert_that!(at_root).is_equal_to(within.clone());
+        assert_that!(above).contains(lock_key(&root));
+        assert_that!(above).contains(lock_key(&other_root));
+    }
+
+    #[test]
+    fn keys_sorted_for_consistent_order() {
+        // given
+        let roots = [
+            net("2001:db9::/32"),
+            net(TREE_BASE_NET),
+            net("2001:db9::/32"),
+        ];
+
+        // when
+        let keys = lock_keys(&net("2001:db8::/15"), &roots);
+
+        // then
+        assert_that!(keys.len()).is_equal_to(2);
+        assert_that!(keys[0]).is_less_than(keys[1]);
+    }
+
+    #[test]
+    fn timeout_is_distinguishable() {
+        // given
+        let target = net(TREE_LEFT_NET);
+        let start = Instant::now() - ACQUIRE_TIMEOUT;
+
+        // when
+        let result = check_timeout(start, &target).context("acquiring lock");
+
+        // then
+        let timeout = result.unwrap_err().downcast::<LockTimeout>().unwrap();
+        assert_that!(timeout.net).is_equal_to(target);
+    }
+
+    #[test]
+    fn outside_tree_locks_itself() {
+        // given
+        let unrelated = net(TREE_UNRELATED_NET);
+
+        // when
+        let keys = lock_keys(&unrelated, &[]);
+
+        // then
+        assert_that!(keys).is_equal_to(vec![lock_key(&unrelated)]);
+    }
+}
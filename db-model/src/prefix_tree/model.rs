@@ -15,7 +15,7 @@ use strum::EnumIter;
 
 use crate::analyse::Confidence;
 
-#[derive(diesel_derive_enum::DbEnum, Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[derive(diesel_derive_enum::DbEnum, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[ExistingTypePath = "crate::sql_types::PrefixMergeStatus"]
 pub enum MergeStatus {
     /// A leaf in the tree.
@@ -25,7 +25,8 @@ pub enum MergeStatus {
     /// A leaf with size /64.
     /// The main purpose of this is to stop probing these nets, as we can't find anything useful there.
     /// They have already been split to the maximum, so the only possible thing we could gain is merging
-    /// them back up and that's not supported at the moment.
+    /// them back up, which is done by the periodic re-aggregation pass if the measurements of the parent
+    /// no longer support the split.
     MinSizeReached,
     /// A non-root node that was split down.
     SplitDown,
@@ -43,13 +44,22 @@ pub enum MergeStatus {
     Blocked,
 }
 
+/// Merge statuses of nodes that are leaves of the prefix tree, i.e. the inferred subnets.
+/// Blocked nodes are no longer probed, but still delimit a subnet.
+pub const LEAF_MERGE_STATUSES: [MergeStatus; 4] = [
+    MergeStatus::Leaf,
+    MergeStatus::MinSizeReached,
+    MergeStatus::UnsplitRoot,
+    MergeStatus::Blocked,
+];
+
 impl MergeStatus {
     pub fn is_eligible_for_split(&self) -> bool {
         matches!(self, MergeStatus::Leaf | MergeStatus::UnsplitRoot)
     }
 
     pub fn split(&self) -> MergeStatus {
-        // NOTE - merge() logic is implemented in raw SQL in merge_redundant
+        // NOTE - inverse of merge(), which is also implemented in raw SQL in merge_redundant
 
         if matches!(self, MergeStatus::UnsplitRoot | MergeStatus::SplitRoot) {
             MergeStatus::SplitRoot
@@ -60,6 +70,18 @@ impl MergeStatus {
         }
     }
 
+    /// Status of a split node after its children were merged back into it,
+    /// or `None` if this node cannot take part in a merge.
+    pub fn merge(&self) -> Option<MergeStatus> {
+        // NOTE - also implemented in raw SQL in merge_redundant
+
+        match self {
+            MergeStatus::SplitRoot => Some(MergeStatus::UnsplitRoot),
+            MergeStatus::SplitDown => Some(MergeStatus::Leaf),
+            _ => None,
+        }
+    }
+
     pub fn new(child_prefix_len: u8) -> MergeStatus {
         if child_prefix_len >= 64 {
             MergeStatus::MinSizeReached
@@ -213,3 +235,16 @@ pub struct AsPrefix {
     pub deleted: bool,
     pub asn: AsNumber,
 }
+
+#[derive(Queryable, Selectable, Insertable, AsChangeset, Debug, Copy, Clone, PartialEq)]
+#[diesel(table_name = crate::schema::as_rate_budget)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+#[diesel(primary_key(asn))]
+pub struct AsRateBudget {
+    pub asn: AsNumber,
+    /// Factor applied to the default amount of prefixes per AS and schedule
+    pub scale: f32,
+    /// Moving average of the share of probes without any response
+    pub unresponsive_ratio: f32,
+    pub observation_count: i64,
+}
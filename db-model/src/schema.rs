@@ -1,6 +1,10 @@
 // @generated automatically by Diesel CLI.
 
 pub mod sql_types {
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "prefix_history_cause"))]
+    pub struct PrefixHistoryCause;
+
     #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "prefix_merge_status"))]
     pub struct PrefixMergeStatus;
@@ -18,6 +22,17 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    as_rate_budget (asn) {
+        asn -> Int8,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        scale -> Float4,
+        unresponsive_ratio -> Float4,
+        observation_count -> Int8,
+    }
+}
+
 diesel::table! {
     as_prefix (net) {
         net -> Cidr,
@@ -26,6 +41,13 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    hitlist (net) {
+        net -> Cidr,
+        interface_ids -> Array<Int8>,
+    }
+}
+
 diesel::table! {
     measurement_tree (target_net) {
         target_net -> Cidr,
@@ -35,6 +57,21 @@ diesel::table! {
         unresponsive_count -> Int4,
         last_hop_routers -> Jsonb,
         weirdness -> Jsonb,
+        responsive_targets -> Jsonb,
+        decayed_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::PrefixPriorityClass;
+
+    prefix_control (net) {
+        net -> Cidr,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        pinned_class -> Nullable<PrefixPriorityClass>,
+        frozen -> Bool,
     }
 }
 
@@ -55,6 +92,27 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::PrefixHistoryCause;
+    use super::sql_types::PrefixMergeStatus;
+    use super::sql_types::PrefixPriorityClass;
+
+    prefix_tree_history (id) {
+        id -> Int8,
+        net -> Cidr,
+        created_at -> Timestamp,
+        cause -> PrefixHistoryCause,
+        split_analysis_id -> Nullable<Int8>,
+        old_merge_status -> Nullable<PrefixMergeStatus>,
+        new_merge_status -> PrefixMergeStatus,
+        old_priority_class -> Nullable<PrefixPriorityClass>,
+        new_priority_class -> PrefixPriorityClass,
+        old_confidence -> Nullable<Int2>,
+        new_confidence -> Int2,
+    }
+}
+
 diesel::table! {
     response_archive (id) {
         id -> Int8,
@@ -73,6 +131,9 @@ diesel::table! {
         #[max_length = 34]
         pending_follow_up -> Nullable<Bpchar>,
         result -> Nullable<Jsonb>,
+        pending_echo_responses -> Int2,
+        echo_responded_by -> Array<Text>,
+        pending_follow_ups -> Jsonb,
     }
 }
 
@@ -81,8 +142,12 @@ diesel::joinable!(split_analysis -> prefix_tree (tree_net));
 diesel::allow_tables_to_appear_in_same_query!(
     as_filter_list,
     as_prefix,
+    as_rate_budget,
+    hitlist,
     measurement_tree,
+    prefix_control,
     prefix_tree,
+    prefix_tree_history,
     response_archive,
     split_analysis,
 );
@@ -0,0 +1,49 @@
+use anyhow::{Context, Result};
+use clap::Args;
+use diesel::{
+    r2d2::{ConnectionManager, Pool},
+    PgConnection,
+};
+
+use crate::persist;
+
+#[derive(Args, Clone)]
+#[group(id = "pool")]
+pub struct Params {
+    /// How many database connections to keep open at most
+    #[arg(long, env = "QUERY_API_DB_POOL_SIZE", default_value = "8")]
+    db_pool_size: u32,
+}
+
+/// Runs blocking Diesel queries on a separate thread, with a connection taken from a pool.
+#[derive(Clone)]
+pub struct Db {
+    pool: Pool<ConnectionManager<PgConnection>>,
+}
+
+impl Db {
+    pub fn new(persist: &persist::Params, params: &Params) -> Result<Self> {
+        let url = persist::connection_url("query-api", persist)?;
+        let pool = Pool::builder()
+            .max_size(params.db_pool_size)
+            .build(ConnectionManager::new(url.as_str()))
+            .context("while creating the connection pool")?;
+        Ok(Self { pool })
+    }
+
+    pub async fn run<T, F>(&self, query: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut PgConnection) -> Result<T> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = pool
+                .get()
+                .context("while taking a connection from the pool")?;
+            query(&mut conn)
+        })
+        .await
+        .context("while waiting for query")?
+    }
+}
@@ -0,0 +1,48 @@
+use anyhow::Result;
+use clap::Parser;
+
+use futures::executor;
+use prefix_crab::helpers::stop::{self, flatten};
+use prefix_crab::helpers::{bootstrap, logging};
+
+// FQNs are needed in some Diesel macros, make them easier to read
+pub use db_model::persist;
+pub use db_model::{schema, sql_types};
+
+mod db;
+mod model;
+mod query;
+mod serve;
+
+#[derive(Parser)]
+#[command(author, version, about)]
+struct Cli {
+    #[clap(flatten)]
+    logging: logging::Params,
+
+    #[clap(flatten)]
+    persist: persist::Params,
+
+    #[clap(flatten)]
+    db: db::Params,
+
+    #[clap(flatten)]
+    serve: serve::Params,
+}
+
+fn main() -> Result<()> {
+    bootstrap::run(Cli::parse, |cli: &Cli| &cli.logging, do_run)
+}
+
+fn do_run(cli: Cli) -> Result<()> {
+    // NOTE: No persist::initialize(), since this must not run migrations (read-only)
+
+    let sig_handler = stop::new();
+    let stop_rx = sig_handler.subscribe_stop();
+    tokio::spawn(sig_handler.wait_for_signal());
+
+    let db = db::Db::new(&cli.persist, &cli.db)?;
+    let serve_handle = tokio::spawn(serve::run(stop_rx, db, cli.serve));
+
+    executor::block_on(flatten(serve_handle))
+}
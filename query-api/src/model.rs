@@ -0,0 +1,159 @@
+use std::{collections::BTreeMap, net::Ipv6Addr};
+
+use chrono::NaiveDateTime;
+use db_model::{
+    analyse::{
+        Confidence, HitCount, LhrSource, MeasurementTree, SplitAnalysis, SplitAnalysisResult,
+    },
+    prefix_tree::{
+        history::{HistoryCause, HistoryEntry, NodeState},
+        AsNumber, LhrSetHash, MergeStatus, PrefixTree, PriorityClass,
+    },
+};
+use ipnet::Ipv6Net;
+use itertools::Itertools;
+use serde::Serialize;
+
+#[derive(Serialize)]
+pub struct Node {
+    pub net: Ipv6Net,
+    pub asn: AsNumber,
+    pub merge_status: MergeStatus,
+    pub priority_class: PriorityClass,
+    pub confidence: Confidence,
+    pub lhr_set_hash: LhrSetHash,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl From<PrefixTree> for Node {
+    fn from(value: PrefixTree) -> Self {
+        Self {
+            net: value.net,
+            asn: value.asn,
+            merge_status: value.merge_status,
+            priority_class: value.priority_class,
+            confidence: value.confidence,
+            lhr_set_hash: value.lhr_set_hash,
+            created_at: value.created_at,
+            updated_at: value.updated_at,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Subtree {
+    pub root: Ipv6Net,
+    /// Whether there were more nodes than returned, due to the configured limit
+    pub truncated: bool,
+    pub nodes: Vec<Node>,
+}
+
+#[derive(Serialize)]
+pub struct Analysis {
+    pub id: i64,
+    pub created_at: NaiveDateTime,
+    pub completed_at: Option<NaiveDateTime>,
+    pub pending_follow_up: Option<String>,
+    pub result: Option<SplitAnalysisResult>,
+    pub pending_echo_responses: i16,
+    pub echo_responded_by: Vec<String>,
+}
+
+impl From<SplitAnalysis> for Analysis {
+    fn from(value: SplitAnalysis) -> Self {
+        Self {
+            id: value.id,
+            created_at: value.created_at,
+            completed_at: value.completed_at,
+            pending_follow_up: value.pending_follow_up,
+            result: value.result,
+            pending_echo_responses: value.pending_echo_responses,
+            echo_responded_by: value.echo_responded_by,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Change {
+    pub id: i64,
+    pub created_at: NaiveDateTime,
+    pub cause: HistoryCause,
+    pub split_analysis_id: Option<i64>,
+    pub old: Option<NodeState>,
+    pub new: NodeState,
+}
+
+impl From<HistoryEntry> for Change {
+    fn from(value: HistoryEntry) -> Self {
+        Self {
+            id: value.id,
+            created_at: value.created_at,
+            cause: value.cause,
+            split_analysis_id: value.split_analysis_id,
+            old: value.old_state(),
+            new: value.new_state(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Measurements {
+    pub net: Ipv6Net,
+    pub asn: AsNumber,
+    /// How many measurement nodes were merged into this
+    pub merged_count: usize,
+    /// Whether there were more measurement nodes than merged, due to the configured limit
+    pub truncated: bool,
+    pub responsive_count: HitCount,
+    pub unresponsive_count: HitCount,
+    pub last_hop_routers: Vec<Lhr>,
+    pub weirdness: BTreeMap<String, HitCount>,
+}
+
+#[derive(Serialize)]
+pub struct Lhr {
+    pub addr: Ipv6Addr,
+    pub hit_count: HitCount,
+    pub sources: Vec<LhrSource>,
+    /// Hits per vantage point, if known
+    pub vantage_points: BTreeMap<String, HitCount>,
+}
+
+impl Measurements {
+    pub fn new(
+        node: &PrefixTree,
+        merged: MeasurementTree,
+        merged_count: usize,
+        truncated: bool,
+    ) -> Self {
+        let last_hop_routers = merged
+            .last_hop_routers
+            .items
+            .into_iter()
+            .map(|(addr, item)| Lhr {
+                addr,
+                hit_count: item.hit_count,
+                sources: item.sources.into_iter().sorted_by_key(|it| format!("{:?}", it)).collect(),
+                vantage_points: item.vantage_points.into_iter().collect(),
+            })
+            .sorted_by_key(|it| std::cmp::Reverse(it.hit_count))
+            .collect();
+        let weirdness = merged
+            .weirdness
+            .items
+            .into_iter()
+            .map(|(kind, item)| (format!("{:?}", kind), item.hit_count))
+            .collect();
+        Self {
+            net: node.net,
+            asn: node.asn,
+            merged_count,
+            truncated,
+            responsive_count: merged.responsive_count,
+            unresponsive_count: merged.unresponsive_count,
+            last_hop_routers,
+            weirdness,
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use std::net::Ipv6Addr;
+
+use anyhow::Result;
+use db_model::{
+    analyse::{MeasurementTree, SplitAnalysis},
+    persist::{
+        dsl::{masklen, CidrMethods},
+        DieselErrorFixCause,
+    },
+    prefix_tree::{
+        context::{self, ContextFetchError},
+        PrefixTree, LEAF_MERGE_STATUSES,
+    },
+};
+use diesel::{prelude::*, PgConnection};
+use ipnet::Ipv6Net;
+
+use crate::model::{Measurements, Node, Subtree};
+
+pub fn longest_match_leaf(conn: &mut PgConnection, addr: &Ipv6Addr) -> Result<Option<Node>> {
+    use crate::schema::prefix_tree::dsl::*;
+
+    let host = Ipv6Net::new(*addr, 128).expect("/128 to be a valid prefix length");
+    let node: Option<PrefixTree> = prefix_tree
+        .filter(net.supernet_or_eq6(&host))
+        // MergedUp and split nodes may also contain the address, but are not relevant
+        .filter(merge_status.eq_any(LEAF_MERGE_STATUSES))
+        .order_by(masklen(net).desc())
+        .select(PrefixTree::as_select())
+        .first(conn)
+        .optional()
+        .fix_cause()?;
+    Ok(node.map(Into::into))
+}
+
+pub fn subtree(conn: &mut PgConnection, root: &Ipv6Net, limit: i64) -> Result<Subtree> {
+    use crate::schema::prefix_tree::dsl::*;
+
+    let mut nodes: Vec<PrefixTree> = prefix_tree
+        .filter(net.subnet_or_eq6(root))
+        .order_by(net.asc())
+        .limit(limit + 1)
+        .select(PrefixTree::as_select())
+        .load(conn)
+        .fix_cause()?;
+    let truncated = nodes.len() as i64 > limit;
+    nodes.truncate(limit as usize);
+    Ok(Subtree {
+        root: *root,
+        truncated,
+        nodes: nodes.into_iter().map(Into::into).collect(),
+    })
+}
+
+/// The `limit` most recent split analyses of a node, newest first
+pub fn analyses(
+    conn: &mut PgConnection,
+    target_net: &Ipv6Net,
+    limit: i64,
+) -> Result<Vec<SplitAnalysis>> {
+    use crate::schema::split_analysis::dsl::*;
+
+    let res = split_analysis
+        .filter(tree_net.eq6(target_net))
+        .order_by(created_at.desc())
+        .limit(limit)
+        .load(conn)
+        .fix_cause()?;
+    Ok(res)
+}
+
+/// Merges the measurements within a net of the prefix tree, like the split analysis does.
+/// At most `limit` measurement nodes are merged, in order of their net.
+/// Returns [None] if the net is not in the prefix tree.
+pub fn measurements(
+    conn: &mut PgConnection,
+    target: &Ipv6Net,
+    limit: i64,
+) -> Result<Option<Measurements>> {
+    use crate::schema::measurement_tree::dsl::*;
+
+    let ctx = match context::fetch(conn, target) {
+        Ok(ctx) => ctx,
+        Err(ContextFetchError::NotInPrefixTree { .. }) => return Ok(None),
+        Err(ContextFetchError::DbError(e)) => return Err(e),
+    };
+    let trees: Vec<MeasurementTree> = measurement_tree
+        .filter(target_net.subnet_or_eq6(target))
+        .order_by(target_net.asc())
+        .limit(limit + 1)
+        .select(MeasurementTree::as_select())
+        .load(conn)
+        .fix_cause()?;
+    merge_up_to(&ctx.node, trees, limit).map(Some)
+}
+
+fn merge_up_to(
+    node: &PrefixTree,
+    mut trees: Vec<MeasurementTree>,
+    limit: i64,
+) -> Result<Measurements> {
+    let truncated = trees.len() as i64 > limit;
+    trees.truncate(limit as usize);
+    let mut merged = MeasurementTree::empty(node.net);
+    for tree in trees.iter() {
+        merged.merge(tree)?;
+    }
+    Ok(Measurements::new(node, merged, trees.len(), truncated))
+}
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+    use chrono::NaiveDateTime;
+    use db_model::{
+        prefix_tree::{LhrSetHash, MergeStatus, PriorityClass},
+        test_utils::*,
+    };
+
+    use super::*;
+
+    fn gen_node() -> PrefixTree {
+        PrefixTree {
+            net: net(TREE_BASE_NET),
+            created_at: NaiveDateTime::default(),
+            updated_at: NaiveDateTime::default(),
+            merge_status: MergeStatus::Leaf,
+            priority_class: PriorityClass::MediumSameMulti,
+            confidence: 100,
+            lhr_set_hash: LhrSetHash::from_u128(0xbeef),
+            asn: 64511,
+        }
+    }
+
+    fn lhr_hits(res: &Measurements) -> Vec<(Ipv6Addr, i32)> {
+        res.last_hop_routers
+            .iter()
+            .map(|it| (it.addr, it.hit_count))
+            .collect()
+    }
+
+    #[test]
+    fn merge_within_limit() -> Result<()> {
+        // given
+        let node = gen_node();
+        let trees = gen_measurements_complex();
+
+        // when
+        let res = merge_up_to(&node, trees, 3)?;
+
+        // then
+        assert_that!(res.net).is_equal_to(node.net);
+        assert_that!(res.asn).is_equal_to(64511);
+        assert_that!(res.merged_count).is_equal_to(3);
+        assert_that!(res.truncated).is_false();
+        assert_that!(lhr_hits(&res))
+            .contains_exactly_in_order(vec![(addr(TREE_LHR_BEEF), 24), (addr(TREE_LHR_101), 23)]);
+        Ok(())
+    }
+
+    #[test]
+    fn merge_truncated_to_limit() -> Result<()> {
+        // given
+        let node = gen_node();
+        let trees = gen_measurements_complex();
+
+        // when
+        let res = merge_up_to(&node, trees, 2)?;
+
+        // then
+        assert_that!(res.merged_count).is_equal_to(2);
+        assert_that!(res.truncated).is_true();
+        assert_that!(lhr_hits(&res))
+            .contains_exactly_in_order(vec![(addr(TREE_LHR_101), 23), (addr(TREE_LHR_BEEF), 6)]);
+        Ok(())
+    }
+
+    #[test]
+    fn merge_rejects_foreign_tree() {
+        // given
+        let node = gen_node();
+        let trees = vec![gen_tree_with_lhr_101(TREE_UNRELATED_NET, 3)];
+
+        // when
+        let res = merge_up_to(&node, trees, 10);
+
+        // then
+        assert_that!(res.is_err()).is_true();
+    }
+}
@@ -0,0 +1,314 @@
+use std::net::{Ipv6Addr, SocketAddr};
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use clap::Args;
+use db_model::prefix_tree::history;
+use ipnet::Ipv6Net;
+use log::{info, warn};
+use serde::Deserialize;
+use thiserror::Error;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    db::Db,
+    model::{Analysis, Change, Measurements, Node, Subtree},
+    query,
+};
+
+#[derive(Args, Clone)]
+#[group(id = "serve")]
+pub struct Params {
+    /// Address and port to listen on for HTTP requests
+    #[arg(long, env = "QUERY_API_BIND", default_value = "127.0.0.1:8087")]
+    bind: SocketAddr,
+
+    /// How many nodes to return from a subtree query at most, if not given in the request
+    #[arg(long, env = "QUERY_API_DEFAULT_SUBTREE_LIMIT", default_value = "1000")]
+    default_subtree_limit: i64,
+
+    /// Upper bound for the subtree limit that can be requested
+    #[arg(long, env = "QUERY_API_MAX_SUBTREE_LIMIT", default_value = "100000")]
+    max_subtree_limit: i64,
+
+    /// How many measurement nodes to merge for an LHR query at most, if not given in the request
+    #[arg(long, env = "QUERY_API_DEFAULT_MEASUREMENT_LIMIT", default_value = "1000")]
+    default_measurement_limit: i64,
+
+    /// Upper bound for the measurement limit that can be requested
+    #[arg(long, env = "QUERY_API_MAX_MEASUREMENT_LIMIT", default_value = "100000")]
+    max_measurement_limit: i64,
+
+    /// How many split analyses to return for a node at most, if not given in the request
+    #[arg(long, env = "QUERY_API_DEFAULT_ANALYSIS_LIMIT", default_value = "100")]
+    default_analysis_limit: i64,
+
+    /// Upper bound for the analysis limit that can be requested
+    #[arg(long, env = "QUERY_API_MAX_ANALYSIS_LIMIT", default_value = "10000")]
+    max_analysis_limit: i64,
+
+    /// How many history entries to return for a node at most, if not given in the request
+    #[arg(long, env = "QUERY_API_DEFAULT_HISTORY_LIMIT", default_value = "100")]
+    default_history_limit: i64,
+
+    /// Upper bound for the history limit that can be requested
+    #[arg(long, env = "QUERY_API_MAX_HISTORY_LIMIT", default_value = "10000")]
+    max_history_limit: i64,
+}
+
+#[derive(Clone)]
+struct AppState {
+    db: Db,
+    params: Params,
+}
+
+pub async fn run(stop_rx: CancellationToken, db: Db, params: Params) -> Result<()> {
+    let bind = params.bind;
+    let state = AppState { db, params };
+    let app = Router::new()
+        .route("/v1/addr/:addr/leaf", get(leaf))
+        .route("/v1/net/:addr/:len/subtree", get(subtree))
+        .route("/v1/net/:addr/:len/analyses", get(analyses))
+        .route("/v1/net/:addr/:len/history", get(node_history))
+        .route("/v1/net/:addr/:len/lhrs", get(lhrs))
+        .with_state(state);
+
+    info!("Query API listening on http://{}", bind);
+    axum::Server::try_bind(&bind)
+        .with_context(|| format!("binding to {}", bind))?
+        .serve(app.into_make_service())
+        .with_graceful_shutdown(stop_rx.cancelled())
+        .await
+        .context("while serving HTTP")?;
+    info!("Query API stopped.");
+    Ok(())
+}
+
+#[derive(Error, Debug)]
+enum ApiError {
+    #[error("{0}")]
+    BadRequest(String),
+
+    #[error("nothing found for {0}")]
+    NotFound(String),
+
+    #[error("internal error")]
+    Internal(#[from] anyhow::Error),
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match &self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Internal(e) => {
+                warn!("Error handling request: {:?}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+        let body = serde_json::json!({ "error": self.to_string() });
+        (status, Json(body)).into_response()
+    }
+}
+
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+fn parse_net(addr: Ipv6Addr, len: u8) -> Result<Ipv6Net, ApiError> {
+    let net = Ipv6Net::new(addr, len).map_err(|e| ApiError::BadRequest(e.to_string()))?;
+    if net.network() != addr {
+        return Err(ApiError::BadRequest(format!(
+            "{} has host bits set, did you mean {}?",
+            net,
+            net.trunc()
+        )));
+    }
+    Ok(net)
+}
+
+/// Longest-match leaf of the prefix tree containing the address
+async fn leaf(State(state): State<AppState>, Path(addr): Path<Ipv6Addr>) -> ApiResult<Node> {
+    let node = state
+        .db
+        .run(move |conn| query::longest_match_leaf(conn, &addr))
+        .await?;
+    node.map(Json)
+        .ok_or_else(|| ApiError::NotFound(addr.to_string()))
+}
+
+#[derive(Deserialize)]
+struct LimitQuery {
+    limit: Option<i64>,
+}
+
+impl LimitQuery {
+    fn resolve(&self, default: i64, max: i64) -> i64 {
+        self.limit.unwrap_or(default).clamp(1, max)
+    }
+}
+
+/// All nodes of the prefix tree within a net (including itself), ordered by net
+async fn subtree(
+    State(state): State<AppState>,
+    Path((addr, len)): Path<(Ipv6Addr, u8)>,
+    Query(req): Query<LimitQuery>,
+) -> ApiResult<Subtree> {
+    let net = parse_net(addr, len)?;
+    let limit = req.resolve(
+        state.params.default_subtree_limit,
+        state.params.max_subtree_limit,
+    );
+    let res = state
+        .db
+        .run(move |conn| query::subtree(conn, &net, limit))
+        .await?;
+    if res.nodes.is_empty() {
+        return Err(ApiError::NotFound(net.to_string()));
+    }
+    Ok(Json(res))
+}
+
+/// Split analysis history of a node, newest first
+async fn analyses(
+    State(state): State<AppState>,
+    Path((addr, len)): Path<(Ipv6Addr, u8)>,
+    Query(req): Query<LimitQuery>,
+) -> ApiResult<Vec<Analysis>> {
+    let net = parse_net(addr, len)?;
+    let limit = req.resolve(
+        state.params.default_analysis_limit,
+        state.params.max_analysis_limit,
+    );
+    let res = state
+        .db
+        .run(move |conn| query::analyses(conn, &net, limit))
+        .await?;
+    Ok(Json(res.into_iter().map(Into::into).collect()))
+}
+
+/// Most recent state changes of a node (e.g. when and due to which analysis it was split),
+/// oldest first
+async fn node_history(
+    State(state): State<AppState>,
+    Path((addr, len)): Path<(Ipv6Addr, u8)>,
+    Query(req): Query<LimitQuery>,
+) -> ApiResult<Vec<Change>> {
+    let net = parse_net(addr, len)?;
+    let limit = req.resolve(
+        state.params.default_history_limit,
+        state.params.max_history_limit,
+    );
+    let res = state
+        .db
+        .run(move |conn| history::of_net(conn, &net, limit))
+        .await?;
+    Ok(Json(res.into_iter().map(Into::into).collect()))
+}
+
+/// Last-hop routers (and other measurement data) seen within a net of the prefix tree
+async fn lhrs(
+    State(state): State<AppState>,
+    Path((addr, len)): Path<(Ipv6Addr, u8)>,
+    Query(req): Query<LimitQuery>,
+) -> ApiResult<Measurements> {
+    let net = parse_net(addr, len)?;
+    let limit = req.resolve(
+        state.params.default_measurement_limit,
+        state.params.max_measurement_limit,
+    );
+    let res = state
+        .db
+        .run(move |conn| query::measurements(conn, &net, limit))
+        .await?;
+    res.map(Json)
+        .ok_or_else(|| ApiError::NotFound(net.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use assertor::*;
+
+    use super::*;
+
+    #[test]
+    fn parse_net_ok() -> Result<()> {
+        // given
+        let addr: Ipv6Addr = "2001:db8::".parse()?;
+
+        // when
+        let res = parse_net(addr, 32);
+
+        // then
+        assert_that!(res.ok()).is_equal_to(Some("2001:db8::/32".parse::<Ipv6Net>()?));
+        Ok(())
+    }
+
+    #[test]
+    fn parse_net_host_bits() -> Result<()> {
+        // given
+        let addr: Ipv6Addr = "2001:db8::1".parse()?;
+
+        // when
+        let res = parse_net(addr, 32);
+
+        // then
+        assert_that!(matches!(res, Err(ApiError::BadRequest(_)))).is_true();
+        Ok(())
+    }
+
+    #[test]
+    fn parse_net_too_long() -> Result<()> {
+        // given
+        let addr: Ipv6Addr = "2001:db8::".parse()?;
+
+        // when
+        let res = parse_net(addr, 129);
+
+        // then
+        assert_that!(matches!(res, Err(ApiError::BadRequest(_)))).is_true();
+        Ok(())
+    }
+
+    #[test]
+    fn limit_defaults_and_clamps() {
+        // given
+        let none = LimitQuery { limit: None };
+        let zero = LimitQuery { limit: Some(0) };
+        let huge = LimitQuery {
+            limit: Some(1_000_000),
+        };
+        let fine = LimitQuery { limit: Some(42) };
+
+        // when
+        let resolved = [&none, &zero, &huge, &fine].map(|it| it.resolve(1000, 100_000));
+
+        // then
+        assert_that!(resolved).is_equal_to([1000, 1, 100_000, 42]);
+    }
+
+    #[test]
+    fn error_status_codes() {
+        // given
+        let errors = [
+            ApiError::BadRequest("bad".to_string()),
+            ApiError::NotFound("2001:db8::/32".to_string()),
+            ApiError::Internal(anyhow!("db down")),
+        ];
+
+        // when
+        let statuses = errors.map(|it| it.into_response().status());
+
+        // then
+        assert_that!(statuses).is_equal_to([
+            StatusCode::BAD_REQUEST,
+            StatusCode::NOT_FOUND,
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ]);
+    }
+}
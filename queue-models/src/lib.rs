@@ -1,6 +1,23 @@
 pub mod probe_response;
 pub mod probe_request;
 
+/// Identifies the location that probes were sent from, s.t. results of buddies running
+/// at multiple vantage points can be told apart.
+pub type VantagePointId = String;
+
+/// Vantage point of deployments (and responses) that do not specify one
+pub const DEFAULT_VANTAGE_POINT: &str = "default";
+
+pub fn default_vantage_point() -> VantagePointId {
+    DEFAULT_VANTAGE_POINT.to_string()
+}
+
+/// Routing key to address a message only to the buddies of a specific vantage point,
+/// while the plain routing key reaches any of them.
+pub fn vantage_routing_key(routing_key: &str, vantage_point: &str) -> String {
+    format!("{}.{}", routing_key, vantage_point)
+}
+
 /// Types that have a (constant) routing key to be used to indicate messages of this type on an exchange where multiple
 /// types of message are sent.
 ///
@@ -4,7 +4,7 @@ use ipnet::Ipv6Net;
 use serde::{Deserialize, Serialize};
 use type_safe_id::{StaticType, TypeSafeId};
 
-use crate::{RoutedMessage, TypeRoutedMessage};
+use crate::{vantage_routing_key, RoutedMessage, TypeRoutedMessage, VantagePointId};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ProbeRequest {
@@ -24,9 +24,37 @@ impl RoutedMessage for ProbeRequest {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct EchoProbeRequest {
     pub target_net: Ipv6Net,
+    /// How many prefix bits to split the target net by, i.e. the net is split into 2^n subnets
+    #[serde(default = "default_split_bits")]
+    pub split_bits: u8,
+    /// Which addresses to probe in each subnet
+    #[serde(default)]
+    pub sampling: SamplingStrategy,
+    /// Known-responsive addresses from the imported hitlist, which are probed first in
+    /// each subnet regardless of the sampling strategy (i.e. before the prober's own hitlist)
+    #[serde(default)]
+    pub hitlist: Vec<Ipv6Addr>,
+    /// Vantage point that should handle this request, any if not given
+    #[serde(default)]
+    pub vantage_point: Option<VantagePointId>,
+}
+
+impl EchoProbeRequest {
+    /// Routing key that also respects [Self::vantage_point]
+    pub fn vantage_routing_key(&self) -> String {
+        match &self.vantage_point {
+            Some(vantage_point) => vantage_routing_key(self.routing_key(), vantage_point),
+            None => self.routing_key().to_string(),
+        }
+    }
+}
+
+/// Requests from before the fan-out was configurable were always split in two
+fn default_split_bits() -> u8 {
+    1
 }
 
 impl TypeRoutedMessage for EchoProbeRequest {
@@ -35,10 +63,49 @@ impl TypeRoutedMessage for EchoProbeRequest {
     }
 }
 
+/// How the prober selects the addresses to probe in each subnet. Regardless of the
+/// strategy, samples are topped up with uniformly random addresses if it cannot
+/// provide enough addresses for a subnet.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub enum SamplingStrategy {
+    /// Uniformly random addresses from the whole subnet
+    #[default]
+    Uniform,
+    /// Interface identifiers `::1` to `::ff` in random /64s
+    LowByte,
+    /// The `::1` address of random /64s
+    FirstHost,
+    /// Addresses from the hitlist configured at the prober
+    Hitlist,
+    /// Addresses that answered in previous measurements of this prefix
+    PreviouslyResponsive { addresses: Vec<Ipv6Addr> },
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TraceRequest {
     pub id: TraceRequestId,
     pub targets: Vec<Ipv6Addr>,
+    /// Vantage point that should handle this request, any if not given. Follow-ups are traced
+    /// from the vantage point whose echo responses requested them, so yarrp-buddies need the
+    /// same `VANTAGE_POINT` as the zmap-buddies at their site.
+    #[serde(default)]
+    pub vantage_point: Option<VantagePointId>,
+    /// Whether this request holds the targets that an earlier, partial trace with the
+    /// same id didn't resolve, see [TraceResponse::requeued]. Remainders aren't split again.
+    ///
+    /// [TraceResponse::requeued]: crate::probe_response::TraceResponse::requeued
+    #[serde(default)]
+    pub is_remainder: bool,
+}
+
+impl TraceRequest {
+    /// Routing key that also respects [Self::vantage_point]
+    pub fn vantage_routing_key(&self) -> String {
+        match &self.vantage_point {
+            Some(vantage_point) => vantage_routing_key(self.routing_key(), vantage_point),
+            None => self.routing_key().to_string(),
+        }
+    }
 }
 
 impl TypeRoutedMessage for TraceRequest {
@@ -4,8 +4,9 @@ use ipnet::Ipv6Net;
 use serde::{Deserialize, Serialize};
 
 use crate::{
+    default_vantage_point,
     probe_request::{EchoProbeRequest, TraceRequest, TraceRequestId},
-    RoutedMessage, TypeRoutedMessage,
+    RoutedMessage, TypeRoutedMessage, VantagePointId,
 };
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -41,6 +42,11 @@ pub struct EchoProbeResponse {
     pub subnet_prefix_len: u8,
     pub sent_ttl: u8,
     pub splits: Vec<SplitResult>,
+    /// Where the probes were sent from
+    #[serde(default = "default_vantage_point")]
+    pub vantage_point: VantagePointId,
+    #[serde(default)]
+    pub incomplete: Option<IncompleteReason>,
 }
 
 impl TypeRoutedMessage for EchoProbeResponse {
@@ -49,6 +55,16 @@ impl TypeRoutedMessage for EchoProbeResponse {
     }
 }
 
+/// Why probing for a response stopped early. Targets without a response in such a
+/// response might just not have been probed, so they must not be counted as unresponsive.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IncompleteReason {
+    /// The deadline of the request passed before the prober finished
+    DeadlineExceeded,
+    /// The prober exited abnormally
+    ProberFailed { message: String },
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SplitResult {
     pub net_index: u8,
@@ -134,6 +150,15 @@ impl DestUnreachKind {
 pub struct TraceResponse {
     pub id: TraceRequestId,
     pub results: Vec<TraceResult>,
+    /// Where the traces were sent from
+    #[serde(default = "default_vantage_point")]
+    pub vantage_point: VantagePointId,
+    #[serde(default)]
+    pub incomplete: Option<IncompleteReason>,
+    /// Targets that were not resolved in time and returned to the queue in a new request with
+    /// the same id, so another response for this id follows
+    #[serde(default)]
+    pub requeued: Vec<Ipv6Addr>,
 }
 
 impl TypeRoutedMessage for TraceResponse {
@@ -148,7 +173,7 @@ pub enum TraceResult {
     NoResponse { target_addr: Ipv6Addr }, // unlikely; usually we'd expect at least some transit router to respond
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LastHop {
     pub target_addr: Ipv6Addr,
     pub last_hop_addr: Ipv6Addr,
@@ -160,7 +185,7 @@ pub struct LastHop {
     pub target_ttl: Option<u8>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TraceResponseType {
     // yarrp ignores all other types of response
 
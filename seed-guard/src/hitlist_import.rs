@@ -0,0 +1,70 @@
+use std::path::PathBuf;
+
+use anyhow::*;
+use clap::Args;
+use db_model::{
+    hitlist::group_by_slash64,
+    persist::{dsl::CidrMethods, DieselErrorFixCause},
+};
+use diesel::{delete, Connection, ExpressionMethods, PgConnection, RunQueryDsl};
+use ipnet::Ipv6Net;
+use itertools::Itertools;
+use log::info;
+use prefix_crab::hitlist;
+use tokio::time::Instant;
+
+#[derive(Args, Debug, Clone)]
+#[group(id = "hitlist_import")]
+pub struct Params {
+    /// Hitlist file to import into the `hitlist` table on startup, replacing its
+    /// previous contents. One IPv6 address per line, # to comment out lines, `.gz`
+    /// files are decompressed. The aggregator mixes these addresses into the samples
+    /// of its analyses. If not given, the table is left as-is.
+    #[arg(long, env = "HITLIST_IMPORT_FILE")]
+    hitlist_import_file: Option<PathBuf>,
+}
+
+/// Two bind parameters per row, Postgres allows at most 65535 per statement
+const ROWS_PER_INSERT: usize = 10_000;
+
+pub fn run(params: Params) -> Result<()> {
+    let path = match params.hitlist_import_file {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    info!("Importing hitlist from {:?}", path);
+    let start = Instant::now();
+
+    let hitlist = hitlist::read_from(path).context("reading hitlist to import")?;
+    let rows = group_by_slash64(hitlist.iter());
+
+    let mut conn = crate::persist::connect("guard - hitlist import")?;
+    conn.transaction(|conn| replace_all(conn, &rows))
+        .context("saving imported hitlist")?;
+
+    info!(
+        "Imported {} hitlist addresses in {} /64s in {}ms.",
+        hitlist.len(),
+        rows.len(),
+        start.elapsed().as_millis()
+    );
+    Ok(())
+}
+
+fn replace_all(conn: &mut PgConnection, rows: &[(Ipv6Net, Vec<i64>)]) -> Result<()> {
+    use db_model::schema::hitlist::dsl::*;
+
+    delete(hitlist).execute(conn).fix_cause()?;
+
+    for chunk in rows.chunks(ROWS_PER_INSERT) {
+        let tuples = chunk
+            .iter()
+            .map(|(row_net, ids)| (net.eq6(row_net), interface_ids.eq(ids)))
+            .collect_vec();
+        diesel::insert_into(hitlist)
+            .values(tuples)
+            .execute(conn)
+            .fix_cause()?;
+    }
+    Ok(())
+}
@@ -12,6 +12,7 @@ pub use db_model::persist;
 pub use db_model::{schema, sql_types};
 
 pub mod as_changeset;
+pub mod hitlist_import;
 pub mod schedule;
 pub mod as_filter_list;
 
@@ -26,6 +27,9 @@ struct Cli {
 
     #[clap(flatten)]
     schedule: schedule::Params,
+
+    #[clap(flatten)]
+    hitlist_import: hitlist_import::Params,
 }
 
 fn main() -> Result<()> {
@@ -34,6 +38,7 @@ fn main() -> Result<()> {
 
 fn do_run(cli: Cli) -> Result<()> {
     persist::initialize(&cli.persist)?;
+    hitlist_import::run(cli.hitlist_import)?;
 
     let sig_handler = stop::new();
     let stop_rx = sig_handler.subscribe_stop();
@@ -7,13 +7,20 @@ use anyhow::*;
 use clap::Args;
 use db_model::{
     persist::{dsl::CidrMethods, DieselErrorFixCause},
-    prefix_tree::MergeStatus,
+    prefix_tree::{
+        history::{self, HistoryCause, NodeState, StateChange},
+        MergeStatus, PrefixTree, PriorityClass,
+    },
     schema::{as_prefix, prefix_tree},
 };
-use diesel::{delete, upsert::excluded, Connection, ExpressionMethods, PgConnection, RunQueryDsl};
+use diesel::{
+    delete, upsert::excluded, Connection, ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl,
+    SelectableHelper,
+};
+use ipnet::{IpNet, Ipv6Net};
 use itertools::Itertools;
 use log::{error, info, warn};
-use prefix_crab::loop_with_stop;
+use prefix_crab::{helpers::ip::ExpectV6, loop_with_stop};
 use tokio::time::{interval, Instant};
 use tokio_util::sync::CancellationToken;
 
@@ -117,6 +124,7 @@ fn save_change(conn: &mut PgConnection, change: &AsSetEntry) -> Result<()> {
             "Removing some prefixes of AS{} from prefix tree: {:?}",
             change.asn, removed
         );
+        record_removed_nodes(conn, removed).context("recording removed prefix nodes")?;
         delete_all_below!(conn, prefix_tree, removed);
         delete_all_below!(conn, as_prefix, removed);
     }
@@ -133,6 +141,23 @@ fn save_change(conn: &mut PgConnection, change: &AsSetEntry) -> Result<()> {
     Ok(())
 }
 
+fn record_removed_nodes(conn: &mut PgConnection, removed: &[Ipv6Net]) -> Result<()> {
+    use db_model::schema::prefix_tree::dsl::*;
+
+    let mut statement = prefix_tree.select(PrefixTree::as_select()).into_boxed();
+    for removed_net in removed.iter() {
+        statement = statement.or_filter(net.subnet_or_eq6(removed_net));
+    }
+    let nodes: Vec<PrefixTree> = statement.load(conn).fix_cause()?;
+
+    let changes = nodes
+        .iter()
+        .map(|it| StateChange::of(it, it.into()))
+        .collect_vec();
+    history::record(conn, HistoryCause::Removed, None, &changes)?;
+    Ok(())
+}
+
 fn save_as_prefixes(conn: &mut PgConnection, change: &AsSetEntry) -> Result<()> {
     use db_model::schema::as_prefix::dsl::*;
 
@@ -167,11 +192,24 @@ fn save_fresh_prefix_nodes(conn: &mut PgConnection, change: &AsSetEntry) -> Resu
         })
         .collect_vec();
 
-    let inserted = diesel::insert_into(prefix_tree)
+    let inserted_nets: Vec<IpNet> = diesel::insert_into(prefix_tree)
         .values(tuples)
         .on_conflict_do_nothing()
-        .execute(conn)
+        .returning(net)
+        .get_results(conn)
         .fix_cause()?;
+    let inserted = inserted_nets.len();
+
+    let seeded = NodeState {
+        merge_status: MergeStatus::UnsplitRoot,
+        priority_class: PriorityClass::default(),
+        confidence: 0,
+    };
+    let changes = inserted_nets
+        .into_iter()
+        .map(|it| StateChange::created(it.expect_v6(), seeded))
+        .collect_vec();
+    history::record(conn, HistoryCause::Seeded, None, &changes)?;
 
     if inserted != change.added.len() {
         warn!(
@@ -1,18 +1,16 @@
+use std::fmt::{Debug, Display};
+
 pub trait IsPermanent {
     fn is_permanent(&self) -> bool;
 }
 
-#[macro_export]
-macro_rules! drop_if_permanent {
-    ($err: ident <- $concrete_ty: ty) => {
-        match $err.downcast_ref::<$concrete_ty>() {
-            Some(inner) => {
-                if inner.is_permanent() {
-                    error!("Permanent error handling a request, skipping it: {:?}", inner);
-                    return Ok(());
-                }
-            },
-            None => {}
-        };
-    }
-}
\ No newline at end of file
+/// Whether the error is (or wraps) a `T` that considers itself permanent, i.e. retrying the
+/// same request will not help.
+pub fn is_permanent_as<T>(err: &anyhow::Error) -> bool
+where
+    T: IsPermanent + Display + Debug + Send + Sync + 'static,
+{
+    err.downcast_ref::<T>()
+        .map(|inner| inner.is_permanent())
+        .unwrap_or(false)
+}
@@ -2,4 +2,5 @@ pub mod bootstrap;
 pub mod ip;
 pub mod logging;
 pub mod rabbit;
+pub mod raw_icmp6;
 pub mod stop;
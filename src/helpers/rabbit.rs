@@ -3,3 +3,4 @@ pub use handle::{ConfigureRabbit, RabbitHandle};
 mod handle;
 pub mod receive;
 pub mod ack_sender;
+pub mod dead_letter;
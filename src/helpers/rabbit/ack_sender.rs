@@ -1,4 +1,4 @@
-use amqprs::channel::BasicAckArguments;
+use amqprs::channel::{BasicAckArguments, BasicNackArguments};
 use anyhow::{Context, Result};
 use tokio::sync::mpsc::UnboundedReceiver;
 use tokio_util::sync::CancellationToken;
@@ -53,7 +53,6 @@ impl<'han> AckSender<'han> {
     }
 
     pub async fn do_ack(&mut self, work: impl CanAck) -> Result<()> {
-        // TODO support for rejects (after n retries maybe)?
         self.handle
             .chan()
             .basic_ack(BasicAckArguments {
@@ -64,4 +63,14 @@ impl<'han> AckSender<'han> {
             .with_context(|| "during ack")?;
         Ok(())
     }
+
+    /// Returns the message to the queue, e.g. if handing it off failed temporarily
+    pub async fn do_requeue(&mut self, work: impl CanAck) -> Result<()> {
+        self.handle
+            .chan()
+            .basic_nack(BasicNackArguments::new(work.delivery_tag(), false, true))
+            .await
+            .with_context(|| "during nack")?;
+        Ok(())
+    }
 }
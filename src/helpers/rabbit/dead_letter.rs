@@ -0,0 +1,50 @@
+use amqprs::{BasicProperties, FieldName, FieldTable, FieldValue, LongStr};
+use anyhow::{anyhow, Context, Result};
+use chrono::Local;
+
+/// Routing key that the message was originally published with, needed to re-publish it.
+pub const ROUTING_KEY_HEADER: &str = "x-prefix-crab-routing-key";
+/// Human-readable summary of the error, i.e. all causes on a single line.
+pub const ERROR_HEADER: &str = "x-prefix-crab-error";
+/// Error chain with one cause per line, outermost first.
+pub const ERROR_CHAIN_HEADER: &str = "x-prefix-crab-error-chain";
+/// Local time when the message was dead-lettered.
+pub const FAILED_AT_HEADER: &str = "x-prefix-crab-failed-at";
+
+/// Properties for publishing a message that failed processing to a dead-letter queue.
+/// The body should be published unchanged, so that it can be replayed later.
+pub fn to_properties(routing_key: &str, error: &anyhow::Error) -> Result<BasicProperties> {
+    let mut headers = FieldTable::new();
+    insert_str(&mut headers, ROUTING_KEY_HEADER, routing_key.to_string())?;
+    insert_str(&mut headers, ERROR_HEADER, format!("{:#}", error))?;
+    let chain = error.chain().map(|it| it.to_string()).collect::<Vec<_>>();
+    insert_str(&mut headers, ERROR_CHAIN_HEADER, chain.join("\n"))?;
+    insert_str(&mut headers, FAILED_AT_HEADER, Local::now().to_rfc3339())?;
+
+    Ok(BasicProperties::default()
+        .with_persistence(true)
+        .with_headers(headers)
+        .finish())
+}
+
+fn insert_str(headers: &mut FieldTable, key: &str, value: String) -> Result<()> {
+    let key = FieldName::try_from(key).map_err(|e| anyhow!("invalid header name {}: {:?}", key, e))?;
+    let value = LongStr::try_from(value).map_err(|e| anyhow!("header value too long: {:?}", e))?;
+    headers.insert(key, FieldValue::S(value));
+    Ok(())
+}
+
+/// Reads a string header that was set by [to_properties].
+pub fn get_str(props: &BasicProperties, key: &str) -> Option<String> {
+    let key = FieldName::try_from(key).ok()?;
+    match props.headers()?.get(&key)? {
+        FieldValue::S(value) => Some(String::from(value.clone())),
+        _ => None,
+    }
+}
+
+/// Routing key of a dead-lettered message, so that it can be re-published to the original exchange.
+pub fn original_routing_key(props: &BasicProperties) -> Result<String> {
+    get_str(props, ROUTING_KEY_HEADER)
+        .with_context(|| format!("dead-lettered message is missing {}", ROUTING_KEY_HEADER))
+}
@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::ErrorKind;
+use std::mem::MaybeUninit;
+use std::net::{Ipv6Addr, SocketAddrV6};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use log::{error, info, trace, warn};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+
+pub const ICMP6_DEST_UNREACH: u8 = 1;
+pub const ICMP6_TIME_EXCEEDED: u8 = 3;
+pub const ICMP6_ECHO_REQUEST: u8 = 128;
+pub const ICMP6_ECHO_REPLY: u8 = 129;
+pub const NEXT_HEADER_ICMP6: u8 = 58;
+
+pub const IPV6_HEADER_LEN: usize = 40;
+pub const ICMP6_HEADER_LEN: usize = 8;
+
+/// Identifies our probes in the payload, in case another process uses the same echo identifier
+const PAYLOAD_MAGIC: &[u8; 4] = b"crab";
+/// Magic and run id, followed by the data of the prober
+const PAYLOAD_HEADER_LEN: usize = 4 + 4;
+
+/// Receive errors in a row after which the socket is considered broken
+const MAX_RECV_ERRORS: u32 = 20;
+const RECV_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const RECV_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+/// Probes sent to a [Session] are grouped in runs, which receive the responses to their probes
+pub trait Run: Send + 'static {
+    type Response: Debug;
+
+    /// Maps an ICMPv6 message received from `from` to the run that caused it, if it was caused
+    /// by one of our probes, see [decode_payload]
+    fn decode(data: &[u8], from: Ipv6Addr, identifier: u16) -> Option<(u32, Self::Response)>;
+
+    fn register(&mut self, response: Self::Response);
+}
+
+/// Raw ICMPv6 socket shared by all runs of a native prober, which needs `CAP_NET_RAW`.
+/// Responses are read by a background thread and routed to the run that sent the probe via the
+/// run id embedded in the payload, which is also quoted by ICMPv6 error messages.
+///
+/// If receiving fails persistently, the session is marked as broken and all runs are dropped,
+/// such that probers notice and can open a new session.
+#[derive(Debug)]
+pub struct Session<R> {
+    socket: Socket,
+    identifier: u16,
+    next_run_id: AtomicU32,
+    runs: Mutex<HashMap<u32, R>>,
+    /// Hop limit that the socket is set to, which must not change between setting and sending
+    hop_limit: Mutex<Option<u8>>,
+    broken: AtomicBool,
+}
+
+impl<R: Run> Session<R> {
+    /// Opens the socket and starts receiving responses in a background thread
+    pub fn open(source_address: &str, interface: Option<&str>) -> Result<Arc<Self>> {
+        let source: Ipv6Addr = source_address
+            .parse()
+            .with_context(|| format!("Failed to parse source IPv6: {}", source_address))?;
+        let socket = Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))
+            .context("opening raw ICMPv6 socket, this needs CAP_NET_RAW")?;
+        socket
+            .bind(&SocketAddrV6::new(source, 0, 0, 0).into())
+            .with_context(|| format!("binding raw socket to {}", source))?;
+        if let Some(interface_name) = interface {
+            socket
+                .bind_device(Some(interface_name.as_bytes()))
+                .with_context(|| format!("binding raw socket to interface {}", interface_name))?;
+        }
+        let session = Arc::new(Self {
+            socket,
+            identifier: rand::random(),
+            next_run_id: AtomicU32::new(0),
+            runs: Mutex::new(HashMap::new()),
+            hop_limit: Mutex::new(None),
+            broken: AtomicBool::new(false),
+        });
+        let receiver = session.clone();
+        thread::spawn(move || receiver.receive_forever());
+        info!(
+            "Opened raw ICMPv6 session with identifier {}",
+            session.identifier
+        );
+        Ok(session)
+    }
+
+    pub fn identifier(&self) -> u16 {
+        self.identifier
+    }
+
+    /// Whether receiving failed persistently, in which case a new session is needed
+    pub fn is_broken(&self) -> bool {
+        self.broken.load(Ordering::Relaxed)
+    }
+
+    pub fn register_run(&self, run: R) -> u32 {
+        let run_id = self.next_run_id.fetch_add(1, Ordering::Relaxed);
+        self.lock_runs().insert(run_id, run);
+        run_id
+    }
+
+    /// Drops the run, after which responses to its probes are ignored
+    pub fn finish_run(&self, run_id: u32) {
+        self.lock_runs().remove(&run_id);
+    }
+
+    /// [None] if the run was finished, or dropped because the session broke
+    pub fn with_run<T>(&self, run_id: u32, f: impl FnOnce(&mut R) -> T) -> Option<T> {
+        self.lock_runs().get_mut(&run_id).map(f)
+    }
+
+    fn lock_runs(&self) -> MutexGuard<'_, HashMap<u32, R>> {
+        self.runs.lock().expect("runs lock not to be poisoned")
+    }
+
+    pub fn send(&self, packet: &[u8], target: &Ipv6Addr, hop_limit: u8) -> Result<()> {
+        if self.is_broken() {
+            bail!("raw ICMPv6 session is broken");
+        }
+        let addr: SockAddr = SocketAddrV6::new(*target, 0, 0, 0).into();
+        let mut current_hop_limit = self.hop_limit.lock().expect("hop limit not to be poisoned");
+        if *current_hop_limit != Some(hop_limit) {
+            self.socket
+                .set_unicast_hops_v6(hop_limit as u32)
+                .context("setting hop limit")?;
+            *current_hop_limit = Some(hop_limit);
+        }
+        // The kernel fills in the checksum for raw ICMPv6 sockets
+        self.socket
+            .send_to(packet, &addr)
+            .with_context(|| format!("sending probe to {} at hop limit {}", target, hop_limit))?;
+        Ok(())
+    }
+
+    fn receive_forever(&self) {
+        let mut buf = [MaybeUninit::<u8>::uninit(); 1500];
+        let mut errors = 0u32;
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => {
+                    errors = 0;
+                    received
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    errors += 1;
+                    if errors >= MAX_RECV_ERRORS {
+                        error!(
+                            "Giving up on raw ICMPv6 socket after {} receive errors in a row: {}",
+                            errors, e
+                        );
+                        self.broken.store(true, Ordering::Relaxed);
+                        // Closes the response channels of the runs
+                        self.lock_runs().clear();
+                        return;
+                    }
+                    let backoff = recv_backoff(errors);
+                    warn!(
+                        "Failed to receive from raw ICMPv6 socket, retrying in {:?}: {}",
+                        backoff, e
+                    );
+                    thread::sleep(backoff);
+                    continue;
+                }
+            };
+            // SAFETY: recv_from initialised the first len bytes
+            let data = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, len) };
+            let Some(from) = from.as_socket_ipv6() else {
+                continue;
+            };
+            let Some((run_id, response)) = R::decode(data, *from.ip(), self.identifier) else {
+                continue;
+            };
+            trace!("[[native result]] run {}: {:?}", run_id, response);
+            if self
+                .with_run(run_id, |run| run.register(response))
+                .is_none()
+            {
+                trace!("Response for run {} arrived after its cooldown", run_id);
+            }
+        }
+    }
+}
+
+/// Doubles with each error in a row
+fn recv_backoff(errors: u32) -> Duration {
+    let doublings = errors.saturating_sub(1).min(16);
+    (RECV_BACKOFF_MIN * 2u32.pow(doublings)).min(RECV_BACKOFF_MAX)
+}
+
+/// Echo request carrying the run id and the prober's `data` in its payload
+pub fn encode_echo_request(identifier: u16, sequence: u16, run_id: u32, data: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(ICMP6_HEADER_LEN + PAYLOAD_HEADER_LEN + data.len());
+    packet.extend_from_slice(&[ICMP6_ECHO_REQUEST, 0, 0, 0]); // type, code, checksum
+    packet.extend_from_slice(&identifier.to_be_bytes());
+    packet.extend_from_slice(&sequence.to_be_bytes());
+    packet.extend_from_slice(PAYLOAD_MAGIC);
+    packet.extend_from_slice(&run_id.to_be_bytes());
+    packet.extend_from_slice(data);
+    packet
+}
+
+/// Run id and the first `data_len` bytes of the prober's data, if `echo` is one of our echo
+/// requests or a reply to one
+pub fn decode_payload(echo: &[u8], identifier: u16, data_len: usize) -> Option<(u32, &[u8])> {
+    let payload = echo.get(ICMP6_HEADER_LEN..ICMP6_HEADER_LEN + PAYLOAD_HEADER_LEN + data_len)?;
+    if echo[4..6] != identifier.to_be_bytes() || payload[..4] != PAYLOAD_MAGIC[..] {
+        return None;
+    }
+    let run_id = u32::from_be_bytes(payload[4..8].try_into().ok()?);
+    Some((run_id, &payload[PAYLOAD_HEADER_LEN..]))
+}
+
+/// Echo request quoted by an ICMPv6 error message, with the hop limit that it had left when
+/// the error was generated
+pub fn quoted_echo_request(data: &[u8]) -> Option<(&[u8], u8)> {
+    let quoted = data.get(ICMP6_HEADER_LEN..)?;
+    if quoted.len() < IPV6_HEADER_LEN || quoted[6] != NEXT_HEADER_ICMP6 {
+        return None;
+    }
+    let echo = &quoted[IPV6_HEADER_LEN..];
+    if echo.first() != Some(&ICMP6_ECHO_REQUEST) {
+        return None;
+    }
+    Some((echo, quoted[7]))
+}
+
+/// Most probes that are sent at once to catch up after falling behind
+const MAX_BURST: u32 = 16;
+/// Shorter waits are skipped, since sleeping that briefly overshoots considerably
+const MIN_SLEEP: Duration = Duration::from_millis(1);
+
+/// Paces probes to a rate by a schedule instead of sleeping after each one. Time spent sending
+/// and oversleeping is caught up on by the next probes, such that it doesn't add up at high rates.
+#[derive(Debug)]
+pub struct Pacer {
+    interval: Duration,
+    next_at: Option<Instant>,
+}
+
+impl Pacer {
+    pub fn new(rate_pps: u32) -> Self {
+        Self {
+            interval: Duration::from_secs(1) / rate_pps.max(1),
+            next_at: None,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Blocks until the next probe is due
+    pub fn wait(&mut self) {
+        if let Some(wait) = self.schedule_at(Instant::now()) {
+            thread::sleep(wait);
+        }
+    }
+
+    /// How long to wait at `now` until the next probe is due, if at all worth it. After a pause
+    /// (e.g. waiting for targets), at most [MAX_BURST] probes are sent without waiting.
+    fn schedule_at(&mut self, now: Instant) -> Option<Duration> {
+        let earliest = now
+            .checked_sub(self.interval * (MAX_BURST - 1))
+            .unwrap_or(now);
+        let due = self.next_at.map_or(now, |it| it.max(earliest));
+        self.next_at = Some(due + self.interval);
+        due.checked_duration_since(now)
+            .filter(|it| *it >= MIN_SLEEP)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+
+    use super::*;
+
+    const IDENTIFIER: u16 = 0x1337;
+
+    #[test]
+    fn payload_round_trip() {
+        // given
+        let data = [1, 2, 3, 4];
+
+        // when
+        let request = encode_echo_request(IDENTIFIER, 7, 42, &data);
+        let decoded = decode_payload(&request, IDENTIFIER, data.len());
+
+        // then
+        assert_that!(decoded).is_equal_to(Some((42, &data[..])));
+    }
+
+    #[test]
+    fn ignore_foreign_identifier() {
+        // given
+        let request = encode_echo_request(IDENTIFIER + 1, 7, 42, &[1, 2]);
+
+        // when
+        let decoded = decode_payload(&request, IDENTIFIER, 2);
+
+        // then
+        assert_that!(decoded).is_equal_to(None);
+    }
+
+    #[test]
+    fn quoted_echo_with_hop_limit() {
+        // given
+        let request = encode_echo_request(IDENTIFIER, 7, 42, &[1, 2]);
+        let mut error = vec![ICMP6_TIME_EXCEEDED, 0, 0, 0, 0, 0, 0, 0];
+        let mut ip_header = [0u8; IPV6_HEADER_LEN];
+        ip_header[6] = NEXT_HEADER_ICMP6;
+        ip_header[7] = 1;
+        error.extend_from_slice(&ip_header);
+        error.extend_from_slice(&request);
+
+        // when
+        let quoted = quoted_echo_request(&error);
+
+        // then
+        assert_that!(quoted).is_equal_to(Some((&request[..], 1)));
+    }
+
+    #[test]
+    fn recv_backoff_grows_up_to_max() {
+        // given
+        let errors = [1, 2, 3, MAX_RECV_ERRORS, u32::MAX];
+
+        // when
+        let backoffs = errors.map(recv_backoff);
+
+        // then
+        assert_that!(backoffs[0]).is_equal_to(RECV_BACKOFF_MIN);
+        assert_that!(backoffs[1]).is_equal_to(RECV_BACKOFF_MIN * 2);
+        assert_that!(backoffs[2]).is_equal_to(RECV_BACKOFF_MIN * 4);
+        assert_that!(backoffs[3]).is_equal_to(RECV_BACKOFF_MAX);
+        assert_that!(backoffs[4]).is_equal_to(RECV_BACKOFF_MAX);
+    }
+
+    #[test]
+    fn pacer_catches_up_without_sleeping() {
+        // given
+        let mut pacer = Pacer::new(1000);
+        let start = Instant::now();
+        pacer.schedule_at(start);
+
+        // when
+        let late = pacer.schedule_at(start + Duration::from_micros(1500));
+        let caught_up = pacer.schedule_at(start + Duration::from_micros(1600));
+        let ahead = pacer.schedule_at(start + Duration::from_micros(1600));
+
+        // then
+        assert_that!(late).is_equal_to(None);
+        assert_that!(caught_up).is_equal_to(None); // due in 0.4ms, not worth sleeping
+        assert_that!(ahead).is_equal_to(Some(Duration::from_micros(1400)));
+    }
+
+    #[test]
+    fn pacer_limits_burst_after_pause() {
+        // given
+        let mut pacer = Pacer::new(1000);
+        let start = Instant::now();
+        pacer.schedule_at(start);
+        let after_pause = start + Duration::from_secs(60);
+
+        // when
+        let sleeps = (0..=MAX_BURST)
+            .map(|_| pacer.schedule_at(after_pause))
+            .collect::<Vec<_>>();
+
+        // then
+        assert_that!(sleeps[..MAX_BURST as usize].iter().all(|it| it.is_none())).is_true();
+        assert_that!(sleeps[MAX_BURST as usize]).is_equal_to(Some(pacer.interval()));
+    }
+}
@@ -1,7 +1,12 @@
-use anyhow::{anyhow, Result};
-use log::info;
+use std::process::{Child, ExitStatus};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
 use nix::sys;
 use nix::sys::signal::Signal;
+use nix::unistd::Pid;
 use tokio::select;
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::task::JoinHandle;
@@ -54,3 +59,112 @@ pub async fn flatten(handle: JoinHandle<Result<()>>) -> Result<()> {
         Err(err) => Err(anyhow!(err)),
     }
 }
+
+/// Cancels a blocking task and waits at most `grace` for it to stop, since it might hang.
+/// Blocking tasks can't be aborted, so if it doesn't stop in time, it is left behind.
+pub async fn cancel_and_join(
+    handle: JoinHandle<Result<()>>,
+    cancel: &CancellationToken,
+    grace: Duration,
+) -> Result<()> {
+    cancel.cancel();
+    match tokio::time::timeout(grace, flatten(handle)).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!(
+            "task didn't stop within {:?} after cancellation",
+            grace
+        )),
+    }
+}
+
+/// How long a child process gets to exit after SIGTERM before it is killed
+const CHILD_TERM_GRACE: Duration = Duration::from_secs(5);
+const CHILD_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Waits for a child process to exit, or terminates it once `cancel` is triggered, in which
+/// case [None] is returned. SIGTERM is sent first, because sudo only relays signals that it
+/// can catch itself, and SIGKILL only if the child didn't exit within [CHILD_TERM_GRACE].
+pub fn wait_or_terminate(
+    child: &mut Child,
+    cancel: &CancellationToken,
+) -> Result<Option<ExitStatus>> {
+    loop {
+        if let Some(status) = child.try_wait().context("waiting for child to exit")? {
+            return Ok(Some(status));
+        }
+        if cancel.is_cancelled() {
+            terminate(child)?;
+            return Ok(None);
+        }
+        thread::sleep(CHILD_POLL_INTERVAL);
+    }
+}
+
+/// Sleeps for `duration`, but returns early once `cancel` is triggered
+pub fn sleep_unless_cancelled(duration: Duration, cancel: &CancellationToken) {
+    let until = Instant::now() + duration;
+    while !cancel.is_cancelled() {
+        let Some(left) = until.checked_duration_since(Instant::now()) else {
+            return;
+        };
+        thread::sleep(left.min(CHILD_POLL_INTERVAL));
+    }
+}
+
+fn terminate(child: &mut Child) -> Result<()> {
+    let pid = Pid::from_raw(child.id() as i32);
+    if let Err(e) = sys::signal::kill(pid, Signal::SIGTERM) {
+        warn!("Failed to send SIGTERM to child {}: {}", pid, e);
+    }
+    let start = Instant::now();
+    while start.elapsed() < CHILD_TERM_GRACE {
+        let exited = child.try_wait().context("waiting for child to exit")?;
+        if exited.is_some() {
+            return Ok(());
+        }
+        thread::sleep(CHILD_POLL_INTERVAL);
+    }
+    warn!("Child {} didn't exit after SIGTERM, killing it.", pid);
+    child.kill().context("killing child")?;
+    child.wait().context("waiting for killed child")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use assertor::*;
+
+    use super::*;
+
+    #[test]
+    fn wait_for_exiting_child() {
+        // given
+        let mut child = Command::new("true").spawn().unwrap();
+        let cancel = CancellationToken::new();
+
+        // when
+        let status = wait_or_terminate(&mut child, &cancel).unwrap();
+
+        // then
+        assert_that!(status.map(|it| it.success())).is_equal_to(Some(true));
+    }
+
+    #[test]
+    fn terminate_hanging_child() {
+        // given
+        let mut child = Command::new("sleep").arg("600").spawn().unwrap();
+        let cancel = CancellationToken::new();
+        let start = Instant::now();
+
+        // when
+        cancel.cancel();
+        let status = wait_or_terminate(&mut child, &cancel).unwrap();
+
+        // then
+        assert_that!(status).is_equal_to(None);
+        assert_that!(child.try_wait().unwrap().is_some()).is_true();
+        assert_that!(start.elapsed()).is_less_than(CHILD_TERM_GRACE);
+    }
+}
@@ -0,0 +1,162 @@
+use itertools::Itertools;
+use flate2::read::GzDecoder;
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    net::{AddrParseError, Ipv6Addr},
+    path::PathBuf,
+};
+use thiserror::Error;
+
+use clap::Args;
+
+use crate::prefix_split::KnownAddrSampler;
+
+#[derive(Args, Clone)]
+#[group(id = "hitlist")]
+pub struct Params {
+    /// Flatfile to read a hitlist of known-responsive addresses from, which can be
+    /// used for sampling instead of random addresses.
+    /// One IPv6 address per line
+    /// # at start of line to comment out the whole line
+    /// No headers or similar
+    /// Files ending in `.gz` are decompressed with gzip.
+    /// If not given, an empty hitlist is used.
+    /// Only used for requests with the `hitlist` sampling strategy. Addresses sent with the
+    /// request (from the hitlist imported by seed-guard) take precedence, and this file only
+    /// fills the remaining samples of each subnet.
+    #[arg(long, env = "HITLIST_FILE")]
+    pub hitlist_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default)]
+pub struct Hitlist {
+    /// sorted & deduplicated
+    entries: Vec<Ipv6Addr>,
+}
+
+impl Hitlist {
+    pub fn new(mut entries: Vec<Ipv6Addr>) -> Self {
+        entries.sort_unstable();
+        entries.dedup();
+        Self { entries }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Iterates the addresses in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = &Ipv6Addr> {
+        self.entries.iter()
+    }
+
+    pub fn sampler(&self) -> KnownAddrSampler<'_> {
+        KnownAddrSampler::from_sorted(&self.entries)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum HitlistReadError {
+    #[error("hitlist file does not exist: `{0}`")]
+    NoSuchFile(PathBuf),
+
+    #[error("failed to open hitlist file `{path}`")]
+    FailedOpen {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("failed to read a line from hitlist file")]
+    FailedRead { source: std::io::Error },
+
+    #[error("invalid IPv6 address encountered: `{line}`")]
+    AddrSyntax {
+        line: String,
+        source: AddrParseError,
+    },
+}
+
+pub type HitlistReadResult = Result<Hitlist, HitlistReadError>;
+
+pub fn read(params: Params) -> HitlistReadResult {
+    match params.hitlist_file {
+        Some(path) => read_from(path),
+        None => Ok(Hitlist::default()),
+    }
+}
+
+pub fn read_from(path: PathBuf) -> HitlistReadResult {
+    use HitlistReadError as E;
+
+    if !path.is_file() {
+        return Err(E::NoSuchFile(path));
+    }
+
+    let mut entries = vec![];
+    let is_gzip = path.extension().is_some_and(|it| it == "gz");
+    let file = File::open(path.clone()).map_err(|source| E::FailedOpen { path, source })?;
+    let reader: Box<dyn Read> = if is_gzip {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    let lines = BufReader::new(reader)
+        .lines()
+        .filter_ok(|line| !line.starts_with('#') && !line.is_empty());
+
+    for line_res in lines {
+        match line_res {
+            Err(source) => return Err(E::FailedRead { source }),
+            Ok(line) => match line.trim().parse() {
+                Ok(addr) => entries.push(addr),
+                Err(source) => return Err(E::AddrSyntax { line, source }),
+            },
+        }
+    }
+
+    Ok(Hitlist::new(entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use assertor::*;
+
+    use crate::test_utils::{addr, net};
+
+    use super::read_from;
+
+    #[test]
+    fn example_hitlist_loads() {
+        // given
+        let path = PathBuf::from("hitlist-example.txt");
+        // when
+        let res = read_from(path);
+        // then
+        assert_that!(res).is_ok();
+        let res = res.unwrap();
+
+        assert_that!(res.len()).is_equal_to(3);
+        let sampler = res.sampler();
+        assert_that!(sampler.known_in(&net("2001:db8::/48")).to_vec())
+            .contains_exactly_in_order(vec![addr("2001:db8::1"), addr("2001:db8:0:1::53")]);
+        assert_that!(sampler.known_in(&net("2001:db9::/32")).to_vec()).is_empty();
+    }
+
+    #[test]
+    fn gzip_hitlist_loads() {
+        // given
+        let path = PathBuf::from("hitlist-example.txt.gz");
+        // when
+        let res = read_from(path);
+        // then
+        assert_that!(res).is_ok();
+        assert_that!(res.unwrap().len()).is_equal_to(3);
+    }
+}
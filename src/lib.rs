@@ -1,6 +1,8 @@
 pub mod blocklist;
 pub mod error;
 pub mod helpers;
+pub mod hitlist;
 pub mod prefix_split;
+pub mod simulation;
 pub mod test_utils;
 pub mod confidence_threshold;
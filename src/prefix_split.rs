@@ -1,38 +1,125 @@
+use std::str::FromStr;
+
 use anyhow::*;
+use clap::Args;
 use ipnet::Ipv6Net;
+use itertools::Itertools;
 
-pub use sample::{SubnetSample, ToSubnetSamples, sample_single_net};
+pub use sample::{
+    sample_single_net, FirstHostSampler, KnownAddrSampler, LowByteSampler, MixedSampler, Sampler,
+    SubnetSample, ToSubnetSamples, UniformSampler,
+};
 pub use split::{NetIndex, PrefixSplit, SplitSubnet, SplitSubnets};
 
 pub const SAMPLES_PER_SUBNET: u16 = 16; // we could reduce this for more-specific prefixes
-pub const PREFIX_BITS_PER_SPLIT: u8 = 1;
-pub const SUBNETS_PER_SPLIT: u8 = 2u8.pow(PREFIX_BITS_PER_SPLIT as u32);
-pub const SUBNETS_PER_SPLIT_USIZE: usize = 2u8.pow(PREFIX_BITS_PER_SPLIT as u32) as usize;
+pub const DEFAULT_BITS_PER_SPLIT: u8 = 1;
+/// Each subnet is probed with [SAMPLES_PER_SUBNET], so this also bounds the probes per split
+pub const MAX_BITS_PER_SPLIT: u8 = 4;
+pub const MAX_SUBNETS_PER_SPLIT: u8 = 2u8.pow(MAX_BITS_PER_SPLIT as u32);
 pub const MAX_PREFIX_LEN: u8 = 64;
 
-/// Splits given network into [SUBNETS_PER_SPLIT] subnets, by increasing the
-/// prefix length by [PREFIX_BITS_PER_SPLIT] up to a maximum of
-/// [MAX_PREFIX_LEN].
-pub fn split(base_net: Ipv6Net) -> Result<PrefixSplit> {
-    split::process(base_net)
+/// Splits given network into 2^`bits` subnets, by increasing the prefix length
+/// by `bits` (at most [MAX_BITS_PER_SPLIT]) up to a maximum of [MAX_PREFIX_LEN].
+pub fn split(base_net: Ipv6Net, bits: u8) -> Result<PrefixSplit> {
+    split::process(base_net, bits)
+}
+
+#[derive(Args, Clone, Debug)]
+#[group(id = "prefixsplit")]
+pub struct Params {
+    /// How many prefix bits to split by, depending on the prefix length of the split net.
+    /// Comma-separated list of `<until prefix len>:<bits>`, e.g. `48:4,64:1` splits nets
+    /// shorter than /48 into 16 subnets each (but never past /48), and more-specific nets
+    /// into two subnets each. Nets not covered by any rule are split into two subnets.
+    #[arg(long, env = "PREFIX_SPLIT_FAN_OUT", default_value = "64:1")]
+    pub prefix_split_fan_out: FanOut,
+}
+
+/// Runtime-selectable fan-out of splits, see [Params::prefix_split_fan_out].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FanOut {
+    /// Ordered by ascending `until_len`, without duplicates
+    rules: Vec<FanOutRule>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct FanOutRule {
+    until_len: u8,
+    bits: u8,
+}
+
+impl FanOut {
+    /// How many bits to split the given net by. Splits never cross the `until` boundary of a rule,
+    /// s.t. e.g. a /46 is split by 2 bits if the rule is `48:4`.
+    pub fn bits_for(&self, base_net: &Ipv6Net) -> u8 {
+        let len = base_net.prefix_len();
+        match self.rules.iter().find(|it| len < it.until_len) {
+            Some(rule) => rule.bits.min(rule.until_len - len),
+            None => DEFAULT_BITS_PER_SPLIT,
+        }
+    }
+}
+
+impl Default for FanOut {
+    fn default() -> Self {
+        Self {
+            rules: vec![FanOutRule {
+                until_len: MAX_PREFIX_LEN,
+                bits: DEFAULT_BITS_PER_SPLIT,
+            }],
+        }
+    }
+}
+
+impl FromStr for FanOut {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let rules = s
+            .split(',')
+            .map(|rule| {
+                parse_rule(rule.trim()).with_context(|| format!("in fan-out rule {:?}", rule))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if rules.is_empty() {
+            bail!("Fan-out needs at least one rule");
+        }
+        if !rules.iter().tuple_windows().all(|(a, b)| a.until_len < b.until_len) {
+            bail!("Fan-out rules must be ordered by ascending prefix length: {}", s);
+        }
+        Ok(Self { rules })
+    }
+}
+
+fn parse_rule(rule: &str) -> Result<FanOutRule> {
+    let (until_len, bits) = rule
+        .split_once(':')
+        .context("expected <until prefix len>:<bits>")?;
+    let until_len: u8 = until_len.parse().context("invalid prefix length")?;
+    let bits: u8 = bits.parse().context("invalid bit count")?;
+    if until_len > MAX_PREFIX_LEN {
+        bail!("Prefix length must be at most {}", MAX_PREFIX_LEN);
+    }
+    if !(1..=MAX_BITS_PER_SPLIT).contains(&bits) {
+        bail!("Bit count must be between 1 and {}", MAX_BITS_PER_SPLIT);
+    }
+    Ok(FanOutRule { until_len, bits })
 }
 
 mod split {
-    use std::iter::Map;
-    use std::{iter::IntoIterator, ops::Range};
+    use std::ops::Range;
 
     use std::ops::Index;
 
     use anyhow::*;
     use ipnet::Ipv6Net;
 
-    use super::{
-        MAX_PREFIX_LEN, PREFIX_BITS_PER_SPLIT, SUBNETS_PER_SPLIT, SUBNETS_PER_SPLIT_USIZE,
-    };
+    use super::{MAX_BITS_PER_SPLIT, MAX_PREFIX_LEN, MAX_SUBNETS_PER_SPLIT};
 
-    pub fn process(base_net: Ipv6Net) -> Result<PrefixSplit> {
-        let subnet_prefix_len = subnet_prefix_len_for(base_net).context("Base net too small")?;
-        let subnets_vec: Vec<Ipv6Net> = base_net
+    pub fn process(base_net: Ipv6Net, bits: u8) -> Result<PrefixSplit> {
+        let subnet_prefix_len =
+            subnet_prefix_len_for(base_net, bits).context("Base net too small")?;
+        let subnets: Vec<Ipv6Net> = base_net
             .subnets(subnet_prefix_len)
             .with_context(|| {
                 format!(
@@ -41,26 +128,32 @@ mod split {
                 )
             })?
             .collect();
-        let subnets: SplitSubnetsRaw = subnets_vec
-            .try_into()
-            .expect("split with n bits to yield 2^n subnets");
         Ok(PrefixSplit::new(base_net, subnet_prefix_len, subnets))
     }
 
-    fn subnet_prefix_len_for(base_net: Ipv6Net) -> Result<u8> {
-        let len = base_net.prefix_len() + PREFIX_BITS_PER_SPLIT;
+    fn subnet_prefix_len_for(base_net: Ipv6Net, bits: u8) -> Result<u8> {
+        if !(1..=MAX_BITS_PER_SPLIT).contains(&bits) {
+            bail!(
+                "Cannot split {} by {} bits, need between 1 and {}",
+                base_net,
+                bits,
+                MAX_BITS_PER_SPLIT
+            );
+        }
+        let len = base_net.prefix_len() + bits;
         if len > MAX_PREFIX_LEN {
             bail!(
-                "Cannot further split this prefix {}, max split prefix len is {}",
+                "Cannot further split this prefix {} by {} bits, max split prefix len is {}",
                 base_net,
+                bits,
                 MAX_PREFIX_LEN
             );
         }
         Ok(len)
     }
 
-    type SplitSubnetsRaw = [Ipv6Net; SUBNETS_PER_SPLIT_USIZE];
-    pub type SplitSubnets = [SplitSubnet; SUBNETS_PER_SPLIT_USIZE];
+    /// Ordered by [NetIndex], with 2^n subnets for a split by n bits
+    pub type SplitSubnets = Vec<SplitSubnet>;
 
     #[derive(Debug, Clone)]
     pub struct PrefixSplit {
@@ -70,15 +163,17 @@ mod split {
     }
 
     impl PrefixSplit {
-        fn new(base_net: Ipv6Net, subnet_prefix_len: u8, subnets_raw: SplitSubnetsRaw) -> Self {
-            let mut next_index = 0u8;
-            let subnets = subnets_raw.map(|network| {
-                next_index += 1;
-                SplitSubnet {
-                    index: (next_index - 1).try_into().unwrap(),
+        fn new(base_net: Ipv6Net, subnet_prefix_len: u8, subnets_raw: Vec<Ipv6Net>) -> Self {
+            let subnets = subnets_raw
+                .into_iter()
+                .enumerate()
+                .map(|(index, network)| SplitSubnet {
+                    index: (index as u8)
+                        .try_into()
+                        .expect("split to yield at most MAX_SUBNETS_PER_SPLIT subnets"),
                     network,
-                }
-            });
+                })
+                .collect();
             Self {
                 base_net,
                 subnet_prefix_len,
@@ -89,11 +184,15 @@ mod split {
         pub fn into_subnets(self) -> SplitSubnets {
             self.subnets
         }
+
+        pub fn bits(&self) -> u8 {
+            self.subnet_prefix_len - self.base_net.prefix_len()
+        }
     }
 
     impl IntoIterator for PrefixSplit {
         type Item = SplitSubnet;
-        type IntoIter = std::array::IntoIter<SplitSubnet, SUBNETS_PER_SPLIT_USIZE>;
+        type IntoIter = std::vec::IntoIter<SplitSubnet>;
 
         fn into_iter(self) -> Self::IntoIter {
             self.subnets.into_iter()
@@ -106,21 +205,12 @@ mod split {
         }
     }
 
+    /// Index of a subnet within its split, valid for splits of up to [MAX_BITS_PER_SPLIT].
     #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
     pub struct NetIndex(u8);
 
-    type NetIndexIter = Map<Range<u8>, fn(u8) -> NetIndex>;
-
     impl NetIndex {
-        const RANGE: Range<u8> = (0..SUBNETS_PER_SPLIT);
-
-        pub fn iter_values() -> NetIndexIter {
-            Self::RANGE.map(|it| Self::try_from(it).expect("in-range value to convert"))
-        }
-
-        pub fn value_count() -> u8 {
-            Self::RANGE.len() as u8
-        }
+        const RANGE: Range<u8> = (0..MAX_SUBNETS_PER_SPLIT);
     }
 
     impl TryFrom<u8> for NetIndex {
@@ -172,6 +262,7 @@ mod split {
     #[cfg(test)]
     mod tests {
         use super::*;
+        use crate::prefix_split::DEFAULT_BITS_PER_SPLIT;
         use assertor::*;
 
         #[test]
@@ -180,11 +271,11 @@ mod split {
             let net = "2001:db8::/32".parse::<Ipv6Net>()?;
 
             // when
-            let len = subnet_prefix_len_for(net);
+            let len = subnet_prefix_len_for(net, DEFAULT_BITS_PER_SPLIT);
 
             // then
             assert_that!(len).is_ok();
-            assert_that!(len.unwrap()).is_equal_to(32 + PREFIX_BITS_PER_SPLIT);
+            assert_that!(len.unwrap()).is_equal_to(32 + DEFAULT_BITS_PER_SPLIT);
             Ok(())
         }
 
@@ -194,7 +285,7 @@ mod split {
             let net = "2001:db8::/63".parse::<Ipv6Net>()?;
 
             // when
-            let len = subnet_prefix_len_for(net);
+            let len = subnet_prefix_len_for(net, DEFAULT_BITS_PER_SPLIT);
 
             // then
             assert_that!(len).is_ok();
@@ -208,7 +299,7 @@ mod split {
             let net = "2001:db8::/64".parse::<Ipv6Net>()?;
 
             // when
-            let len = subnet_prefix_len_for(net);
+            let len = subnet_prefix_len_for(net, DEFAULT_BITS_PER_SPLIT);
 
             // then
             assert_that!(len).is_err();
@@ -225,7 +316,7 @@ mod split {
                 .collect();
 
             // when
-            let result = process(net)?;
+            let result = process(net, DEFAULT_BITS_PER_SPLIT)?;
 
             // then
             let actual_subnets: Vec<Ipv6Net> = result.iter().map(|r| r.network).collect();
@@ -233,13 +324,47 @@ mod split {
             Ok(())
         }
 
+        #[test]
+        fn subnets_multiple_bits() -> Result<()> {
+            // given
+            let net = "2001:db8::/32".parse::<Ipv6Net>()?;
+
+            // when
+            let result = process(net, 2)?;
+
+            // then
+            let actual_subnets: Vec<String> =
+                result.iter().map(|r| r.network.to_string()).collect();
+            assert_that!(actual_subnets).contains_exactly_in_order(vec![
+                "2001:db8::/34".to_string(),
+                "2001:db8:4000::/34".to_string(),
+                "2001:db8:8000::/34".to_string(),
+                "2001:db8:c000::/34".to_string(),
+            ]);
+            assert_that!(result.bits()).is_equal_to(2);
+            Ok(())
+        }
+
+        #[test]
+        fn prefix_len_too_many_bits() -> Result<()> {
+            // given
+            let net = "2001:db8::/32".parse::<Ipv6Net>()?;
+
+            // when
+            let len = subnet_prefix_len_for(net, MAX_BITS_PER_SPLIT + 1);
+
+            // then
+            assert_that!(len).is_err();
+            Ok(())
+        }
+
         #[test]
         fn split_object_indices() -> Result<()> {
             // given
             let net = "2001:db8::/32".parse::<Ipv6Net>()?;
 
             // when
-            let result = process(net)?;
+            let result = process(net, DEFAULT_BITS_PER_SPLIT)?;
 
             // then
             for (index, subnet) in result.iter().enumerate() {
@@ -263,7 +388,7 @@ mod split {
         #[test]
         fn index_try_from_limit() {
             // given
-            let index = SUBNETS_PER_SPLIT - 1;
+            let index = MAX_SUBNETS_PER_SPLIT - 1;
             // when
             let result = NetIndex::try_from(index);
             // then
@@ -274,7 +399,7 @@ mod split {
         #[test]
         fn index_try_from_too_high() {
             // given
-            let index = SUBNETS_PER_SPLIT;
+            let index = MAX_SUBNETS_PER_SPLIT;
             // when
             let result = NetIndex::try_from(index);
             // then
@@ -284,10 +409,14 @@ mod split {
 }
 
 mod sample {
-    use std::{net::Ipv6Addr, ops::Range};
+    use std::{borrow::Cow, collections::HashSet, net::Ipv6Addr, ops::Range};
 
     use ipnet::{IpAdd, Ipv6Net};
-    use rand::distributions::{Distribution, Uniform};
+    use rand::{
+        distributions::{Distribution, Uniform},
+        seq::SliceRandom,
+        Rng,
+    };
 
     use super::{split::NetIndex, PrefixSplit, SplitSubnet};
 
@@ -307,15 +436,147 @@ mod sample {
         }
     }
 
+    /// Chooses which addresses of a subnet to probe. Samples are always topped up with
+    /// uniformly random addresses, so implementations only need to provide the addresses
+    /// they would prefer to probe.
+    pub trait Sampler {
+        /// Returns at most `count` distinct addresses from `subnet`.
+        fn preferred(&self, subnet: &Ipv6Net, count: u16) -> Vec<Ipv6Addr>;
+    }
+
+    /// Uniformly random addresses from the whole subnet.
+    pub struct UniformSampler;
+
+    impl Sampler for UniformSampler {
+        fn preferred(&self, _subnet: &Ipv6Net, _count: u16) -> Vec<Ipv6Addr> {
+            vec![]
+        }
+    }
+
+    /// Addresses with an interface identifier of `::1` to `::ff` in random /64s of the
+    /// subnet, which are more likely to be assigned to routers and servers.
+    pub struct LowByteSampler;
+
+    impl Sampler for LowByteSampler {
+        fn preferred(&self, subnet: &Ipv6Net, count: u16) -> Vec<Ipv6Addr> {
+            let mut rng = rand::thread_rng();
+            distinct(count, || {
+                random_slash64(subnet, &mut rng).saturating_add(rng.gen_range(1..=0xff))
+            })
+        }
+    }
+
+    /// The `::1` address of random /64s of the subnet.
+    pub struct FirstHostSampler;
+
+    impl Sampler for FirstHostSampler {
+        fn preferred(&self, subnet: &Ipv6Net, count: u16) -> Vec<Ipv6Addr> {
+            let mut rng = rand::thread_rng();
+            distinct(count, || random_slash64(subnet, &mut rng).saturating_add(1))
+        }
+    }
+
+    /// Random picks from a set of addresses that are known to exist, e.g. from a hitlist
+    /// or from previous measurements.
+    pub struct KnownAddrSampler<'a> {
+        known: Cow<'a, [Ipv6Addr]>,
+    }
+
+    impl KnownAddrSampler<'_> {
+        pub fn new(mut known: Vec<Ipv6Addr>) -> Self {
+            known.sort_unstable();
+            known.dedup();
+            Self {
+                known: Cow::Owned(known),
+            }
+        }
+    }
+
+    impl<'a> KnownAddrSampler<'a> {
+        /// `known` must be sorted and free of duplicates.
+        pub fn from_sorted(known: &'a [Ipv6Addr]) -> Self {
+            Self {
+                known: Cow::Borrowed(known),
+            }
+        }
+
+        pub fn known_in(&self, net: &Ipv6Net) -> &[Ipv6Addr] {
+            let start = self.known.partition_point(|it| *it < net.network());
+            let end = self.known.partition_point(|it| *it <= net.broadcast());
+            &self.known[start..end]
+        }
+    }
+
+    impl Sampler for KnownAddrSampler<'_> {
+        fn preferred(&self, subnet: &Ipv6Net, count: u16) -> Vec<Ipv6Addr> {
+            self.known_in(subnet)
+                .choose_multiple(&mut rand::thread_rng(), count as usize)
+                .copied()
+                .collect()
+        }
+    }
+
+    /// Prefers the known addresses, e.g. from a hitlist, and then asks another sampler
+    /// for the remaining addresses.
+    pub struct MixedSampler<'a> {
+        known: KnownAddrSampler<'a>,
+        rest: &'a dyn Sampler,
+    }
+
+    impl<'a> MixedSampler<'a> {
+        pub fn new(known: KnownAddrSampler<'a>, rest: &'a dyn Sampler) -> Self {
+            Self { known, rest }
+        }
+    }
+
+    impl Sampler for MixedSampler<'_> {
+        fn preferred(&self, subnet: &Ipv6Net, count: u16) -> Vec<Ipv6Addr> {
+            let mut result = self.known.preferred(subnet, count);
+            let missing = count.saturating_sub(result.len() as u16);
+            if missing > 0 {
+                for addr in self.rest.preferred(subnet, missing) {
+                    if !result.contains(&addr) {
+                        result.push(addr);
+                    }
+                }
+            }
+            result
+        }
+    }
+
+    /// Generated addresses can repeat for small subnets, so give up after a few attempts
+    const ATTEMPTS_PER_ADDRESS: usize = 4;
+
+    fn distinct(count: u16, mut generate: impl FnMut() -> Ipv6Addr) -> Vec<Ipv6Addr> {
+        let mut seen = HashSet::new();
+        let mut result = Vec::with_capacity(count as usize);
+        for _ in 0..(count as usize * ATTEMPTS_PER_ADDRESS) {
+            if result.len() >= count as usize {
+                break;
+            }
+            let addr = generate();
+            if seen.insert(addr) {
+                result.push(addr);
+            }
+        }
+        result
+    }
+
+    fn random_slash64(subnet: &Ipv6Net, rng: &mut impl Rng) -> Ipv6Addr {
+        let free_bits = 64_u8.saturating_sub(subnet.prefix_len());
+        let index = rng.gen_range(0..(1_u128 << free_bits));
+        subnet.network().saturating_add(index << 64)
+    }
+
     pub trait ToSubnetSamples {
-        fn to_samples(&self, hosts_per_sample: u16) -> Vec<SubnetSample>;
+        fn to_samples(&self, sampler: &dyn Sampler, hosts_per_sample: u16) -> Vec<SubnetSample>;
     }
 
     impl ToSubnetSamples for PrefixSplit {
-        fn to_samples(&self, hosts_per_sample: u16) -> Vec<SubnetSample> {
+        fn to_samples(&self, sampler: &dyn Sampler, hosts_per_sample: u16) -> Vec<SubnetSample> {
             let distribution = Uniform::from(determine_host_range(&self.base_net, self.subnet_prefix_len));
             self.iter()
-                .map(|subnet| to_sample(subnet, distribution, hosts_per_sample))
+                .map(|subnet| to_sample(subnet, sampler, distribution, hosts_per_sample))
                 .collect()
         }
     }
@@ -327,14 +588,17 @@ mod sample {
 
     fn to_sample(
         subnet: &SplitSubnet,
+        sampler: &dyn Sampler,
         distribution: Uniform<u128>,
         hosts_per_sample: u16,
     ) -> SubnetSample {
         let base_addr = subnet.network.network();
         let mut rng = rand::thread_rng();
-        let addresses = (0..hosts_per_sample)
-            .map(|_| base_addr.saturating_add(distribution.sample(&mut rng)))
-            .collect();
+        let mut addresses = sampler.preferred(&subnet.network, hosts_per_sample);
+        addresses.truncate(hosts_per_sample as usize);
+        let missing = hosts_per_sample as usize - addresses.len();
+        addresses
+            .extend((0..missing).map(|_| base_addr.saturating_add(distribution.sample(&mut rng))));
         SubnetSample {
             network: subnet.network,
             index: subnet.index,
@@ -348,13 +612,15 @@ mod sample {
             index: 0u8.try_into().expect("0 to be a net index"),
             network: *net,
         };
-        to_sample(&fake_net, distribution, hosts_per_sample)
+        to_sample(&fake_net, &UniformSampler, distribution, hosts_per_sample)
     }
 
     #[cfg(test)]
     mod tests {
         use super::*;
+        use crate::test_utils::{addr, net};
         use anyhow::*;
+        use assertor::*;
 
         #[test]
         fn addresses_in_subnet() -> Result<()> {
@@ -364,9 +630,9 @@ mod sample {
                 "2001:db8::/33".parse::<Ipv6Net>()?,
                 "2001:db8:8000::/33".parse::<Ipv6Net>()?,
             ];
-            let split = super::super::split(net)?;
+            let split = super::super::split(net, 1)?;
             // when
-            let result = &split.to_samples(512);
+            let result = &split.to_samples(&UniformSampler, 512);
 
             // then
             for (i, subnet) in subnets.iter().enumerate() {
@@ -376,5 +642,161 @@ mod sample {
             }
             Ok(())
         }
+
+        #[test]
+        fn first_host_in_subnet() -> Result<()> {
+            // given
+            let split = super::super::split(net("2001:db8::/48"), 2)?;
+
+            // when
+            let result = split.to_samples(&FirstHostSampler, 16);
+
+            // then
+            for sample in result {
+                assert_that!(sample.addresses).has_length(16);
+                for address in sample.addresses {
+                    assert!(sample.network.contains(&address));
+                    assert_that!(u128::from(address) & u128::from(u64::MAX)).is_equal_to(1);
+                }
+            }
+            Ok(())
+        }
+
+        #[test]
+        fn first_host_small_subnet_topped_up() -> Result<()> {
+            // given
+            let split = super::super::split(net("2001:db8::/62"), 1)?;
+
+            // when
+            let result = split.to_samples(&FirstHostSampler, 16);
+
+            // then
+            for sample in result {
+                assert_that!(sample.addresses).has_length(16);
+                let first_hosts = sample
+                    .addresses
+                    .iter()
+                    .filter(|it| u128::from(**it) & u128::from(u64::MAX) == 1)
+                    .count();
+                assert_that!(first_hosts).is_at_least(2);
+            }
+            Ok(())
+        }
+
+        #[test]
+        fn known_addresses_preferred() -> Result<()> {
+            // given
+            let sampler = KnownAddrSampler::new(vec![
+                addr("2001:db8:8000::5"),
+                addr("2001:db8::17"),
+                addr("2001:db9::1"),
+                addr("2001:db8::17"),
+            ]);
+            let split = super::super::split(net("2001:db8::/32"), 1)?;
+
+            // when
+            let result = split.to_samples(&sampler, 4);
+
+            // then
+            assert_that!(result[0].addresses).contains(addr("2001:db8::17"));
+            assert_that!(result[1].addresses).contains(addr("2001:db8:8000::5"));
+            assert_that!(result[0].addresses).does_not_contain(addr("2001:db9::1"));
+            assert_that!(result[0].addresses).has_length(4);
+            Ok(())
+        }
+
+        #[test]
+        fn mixed_known_first() -> Result<()> {
+            // given
+            let known = KnownAddrSampler::new(vec![addr("2001:db8::17"), addr("2001:db8::18")]);
+            let sampler = MixedSampler::new(known, &FirstHostSampler);
+            let split = super::super::split(net("2001:db8::/48"), 1)?;
+
+            // when
+            let result = split.to_samples(&sampler, 4);
+
+            // then
+            assert_that!(result[0].addresses).has_length(4);
+            assert_that!(result[0].addresses).contains(addr("2001:db8::17"));
+            assert_that!(result[0].addresses).contains(addr("2001:db8::18"));
+            let first_hosts = result[1]
+                .addresses
+                .iter()
+                .filter(|it| u128::from(**it) & u128::from(u64::MAX) == 1)
+                .count();
+            assert_that!(first_hosts).is_equal_to(4);
+            Ok(())
+        }
+
+        #[test]
+        fn known_in_range() {
+            // given
+            let sampler = KnownAddrSampler::new(vec![
+                addr("2001:db8::"),
+                addr("2001:db8::ffff:ffff:ffff:ffff"),
+                addr("2001:db8:0:1::"),
+                addr("2001:db7:ffff:ffff:ffff:ffff:ffff:ffff"),
+            ]);
+
+            // when
+            let result = sampler.known_in(&net("2001:db8::/64"));
+
+            // then
+            assert_that!(result.to_vec()).contains_exactly_in_order(vec![
+                addr("2001:db8::"),
+                addr("2001:db8::ffff:ffff:ffff:ffff"),
+            ]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::net;
+    use assertor::*;
+
+    #[test]
+    fn fan_out_default() {
+        // given
+        let fan_out = FanOut::default();
+
+        // when
+        let parsed: FanOut = "64:1".parse().unwrap();
+
+        // then
+        assert_that!(parsed).is_equal_to(fan_out.clone());
+        assert_that!(fan_out.bits_for(&net("2001:db8::/29"))).is_equal_to(1);
+        assert_that!(fan_out.bits_for(&net("2001:db8::/63"))).is_equal_to(1);
+    }
+
+    #[test]
+    fn fan_out_stops_at_boundary() {
+        // given
+        let fan_out: FanOut = "48:4,64:1".parse().unwrap();
+
+        // when
+        let at_32 = fan_out.bits_for(&net("2001:db8::/32"));
+        let at_46 = fan_out.bits_for(&net("2001:db8::/46"));
+        let at_48 = fan_out.bits_for(&net("2001:db8::/48"));
+
+        // then
+        assert_that!(at_32).is_equal_to(4);
+        assert_that!(at_46).is_equal_to(2);
+        assert_that!(at_48).is_equal_to(1);
+    }
+
+    #[test]
+    fn fan_out_invalid() {
+        // given
+        let inputs = ["", "64", "64:0", "64:5", "65:1", "64:1,48:4", "48:4,48:1"];
+
+        // when
+        let results = inputs.map(|it| it.parse::<FanOut>());
+
+        // then
+        for result in results {
+            assert_that!(result).is_err();
+        }
     }
 }
@@ -0,0 +1,614 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::File,
+    hash::Hasher,
+    io::{BufRead, BufReader},
+    net::Ipv6Addr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use clap::Args;
+use itertools::Itertools;
+use siphasher::sip::SipHasher13;
+use thiserror::Error;
+
+use ipnet::Ipv6Net;
+
+use crate::prefix_split::{FanOut, MAX_PREFIX_LEN};
+
+#[derive(Args, Clone)]
+#[group(id = "simulation")]
+pub struct Params {
+    /// Flatfile describing a simulated network topology. If given, no probes are actually
+    /// sent (and no sudo access is needed); responses are instead generated deterministically
+    /// from this file. Intended for end-to-end tests. See simulation-topology-example.txt
+    #[arg(long, env = "SIMULATION_TOPOLOGY_FILE")]
+    pub simulation_topology_file: Option<PathBuf>,
+}
+
+impl Params {
+    /// Reads the topology if simulation is enabled.
+    pub fn read_topology(&self) -> Result<Option<Topology>, TopologyReadError> {
+        self.simulation_topology_file
+            .as_ref()
+            .map(|path| read_from(path.to_path_buf()))
+            .transpose()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SimulatedBehaviour {
+    /// The target itself answers with an echo reply
+    EchoReply,
+    /// The last-hop router answers with destination unreachable and this code
+    DestinationUnreachable { code: u8 },
+    /// The last-hop router answers with time exceeded, even for the hop after itself (routing loop)
+    TimeExceeded,
+    /// Probes are silently dropped beyond the last-hop router
+    NoResponse,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WeightedRouter {
+    pub addr: Ipv6Addr,
+    /// Relative share of targets routed via this router
+    pub weight: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct TopologyEntry {
+    pub net: Ipv6Net,
+    /// Last-hop routers that targets in this prefix are distributed over
+    pub last_hops: Vec<WeightedRouter>,
+    /// TTL needed to reach the last-hop router; the target is one hop further
+    pub hops: u8,
+    pub behaviour: SimulatedBehaviour,
+    /// Share of probes that get lost, between 0 and 1
+    pub loss_rate: f64,
+    /// How many ICMP errors each last-hop router sends per run at most
+    pub rate_limit: Option<u32>,
+}
+
+impl TopologyEntry {
+    /// Last-hop router that the given target is routed through. Depends only on the
+    /// address, so that all probes to a target take the same path.
+    pub fn last_hop_for(&self, target: &Ipv6Addr) -> Ipv6Addr {
+        let total: u64 = self.last_hops.iter().map(|it| it.weight as u64).sum();
+        let mut roll = route_hash(target) % total.max(1);
+        for router in self.last_hops.iter() {
+            if roll < router.weight as u64 {
+                return router.addr;
+            }
+            roll -= router.weight as u64;
+        }
+        self.last_hops[0].addr
+    }
+}
+
+impl Display for TopologyEntry {
+    /// Same format as expected by [Params::read_topology]
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use SimulatedBehaviour as B;
+
+        let routers = self
+            .last_hops
+            .iter()
+            .map(|it| format!("{}={}", it.addr, it.weight))
+            .join(",");
+        let behaviour = match self.behaviour {
+            B::EchoReply => "echo".to_string(),
+            B::DestinationUnreachable { code } => format!("unreach:{}", code),
+            B::TimeExceeded => "timxceed".to_string(),
+            B::NoResponse => "none".to_string(),
+        };
+        write!(
+            f,
+            "{} {} {} {} {}",
+            self.net, routers, self.hops, behaviour, self.loss_rate
+        )?;
+        if let Some(limit) = self.rate_limit {
+            write!(f, " {}", limit)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimulatedResponse {
+    pub icmp_type: u8,
+    pub icmp_code: u8,
+    pub target: Ipv6Addr,
+    pub from: Ipv6Addr,
+    /// TTL the eliciting probe was sent with
+    pub sent_ttl: u8,
+    /// Distance of the responding node, for deriving the TTL of the response
+    pub responder_hops: u8,
+}
+
+pub const ICMP_DEST_UNREACH: u8 = 1;
+pub const ICMP_TIME_EXCEEDED: u8 = 3;
+pub const ICMP_ECHO_REPLY: u8 = 129;
+
+/// Simulated network, answering probes based on the most specific matching [TopologyEntry].
+/// Only the last-hop routers and the targets themselves are simulated, earlier hops stay silent.
+///
+/// Responses are fully deterministic: Whether a probe is lost depends only on its target
+/// address and TTL, so repeated runs (of the same build) yield identical results.
+#[derive(Debug, Clone)]
+pub struct Topology {
+    entries: Vec<TopologyEntry>,
+}
+
+impl Topology {
+    pub fn new(mut entries: Vec<TopologyEntry>) -> Self {
+        // Most specific first, so that the first match is the longest one
+        entries.sort_by_key(|it| std::cmp::Reverse(it.net.prefix_len()));
+        Self { entries }
+    }
+
+    pub fn entries(&self) -> &[TopologyEntry] {
+        &self.entries
+    }
+
+    /// Most specific entry covering the given network, if any.
+    pub fn find_covering(&self, net: &Ipv6Net) -> Option<&TopologyEntry> {
+        self.entries.iter().find(|it| it.net.contains(net))
+    }
+
+    fn find(&self, target: &Ipv6Addr) -> Option<&TopologyEntry> {
+        self.entries.iter().find(|it| it.net.contains(target))
+    }
+
+    /// Ground truth for the prefix tree: The least specific prefixes (reachable by splits of
+    /// `net` with the given fan-out) where all addresses share the same set of last-hop routers,
+    /// i.e. the leaves that a perfect analysis would end up with. Never splits beyond
+    /// [MAX_PREFIX_LEN].
+    pub fn ideal_leaves(&self, net: Ipv6Net, fan_out: &FanOut) -> Vec<Ipv6Net> {
+        if net.prefix_len() >= MAX_PREFIX_LEN || self.is_homogeneous(&net) {
+            return vec![net];
+        }
+        let subnet_len = (net.prefix_len() + fan_out.bits_for(&net)).min(MAX_PREFIX_LEN);
+        net.subnets(subnet_len)
+            .expect("prefix len below max to be splittable")
+            .flat_map(|subnet| self.ideal_leaves(subnet, fan_out))
+            .collect_vec()
+    }
+
+    fn is_homogeneous(&self, net: &Ipv6Net) -> bool {
+        let own = lhr_signature(self.find_covering(net));
+        self.entries
+            .iter()
+            .filter(|it| it.net.prefix_len() > net.prefix_len() && net.contains(&it.net))
+            .all(|it| lhr_signature(Some(it)) == own)
+    }
+
+    /// Starts a new probing run, which corresponds to a single call of the real prober.
+    /// Rate limits are applied per run.
+    pub fn start_run(&self) -> SimulatedRun<'_> {
+        SimulatedRun {
+            topology: self,
+            router_responses: HashMap::new(),
+        }
+    }
+}
+
+pub struct SimulatedRun<'top> {
+    topology: &'top Topology,
+    router_responses: HashMap<Ipv6Addr, u32>,
+}
+
+impl SimulatedRun<'_> {
+    /// Response to a single echo request sent with the given TTL, if any.
+    pub fn echo(&mut self, target: &Ipv6Addr, sent_ttl: u8) -> Option<SimulatedResponse> {
+        let topology = self.topology;
+        let entry = topology.find(target)?;
+        if sent_ttl <= entry.hops {
+            return None;
+        }
+        self.answer(entry, target, sent_ttl)
+    }
+
+    /// Responses to probes sent with each TTL in the (inclusive) range.
+    pub fn trace(&mut self, target: &Ipv6Addr, min_ttl: u8, max_ttl: u8) -> Vec<SimulatedResponse> {
+        let topology = self.topology;
+        let Some(entry) = topology.find(target) else {
+            return vec![];
+        };
+        (min_ttl..=max_ttl)
+            .filter_map(|ttl| {
+                if ttl == entry.hops {
+                    let last_hop = entry.last_hop_for(target);
+                    self.respond(entry, target, ttl, (ICMP_TIME_EXCEEDED, 0), last_hop, entry.hops)
+                } else if ttl == entry.hops.saturating_add(1) {
+                    self.answer(entry, target, ttl)
+                } else {
+                    None
+                }
+            })
+            .collect_vec()
+    }
+
+    fn answer(
+        &mut self,
+        entry: &TopologyEntry,
+        target: &Ipv6Addr,
+        sent_ttl: u8,
+    ) -> Option<SimulatedResponse> {
+        use SimulatedBehaviour as B;
+
+        let last_hop = entry.last_hop_for(target);
+        let (icmp, from, responder_hops) = match entry.behaviour {
+            B::EchoReply => ((ICMP_ECHO_REPLY, 0), *target, entry.hops.saturating_add(1)),
+            B::DestinationUnreachable { code } => ((ICMP_DEST_UNREACH, code), last_hop, entry.hops),
+            B::TimeExceeded => ((ICMP_TIME_EXCEEDED, 0), last_hop, entry.hops),
+            B::NoResponse => return None,
+        };
+        self.respond(entry, target, sent_ttl, icmp, from, responder_hops)
+    }
+
+    fn respond(
+        &mut self,
+        entry: &TopologyEntry,
+        target: &Ipv6Addr,
+        sent_ttl: u8,
+        (icmp_type, icmp_code): (u8, u8),
+        from: Ipv6Addr,
+        responder_hops: u8,
+    ) -> Option<SimulatedResponse> {
+        if is_lost(target, sent_ttl, entry.loss_rate) {
+            return None;
+        }
+        if from != *target && self.is_rate_limited(entry, from) {
+            return None;
+        }
+        Some(SimulatedResponse {
+            icmp_type,
+            icmp_code,
+            target: *target,
+            from,
+            sent_ttl,
+            responder_hops,
+        })
+    }
+
+    fn is_rate_limited(&mut self, entry: &TopologyEntry, router: Ipv6Addr) -> bool {
+        let Some(limit) = entry.rate_limit else {
+            return false;
+        };
+        let sent = self.router_responses.entry(router).or_default();
+        if *sent >= limit {
+            true
+        } else {
+            *sent += 1;
+            false
+        }
+    }
+}
+
+/// Last-hop routers as observable by tracing, sorted. Empty if the prefix does not respond.
+fn lhr_signature(entry: Option<&TopologyEntry>) -> Vec<Ipv6Addr> {
+    match entry {
+        Some(entry) if entry.behaviour != SimulatedBehaviour::NoResponse => {
+            entry.last_hops.iter().map(|it| it.addr).sorted().dedup().collect_vec()
+        }
+        _ => vec![],
+    }
+}
+
+/// Keys are fixed and the input is written as explicit bytes (instead of via [std::hash::Hash]),
+/// so that simulated routes and losses are reproducible across runs, platforms and Rust releases.
+const LOSS_HASH_KEYS: (u64, u64) = (0x7072_6566_6978, 0x6372_6162);
+/// Separate keys for routing, so that the last hop of a target does not correlate with its losses
+const ROUTE_HASH_KEYS: (u64, u64) = (0x6c61_7374_686f, 0x726f_7574);
+
+fn route_hash(target: &Ipv6Addr) -> u64 {
+    let mut hasher = SipHasher13::new_with_keys(ROUTE_HASH_KEYS.0, ROUTE_HASH_KEYS.1);
+    hasher.write(&target.octets());
+    hasher.finish()
+}
+
+fn stable_hash(target: &Ipv6Addr, sent_ttl: u8) -> u64 {
+    let mut hasher = SipHasher13::new_with_keys(LOSS_HASH_KEYS.0, LOSS_HASH_KEYS.1);
+    hasher.write(&target.octets());
+    hasher.write_u8(sent_ttl);
+    hasher.finish()
+}
+
+fn is_lost(target: &Ipv6Addr, sent_ttl: u8, loss_rate: f64) -> bool {
+    if loss_rate <= 0.0 {
+        return false;
+    }
+    let roll = stable_hash(target, sent_ttl) as f64 / u64::MAX as f64;
+    roll < loss_rate
+}
+
+/// Reads back the targets that a prober was given in a file, one address per line.
+pub fn read_targets(path: &Path) -> anyhow::Result<Vec<Ipv6Addr>> {
+    let file = File::open(path).with_context(|| format!("opening targets file {:?}", path))?;
+    BufReader::new(file)
+        .lines()
+        .map(|line| {
+            let line = line.context("reading targets file")?;
+            line.parse::<Ipv6Addr>()
+                .with_context(|| format!("invalid target address `{}`", line))
+        })
+        .collect()
+}
+
+#[derive(Error, Debug)]
+pub enum TopologyReadError {
+    #[error("topology file does not exist: `{0}`")]
+    NoSuchFile(PathBuf),
+
+    #[error("failed to open topology file `{path}`")]
+    FailedOpen {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("failed to read a line from topology file")]
+    FailedRead { source: std::io::Error },
+
+    #[error("invalid topology entry `{line}`: {reason}")]
+    EntrySyntax { line: String, reason: String },
+}
+
+fn read_from(path: PathBuf) -> Result<Topology, TopologyReadError> {
+    use TopologyReadError as E;
+
+    if !path.is_file() {
+        return Err(E::NoSuchFile(path));
+    }
+
+    let mut entries = vec![];
+    let file = File::open(path.clone()).map_err(|source| E::FailedOpen { path, source })?;
+    let lines = BufReader::new(file)
+        .lines()
+        .filter_ok(|line| !line.starts_with('#') && !line.trim().is_empty());
+
+    for line_res in lines {
+        let line = line_res.map_err(|source| E::FailedRead { source })?;
+        match parse_entry(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(reason) => return Err(E::EntrySyntax { line, reason }),
+        }
+    }
+
+    Ok(Topology::new(entries))
+}
+
+fn parse_entry(line: &str) -> Result<TopologyEntry, String> {
+    let parts = line.split_whitespace().collect_vec();
+    if !(4..=6).contains(&parts.len()) {
+        return Err(format!("expected 4 to 6 fields, got {}", parts.len()));
+    }
+    let net = parts[0].parse().map_err(|e| format!("prefix: {}", e))?;
+    let last_hops = parts[1]
+        .split(',')
+        .map(parse_router)
+        .collect::<Result<Vec<_>, _>>()?;
+    let hops = parts[2].parse().map_err(|e| format!("hops: {}", e))?;
+    let behaviour = parse_behaviour(parts[3])?;
+    let loss_rate = match parts.get(4) {
+        Some(raw) => raw.parse().map_err(|e| format!("loss rate: {}", e))?,
+        None => 0.0,
+    };
+    if !(0.0..=1.0).contains(&loss_rate) {
+        return Err(format!("loss rate must be between 0 and 1, got {}", loss_rate));
+    }
+    let rate_limit = match parts.get(5) {
+        Some(raw) => Some(raw.parse().map_err(|e| format!("rate limit: {}", e))?),
+        None => None,
+    };
+    Ok(TopologyEntry {
+        net,
+        last_hops,
+        hops,
+        behaviour,
+        loss_rate,
+        rate_limit,
+    })
+}
+
+fn parse_router(raw: &str) -> Result<WeightedRouter, String> {
+    let (addr, weight) = match raw.split_once('=') {
+        Some((addr, weight)) => (addr, weight.parse().map_err(|e| format!("weight: {}", e))?),
+        None => (raw, 1),
+    };
+    if weight == 0 {
+        return Err(format!("weight of router {} must be positive", addr));
+    }
+    Ok(WeightedRouter {
+        addr: addr.parse().map_err(|e| format!("last hop: {}", e))?,
+        weight,
+    })
+}
+
+fn parse_behaviour(raw: &str) -> Result<SimulatedBehaviour, String> {
+    use SimulatedBehaviour as B;
+
+    match raw.split_once(':') {
+        Some(("unreach", code)) => Ok(B::DestinationUnreachable {
+            code: code.parse().map_err(|e| format!("unreach code: {}", e))?,
+        }),
+        None if raw == "echo" => Ok(B::EchoReply),
+        None if raw == "timxceed" => Ok(B::TimeExceeded),
+        None if raw == "none" => Ok(B::NoResponse),
+        _ => Err(format!("unknown response `{}`", raw)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use assertor::*;
+
+    use crate::test_utils::{addr, net};
+
+    use super::*;
+
+    #[test]
+    fn loss_hash_is_stable() {
+        // given
+        let target = addr("2001:db8::1");
+        // when
+        let hash = stable_hash(&target, 64);
+        // then
+        // must only change deliberately, since it would change simulated results
+        assert_that!(hash).is_equal_to(0x1b6b_da33_129b_7987);
+    }
+
+    fn load_example() -> Topology {
+        read_from(PathBuf::from("simulation-topology-example.txt")).expect("example to load")
+    }
+
+    #[test]
+    fn example_topology_echo() {
+        // given
+        let topology = load_example();
+        let mut run = topology.start_run();
+        // when
+        let echo = run.echo(&addr("2001:db8:100::8"), 64);
+        let unreach = run.echo(&addr("2001:db8:100:8000::7"), 64);
+        let silent = run.echo(&addr("2001:db8:400::7"), 64);
+        let unknown = run.echo(&addr("2001:db9::7"), 64);
+        // then
+        let echo = echo.expect("echo response");
+        assert_that!(echo.icmp_type).is_equal_to(ICMP_ECHO_REPLY);
+        assert_that!(echo.from).is_equal_to(addr("2001:db8:100::8"));
+        let unreach = unreach.expect("unreach response");
+        assert_that!(unreach.icmp_type).is_equal_to(ICMP_DEST_UNREACH);
+        assert_that!(unreach.icmp_code).is_equal_to(3);
+        assert_that!(unreach.from).is_equal_to(addr("2001:db8:ffff::180"));
+        assert_that!(silent).is_none();
+        assert_that!(unknown).is_none();
+    }
+
+    #[test]
+    fn trace_yields_last_hop_and_target() {
+        // given
+        let topology = load_example();
+        let target = addr("2001:db8:100::7");
+        // when
+        let responses = topology.start_run().trace(&target, 2, 16);
+        // then
+        let summary = responses
+            .iter()
+            .map(|it| (it.sent_ttl, it.icmp_type, it.from))
+            .collect_vec();
+        assert_that!(summary).contains_exactly_in_order(vec![
+            (9, ICMP_TIME_EXCEEDED, addr("2001:db8:ffff::100")),
+            (10, ICMP_ECHO_REPLY, target),
+        ]);
+    }
+
+    #[test]
+    fn loss_is_deterministic() {
+        // given
+        let topology = load_example();
+        let targets = (0..200u16)
+            .map(|i| addr(&format!("2001:db8:200::{:x}", i)))
+            .collect_vec();
+        // when
+        let mut first_run = topology.start_run();
+        let first = targets.iter().map(|it| first_run.echo(it, 64)).collect_vec();
+        let mut second_run = topology.start_run();
+        let second = targets.iter().map(|it| second_run.echo(it, 64)).collect_vec();
+        // then
+        assert_that!(first).is_equal_to(second);
+        let lost = first.iter().filter(|it| it.is_none()).count();
+        assert_that!(lost).is_greater_than(0);
+        assert_that!(lost).is_less_than(200);
+    }
+
+    #[test]
+    fn rejects_invalid_entry() {
+        // given
+        let line = "2001:db8::/32 2001:db8::1 8 bounce";
+        // when
+        let res = parse_entry(line);
+        // then
+        assert_that!(res).is_err();
+    }
+
+    #[test]
+    fn rate_limit_applies_per_router_and_run() {
+        // given
+        let entry = parse_entry("2001:db8:500::/40 2001:db8:ffff::500,2001:db8:ffff::501 6 unreach:5 0 10");
+        let topology = Topology::new(vec![entry.expect("entry to parse")]);
+        let targets = (0..100u16)
+            .map(|i| addr(&format!("2001:db8:500::{:x}", i)))
+            .collect_vec();
+        // when
+        let mut run = topology.start_run();
+        let responses = targets.iter().filter_map(|it| run.echo(it, 64)).collect_vec();
+        let mut next_run = topology.start_run();
+        let next_response = next_run.echo(&targets[0], 64);
+        // then
+        let per_router = responses.iter().counts_by(|it| it.from);
+        assert_that!(per_router.len()).is_equal_to(2);
+        assert_that!(per_router.values().all(|it| *it == 10)).is_true();
+        assert_that!(next_response).is_some();
+    }
+
+    #[test]
+    fn entry_display_roundtrips() {
+        // given
+        let line = "2001:db8:500::/40 2001:db8:ffff::500=3,2001:db8:ffff::501=1 6 unreach:5 0.05 10";
+        let entry = parse_entry(line).expect("entry to parse");
+        // when
+        let displayed = entry.to_string();
+        // then
+        assert_that!(displayed.as_str()).is_equal_to(line);
+    }
+
+    #[test]
+    fn ideal_leaves_split_only_where_lhrs_differ() {
+        // given
+        let topology = Topology::new(
+            [
+                "2001:db8:100::/40 2001:db8:ffff::1 8 unreach:3",
+                "2001:db8:100::/42 2001:db8:ffff::1 8 echo", // same LHR, no split needed
+                "2001:db8:1c0::/42 2001:db8:ffff::2 8 unreach:3",
+            ]
+            .into_iter()
+            .map(|it| parse_entry(it).expect("entry to parse"))
+            .collect_vec(),
+        );
+        // when
+        let leaves = topology.ideal_leaves(net("2001:db8:100::/40"), &FanOut::default());
+        // then
+        assert_that!(leaves).contains_exactly_in_order(vec![
+            net("2001:db8:100::/41"),
+            net("2001:db8:180::/42"),
+            net("2001:db8:1c0::/42"),
+        ]);
+    }
+
+    #[test]
+    fn ideal_leaves_follow_fan_out() {
+        // given
+        let topology = Topology::new(
+            [
+                "2001:db8:100::/40 2001:db8:ffff::1 8 unreach:3",
+                "2001:db8:1c0::/42 2001:db8:ffff::2 8 unreach:3",
+            ]
+            .into_iter()
+            .map(|it| parse_entry(it).expect("entry to parse"))
+            .collect_vec(),
+        );
+        let fan_out = "44:2,64:1".parse::<FanOut>().expect("fan-out to parse");
+        // when
+        let leaves = topology.ideal_leaves(net("2001:db8:100::/40"), &fan_out);
+        // then
+        assert_that!(leaves).contains_exactly_in_order(vec![
+            net("2001:db8:100::/42"),
+            net("2001:db8:140::/42"),
+            net("2001:db8:180::/42"),
+            net("2001:db8:1c0::/42"),
+        ]);
+    }
+}
@@ -4,23 +4,25 @@ use anyhow::bail;
 use itertools::Itertools;
 use log::{warn, info};
 use queue_models::{
-    probe_request::TraceRequestId,
-    probe_response::{DestUnreachKind, TraceResponseType},
+    probe_request::{TraceRequest, TraceRequestId},
+    probe_response::{DestUnreachKind, LastHop, TraceResponseType, TraceResult},
+    VantagePointId,
 };
 
-use crate::schedule::{ProbeResponse, TaskRequest};
+use crate::schedule::{ProbeOutput, ProbeResponse, TaskRequest};
 
 #[derive(Debug, Default)]
 pub struct ProbeStore {
     store: HashMap<Ipv6Addr, Target>,
     empty_requests: Vec<TraceRequestId>,
-    acks_per_request: HashMap<u128, u64>,
+    /// request id -> group without targets, which are only added once the store is consumed
+    groups: HashMap<u128, RequestGroup>,
 }
 
 impl ProbeStore {
     pub fn request_all(&mut self, req: &TaskRequest) {
-        self.acks_per_request
-            .insert(req.model.id.uuid().as_u128(), req.delivery_tag_to_ack);
+        self.groups
+            .insert(req.model.id.uuid().as_u128(), RequestGroup::new(req));
         if req.model.targets.is_empty() {
             self.empty_requests.push(req.model.id);
             return;
@@ -40,7 +42,28 @@ impl ProbeStore {
         }
     }
 
-    pub fn register_response(&mut self, response: ProbeResponse) {
+    pub fn register(&mut self, output: ProbeOutput) {
+        match output {
+            ProbeOutput::Hop(response) => self.register_response(response),
+            ProbeOutput::Result(TraceResult::LastResponsiveHop(last_hop)) => {
+                self.register_last_hop(last_hop)
+            }
+            // Targets without a last hop are reported as such anyways
+            ProbeOutput::Result(TraceResult::NoResponse { .. }) => {}
+        }
+    }
+
+    fn register_last_hop(&mut self, last_hop: LastHop) {
+        match self.store.get_mut(&last_hop.target_addr) {
+            Some(target) => target.resolve(last_hop),
+            None => info!(
+                "Received last hop for an unknown target {}, ignoring.",
+                last_hop.target_addr
+            ),
+        }
+    }
+
+    fn register_response(&mut self, response: ProbeResponse) {
         let key = if response.intended_target.is_unspecified() { // intended may be :: if we don't know
             response.actual_from // <-- likely not super useful, as this is the router, but better than ::
         } else {
@@ -50,7 +73,8 @@ impl ProbeStore {
         if entry.is_none() {
             // most likely this is an echo reply coming from the router directly, and it wasn't caught in the
             // ZMAP stage due to ICMP rate limiting or similar. Echo replies don't quote the incoming packet header,
-            // so the intended target cannot be determined (?).
+            // so yarrp cannot determine the intended target. The native Tracer encodes it in the echoed payload and
+            // resolves last hops itself.
             info!(
                 "Received response {}/{} for an unknown target {} - directed at {} and coming from {}, ignoring.",
                  response.icmp_type, response.icmp_code, key, response.intended_target, response.actual_from
@@ -101,6 +125,16 @@ impl Target {
         }
     }
 
+    /// Takes over a last hop that the prober resolved from all responses to the target so far
+    fn resolve(&mut self, last_hop: LastHop) {
+        self.target_own_ttl = last_hop.target_ttl;
+        self.last_hop = Some(Hop {
+            addr: last_hop.last_hop_addr,
+            sent_ttl: last_hop.last_hop_ttl,
+            response_type: last_hop.response_type,
+        });
+    }
+
     fn is_better_last_hop(&self, hop: &Hop) -> bool {
         match &self.last_hop {
             None => true,
@@ -146,24 +180,58 @@ pub struct RequestGroup {
     pub request_id: TraceRequestId,
     pub targets: Vec<Target>,
     pub delivery_tag: u64,
+    pub redelivered: bool,
+    pub vantage_point: Option<VantagePointId>,
+    pub is_remainder: bool,
 }
 
 impl RequestGroup {
-    fn new(request_id: TraceRequestId, delivery_tag: u64) -> Self {
+    fn new(req: &TaskRequest) -> Self {
         Self {
-            request_id,
+            request_id: req.model.id,
             targets: vec![],
-            delivery_tag,
+            delivery_tag: req.delivery_tag_to_ack,
+            redelivered: req.redelivered,
+            vantage_point: req.model.vantage_point.clone(),
+            is_remainder: req.model.is_remainder,
         }
     }
 
     fn add(&mut self, entry: Target) {
         self.targets.push(entry);
     }
+
+    /// Whether a last hop was found for any target
+    pub fn has_responses(&self) -> bool {
+        self.targets.iter().any(|it| it.last_hop.is_some())
+    }
+
+    /// Request for the targets without a last hop, keeping the id such that upstream can
+    /// match it. Remainders don't have a remainder themselves, so targets aren't retried forever.
+    pub fn remainder(&self) -> Option<TraceRequest> {
+        if self.is_remainder {
+            return None;
+        }
+        let targets = self
+            .targets
+            .iter()
+            .filter(|it| it.last_hop.is_none())
+            .map(|it| it.addr)
+            .collect_vec();
+        if targets.is_empty() {
+            return None;
+        }
+        Some(TraceRequest {
+            id: self.request_id,
+            targets,
+            vantage_point: self.vantage_point.clone(),
+            is_remainder: true,
+        })
+    }
 }
 
 impl ProbeStore {
-    pub fn into_request_groups(self) -> Vec<RequestGroup> {
+    pub fn into_request_groups(mut self) -> Vec<RequestGroup> {
         let group_by = self
             .store
             .into_values()
@@ -172,7 +240,7 @@ impl ProbeStore {
 
         let mut results = vec![];
         for (id, entries) in group_by.into_iter() {
-            let mut group = Self::make_group(&self.acks_per_request, id);
+            let mut group = Self::take_group(&mut self.groups, id);
             for entry in entries {
                 group.add(entry)
             }
@@ -180,15 +248,14 @@ impl ProbeStore {
         }
         for id in self.empty_requests {
             // Upstream clears some less-promising requests to reduce overall load
-            results.push(Self::make_group(&self.acks_per_request, id));
+            results.push(Self::take_group(&mut self.groups, id));
         }
         results
     }
 
-    fn make_group(acks_per_request: &HashMap<u128, u64>, id: TraceRequestId) -> RequestGroup {
-        let delivery_tag = acks_per_request
-            .get(&id.uuid().as_u128())
-            .expect("request to be in ack store");
-        RequestGroup::new(id, *delivery_tag)
+    fn take_group(groups: &mut HashMap<u128, RequestGroup>, id: TraceRequestId) -> RequestGroup {
+        groups
+            .remove(&id.uuid().as_u128())
+            .expect("request to be in group store")
     }
 }
@@ -5,6 +5,8 @@ use tokio::sync::mpsc;
 use tokio::sync::mpsc::UnboundedReceiver;
 use tokio_util::sync::CancellationToken;
 
+use queue_models::{VantagePointId, DEFAULT_VANTAGE_POINT};
+
 use crate::schedule::{TaskRequest, TaskResponse};
 
 /// Handles configuration and connection setup.
@@ -37,6 +39,11 @@ pub struct Params {
     /// Whether to pretty print JSON in RabbitMQ responses.
     #[arg(long, env = "PRETTY_PRINT")]
     pretty_print: bool,
+
+    /// Vantage point this buddy probes from, which is attached to all responses. Buddies
+    /// at different vantage points need distinct queue names to each receive requests.
+    #[arg(long, env = "VANTAGE_POINT", default_value = DEFAULT_VANTAGE_POINT)]
+    vantage_point: VantagePointId,
 }
 
 pub async fn run(
@@ -48,7 +55,13 @@ pub async fn run(
     let handle = prepare::prepare(&params)
         .await?;
     let sender = send::run(
-        &handle, result_receiver, params.out_exchange_name, params.pretty_print, stop_rx.clone(),
+        &handle,
+        result_receiver,
+        params.out_exchange_name,
+        params.in_exchange_name,
+        params.pretty_print,
+        params.vantage_point,
+        stop_rx.clone(),
     );
     let receiver = receive::run(
         &handle, params.in_queue_name, work_sender, stop_rx,
@@ -2,7 +2,7 @@ use anyhow::*;
 
 use prefix_crab::helpers::rabbit::ConfigureRabbit;
 pub use prefix_crab::helpers::rabbit::RabbitHandle;
-use queue_models::{probe_request::TraceRequest, TypeRoutedMessage};
+use queue_models::{probe_request::TraceRequest, vantage_routing_key, TypeRoutedMessage};
 
 use super::Params;
 
@@ -22,6 +22,12 @@ pub async fn prepare(params: &Params) -> Result<RabbitHandle> {
         .declare_exchange(in_exchange_name, "direct")
         .await?
         .bind_queue_routing(queue_name, in_exchange_name, TraceRequest::routing_key())
+        .await?
+        .bind_queue_routing(
+            queue_name,
+            in_exchange_name,
+            &vantage_routing_key(TraceRequest::routing_key(), &params.vantage_point),
+        )
         .await?;
 
     Ok(handle)
@@ -42,6 +42,8 @@ impl MessageHandler for TaskHandler {
         let request = TaskRequest {
             model,
             delivery_tag_to_ack: deliver.delivery_tag(),
+            redelivered: deliver.redelivered(),
+            deadline: None,
         };
         self.work_sender
             .send(request)
@@ -1,11 +1,15 @@
-use amqprs::channel::{BasicAckArguments, BasicPublishArguments};
+use std::fmt::Debug;
+
+use amqprs::channel::{BasicAckArguments, BasicNackArguments, BasicPublishArguments};
 use amqprs::BasicProperties;
 use anyhow::{Context, Result};
-use log::warn;
+use log::{info, warn};
 use prefix_crab::loop_with_stop;
-use queue_models::RoutedMessage;
+use queue_models::{RoutedMessage, VantagePointId};
+use serde::Serialize;
 use tokio::sync::mpsc::UnboundedReceiver;
 
+use queue_models::probe_request::TraceRequest;
 use queue_models::probe_response::TraceResponse;
 use tokio_util::sync::CancellationToken;
 
@@ -15,21 +19,28 @@ use super::prepare::RabbitHandle;
 
 struct RabbitSender<'han> {
     exchange_name: String,
+    /// Where remainders of partial traces are returned to, i.e. the exchange requests come from
+    request_exchange_name: String,
     handle: &'han RabbitHandle,
     pretty_print: bool,
+    vantage_point: VantagePointId,
 }
 
 pub async fn run(
     handle: &RabbitHandle,
     work_rx: UnboundedReceiver<TaskResponse>,
     exchange_name: String,
+    request_exchange_name: String,
     pretty_print: bool,
+    vantage_point: VantagePointId,
     stop_rx: CancellationToken,
 ) -> Result<()> {
     RabbitSender {
         exchange_name,
+        request_exchange_name,
         handle,
         pretty_print,
+        vantage_point,
     }
     .run(work_rx, stop_rx)
     .await
@@ -49,7 +60,21 @@ impl RabbitSender<'_> {
     }
 
     async fn do_send(&mut self, msg: TaskResponse) -> Result<()> {
-        let res = {
+        let res = if msg.requeue {
+            warn!(
+                "Nothing resolved for {} ({:?}), returning it to the queue.",
+                msg.model.id, msg.model.incomplete
+            );
+            self.requeue(msg.acks_delivery_tag).await
+        } else {
+            if let Some(remainder) = &msg.remainder {
+                info!(
+                    "Returning {} unresolved targets of {} to the queue.",
+                    remainder.targets.len(),
+                    remainder.id
+                );
+                self.publish_remainder(remainder).await?;
+            }
             self.publish(msg.model).await?;
             self.ack(msg.acks_delivery_tag).await
         };
@@ -59,12 +84,29 @@ impl RabbitSender<'_> {
         Ok(())
     }
 
-    async fn publish(&self, msg: TraceResponse) -> Result<()> {
+    async fn publish(&self, mut msg: TraceResponse) -> Result<()> {
+        msg.vantage_point = self.vantage_point.clone();
         let args = BasicPublishArguments::new(&self.exchange_name, msg.routing_key());
+        self.publish_json(&msg, args).await
+    }
+
+    /// Published before the partial response, such that it is never missing once the
+    /// response announces it
+    async fn publish_remainder(&self, msg: &TraceRequest) -> Result<()> {
+        let args =
+            BasicPublishArguments::new(&self.request_exchange_name, &msg.vantage_routing_key());
+        self.publish_json(msg, args).await
+    }
+
+    async fn publish_json<T: Serialize + Debug>(
+        &self,
+        msg: &T,
+        args: BasicPublishArguments,
+    ) -> Result<()> {
         let bin = if self.pretty_print {
-            serde_json::to_vec_pretty(&msg)
+            serde_json::to_vec_pretty(msg)
         } else {
-            serde_json::to_vec(&msg)
+            serde_json::to_vec(msg)
         }
         .with_context(|| format!("during serialisation of {:?}", msg))?;
         self.handle
@@ -83,4 +125,17 @@ impl RabbitSender<'_> {
             .with_context(|| "during ack")?;
         Ok(())
     }
+
+    async fn requeue(&self, delivery_tag: u64) -> Result<()> {
+        self.handle
+            .chan()
+            .basic_nack(BasicNackArguments::new(
+                delivery_tag,
+                /* multiple = */ false,
+                /* requeue = */ true,
+            ))
+            .await
+            .with_context(|| "during nack")?;
+        Ok(())
+    }
 }
@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Context, Result};
 use clap::Args;
@@ -11,7 +11,7 @@ use tokio_stream::{Stream, StreamExt};
 use crate::schedule::task::SchedulerTask;
 use crate::yarrp_call;
 
-pub use self::model::{ProbeResponse, TaskRequest, TaskResponse};
+pub use self::model::{ProbeOutput, ProbeResponse, TaskRequest, TaskResponse};
 
 mod model;
 mod task;
@@ -34,6 +34,11 @@ pub struct Params {
     /// buffered, a chunk is immediately created and zmap will be invoked.
     #[arg(long, default_value = "32", env = "YARRP_MAX_CHUNK_SIZE")]
     max_chunk_size: usize,
+
+    /// How long tracing for a request may take at most. After that, partial results are
+    /// published for the targets that were resolved so far.
+    #[arg(long, default_value = "1800", env = "YARRP_REQUEST_TIMEOUT_SECS")]
+    request_timeout_secs: u64,
 }
 
 struct Scheduler {
@@ -72,14 +77,11 @@ impl Scheduler {
     async fn check_preflight(&self) -> Result<()> {
         let params = self.params.clone();
         tokio::task::spawn_blocking(move || {
-            params
-                .base
-                .to_caller_verifying_sudo()?
-                .verify_sudo_access()?;
+            params.base.to_prober_verifying_access()?;
             Ok::<(), anyhow::Error>(())
         })
         .await
-        .context("pre-flight sudo access check failed")??;
+        .context("pre-flight prober access check failed")??;
         info!(
             "Loading blocklist from `{:?}`",
             params.blocklist.blocklist_file
@@ -98,12 +100,16 @@ impl Scheduler {
             }
         }
     }
-    async fn do_scan_batch(&self, chunk: Vec<TaskRequest>) -> Result<()> {
+    async fn do_scan_batch(&self, mut chunk: Vec<TaskRequest>) -> Result<()> {
         info!(
             "Scanning a batch of {} requests, comprising {} targets...", 
             chunk.len(),
             chunk.iter().map(|req| req.model.targets.len()).sum::<usize>(),
         );
+        let deadline = Instant::now() + Duration::from_secs(self.params.request_timeout_secs);
+        for item in chunk.iter_mut() {
+            item.deadline = Some(deadline);
+        }
         let mut task = SchedulerTask::new(self.params.clone())?;
         let mut at_least_one_ok = false;
         for item in chunk.into_iter() {
@@ -118,12 +124,6 @@ impl Scheduler {
                 "None of the work in this chunk could be pushed successfully"
             ));
         }
-        let results = task.run().await?;
-        for result in results {
-            self.result_tx
-                .send(result)
-                .with_context(|| "while sending response over channel")?;
-        }
-        Ok(())
+        task.run(&self.result_tx).await
     }
 }
\ No newline at end of file
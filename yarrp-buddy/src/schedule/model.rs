@@ -1,5 +1,6 @@
 use std::net::Ipv6Addr;
-use queue_models::probe_response::TraceResponse;
+use std::time::Instant;
+use queue_models::probe_response::{TraceResponse, TraceResult};
 use queue_models::probe_request::TraceRequest;
 
 #[derive(Debug, serde::Deserialize)]
@@ -12,6 +13,16 @@ pub struct ProbeResponse {
     pub received_ttl: u8,
 }
 
+/// What a prober reports while tracing
+#[derive(Debug)]
+pub enum ProbeOutput {
+    /// Response from a single hop, which is aggregated into the last hop of its target later
+    Hop(ProbeResponse),
+    /// Last hop of a target as resolved by the prober itself, which supersedes any earlier
+    /// output for the same target
+    Result(TraceResult),
+}
+
 #[derive(Debug)]
 pub struct TaskRequest {
     pub model: TraceRequest,
@@ -20,6 +31,14 @@ pub struct TaskRequest {
     /// This is used so that only requests that were actually fully processed are
     /// removed from the queue (e.g. buddy crashes).
     pub delivery_tag_to_ack: u64,
+
+    /// Whether RabbitMQ delivered this request before, i.e. it was returned to the queue.
+    pub redelivered: bool,
+
+    /// When probing for this request must be complete, after which partial results are
+    /// published. Set by the scheduler once probing starts, such that time spent waiting
+    /// in the buddy doesn't count.
+    pub deadline: Option<Instant>,
 }
 
 #[derive(Debug)]
@@ -28,4 +47,12 @@ pub struct TaskResponse {
 
     /// The delivery tag that this response handles, and which shall thus be ack'd.
     pub acks_delivery_tag: u64,
+
+    /// Instead of publishing the response, return the request to the queue, because
+    /// probing failed before anything was resolved.
+    pub requeue: bool,
+
+    /// Request for the targets that weren't resolved before probing stopped, which is
+    /// published before the response, see [TraceResponse::requeued].
+    pub remainder: Option<TraceRequest>,
 }
@@ -1,35 +1,49 @@
 use std::net::Ipv6Addr;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
 use itertools::Itertools;
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
 use prefix_crab::blocklist::{self, PrefixBlocklist};
+use prefix_crab::helpers::stop;
 use queue_models::{
+    default_vantage_point,
     probe_request::TraceRequest,
-    probe_response::{LastHop, TraceResponse, TraceResult},
+    probe_response::{IncompleteReason, LastHop, TraceResponse, TraceResult},
 };
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::timeout_at;
+use tokio_util::sync::CancellationToken;
 
 use crate::{
     probe_store::{ProbeStore, RequestGroup, Target},
-    yarrp_call::{Caller, TargetCollector},
+    yarrp_call::{Prober, TargetCollector},
 };
 
 use super::{TaskRequest, TaskResponse};
 
+/// How long to wait for the prober to stop once the deadline has passed
+const PROBER_STOP_GRACE: Duration = Duration::from_secs(10);
+
 pub struct SchedulerTask {
     store: ProbeStore,
-    caller: Caller,
+    prober: Box<dyn Prober>,
     targets: TargetCollector,
     blocklist: PrefixBlocklist,
+    /// Earliest deadline of any request in this task
+    deadline: Option<Instant>,
+    stop_grace: Duration,
 }
 
 impl SchedulerTask {
     pub fn new(params: super::Params) -> Result<Self> {
         Ok(Self {
             store: ProbeStore::default(),
-            caller: params.base.to_caller_assuming_sudo()?,
+            prober: params.base.to_prober_assuming_access()?,
             targets: TargetCollector::new_default()?,
             blocklist: blocklist::read(params.blocklist)?,
+            deadline: None,
+            stop_grace: PROBER_STOP_GRACE,
         })
     }
 
@@ -39,6 +53,7 @@ impl SchedulerTask {
     }
 
     fn push_work_internal(&mut self, item: &mut TaskRequest) -> Result<()> {
+        self.deadline = self.deadline.into_iter().chain(item.deadline).min();
         self.apply_blocklist(&mut item.model);
         self.targets.push_slice(&item.model.targets)?;
         self.store.request_all(item);
@@ -58,39 +73,105 @@ impl SchedulerTask {
         item.targets = filtered_targets;
     }
 
-    pub async fn run(mut self) -> Result<Vec<TaskResponse>> {
-        if !self.targets.is_empty() {
-            let mut response_rx = self.caller.request_responses();
-            self.targets.flush()?;
-            let yarrp_task =
-                tokio::task::spawn_blocking(move || self.caller.consume_run(self.targets));
-            while let Some(response) = response_rx.recv().await {
-                trace!("response from yarrp: {:?}", response);
-                self.store.register_response(response);
-            }
-            response_rx.close(); // ensure nothing else is sent
-            yarrp_task
-                .await
-                .with_context(|| "during blocking yarrp call (await)")??;
-        } else {
+    /// All requests of a chunk share the same deadline. If it passes or yarrp fails, partial
+    /// results are published for all of them. On deadline, yarrp is stopped, but not waited
+    /// for longer than a grace period, since it might hang.
+    pub async fn run(mut self, result_tx: &UnboundedSender<TaskResponse>) -> Result<()> {
+        if self.targets.is_empty() {
             debug!("Skipping call, all requests of this chunk are empty.");
+            return send_responses(self.store, None, result_tx);
+        }
+        let mut response_rx = self.prober.request_responses();
+        self.targets.flush()?;
+        let cancel = CancellationToken::new();
+        let prober_cancel = cancel.clone();
+        let yarrp_task = tokio::task::spawn_blocking(move || {
+            self.prober.consume_run(self.targets, prober_cancel)
+        });
+        let mut deadline_exceeded = false;
+        loop {
+            let received = match self.deadline {
+                Some(deadline) => match timeout_at(deadline.into(), response_rx.recv()).await {
+                    Ok(received) => received,
+                    Err(_) => {
+                        deadline_exceeded = true;
+                        break;
+                    }
+                },
+                None => response_rx.recv().await,
+            };
+            let Some(response) = received else {
+                break;
+            };
+            trace!("response from yarrp: {:?}", response);
+            self.store.register(response);
+        }
+        response_rx.close(); // ensure nothing else is sent
+
+        if deadline_exceeded {
+            warn!("Deadline exceeded while tracing, publishing partial results.");
+            // yarrp might hang, so don't wait for it before publishing
+            send_responses(
+                self.store,
+                Some(IncompleteReason::DeadlineExceeded),
+                result_tx,
+            )?;
+            if let Err(e) = stop::cancel_and_join(yarrp_task, &cancel, self.stop_grace).await {
+                warn!(
+                    "yarrp didn't stop cleanly after the deadline passed: {:#}",
+                    e
+                );
+            }
+            return Ok(());
+        }
+        let incomplete = match yarrp_task.await {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(format!("{:#}", e)),
+            Err(e) => Some(format!("during blocking yarrp call (await): {}", e)),
         }
-        Ok(map_into_responses(self.store))
+        .map(|message| {
+            warn!("yarrp failed, publishing partial results: {}", message);
+            IncompleteReason::ProberFailed { message }
+        });
+        send_responses(self.store, incomplete, result_tx)
     }
 }
 
-fn map_into_responses(store: ProbeStore) -> Vec<TaskResponse> {
-    store
-        .into_request_groups()
-        .into_iter()
-        .map(map_into_response)
-        .collect()
+fn send_responses(
+    store: ProbeStore,
+    incomplete: Option<IncompleteReason>,
+    result_tx: &UnboundedSender<TaskResponse>,
+) -> Result<()> {
+    for group in store.into_request_groups() {
+        result_tx
+            .send(map_into_response(group, incomplete.clone()))
+            .with_context(|| "while sending response over channel")?;
+    }
+    Ok(())
 }
 
-fn map_into_response(group: RequestGroup) -> TaskResponse {
+/// Partial results are published if a last hop was found for at least some targets, and the
+/// remaining targets are returned to the queue in a narrowed request. Otherwise, the whole
+/// request is returned to the queue, unless that already happened before, so it isn't
+/// retried forever.
+fn map_into_response(group: RequestGroup, incomplete: Option<IncompleteReason>) -> TaskResponse {
+    let requeue = incomplete.is_some() && !group.redelivered && !group.has_responses();
+    let remainder = if incomplete.is_some() && !requeue {
+        group.remainder()
+    } else {
+        None
+    };
+    let acks_delivery_tag = group.delivery_tag;
+    let mut model: TraceResponse = group.into();
+    model.incomplete = incomplete;
+    if let Some(remainder) = &remainder {
+        model.requeued = remainder.targets.clone();
+    }
     TaskResponse {
-        acks_delivery_tag: group.delivery_tag,
-        model: group.into(),
+        acks_delivery_tag,
+        model,
+        requeue,
+        remainder,
     }
 }
 
@@ -99,6 +180,10 @@ impl From<RequestGroup> for TraceResponse {
         Self {
             id: value.request_id,
             results: value.targets.into_iter().map_into().collect(),
+            // set by the sender, which knows its vantage point
+            vantage_point: default_vantage_point(),
+            incomplete: None,
+            requeued: vec![],
         }
     }
 }
@@ -119,3 +204,154 @@ impl From<Target> for TraceResult {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{self as std_mpsc, Receiver};
+
+    use assertor::*;
+    use queue_models::probe_request::TraceRequestId;
+    use queue_models::probe_response::TraceResponseType;
+    use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+    use crate::schedule::ProbeOutput;
+
+    use super::*;
+
+    /// Ignores cancellation and only exits once the test drops the other end of `release`
+    #[derive(Debug)]
+    struct HangingProber {
+        release: Receiver<()>,
+        response_tx: Option<UnboundedSender<ProbeOutput>>,
+    }
+
+    impl Prober for HangingProber {
+        fn request_responses(&mut self) -> UnboundedReceiver<ProbeOutput> {
+            let (tx, rx) = mpsc::unbounded_channel();
+            self.response_tx = Some(tx);
+            rx
+        }
+
+        fn consume_run(
+            self: Box<Self>,
+            _targets: TargetCollector,
+            _cancel: CancellationToken,
+        ) -> Result<()> {
+            let _ = self.release.recv();
+            Ok(())
+        }
+    }
+
+    fn gen_task(release: Receiver<()>) -> SchedulerTask {
+        SchedulerTask {
+            store: ProbeStore::default(),
+            prober: Box::new(HangingProber {
+                release,
+                response_tx: None,
+            }),
+            targets: TargetCollector::new_default().unwrap(),
+            blocklist: PrefixBlocklist::new(vec![]),
+            deadline: None,
+            stop_grace: Duration::from_millis(100),
+        }
+    }
+
+    fn addr(it: &str) -> Ipv6Addr {
+        it.parse().unwrap()
+    }
+
+    fn gen_request(targets: Vec<Ipv6Addr>, is_remainder: bool) -> TaskRequest {
+        TaskRequest {
+            model: TraceRequest {
+                id: TraceRequestId::new(),
+                targets,
+                vantage_point: Some("vienna".to_string()),
+                is_remainder,
+            },
+            delivery_tag_to_ack: 17,
+            redelivered: false,
+            deadline: None,
+        }
+    }
+
+    fn gen_last_hop(target_addr: Ipv6Addr) -> ProbeOutput {
+        ProbeOutput::Result(TraceResult::LastResponsiveHop(LastHop {
+            target_addr,
+            last_hop_addr: addr("2001:db8:ffff::1"),
+            response_type: TraceResponseType::TimeExceeded,
+            last_hop_ttl: 7,
+            target_ttl: None,
+        }))
+    }
+
+    fn map_only_group(store: ProbeStore) -> TaskResponse {
+        let mut groups = store.into_request_groups();
+        assert_that!(groups).has_length(1);
+        map_into_response(groups.remove(0), Some(IncompleteReason::DeadlineExceeded))
+    }
+
+    #[tokio::test]
+    async fn return_after_deadline_if_prober_hangs() {
+        // given
+        let (release_tx, release_rx) = std_mpsc::channel();
+        let mut request = gen_request(vec![addr("2001:db8:cafe::1")], false);
+        request.deadline = Some(Instant::now() + Duration::from_millis(50));
+        let mut task = gen_task(release_rx);
+        task.push_work(request).unwrap();
+        let (result_tx, mut result_rx) = mpsc::unbounded_channel();
+
+        // when
+        let ran = tokio::time::timeout(Duration::from_secs(5), task.run(&result_tx)).await;
+
+        // then
+        assert_that!(ran.map(|it| it.is_ok())).is_equal_to(Ok(true));
+        let response = result_rx
+            .try_recv()
+            .expect("expired request to be answered");
+        assert_that!(response.model.incomplete)
+            .is_equal_to(Some(IncompleteReason::DeadlineExceeded));
+        assert_that!(response.requeue).is_true();
+        drop(release_tx);
+    }
+
+    #[test]
+    fn requeue_only_unresolved_targets() {
+        // given
+        let resolved = addr("2001:db8:cafe::1");
+        let unresolved = addr("2001:db8:beef::1");
+        let request = gen_request(vec![resolved, unresolved], false);
+        let mut store = ProbeStore::default();
+        store.request_all(&request);
+        store.register(gen_last_hop(resolved));
+
+        // when
+        let response = map_only_group(store);
+
+        // then
+        assert_that!(response.requeue).is_false();
+        assert_that!(response.model.requeued).contains_exactly(vec![unresolved]);
+        let remainder = response.remainder.expect("remainder to be requeued");
+        assert_that!(remainder.id).is_equal_to(request.model.id);
+        assert_that!(remainder.targets).contains_exactly(vec![unresolved]);
+        assert_that!(remainder.vantage_point).is_equal_to(request.model.vantage_point);
+        assert_that!(remainder.is_remainder).is_true();
+    }
+
+    #[test]
+    fn do_not_requeue_remainder_again() {
+        // given
+        let resolved = addr("2001:db8:cafe::1");
+        let request = gen_request(vec![resolved, addr("2001:db8:beef::1")], true);
+        let mut store = ProbeStore::default();
+        store.request_all(&request);
+        store.register(gen_last_hop(resolved));
+
+        // when
+        let response = map_only_group(store);
+
+        // then
+        assert_that!(response.requeue).is_false();
+        assert_that!(response.remainder.is_none()).is_true();
+        assert_that!(response.model.requeued).is_empty();
+    }
+}
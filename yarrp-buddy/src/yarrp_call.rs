@@ -1,11 +1,20 @@
 use anyhow::{Context, Result};
 use clap::Args;
-use log::debug;
+use log::{debug, info};
+use prefix_crab::simulation;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio_util::sync::CancellationToken;
+
+use crate::schedule::ProbeOutput;
 
 pub use self::caller::Caller;
+pub use self::native::Tracer;
+pub use self::simulate::Simulator;
 pub use self::targets::TargetCollector;
 
 mod caller;
+mod native;
+mod simulate;
 mod targets;
 
 #[derive(Args, Clone)]
@@ -47,6 +56,16 @@ pub struct Params {
     #[arg(long, env = "YARRP_SHUTDOWN_WAIT_SECS", default_value = "23")]
     shutdown_wait_secs: u16,
 
+    /// Trace with the built-in engine from a raw socket instead of calling yarrp.
+    /// Needs CAP_NET_RAW instead of sudo access. Routing is left to the kernel, so
+    /// the gateway MAC is ignored.
+    #[arg(long, env = "YARRP_NATIVE")]
+    native: bool,
+
+    /// How long the built-in engine waits for responses after sending the last probe
+    #[arg(long, env = "YARRP_NATIVE_COOLDOWN_SECS", default_value = "4")]
+    native_cooldown_secs: u16,
+
     /// FQ path to yarrp binary
     #[arg(long, default_value = "/usr/local/bin/yarrp", env = "YARRP_BIN_PATH")]
     bin_path: String,
@@ -54,15 +73,72 @@ pub struct Params {
     /// FQ path to sudo binary
     #[arg(long, default_value = "/usr/bin/sudo")]
     sudo_path: String,
+
+    #[clap(flatten)]
+    simulation: simulation::Params,
+}
+
+/// Traces a set of targets and reports the responses of each hop, either by calling
+/// yarrp ([Caller]) or by consulting a simulated topology ([Simulator]). The built-in
+/// engine ([Tracer]) reports the last hop of each target instead.
+pub trait Prober: Send + std::fmt::Debug {
+    /// Request responses to be captured instead of just printed.
+    /// Responses will be provided to the returned [UnboundedReceiver].
+    /// The sender will be dropped once probing is complete.
+    fn request_responses(&mut self) -> UnboundedReceiver<ProbeOutput>;
+
+    /// Probes all targets, consuming this instance. Blocks until probing is complete, or
+    /// until `cancel` is triggered, e.g. because the deadline of all requests has passed.
+    fn consume_run(
+        self: Box<Self>,
+        targets: TargetCollector,
+        cancel: CancellationToken,
+    ) -> Result<()>;
 }
 
 impl Params {
-    pub fn to_caller_verifying_sudo(&self) -> Result<Caller> {
+    pub fn to_prober_verifying_access(&self) -> Result<Box<dyn Prober>> {
+        if let Some(simulator) = self._make_simulator()? {
+            return Ok(simulator);
+        }
+        if self.native {
+            // opening the session fails without CAP_NET_RAW
+            return self._make_tracer();
+        }
         let mut caller = self._make_caller()?;
         caller
             .verify_sudo_access()
             .with_context(|| "If not using NOPASSWD, you might need to re-run sudo manually.")?;
-        Ok(caller)
+        Ok(Box::new(caller))
+    }
+
+    pub fn to_prober_assuming_access(&self) -> Result<Box<dyn Prober>> {
+        if let Some(simulator) = self._make_simulator()? {
+            return Ok(simulator);
+        }
+        if self.native {
+            return self._make_tracer();
+        }
+        let mut caller = self._make_caller()?;
+        caller.assume_sudo_access();
+        Ok(Box::new(caller))
+    }
+
+    fn _make_simulator(&self) -> Result<Option<Box<dyn Prober>>> {
+        let topology = self
+            .simulation
+            .read_topology()
+            .context("reading simulated topology")?;
+        Ok(topology.map(|it| {
+            info!("Simulating yarrp, no probes will actually be sent.");
+            let simulator = Simulator::new(it, self.min_ttl, self.max_ttl);
+            Box::new(simulator) as Box<dyn Prober>
+        }))
+    }
+
+    fn _make_tracer(&self) -> Result<Box<dyn Prober>> {
+        let session = native::shared_session(self)?;
+        Ok(Box::new(Tracer::new(session, self)))
     }
 
     fn _make_caller(&self) -> Result<Caller> {
@@ -71,10 +147,4 @@ impl Params {
         debug!("Using zmap caller: {:?}", caller);
         Ok(caller)
     }
-
-    pub fn to_caller_assuming_sudo(&self) -> Result<Caller> {
-        let mut caller = self._make_caller()?;
-        caller.assume_sudo_access();
-        Ok(caller)
-    }
 }
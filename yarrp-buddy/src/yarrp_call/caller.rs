@@ -3,22 +3,45 @@ use std::io::{self, BufRead, Read};
 use std::net::Ipv6Addr;
 use std::process::{ChildStdout, Command, Stdio};
 
-use crate::schedule::ProbeResponse;
+use crate::schedule::ProbeOutput;
 use anyhow::{bail, Context, Result};
 use log::Level::Debug;
 use log::{debug, error, log_enabled, trace, warn};
+use prefix_crab::helpers::stop;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio_util::sync::CancellationToken;
 
 use super::targets::TargetCollector;
-use super::Params;
+use super::{Params, Prober};
 
 #[derive(Debug)]
 pub struct Caller {
     cmd: Command,
     bin_path: String,
     sudo_verified: bool,
-    response_tx: Option<UnboundedSender<ProbeResponse>>,
+    response_tx: Option<UnboundedSender<ProbeOutput>>,
+}
+
+impl Prober for Caller {
+    /// The sender will be dropped once yarrp closes stdout (i.e. exits).
+    fn request_responses(&mut self) -> UnboundedReceiver<ProbeOutput> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.response_tx = Some(tx);
+        rx
+    }
+
+    /// Runs the configured command, consuming this instance.
+    fn consume_run(
+        self: Box<Self>,
+        targets: TargetCollector,
+        cancel: CancellationToken,
+    ) -> Result<()> {
+        let mut this = *self;
+        this.set_base();
+        this.push_targets(targets)?;
+        this.do_call(&cancel)
+    }
 }
 
 impl Caller {
@@ -64,15 +87,6 @@ impl Caller {
         self.sudo_verified = true;
     }
 
-    /// Request responses to be captured instead of just printed.
-    /// Responses will be provided to the returned [Receiver].
-    /// The sender will be dropped once zmap closes stdout (i.e. exits).
-    pub fn request_responses(&mut self) -> UnboundedReceiver<ProbeResponse> {
-        let (tx, rx) = mpsc::unbounded_channel();
-        self.response_tx = Some(tx);
-        rx
-    }
-
     pub fn setup(&mut self, params: &Params) {
         if let Some(interface_name) = &params.interface {
             self.cmd.arg(format!("--interface={}", interface_name));
@@ -95,13 +109,6 @@ impl Caller {
             .arg("--max_null_reads=5");
     }
 
-    /// Runs the configured command, consuming this instance.
-    pub fn consume_run(mut self, targets: TargetCollector) -> Result<()> {
-        self.set_base();
-        self.push_targets(targets)?;
-        self.do_call()
-    }
-
     fn push_targets(&mut self, mut collector: TargetCollector) -> Result<()> {
         // Collector moved intentionally; Writing to it while the program is running
         // has undefined effect, so we prohibit that.
@@ -114,7 +121,7 @@ impl Caller {
         Ok(())
     }
 
-    fn do_call(&mut self) -> Result<()> {
+    fn do_call(&mut self, cancel: &CancellationToken) -> Result<()> {
         if log_enabled!(Debug) {
             let args: Vec<Cow<'_, str>> = self
                 .cmd
@@ -135,9 +142,11 @@ impl Caller {
             None => self.watch_logger_fd(&mut child.stdout),
         }
 
-        let exit_status = child
-            .wait()
-            .with_context(|| "Failed to wait for child to exit")?;
+        let Some(exit_status) = stop::wait_or_terminate(&mut child, cancel)
+            .with_context(|| "Failed to wait for child to exit")?
+        else {
+            bail!("yarrp call was terminated because it was cancelled");
+        };
 
         if exit_status.success() {
             debug!("yarrp call exited successfully");
@@ -164,7 +173,7 @@ impl Caller {
         });
     }
 
-    fn watch_stdout(&mut self, fd: &mut Option<ChildStdout>, tx: UnboundedSender<ProbeResponse>) {
+    fn watch_stdout(&mut self, fd: &mut Option<ChildStdout>, tx: UnboundedSender<ProbeOutput>) {
         let taken_fd = fd.take().expect("Failed to open output stream of child");
         std::thread::spawn(move || {
             let mut reader = csv::ReaderBuilder::new()
@@ -208,7 +217,7 @@ impl Caller {
                     warn!("Failed to deserialise record: {:?} - from: {:?}", e, work_record);
                     continue;
                 }
-                if let Err(e) = tx.send(model_res.unwrap()) {
+                if let Err(e) = tx.send(ProbeOutput::Hop(model_res.unwrap())) {
                     warn!(
                         "Unable to send response over channel; maybe the receiver disconnected? {}",
                         e
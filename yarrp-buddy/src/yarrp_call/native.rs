@@ -0,0 +1,506 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use log::{debug, warn};
+use prefix_crab::helpers::raw_icmp6::{self, Pacer, Run};
+use prefix_crab::helpers::raw_icmp6::{ICMP6_DEST_UNREACH, ICMP6_ECHO_REPLY, ICMP6_TIME_EXCEEDED};
+use prefix_crab::helpers::stop;
+use prefix_crab::simulation;
+use queue_models::probe_response::{LastHop, TraceResult};
+use rand::seq::SliceRandom;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio_util::sync::CancellationToken;
+
+use crate::probe_store::Hop;
+use crate::schedule::{ProbeOutput, ProbeResponse};
+
+use super::targets::TargetCollector;
+use super::{Params, Prober};
+
+/// target, TTL word, checksum compensation
+const DATA_LEN: usize = 16 + 2 + 2;
+
+/// Probes are sent in random order over (target, TTL), and everything needed to interpret a
+/// response is encoded in the probe itself, like yarrp does. See [raw_icmp6::Session].
+pub type Session = raw_icmp6::Session<RunState>;
+
+/// The session is opened on first use and then kept open for the lifetime of the process.
+static SESSION: Mutex<Option<Arc<Session>>> = Mutex::new(None);
+
+/// Opens a new session if there is none yet or if the previous one broke
+pub fn shared_session(params: &Params) -> Result<Arc<Session>> {
+    let mut shared = SESSION.lock().expect("session lock not to be poisoned");
+    if let Some(session) = shared.as_ref().filter(|it| !it.is_broken()) {
+        return Ok(session.clone());
+    }
+    let session = Session::open(&params.source_address, params.interface.as_deref())?;
+    *shared = Some(session.clone());
+    Ok(session)
+}
+
+/// Resolves the last hop of each target from the responses as they arrive
+#[derive(Debug)]
+pub struct RunState {
+    tx: UnboundedSender<ProbeOutput>,
+    max_ttl: u8,
+    fill_mode_max_ttl: u8,
+    /// Last hop of each target so far. Probes above the TTL at which the target answered itself
+    /// are skipped.
+    last_hops: HashMap<Ipv6Addr, LastHop>,
+    /// Probes beyond max_ttl for targets that were still in transit at max_ttl
+    fill_probes: Vec<(Ipv6Addr, u8)>,
+}
+
+impl Run for RunState {
+    type Response = ProbeResponse;
+
+    fn decode(data: &[u8], from: Ipv6Addr, identifier: u16) -> Option<(u32, ProbeResponse)> {
+        decode_response(data, from, identifier)
+    }
+
+    /// Reports the last hop of the target whenever it changes, such that partial results are
+    /// available if tracing is cut short.
+    fn register(&mut self, response: ProbeResponse) {
+        if response.icmp_type == ICMP6_TIME_EXCEEDED
+            && response.sent_ttl >= self.max_ttl
+            && response.sent_ttl < self.fill_mode_max_ttl
+        {
+            self.fill_probes
+                .push((response.intended_target, response.sent_ttl + 1));
+        }
+        let Some(last_hop) = self.update_last_hop(&response) else {
+            return;
+        };
+        let output = ProbeOutput::Result(TraceResult::LastResponsiveHop(last_hop.clone()));
+        if let Err(e) = self.tx.send(output) {
+            warn!(
+                "Unable to send response over channel; maybe the receiver disconnected? {}",
+                e
+            );
+        }
+    }
+}
+
+impl RunState {
+    /// The last hop is the response to the highest TTL, like for yarrp output
+    fn update_last_hop(&mut self, response: &ProbeResponse) -> Option<&LastHop> {
+        let hop = match Hop::try_from(response) {
+            Ok(hop) => hop,
+            Err(e) => {
+                warn!("Failed to construct hop: {:?}", e);
+                return None;
+            }
+        };
+        let target = response.intended_target;
+        let reached_ttl =
+            (hop.addr == target || response.icmp_type == ICMP6_ECHO_REPLY).then_some(hop.sent_ttl);
+        match self.last_hops.entry(target) {
+            Entry::Vacant(entry) => Some(entry.insert(LastHop {
+                target_addr: target,
+                last_hop_addr: hop.addr,
+                response_type: hop.response_type,
+                last_hop_ttl: hop.sent_ttl,
+                target_ttl: reached_ttl,
+            })),
+            Entry::Occupied(entry) => {
+                let last_hop = entry.into_mut();
+                let mut changed = false;
+                if let Some(ttl) = reached_ttl {
+                    if last_hop.target_ttl.map_or(true, |it| ttl < it) {
+                        last_hop.target_ttl = Some(ttl);
+                        changed = true;
+                    }
+                }
+                if hop.sent_ttl > last_hop.last_hop_ttl {
+                    last_hop.last_hop_addr = hop.addr;
+                    last_hop.response_type = hop.response_type;
+                    last_hop.last_hop_ttl = hop.sent_ttl;
+                    changed = true;
+                }
+                changed.then_some(&*last_hop)
+            }
+        }
+    }
+
+    fn is_reached_below(&self, target: &Ipv6Addr, ttl: u8) -> bool {
+        self.last_hops
+            .get(target)
+            .and_then(|it| it.target_ttl)
+            .map(|it| it < ttl)
+            .unwrap_or(false)
+    }
+}
+
+/// Paris-traceroute engine that sends probes via a raw socket instead of calling yarrp, and
+/// reports the last hop of each target directly instead of the response of each hop.
+///
+/// All probes to a target share the same flow identifiers (echo identifier, sequence number
+/// and checksum), such that load balancers route them along the same path. Probing a target
+/// stops at the TTL where the target itself answered, and targets that are still in transit
+/// at max_ttl are probed further up to fill_mode_max_ttl.
+#[derive(Debug)]
+pub struct Tracer {
+    session: Arc<Session>,
+    min_ttl: u8,
+    max_ttl: u8,
+    fill_mode_max_ttl: u8,
+    neighborhood_max_ttl: u8,
+    rate_pps: u32,
+    cooldown: Duration,
+    response_tx: Option<UnboundedSender<ProbeOutput>>,
+}
+
+impl Tracer {
+    pub fn new(session: Arc<Session>, params: &Params) -> Self {
+        Self {
+            session,
+            min_ttl: params.min_ttl,
+            max_ttl: params.max_ttl,
+            fill_mode_max_ttl: params.fill_mode_max_ttl,
+            neighborhood_max_ttl: params.neighborhood_max_ttl,
+            rate_pps: params.rate_pps as u32,
+            cooldown: Duration::from_secs(params.native_cooldown_secs as u64),
+            response_tx: None,
+        }
+    }
+
+    /// TTLs up to the neighborhood are shared by all targets, so they are only probed for the
+    /// first target of a run.
+    fn plan_probes(&self, targets: &[Ipv6Addr]) -> Vec<(Ipv6Addr, u8)> {
+        let mut probes = vec![];
+        for (i, target) in targets.iter().enumerate() {
+            for ttl in self.min_ttl..=self.max_ttl {
+                if i == 0 || ttl > self.neighborhood_max_ttl {
+                    probes.push((*target, ttl));
+                }
+            }
+        }
+        probes.shuffle(&mut rand::thread_rng());
+        probes
+    }
+
+    /// [None] once all probes are sent, or if the run was dropped because the session broke
+    fn next_probe(&self, run_id: u32, planned: &mut Vec<(Ipv6Addr, u8)>) -> Option<(Ipv6Addr, u8)> {
+        self.session.with_run(run_id, |run| {
+            while let Some((target, ttl)) = run.fill_probes.pop().or_else(|| planned.pop()) {
+                if !run.is_reached_below(&target, ttl) {
+                    return Some((target, ttl));
+                }
+            }
+            None
+        })?
+    }
+
+    fn has_fill_probes(&self, run_id: u32) -> bool {
+        self.session
+            .with_run(run_id, |run| !run.fill_probes.is_empty())
+            .unwrap_or(false)
+    }
+
+    fn send_probe(&self, run_id: u32, target: &Ipv6Addr, ttl: u8) {
+        let packet = encode_echo_request(self.session.identifier(), run_id, target, ttl);
+        if let Err(e) = self.session.send(&packet, target, ttl) {
+            // e.g. no route to host, the target just won't have a response
+            debug!("{:?}", e);
+        }
+    }
+}
+
+impl Prober for Tracer {
+    /// The sender will be dropped after the cooldown following the last probe.
+    fn request_responses(&mut self) -> UnboundedReceiver<ProbeOutput> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.response_tx = Some(tx);
+        rx
+    }
+
+    fn consume_run(
+        self: Box<Self>,
+        mut targets: TargetCollector,
+        cancel: CancellationToken,
+    ) -> Result<()> {
+        targets.flush()?;
+        let addrs = simulation::read_targets(&targets.path)?;
+        let Some(tx) = self.response_tx.clone() else {
+            warn!("Native tracer run without response channel, not sending any probes.");
+            return Ok(());
+        };
+        let mut planned = self.plan_probes(&addrs);
+        let run_id = self.session.register_run(RunState {
+            tx,
+            max_ttl: self.max_ttl,
+            fill_mode_max_ttl: self.fill_mode_max_ttl,
+            last_hops: HashMap::new(),
+            fill_probes: vec![],
+        });
+        debug!(
+            "Native tracing run {} for {} targets with {} probes",
+            run_id,
+            addrs.len(),
+            planned.len()
+        );
+        let mut pacer = Pacer::new(self.rate_pps);
+        loop {
+            while let Some((target, ttl)) = self.next_probe(run_id, &mut planned) {
+                if cancel.is_cancelled() {
+                    break;
+                }
+                pacer.wait();
+                self.send_probe(run_id, &target, ttl);
+            }
+            stop::sleep_unless_cancelled(self.cooldown, &cancel);
+            if cancel.is_cancelled() {
+                debug!("Native tracing run {} cancelled", run_id);
+                break;
+            }
+            if !self.has_fill_probes(run_id) {
+                break;
+            }
+        }
+        // Drops the sender, which closes the response channel
+        self.session.finish_run(run_id);
+        Ok(())
+    }
+}
+
+/// The payload only differs in the TTL between probes to the same target. The compensation
+/// word is its one's complement, so the one's complement sum and thus the checksum stays the same.
+fn encode_echo_request(identifier: u16, run_id: u32, target: &Ipv6Addr, ttl: u8) -> Vec<u8> {
+    let ttl_word = ttl as u16;
+    let mut data = Vec::with_capacity(DATA_LEN);
+    data.extend_from_slice(&target.octets());
+    data.extend_from_slice(&ttl_word.to_be_bytes());
+    data.extend_from_slice(&(!ttl_word).to_be_bytes());
+    raw_icmp6::encode_echo_request(identifier, 0, run_id, &data)
+}
+
+/// Maps an ICMPv6 message received from `from` to the run that caused it, if it was caused
+/// by one of our probes. Error messages are matched by the echo request that they quote.
+fn decode_response(data: &[u8], from: Ipv6Addr, identifier: u16) -> Option<(u32, ProbeResponse)> {
+    let (&icmp_type, rest) = data.split_first()?;
+    let &icmp_code = rest.first()?;
+    let echo = match icmp_type {
+        ICMP6_ECHO_REPLY => data,
+        ICMP6_DEST_UNREACH | ICMP6_TIME_EXCEEDED => raw_icmp6::quoted_echo_request(data)?.0,
+        // yarrp ignores all other types as well
+        _ => return None,
+    };
+
+    let (run_id, data) = raw_icmp6::decode_payload(echo, identifier, DATA_LEN)?;
+    let target: [u8; 16] = data[..16].try_into().ok()?;
+    let ttl_word = u16::from_be_bytes(data[16..18].try_into().ok()?);
+    Some((
+        run_id,
+        ProbeResponse {
+            icmp_type,
+            icmp_code,
+            sent_ttl: ttl_word as u8,
+            intended_target: target.into(),
+            actual_from: from,
+            // The raw socket doesn't expose the IPv6 header of the response
+            received_ttl: 0,
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+
+    use prefix_crab::helpers::raw_icmp6::{IPV6_HEADER_LEN, NEXT_HEADER_ICMP6};
+
+    use super::*;
+
+    const IDENTIFIER: u16 = 0x1337;
+
+    fn addr(it: &str) -> Ipv6Addr {
+        it.parse().unwrap()
+    }
+
+    fn gen_quoting_error(icmp_type: u8, probe: &[u8], target: &Ipv6Addr) -> Vec<u8> {
+        let mut packet = vec![icmp_type, 0, 0, 0, 0, 0, 0, 0];
+        let mut ip_header = [0u8; IPV6_HEADER_LEN];
+        ip_header[0] = 0x60;
+        ip_header[6] = NEXT_HEADER_ICMP6;
+        ip_header[7] = 1;
+        ip_header[24..].copy_from_slice(&target.octets());
+        packet.extend_from_slice(&ip_header);
+        packet.extend_from_slice(probe);
+        packet
+    }
+
+    fn ones_complement_sum(packet: &[u8]) -> u16 {
+        let mut sum = 0u32;
+        for word in packet.chunks(2) {
+            sum += u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]) as u32;
+        }
+        while sum > 0xFFFF {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+        sum as u16
+    }
+
+    #[test]
+    fn flow_stays_same_across_ttls() {
+        // given
+        let target = addr("2001:db8:cafe::1");
+
+        // when
+        let low = encode_echo_request(IDENTIFIER, 42, &target, 3);
+        let high = encode_echo_request(IDENTIFIER, 42, &target, 17);
+
+        // then
+        assert_that!(low[..8].to_vec()).is_equal_to(high[..8].to_vec());
+        assert_that!(ones_complement_sum(&low)).is_equal_to(ones_complement_sum(&high));
+    }
+
+    #[test]
+    fn decode_quoted_time_exceeded() {
+        // given
+        let target = addr("2001:db8:cafe::1");
+        let router = addr("2001:db8:ffff::100");
+        let probe = encode_echo_request(IDENTIFIER, 42, &target, 9);
+        let error = gen_quoting_error(ICMP6_TIME_EXCEEDED, &probe, &target);
+
+        // when
+        let decoded = decode_response(&error, router, IDENTIFIER);
+
+        // then
+        let (run_id, response) = decoded.expect("error to be decoded");
+        assert_that!(run_id).is_equal_to(42);
+        assert_that!(response.sent_ttl).is_equal_to(9);
+        assert_that!(response.intended_target).is_equal_to(target);
+        assert_that!(response.actual_from).is_equal_to(router);
+    }
+
+    #[test]
+    fn decode_echo_reply_with_target() {
+        // given
+        let target = addr("2001:db8:cafe::1");
+        let mut reply = encode_echo_request(IDENTIFIER, 42, &target, 12);
+        reply[0] = ICMP6_ECHO_REPLY;
+
+        // when
+        let decoded = decode_response(&reply, target, IDENTIFIER);
+
+        // then
+        let (_, response) = decoded.expect("reply to be decoded");
+        assert_that!(response.sent_ttl).is_equal_to(12);
+        assert_that!(response.intended_target).is_equal_to(target);
+    }
+
+    fn gen_run(tx: UnboundedSender<ProbeOutput>) -> RunState {
+        RunState {
+            tx,
+            max_ttl: 16,
+            fill_mode_max_ttl: 32,
+            last_hops: HashMap::new(),
+            fill_probes: vec![],
+        }
+    }
+
+    fn gen_response(
+        icmp_type: u8,
+        sent_ttl: u8,
+        target: Ipv6Addr,
+        from: Ipv6Addr,
+    ) -> ProbeResponse {
+        ProbeResponse {
+            icmp_type,
+            icmp_code: 0,
+            sent_ttl,
+            intended_target: target,
+            actual_from: from,
+            received_ttl: 0,
+        }
+    }
+
+    fn last_hops(rx: &mut UnboundedReceiver<ProbeOutput>) -> Vec<LastHop> {
+        let mut last_hops = vec![];
+        while let Ok(output) = rx.try_recv() {
+            match output {
+                ProbeOutput::Result(TraceResult::LastResponsiveHop(it)) => last_hops.push(it),
+                other => panic!("unexpected output {:?}", other),
+            }
+        }
+        last_hops
+    }
+
+    #[test]
+    fn stop_above_reached_ttl() {
+        // given
+        let target = addr("2001:db8:cafe::1");
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let mut run = gen_run(tx);
+
+        // when
+        run.register(gen_response(ICMP6_ECHO_REPLY, 11, target, target));
+
+        // then
+        assert_that!(run.is_reached_below(&target, 11)).is_false();
+        assert_that!(run.is_reached_below(&target, 12)).is_true();
+    }
+
+    #[test]
+    fn fill_beyond_max_ttl() {
+        // given
+        let target = addr("2001:db8:cafe::1");
+        let router = addr("2001:db8:ffff::100");
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let mut run = gen_run(tx);
+
+        // when
+        run.register(gen_response(ICMP6_TIME_EXCEEDED, 16, target, router));
+
+        // then
+        assert_that!(run.fill_probes).is_equal_to(vec![(target, 17)]);
+    }
+
+    #[test]
+    fn report_last_hop_with_target_ttl() {
+        // given
+        let target = addr("2001:db8:cafe::1");
+        let router = addr("2001:db8:ffff::100");
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut run = gen_run(tx);
+
+        // when
+        run.register(gen_response(ICMP6_TIME_EXCEEDED, 9, target, router));
+        run.register(gen_response(ICMP6_ECHO_REPLY, 10, target, target));
+
+        // then
+        let last_hops = last_hops(&mut rx);
+        assert_that!(last_hops.len()).is_equal_to(2);
+        let last = &last_hops[1];
+        assert_that!(last.target_addr).is_equal_to(target);
+        assert_that!(last.last_hop_addr).is_equal_to(target);
+        assert_that!(last.last_hop_ttl).is_equal_to(10);
+        assert_that!(last.target_ttl).is_equal_to(Some(10));
+    }
+
+    #[test]
+    fn keep_last_hop_on_lower_ttl() {
+        // given
+        let target = addr("2001:db8:cafe::1");
+        let router = addr("2001:db8:ffff::100");
+        let neighbor = addr("2001:db8::1");
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut run = gen_run(tx);
+
+        // when
+        run.register(gen_response(ICMP6_TIME_EXCEEDED, 9, target, router));
+        run.register(gen_response(ICMP6_TIME_EXCEEDED, 5, target, neighbor));
+
+        // then
+        let last_hops = last_hops(&mut rx);
+        assert_that!(last_hops.len()).is_equal_to(1);
+        assert_that!(last_hops[0].last_hop_addr).is_equal_to(router);
+        assert_that!(last_hops[0].last_hop_ttl).is_equal_to(9);
+        assert_that!(last_hops[0].target_ttl).is_equal_to(None);
+    }
+}
@@ -0,0 +1,85 @@
+use anyhow::Result;
+use log::{debug, trace, warn};
+use prefix_crab::simulation::{self, SimulatedResponse, Topology};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio_util::sync::CancellationToken;
+
+use crate::schedule::{ProbeOutput, ProbeResponse};
+
+use super::targets::TargetCollector;
+use super::Prober;
+
+/// TTL that simulated responders send their responses with
+const RESPONDER_TTL: u8 = 64;
+
+/// Answers probes from a simulated [Topology] instead of calling yarrp.
+#[derive(Debug)]
+pub struct Simulator {
+    topology: Topology,
+    min_ttl: u8,
+    max_ttl: u8,
+    response_tx: Option<UnboundedSender<ProbeOutput>>,
+}
+
+impl Simulator {
+    pub fn new(topology: Topology, min_ttl: u8, max_ttl: u8) -> Self {
+        Self {
+            topology,
+            min_ttl,
+            max_ttl,
+            response_tx: None,
+        }
+    }
+}
+
+impl Prober for Simulator {
+    fn request_responses(&mut self) -> UnboundedReceiver<ProbeOutput> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.response_tx = Some(tx);
+        rx
+    }
+
+    fn consume_run(
+        self: Box<Self>,
+        mut targets: TargetCollector,
+        cancel: CancellationToken,
+    ) -> Result<()> {
+        targets.flush()?;
+        let addrs = simulation::read_targets(&targets.path)?;
+        debug!("Simulating yarrp call for {} targets", addrs.len());
+        let Some(tx) = self.response_tx else {
+            return Ok(());
+        };
+        let mut run = self.topology.start_run();
+        for addr in addrs {
+            if cancel.is_cancelled() {
+                debug!("Simulated run cancelled");
+                break;
+            }
+            for response in run.trace(&addr, self.min_ttl, self.max_ttl) {
+                let record = to_record(response);
+                trace!("[[simulated yarrp record]] {:?}", record);
+                if let Err(e) = tx.send(ProbeOutput::Hop(record)) {
+                    warn!(
+                        "Unable to send response over channel; maybe the receiver disconnected? {}",
+                        e
+                    );
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn to_record(response: SimulatedResponse) -> ProbeResponse {
+    ProbeResponse {
+        icmp_type: response.icmp_type,
+        icmp_code: response.icmp_code,
+        sent_ttl: response.sent_ttl,
+        intended_target: response.target,
+        actual_from: response.from,
+        received_ttl: RESPONDER_TTL.saturating_sub(response.responder_hops),
+    }
+}
@@ -2,6 +2,7 @@
 
 use derive_where::derive_where;
 
+use queue_models::default_vantage_point;
 use queue_models::probe_response::EchoProbeResponse;
 
 use crate::schedule::ProbeResponse;
@@ -31,6 +32,13 @@ impl<ExtraData: Sized> PrefixStoreDispatcher<ExtraData> {
     }
 }
 
+impl<ExtraData: Sized> PrefixStoreDispatcher<ExtraData> {
+    /// Whether any response was registered for any subnet
+    pub fn has_responses(&self) -> bool {
+        self.dispatcher.stores.iter().any(|it| it.has_responses())
+    }
+}
+
 impl<ExtraData: Sized> ProbeStoreDispatcher<PrefixStoreDispatcher<ExtraData>> {
     pub fn register_request(
         &mut self,
@@ -60,6 +68,9 @@ impl<ExtraData: Sized> From<PrefixStoreDispatcher<ExtraData>> for EchoProbeRespo
                 .into_iter()
                 .map(|it| it.into())
                 .collect(),
+            // set by the sender, which knows its vantage point
+            vantage_point: default_vantage_point(),
+            incomplete: None,
         }
     }
 }
@@ -71,6 +71,10 @@ impl SubnetStore {
         self.sample.addresses.contains(&addr)
     }
 
+    pub fn has_responses(&self) -> bool {
+        self.responses.keys().any(|it| *it != ResponseKey::NoResponse)
+    }
+
     fn entry(&mut self, key: ResponseKey) -> &mut Responses {
         self.responses.entry(key).or_insert(Responses::empty())
     }
@@ -182,6 +186,22 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn has_responses_ignores_missing() -> Result<()> {
+        // given
+        let prefix = gen_any_sample()?;
+        let responsive_addr = prefix.addresses[0];
+        let mut store = SubnetStore::new(prefix);
+        store.fill_missing();
+        let had_before = store.has_responses();
+        // when
+        store.register_response(&gen_timxceed(responsive_addr));
+        // then
+        assert!(!had_before);
+        assert!(store.has_responses());
+        Ok(())
+    }
+
     #[test]
     fn is_respo_checks_intended_not_actual_pos() -> Result<()> {
         // given
@@ -1,9 +1,9 @@
-use amqprs::channel::{BasicAckArguments, BasicPublishArguments};
+use amqprs::channel::{BasicAckArguments, BasicNackArguments, BasicPublishArguments};
 use amqprs::BasicProperties;
 use anyhow::{Context, Result};
 use log::warn;
 use prefix_crab::loop_with_stop;
-use queue_models::RoutedMessage;
+use queue_models::{RoutedMessage, VantagePointId};
 use tokio::sync::mpsc::UnboundedReceiver;
 
 use queue_models::probe_response::EchoProbeResponse;
@@ -17,6 +17,7 @@ struct RabbitSender<'han> {
     exchange_name: String,
     handle: &'han RabbitHandle,
     pretty_print: bool,
+    vantage_point: VantagePointId,
 }
 
 pub async fn run(
@@ -24,12 +25,14 @@ pub async fn run(
     work_rx: UnboundedReceiver<TaskResponse>,
     exchange_name: String,
     pretty_print: bool,
+    vantage_point: VantagePointId,
     stop_rx: CancellationToken,
 ) -> Result<()> {
     RabbitSender {
         exchange_name,
         handle,
         pretty_print,
+        vantage_point,
     }
     .run(stop_rx, work_rx)
     .await
@@ -56,12 +59,20 @@ impl RabbitSender<'_> {
     }
 
     async fn send_and_ack(&mut self, msg: TaskResponse) -> Result<()> {
+        if msg.requeue {
+            warn!(
+                "Nothing resolved for {} ({:?}), returning it to the queue.",
+                msg.model.target_net, msg.model.incomplete
+            );
+            return self.requeue(msg.acks_delivery_tag).await;
+        }
         self.publish(msg.model).await?;
         self.ack(msg.acks_delivery_tag).await?;
         Ok(())
     }
 
-    async fn publish(&self, msg: EchoProbeResponse) -> Result<()> {
+    async fn publish(&self, mut msg: EchoProbeResponse) -> Result<()> {
+        msg.vantage_point = self.vantage_point.clone();
         let args = BasicPublishArguments::new(&self.exchange_name, msg.routing_key());
         let bin = if self.pretty_print {
             serde_json::to_vec_pretty(&msg)
@@ -85,4 +96,17 @@ impl RabbitSender<'_> {
             .with_context(|| "during ack")?;
         Ok(())
     }
+
+    async fn requeue(&self, delivery_tag: u64) -> Result<()> {
+        self.handle
+            .chan()
+            .basic_nack(BasicNackArguments::new(
+                delivery_tag,
+                /* multiple = */ false,
+                /* requeue = */ true,
+            ))
+            .await
+            .with_context(|| "during nack")?;
+        Ok(())
+    }
 }
@@ -1,9 +1,11 @@
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Context, Result};
 use clap::Args;
 use log::{error, info, trace, warn};
 use prefix_crab::blocklist;
+use prefix_crab::hitlist::{self, Hitlist};
 use tokio::sync::mpsc::{Receiver, UnboundedSender};
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::{Stream, StreamExt};
@@ -14,6 +16,7 @@ use crate::zmap_call;
 pub use self::model::{ProbeResponse, TaskRequest, TaskResponse};
 
 mod interleave;
+mod stream;
 mod task;
 
 #[derive(Args, Clone)]
@@ -25,6 +28,9 @@ pub struct Params {
     #[clap(flatten)]
     blocklist: blocklist::Params,
 
+    #[clap(flatten)]
+    hitlist: hitlist::Params,
+
     /// How long to wait for enough probes to arrive before flushing the chunk anyways
     /// and invoking zmap with less than the chunk size
     #[arg(long, default_value = "60", env = "CHUNK_TIMEOUT_SECS")]
@@ -34,6 +40,17 @@ pub struct Params {
     /// buffered, a chunk is immediately created and zmap will be invoked.
     #[arg(long, default_value = "16", env = "MAX_CHUNK_SIZE")]
     max_chunk_size: usize,
+
+    /// Instead of chunking requests, keep a single probe stream open, feed targets as
+    /// requests arrive, and complete each request on its own deadline. Needs the
+    /// native prober.
+    #[arg(long, env = "STREAMING")]
+    streaming: bool,
+
+    /// How long probing for a request may take at most. After that, partial results are
+    /// published for the targets that were resolved so far.
+    #[arg(long, default_value = "600", env = "REQUEST_TIMEOUT_SECS")]
+    request_timeout_secs: u64,
 }
 
 mod model;
@@ -42,6 +59,7 @@ const SAMPLES_PER_SUBNET: u16 = 16;
 
 struct Scheduler {
     params: Params,
+    hitlist: Arc<Hitlist>,
     result_tx: UnboundedSender<TaskResponse>,
 }
 
@@ -50,11 +68,28 @@ pub async fn run(
     result_tx: UnboundedSender<TaskResponse>,
     params: Params,
 ) -> Result<()> {
+    let hitlist = load_hitlist(params.hitlist.clone())?;
+    if params.streaming {
+        return stream::run(work_rx, result_tx, params, hitlist).await;
+    }
     let work_stream = ReceiverStream::new(work_rx).chunks_timeout(
         params.max_chunk_size,
         Duration::from_secs(params.chunk_timeout_secs),
     );
-    Scheduler { params, result_tx }.run(work_stream).await
+    Scheduler {
+        params,
+        hitlist,
+        result_tx,
+    }
+    .run(work_stream)
+    .await
+}
+
+fn load_hitlist(params: hitlist::Params) -> Result<Arc<Hitlist>> {
+    info!("Loading hitlist from `{:?}`", params.hitlist_file);
+    let hitlist = hitlist::read(params).context("loading hitlist")?;
+    info!("Hitlist contains {} addresses", hitlist.len());
+    Ok(Arc::new(hitlist))
 }
 
 impl Scheduler {
@@ -76,14 +111,11 @@ impl Scheduler {
     async fn check_preflight(&self) -> Result<()> {
         let params = self.params.clone();
         tokio::task::spawn_blocking(move || {
-            params
-                .base
-                .to_caller_verifying_sudo()?
-                .verify_sudo_access()?;
+            params.base.to_prober_verifying_access()?;
             Ok::<(), anyhow::Error>(())
         })
         .await
-        .context("pre-flight sudo access check failed")??;
+        .context("pre-flight prober access check failed")??;
         info!(
             "Loading blocklist from `{:?}`",
             params.blocklist.blocklist_file
@@ -103,13 +135,20 @@ impl Scheduler {
         }
     }
 
-    async fn do_scan_batch(&self, batch: Vec<TaskRequest>) -> Result<()> {
+    async fn do_scan_batch(&self, mut batch: Vec<TaskRequest>) -> Result<()> {
         info!(
             "Scanning a batch of {} prefixes, {} probes total...",
             batch.len(),
-            SAMPLES_PER_SUBNET as usize * 2 * batch.len(),
+            batch
+                .iter()
+                .map(|it| SAMPLES_PER_SUBNET as usize * 2usize.pow(it.model.split_bits as u32))
+                .sum::<usize>(),
         );
-        let mut task = SchedulerTask::new(self.params.clone())?;
+        let deadline = Instant::now() + Duration::from_secs(self.params.request_timeout_secs);
+        for item in batch.iter_mut() {
+            item.deadline = Some(deadline);
+        }
+        let mut task = SchedulerTask::new(self.params.clone(), self.hitlist.clone())?;
         let mut at_least_one_ok = false;
         for item in batch.iter() {
             match task.push_work(item) {
@@ -123,12 +162,6 @@ impl Scheduler {
                 "None of the work in this chunk could be pushed successfully"
             ));
         }
-        let results = task.run().await?;
-        for result in results {
-            self.result_tx
-                .send(result)
-                .with_context(|| "while sending response over channel")?;
-        }
-        Ok(())
+        task.run(&self.result_tx).await
     }
 }
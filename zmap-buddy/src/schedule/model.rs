@@ -1,4 +1,5 @@
 use std::net::Ipv6Addr;
+use std::time::Instant;
 use queue_models::probe_response::EchoProbeResponse;
 use queue_models::probe_request::EchoProbeRequest;
 
@@ -30,6 +31,14 @@ pub struct TaskRequest {
     /// This is used so that only requests that were actually fully processed are
     /// removed from the queue (e.g. buddy crashes).
     pub delivery_tag_to_ack: u64,
+
+    /// Whether RabbitMQ delivered this request before, i.e. it was returned to the queue.
+    pub redelivered: bool,
+
+    /// When probing for this request must be complete, after which partial results are
+    /// published. Set by the scheduler once probing starts, such that time spent waiting
+    /// in the buddy doesn't count.
+    pub deadline: Option<Instant>,
 }
 
 #[derive(Debug)]
@@ -38,4 +47,8 @@ pub struct TaskResponse {
 
     /// The delivery tag that this response handles, and which shall thus be ack'd.
     pub acks_delivery_tag: u64,
+
+    /// Instead of publishing the response, return the request to the queue, because
+    /// probing failed before anything was resolved.
+    pub requeue: bool,
 }
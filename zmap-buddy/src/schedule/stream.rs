@@ -0,0 +1,424 @@
+use std::borrow::Borrow;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info, trace, warn};
+use prefix_crab::blocklist::{self, PrefixBlocklist};
+use prefix_crab::hitlist::Hitlist;
+use prefix_crab::prefix_split::split;
+use queue_models::default_vantage_point;
+use queue_models::probe_response::{EchoProbeResponse, IncompleteReason};
+use tokio::sync::mpsc::{Receiver, UnboundedSender};
+use tokio::time::{sleep_until, Instant};
+
+use crate::probe_store::{self, PrefixSplitProbeStore, ProbeStore};
+use crate::zmap_call::{BatchSent, ProbeStream, SENT_TTL};
+
+use super::interleave::InterleavedTargetsIter;
+use super::task::{self, to_samples};
+use super::{Params, TaskRequest, TaskResponse};
+
+#[derive(Debug)]
+struct Pending {
+    request: TaskRequest,
+    /// Batch of the [ProbeStream] that the targets of the request were fed as
+    batch_id: u64,
+    /// When responses to all targets of the request can be expected to have arrived, known
+    /// once the last of them was actually sent
+    expected: Option<Instant>,
+}
+
+impl Borrow<TaskRequest> for Pending {
+    fn borrow(&self) -> &TaskRequest {
+        &self.request
+    }
+}
+
+/// Streaming counterpart of the chunked scheduler: Targets of each request are fed into a
+/// single [ProbeStream] as soon as the request arrives, and the request is completed once
+/// the cooldown after its last target was sent has passed.
+struct StreamScheduler {
+    stream: ProbeStream,
+    blocklist: PrefixBlocklist,
+    hitlist: Arc<Hitlist>,
+    pending: PrefixSplitProbeStore<Pending>,
+    result_tx: UnboundedSender<TaskResponse>,
+    request_timeout: Duration,
+}
+
+pub async fn run(
+    mut work_rx: Receiver<TaskRequest>,
+    result_tx: UnboundedSender<TaskResponse>,
+    params: Params,
+    hitlist: Arc<Hitlist>,
+) -> Result<()> {
+    let base = params.base.clone();
+    let (stream, mut response_rx, mut sent_rx) =
+        tokio::task::spawn_blocking(move || base.to_probe_stream())
+            .await
+            .context("pre-flight probe stream check failed")??;
+    info!(
+        "Loading blocklist from `{:?}`",
+        params.blocklist.blocklist_file
+    );
+    let blocklist =
+        blocklist::read(params.blocklist).context("pre-flight blocklist check failed")?;
+    let mut scheduler = StreamScheduler {
+        stream,
+        blocklist,
+        hitlist,
+        pending: probe_store::create(),
+        result_tx,
+        request_timeout: Duration::from_secs(params.request_timeout_secs),
+    };
+
+    info!("Streaming scheduler up & running!");
+    let mut accepting = true;
+    let mut sending = true;
+    while accepting || !scheduler.pending.stores.is_empty() {
+        let next_deadline = scheduler.next_deadline();
+        tokio::select! {
+            received = work_rx.recv(), if accepting => match received {
+                Some(request) => scheduler.push_work(request)?,
+                None => {
+                    info!("Streaming scheduler shutting down after pending requests.");
+                    accepting = false;
+                }
+            },
+            received = sent_rx.recv(), if sending => match received {
+                Some(sent) => scheduler.register_sent(sent),
+                // the response channel closes as well once the cooldown has passed
+                None => sending = false,
+            },
+            received = response_rx.recv() => match received {
+                Some(response) => {
+                    trace!("response from stream: {:?}", response);
+                    scheduler.pending.register_response(&response);
+                }
+                None => {
+                    let message = "probe stream closed unexpectedly".to_string();
+                    scheduler.complete_all(IncompleteReason::ProberFailed { message })?;
+                    bail!("Probe stream closed unexpectedly");
+                }
+            },
+            _ = sleep_until(next_deadline.unwrap_or_else(Instant::now)), if next_deadline.is_some() => {
+                scheduler.complete_expired()?;
+            },
+        }
+    }
+    Ok(())
+}
+
+impl StreamScheduler {
+    /// Requests that can't be probed are completed right away. If feeding fails, none of the
+    /// targets were sent, so the request is completed with [IncompleteReason::ProberFailed]. Like
+    /// other responses without results, it is returned to the queue unless it was redelivered
+    /// already (see [task::map_into_response]), in which case the failure is reported instead.
+    fn push_work(&mut self, mut request: TaskRequest) -> Result<()> {
+        let base_net = request.model.target_net;
+        let split = match split(base_net, request.model.split_bits) {
+            Ok(split) => split,
+            Err(e) => {
+                warn!(
+                    "Unable to split request for streaming: {:?} - {:?}",
+                    e, request
+                );
+                return self.send_all(vec![reject_unsplittable(request, e)]);
+            }
+        };
+        let samples = if self.blocklist.is_whole_net_blocked(&base_net) {
+            vec![]
+        } else {
+            to_samples(&split, &request.model, &self.hitlist)
+        };
+        let mut targets = vec![];
+        for addr in InterleavedTargetsIter::new(&samples) {
+            if self.blocklist.is_blocked(&addr) {
+                info!("Omitting {} due to blocklist", addr);
+            } else {
+                targets.push(addr);
+            }
+        }
+        debug!("Streaming {} probes for {}", targets.len(), base_net);
+        request.deadline = Some(std::time::Instant::now() + self.request_timeout);
+        match self.stream.feed(targets) {
+            Ok(batch_id) => {
+                let pending = Pending {
+                    request,
+                    batch_id,
+                    expected: None,
+                };
+                self.pending.register_request(split, samples, pending);
+                Ok(())
+            }
+            Err(e) => {
+                warn!("Unable to push work to stream {:?}", e);
+                let mut unfed = probe_store::create();
+                unfed.register_request(split, samples, request);
+                let message = format!("{:#}", e);
+                let responses = unfed
+                    .stores
+                    .into_iter()
+                    .map(|it| {
+                        let reason = IncompleteReason::ProberFailed {
+                            message: message.clone(),
+                        };
+                        task::map_into_response(it, Some(reason))
+                    })
+                    .collect();
+                self.send_all(responses)
+            }
+        }
+    }
+
+    fn register_sent(&mut self, sent: BatchSent) {
+        let expected = Instant::from_std(sent.at) + self.stream.cooldown();
+        let batch = self
+            .pending
+            .stores
+            .iter_mut()
+            .filter(|it| it.extra_data.batch_id == sent.batch_id);
+        for store in batch {
+            store.extra_data.expected = Some(expected);
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        let expected = self
+            .pending
+            .stores
+            .iter()
+            .filter_map(|it| it.extra_data.expected);
+        expected.chain(task::next_deadline(&self.pending)).min()
+    }
+
+    /// Requests whose responses should have arrived are complete, even if their deadline
+    /// has passed in the meantime.
+    fn complete_expired(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let (complete, open): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending.stores)
+            .into_iter()
+            .partition(|it| it.extra_data.expected.is_some_and(|it| it <= now));
+        self.pending.stores = open;
+        let mut responses: Vec<_> = complete
+            .into_iter()
+            .map(|it| task::map_into_response(it, None))
+            .collect();
+        responses.extend(task::take_expired(
+            &mut self.pending,
+            IncompleteReason::DeadlineExceeded,
+        ));
+        self.send_all(responses)
+    }
+
+    fn complete_all(&mut self, reason: IncompleteReason) -> Result<()> {
+        let responses = std::mem::take(&mut self.pending.stores)
+            .into_iter()
+            .map(|it| task::map_into_response(it, Some(reason.clone())))
+            .collect();
+        self.send_all(responses)
+    }
+
+    fn send_all(&self, responses: Vec<TaskResponse>) -> Result<()> {
+        for response in responses {
+            self.result_tx
+                .send(response)
+                .with_context(|| "while sending response over channel")?;
+        }
+        Ok(())
+    }
+}
+
+/// Retrying won't help if the request can't be split, so the request is completed without any
+/// results instead of returning it to the queue
+fn reject_unsplittable(request: TaskRequest, error: anyhow::Error) -> TaskResponse {
+    let target_net = request.model.target_net;
+    let subnet_prefix_len = target_net
+        .prefix_len()
+        .saturating_add(request.model.split_bits)
+        .min(128);
+    TaskResponse {
+        model: EchoProbeResponse {
+            target_net,
+            subnet_prefix_len,
+            sent_ttl: SENT_TTL,
+            splits: vec![],
+            // set by the sender, which knows its vantage point
+            vantage_point: default_vantage_point(),
+            incomplete: Some(IncompleteReason::ProberFailed {
+                message: format!("{:#}", error),
+            }),
+        },
+        acks_delivery_tag: request.delivery_tag_to_ack,
+        requeue: false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use assertor::*;
+    use queue_models::probe_request::EchoProbeRequest;
+    use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+    use super::*;
+
+    const COOLDOWN: Duration = Duration::from_secs(4);
+
+    /// Batches fed to the probe stream, by batch ID
+    type Batches = UnboundedReceiver<(u64, Vec<Ipv6Addr>)>;
+
+    fn gen_scheduler(
+        request_timeout: Duration,
+    ) -> (StreamScheduler, Batches, UnboundedReceiver<TaskResponse>) {
+        let (stream, batches_rx) = ProbeStream::new(COOLDOWN);
+        let (result_tx, result_rx) = mpsc::unbounded_channel();
+        let scheduler = StreamScheduler {
+            stream,
+            blocklist: PrefixBlocklist::new(vec![]),
+            hitlist: Arc::new(Hitlist::default()),
+            pending: probe_store::create(),
+            result_tx,
+            request_timeout,
+        };
+        (scheduler, batches_rx, result_rx)
+    }
+
+    fn gen_request(split_bits: u8) -> TaskRequest {
+        TaskRequest {
+            model: EchoProbeRequest {
+                target_net: "2001:db8:cafe::/48".parse().unwrap(),
+                split_bits,
+                sampling: Default::default(),
+                hitlist: vec![],
+                vantage_point: None,
+            },
+            delivery_tag_to_ack: 17,
+            redelivered: false,
+            deadline: None,
+        }
+    }
+
+    fn sent_ago(batch_id: u64, ago: Duration) -> BatchSent {
+        BatchSent {
+            batch_id,
+            at: std::time::Instant::now() - ago,
+        }
+    }
+
+    #[test]
+    fn wait_until_batch_sent() {
+        // given
+        let (mut scheduler, mut batches_rx, mut result_rx) = gen_scheduler(Duration::from_secs(60));
+        scheduler.push_work(gen_request(1)).unwrap();
+
+        // when
+        scheduler.complete_expired().unwrap();
+
+        // then
+        let (batch_id, targets) = batches_rx.try_recv().expect("targets to be fed");
+        assert_that!(batch_id).is_equal_to(0);
+        assert_that!(targets.is_empty()).is_false();
+        assert_that!(result_rx.try_recv().is_err()).is_true();
+        assert_that!(scheduler.next_deadline().is_some()).is_true(); // the request deadline
+    }
+
+    #[test]
+    fn complete_after_cooldown_since_sent() {
+        // given
+        let (mut scheduler, _batches_rx, mut result_rx) = gen_scheduler(Duration::from_secs(60));
+        scheduler.push_work(gen_request(1)).unwrap();
+        scheduler.push_work(gen_request(1)).unwrap();
+
+        // when
+        scheduler.register_sent(sent_ago(0, COOLDOWN + Duration::from_secs(1)));
+        scheduler.register_sent(sent_ago(1, Duration::ZERO));
+        scheduler.complete_expired().unwrap();
+
+        // then
+        let response = result_rx.try_recv().expect("first request to be complete");
+        assert_that!(response.model.incomplete).is_equal_to(None);
+        assert_that!(response.requeue).is_false();
+        assert_that!(result_rx.try_recv().is_err()).is_true();
+        assert_that!(scheduler.pending.stores.len()).is_equal_to(1);
+    }
+
+    #[test]
+    fn deadline_before_sent() {
+        // given
+        let (mut scheduler, _batches_rx, mut result_rx) = gen_scheduler(Duration::ZERO);
+        scheduler.push_work(gen_request(1)).unwrap();
+
+        // when
+        scheduler.complete_expired().unwrap();
+
+        // then
+        let response = result_rx.try_recv().expect("request to be expired");
+        assert_that!(response.model.incomplete)
+            .is_equal_to(Some(IncompleteReason::DeadlineExceeded));
+        assert_that!(response.requeue).is_true();
+    }
+
+    #[test]
+    fn requeue_if_feeding_fails() {
+        // given
+        let (mut scheduler, batches_rx, mut result_rx) = gen_scheduler(Duration::from_secs(60));
+        drop(batches_rx);
+
+        // when
+        scheduler.push_work(gen_request(1)).unwrap();
+
+        // then
+        let response = result_rx.try_recv().expect("request to be completed");
+        assert_that!(response.acks_delivery_tag).is_equal_to(17);
+        assert_that!(response.requeue).is_true();
+        assert_that!(matches!(
+            response.model.incomplete,
+            Some(IncompleteReason::ProberFailed { .. })
+        ))
+        .is_true();
+        assert_that!(scheduler.pending.stores.is_empty()).is_true();
+    }
+
+    #[test]
+    fn report_failure_if_feeding_redelivered_fails() {
+        // given
+        let (mut scheduler, batches_rx, mut result_rx) = gen_scheduler(Duration::from_secs(60));
+        drop(batches_rx);
+        let request = TaskRequest {
+            redelivered: true,
+            ..gen_request(1)
+        };
+
+        // when
+        scheduler.push_work(request).unwrap();
+
+        // then
+        let response = result_rx.try_recv().expect("request to be completed");
+        assert_that!(response.requeue).is_false();
+        assert_that!(matches!(
+            response.model.incomplete,
+            Some(IncompleteReason::ProberFailed { .. })
+        ))
+        .is_true();
+        assert_that!(result_rx.try_recv().is_err()).is_true();
+    }
+
+    #[test]
+    fn reject_unsplittable_request() {
+        // given
+        let (mut scheduler, mut batches_rx, mut result_rx) = gen_scheduler(Duration::from_secs(60));
+
+        // when
+        scheduler.push_work(gen_request(0)).unwrap();
+
+        // then
+        let response = result_rx.try_recv().expect("request to be rejected");
+        assert_that!(response.requeue).is_false();
+        assert_that!(response.model.splits.is_empty()).is_true();
+        assert_that!(response.model.incomplete.is_some()).is_true();
+        assert_that!(batches_rx.try_recv().is_err()).is_true();
+    }
+}
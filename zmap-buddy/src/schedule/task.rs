@@ -1,28 +1,46 @@
+use std::borrow::Borrow;
+use std::sync::Arc;
+use std::time::Duration;
+
 use anyhow::{Context, Result};
-use log::{info, trace};
+use log::{info, trace, warn};
 use prefix_crab::blocklist::{self, PrefixBlocklist};
+use prefix_crab::helpers::stop;
+use prefix_crab::hitlist::Hitlist;
+use queue_models::probe_request::{EchoProbeRequest, SamplingStrategy};
+use queue_models::probe_response::{EchoProbeResponse, IncompleteReason};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::{sleep_until, Instant};
+use tokio_util::sync::CancellationToken;
 
 use crate::probe_store::{self, PrefixSplitProbeStore, PrefixStoreDispatcher, ProbeStore};
-use crate::zmap_call::{Caller, TargetCollector};
+use crate::zmap_call::{Prober, TargetCollector};
 use prefix_crab::prefix_split::*;
 
 use super::interleave::InterleavedTargetsIter;
 use super::{TaskRequest, TaskResponse};
 
+/// How long to wait for the prober to stop once all requests of a task have expired
+const PROBER_STOP_GRACE: Duration = Duration::from_secs(10);
+
 pub struct SchedulerTask<'req> {
     store: PrefixSplitProbeStore<&'req TaskRequest>,
-    caller: Caller,
+    prober: Box<dyn Prober>,
     target_samples: Vec<SubnetSample>,
     blocklist: PrefixBlocklist,
+    hitlist: Arc<Hitlist>,
+    stop_grace: Duration,
 }
 
 impl<'req> SchedulerTask<'req> {
-    pub fn new(params: super::Params) -> Result<Self> {
+    pub fn new(params: super::Params, hitlist: Arc<Hitlist>) -> Result<Self> {
         Ok(Self {
             store: probe_store::create(),
-            caller: params.base.to_caller_assuming_sudo()?,
+            prober: params.base.to_prober_assuming_access()?,
             target_samples: vec![],
             blocklist: blocklist::read(params.blocklist)?,
+            hitlist,
+            stop_grace: PROBER_STOP_GRACE,
         })
     }
 
@@ -33,13 +51,13 @@ impl<'req> SchedulerTask<'req> {
 
     fn push_work_internal(&mut self, item: &'req TaskRequest) -> Result<()> {
         let base_net = item.model.target_net;
-        let split = split(base_net).context("splitting IPv6 prefix")?;
+        let split = split(base_net, item.model.split_bits).context("splitting IPv6 prefix")?;
 
         // Stage samples instead of pushing directly to allow interweaving of different requests
         let samples = if self.blocklist.is_whole_net_blocked(&base_net) {
             vec![]
         } else {
-            split.to_samples(super::SAMPLES_PER_SUBNET)
+            to_samples(&split, &item.model, &self.hitlist)
         };
         self.target_samples.extend_from_slice(&samples);
 
@@ -47,24 +65,68 @@ impl<'req> SchedulerTask<'req> {
         Ok(())
     }
 
-    pub async fn run(mut self) -> Result<Vec<TaskResponse>> {
-        let mut response_rx = self.caller.request_responses();
+    /// Publishes the responses of each request as soon as it is complete, which is either when
+    /// zmap exits or when the deadline of the request passes. If zmap fails, partial results
+    /// are published for the remaining requests. Once all requests have expired, zmap is
+    /// stopped, but not waited for longer than a grace period, since it might hang.
+    pub async fn run(mut self, result_tx: &UnboundedSender<TaskResponse>) -> Result<()> {
+        let mut response_rx = self.prober.request_responses();
         let targets = self.collect_targets()?;
+        let cancel = CancellationToken::new();
+        let prober_cancel = cancel.clone();
         let zmap_task = tokio::task::spawn_blocking(move || {
             trace!("Now calling zmap");
-            self.caller.consume_run(targets)
+            self.prober.consume_run(targets, prober_cancel)
         });
         let mut not_moved_store = self.store;
-        while let Some(record) = response_rx.recv().await {
-            trace!("response from zmap: {:?}", record);
-            not_moved_store.register_response(&record);
+        let mut all_expired = false;
+        loop {
+            let next_deadline = next_deadline(&not_moved_store);
+            tokio::select! {
+                received = response_rx.recv() => match received {
+                    Some(record) => {
+                        trace!("response from zmap: {:?}", record);
+                        not_moved_store.register_response(&record);
+                    }
+                    None => break,
+                },
+                _ = sleep_until(next_deadline.unwrap_or_else(Instant::now)), if next_deadline.is_some() => {
+                    let reason = IncompleteReason::DeadlineExceeded;
+                    for response in take_expired(&mut not_moved_store, reason) {
+                        result_tx.send(response).context("while sending response over channel")?;
+                    }
+                    if not_moved_store.stores.is_empty() {
+                        all_expired = true;
+                        break;
+                    }
+                },
+            }
         }
         response_rx.close(); // ensure nothing else is sent
-        zmap_task
-            .await
-            .with_context(|| "during blocking zmap call (await)")??;
-        not_moved_store.fill_missing();
-        Ok(map_into_responses(not_moved_store))
+        if all_expired {
+            if let Err(e) = stop::cancel_and_join(zmap_task, &cancel, self.stop_grace).await {
+                warn!(
+                    "zmap didn't stop cleanly after all requests expired: {:#}",
+                    e
+                );
+            }
+            return Ok(());
+        }
+        let incomplete = match zmap_task.await {
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(format!("{:#}", e)),
+            Err(e) => Some(format!("during blocking zmap call (await): {}", e)),
+        }
+        .map(|message| {
+            warn!("zmap failed, publishing partial results: {}", message);
+            IncompleteReason::ProberFailed { message }
+        });
+        for store in not_moved_store.stores {
+            result_tx
+                .send(map_into_response(store, incomplete.clone()))
+                .context("while sending response over channel")?;
+        }
+        Ok(())
     }
 
     fn collect_targets(&mut self) -> Result<TargetCollector> {
@@ -87,14 +149,154 @@ impl<'req> SchedulerTask<'req> {
     }
 }
 
-fn map_into_responses(store: PrefixSplitProbeStore<&TaskRequest>) -> Vec<TaskResponse> {
-    store.stores.into_iter().map(map_into_response).collect()
+pub fn to_samples(
+    split: &PrefixSplit,
+    request: &EchoProbeRequest,
+    hitlist: &Hitlist,
+) -> Vec<SubnetSample> {
+    let strategy: Box<dyn Sampler + '_> = match &request.sampling {
+        SamplingStrategy::Uniform => Box::new(UniformSampler),
+        SamplingStrategy::LowByte => Box::new(LowByteSampler),
+        SamplingStrategy::FirstHost => Box::new(FirstHostSampler),
+        SamplingStrategy::Hitlist => Box::new(hitlist.sampler()),
+        SamplingStrategy::PreviouslyResponsive { addresses } => {
+            Box::new(KnownAddrSampler::new(addresses.clone()))
+        }
+    };
+    let known = KnownAddrSampler::new(request.hitlist.clone());
+    let sampler = MixedSampler::new(known, strategy.as_ref());
+    split.to_samples(&sampler, super::SAMPLES_PER_SUBNET)
 }
 
-fn map_into_response(store: PrefixStoreDispatcher<&TaskRequest>) -> TaskResponse {
-    let acks_delivery_tag = store.extra_data.delivery_tag_to_ack;
+pub fn next_deadline<R: Borrow<TaskRequest>>(store: &PrefixSplitProbeStore<R>) -> Option<Instant> {
+    store
+        .stores
+        .iter()
+        .filter_map(|it| it.extra_data.borrow().deadline)
+        .min()
+        .map(Instant::from_std)
+}
+
+/// Removes the requests whose deadline has passed from the store and maps them into responses
+pub fn take_expired<R: Borrow<TaskRequest>>(
+    store: &mut PrefixSplitProbeStore<R>,
+    reason: IncompleteReason,
+) -> Vec<TaskResponse> {
+    let now = std::time::Instant::now();
+    let (expired, open): (Vec<_>, Vec<_>) = std::mem::take(&mut store.stores)
+        .into_iter()
+        .partition(|it| it.extra_data.borrow().deadline.is_some_and(|it| it <= now));
+    store.stores = open;
+    expired
+        .into_iter()
+        .map(|it| map_into_response(it, Some(reason.clone())))
+        .collect()
+}
+
+/// Partial results are published if at least some responses arrived. Otherwise, the request
+/// is returned to the queue, unless that already happened before, so it isn't retried forever.
+pub fn map_into_response<R: Borrow<TaskRequest>>(
+    mut store: PrefixStoreDispatcher<R>,
+    incomplete: Option<IncompleteReason>,
+) -> TaskResponse {
+    let request = store.extra_data.borrow();
+    let acks_delivery_tag = request.delivery_tag_to_ack;
+    let requeue = incomplete.is_some() && !request.redelivered && !store.has_responses();
+    store.fill_missing();
+    let mut model: EchoProbeResponse = store.into();
+    model.incomplete = incomplete;
     TaskResponse {
-        model: store.into(),
+        model,
         acks_delivery_tag,
+        requeue,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{self as std_mpsc, Receiver};
+
+    use assertor::*;
+    use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+    use crate::schedule::ProbeResponse;
+
+    use super::*;
+
+    /// Ignores cancellation and only exits once the test drops the other end of `release`
+    #[derive(Debug)]
+    struct HangingProber {
+        release: Receiver<()>,
+        response_tx: Option<UnboundedSender<ProbeResponse>>,
+    }
+
+    impl Prober for HangingProber {
+        fn request_responses(&mut self) -> UnboundedReceiver<ProbeResponse> {
+            let (tx, rx) = mpsc::unbounded_channel();
+            self.response_tx = Some(tx);
+            rx
+        }
+
+        fn consume_run(
+            self: Box<Self>,
+            _targets: TargetCollector,
+            _cancel: CancellationToken,
+        ) -> Result<()> {
+            let _ = self.release.recv();
+            Ok(())
+        }
+    }
+
+    fn gen_task<'req>(release: Receiver<()>) -> SchedulerTask<'req> {
+        SchedulerTask {
+            store: probe_store::create(),
+            prober: Box::new(HangingProber {
+                release,
+                response_tx: None,
+            }),
+            target_samples: vec![],
+            blocklist: PrefixBlocklist::new(vec![]),
+            hitlist: Arc::new(Hitlist::default()),
+            stop_grace: Duration::from_millis(100),
+        }
+    }
+
+    fn gen_request(deadline: Duration) -> TaskRequest {
+        TaskRequest {
+            model: EchoProbeRequest {
+                target_net: "2001:db8:cafe::/48".parse().unwrap(),
+                split_bits: 1,
+                sampling: Default::default(),
+                hitlist: vec![],
+                vantage_point: None,
+            },
+            delivery_tag_to_ack: 17,
+            redelivered: false,
+            deadline: Some(std::time::Instant::now() + deadline),
+        }
+    }
+
+    #[tokio::test]
+    async fn return_after_deadline_if_prober_hangs() {
+        // given
+        let (release_tx, release_rx) = std_mpsc::channel();
+        let request = gen_request(Duration::from_millis(50));
+        let mut task = gen_task(release_rx);
+        task.push_work(&request).unwrap();
+        let (result_tx, mut result_rx) = mpsc::unbounded_channel();
+
+        // when
+        let ran = tokio::time::timeout(Duration::from_secs(5), task.run(&result_tx)).await;
+
+        // then
+        assert_that!(ran.map(|it| it.is_ok())).is_equal_to(Ok(true));
+        let response = result_rx
+            .try_recv()
+            .expect("expired request to be answered");
+        assert_that!(response.model.incomplete)
+            .is_equal_to(Some(IncompleteReason::DeadlineExceeded));
+        assert_that!(response.requeue).is_true();
+        assert_that!(result_rx.try_recv().is_err()).is_true();
+        drop(release_tx);
     }
 }
@@ -1,18 +1,30 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use clap::Args;
-use log::debug;
+use log::{debug, info};
+use prefix_crab::simulation;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio_util::sync::CancellationToken;
+
+use crate::schedule::ProbeResponse;
 
 pub use self::caller::Caller;
+pub use self::native::{BatchSent, NativeProber, ProbeStream};
+pub use self::simulate::Simulator;
 pub use self::targets::TargetCollector;
 
 mod targets;
 mod caller;
+mod native;
+mod simulate;
 
 // 64 is default for Linux and should be enough for most "reasonable" topologies
 // https://networkengineering.stackexchange.com/a/2222
 // https://www.rfc-editor.org/rfc/rfc1700 -> 64 is also the recommended default
 pub const SENT_TTL: u8 = 64;
 
+/// TTL that zmap actually sends its probes with
+const PROBE_TTL: u8 = 128;
+
 #[derive(Args)]
 #[derive(Clone)]
 #[group(id = "zmap")]
@@ -42,14 +54,94 @@ pub struct Params {
 
     #[arg(long, env = "ZMAP_SHUTDOWN_WAIT_SECS", default_value = "23")]
     shutdown_wait_secs: u16,
+
+    /// Send probes from a raw socket kept open across chunks instead of calling zmap.
+    /// Needs CAP_NET_RAW instead of sudo access. Routing is left to the kernel, so
+    /// the gateway MAC is ignored.
+    #[arg(long, env = "ZMAP_NATIVE")]
+    native: bool,
+
+    /// How long the native prober waits for responses after sending the last probe of a chunk
+    #[arg(long, env = "ZMAP_NATIVE_COOLDOWN_SECS", default_value = "4")]
+    native_cooldown_secs: u16,
+
+    #[clap(flatten)]
+    simulation: simulation::Params,
+}
+
+/// Sends echo probes to a set of targets and reports the responses, either by calling
+/// zmap ([Caller]), from a raw socket ([NativeProber]), or by consulting a simulated
+/// topology ([Simulator]).
+pub trait Prober: Send + std::fmt::Debug {
+    /// Request responses to be captured instead of just printed.
+    /// Responses will be provided to the returned [UnboundedReceiver].
+    /// The sender will be dropped once probing is complete.
+    fn request_responses(&mut self) -> UnboundedReceiver<ProbeResponse>;
+
+    /// Probes all targets, consuming this instance. Blocks until probing is complete, or
+    /// until `cancel` is triggered, e.g. because the deadline of all requests has passed.
+    fn consume_run(
+        self: Box<Self>,
+        targets: TargetCollector,
+        cancel: CancellationToken,
+    ) -> Result<()>;
 }
 
 impl Params {
-    pub fn to_caller_verifying_sudo(&self) -> Result<Caller> {
+    pub fn to_prober_verifying_access(&self) -> Result<Box<dyn Prober>> {
+        if let Some(simulator) = self._make_simulator()? {
+            return Ok(simulator);
+        }
+        if self.native {
+            // opening the session fails without CAP_NET_RAW
+            return self._make_native();
+        }
         let mut caller = self._make_caller()?;
         caller.verify_sudo_access()
             .with_context(|| "If not using NOPASSWD, you might need to re-run sudo manually.")?;
-        Ok(caller)
+        Ok(Box::new(caller))
+    }
+
+    pub fn to_prober_assuming_access(&self) -> Result<Box<dyn Prober>> {
+        if let Some(simulator) = self._make_simulator()? {
+            return Ok(simulator);
+        }
+        if self.native {
+            return self._make_native();
+        }
+        let mut caller = self._make_caller()?;
+        caller.assume_sudo_access();
+        Ok(Box::new(caller))
+    }
+
+    fn _make_simulator(&self) -> Result<Option<Box<dyn Prober>>> {
+        let topology = self.simulation.read_topology()
+            .context("reading simulated topology")?;
+        Ok(topology.map(|it| {
+            info!("Simulating zmap, no probes will actually be sent.");
+            Box::new(Simulator::new(it)) as Box<dyn Prober>
+        }))
+    }
+
+    /// Opens a long-lived stream of probes, which is only supported by the native prober,
+    /// since zmap doesn't take new targets while it is running.
+    pub fn to_probe_stream(
+        &self,
+    ) -> Result<(
+        ProbeStream,
+        UnboundedReceiver<ProbeResponse>,
+        UnboundedReceiver<BatchSent>,
+    )> {
+        if !self.native {
+            bail!("Streaming needs the native prober (--native)");
+        }
+        let session = native::shared_session(self)?;
+        Ok(native::open_stream(&session, self))
+    }
+
+    fn _make_native(&self) -> Result<Box<dyn Prober>> {
+        let session = native::shared_session(self)?;
+        Ok(Box::new(NativeProber::new(session, self)))
     }
 
     fn _make_caller(&self) -> Result<Caller> {
@@ -60,10 +152,14 @@ impl Params {
         caller.setup(self)?;
         Ok(caller)
     }
+}
 
-    pub fn to_caller_assuming_sudo(&self) -> Result<Caller> {
-        let mut caller = self._make_caller()?;
-        caller.assume_sudo_access();
-        Ok(caller)
+/// Classification of a response by ICMPv6 type, as output by zmap
+fn classify(icmp_type: u8) -> &'static str {
+    match icmp_type {
+        simulation::ICMP_ECHO_REPLY => "echoreply",
+        simulation::ICMP_DEST_UNREACH => "unreach",
+        simulation::ICMP_TIME_EXCEEDED => "timxceed",
+        _ => "other",
     }
 }
@@ -7,11 +7,13 @@ use crate::schedule::ProbeResponse;
 use anyhow::{bail, Context, Result};
 use log::Level::Debug;
 use log::{debug, error, log_enabled, trace, warn};
+use prefix_crab::helpers::stop;
 use regex::Regex;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio_util::sync::CancellationToken;
 
-use super::Params;
+use super::{Params, Prober, PROBE_TTL};
 use super::targets::TargetCollector;
 
 /// Base config for calling zmap
@@ -23,6 +25,27 @@ pub struct Caller {
     response_tx: Option<UnboundedSender<ProbeResponse>>,
 }
 
+impl Prober for Caller {
+    /// The sender will be dropped once zmap closes stdout (i.e. exits).
+    fn request_responses(&mut self) -> UnboundedReceiver<ProbeResponse> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.response_tx = Some(tx);
+        rx
+    }
+
+    /// Runs the configured command, consuming this instance.
+    fn consume_run(
+        self: Box<Self>,
+        targets: TargetCollector,
+        cancel: CancellationToken,
+    ) -> Result<()> {
+        let mut this = *self;
+        this.set_base();
+        this.push_targets(targets)?;
+        this.do_call(&cancel)
+    }
+}
+
 impl Caller {
     pub fn new(sudo_path: String, bin_path: String) -> Self {
         let mut cmd = Command::new(sudo_path);
@@ -83,22 +106,6 @@ impl Caller {
         Ok(())
     }
 
-    /// Request responses to be captured instead of just printed.
-    /// Responses will be provided to the returned [Receiver].
-    /// The sender will be dropped once zmap closes stdout (i.e. exits).
-    pub fn request_responses(&mut self) -> UnboundedReceiver<ProbeResponse> {
-        let (tx, rx) = mpsc::unbounded_channel();
-        self.response_tx = Some(tx);
-        rx
-    }
-
-    /// Runs the configured command, consuming this instance.
-    pub fn consume_run(mut self, targets: TargetCollector) -> Result<()> {
-        self.set_base();
-        self.push_targets(targets)?;
-        self.do_call()
-    }
-
     fn push_targets(&mut self, mut collector: TargetCollector) -> Result<()> {
         // Collector moved intentionally; Writing to it while the program is running
         // has undefined effect, so we prohibit that.
@@ -111,7 +118,7 @@ impl Caller {
         Ok(())
     }
 
-    fn do_call(&mut self) -> Result<()> {
+    fn do_call(&mut self, cancel: &CancellationToken) -> Result<()> {
         if log_enabled!(Debug) {
             let args: Vec<Cow<'_, str>> = self
                 .cmd
@@ -132,9 +139,11 @@ impl Caller {
             None => self.watch_logger_fd(&mut child.stdout),
         }
 
-        let exit_status = child
-            .wait()
-            .with_context(|| "Failed to wait for child to exit")?;
+        let Some(exit_status) = stop::wait_or_terminate(&mut child, cancel)
+            .with_context(|| "Failed to wait for child to exit")?
+        else {
+            bail!("zmap call was terminated because it was cancelled");
+        };
 
         if exit_status.success() {
             debug!("zmap call exited successfully");
@@ -200,7 +209,7 @@ impl Caller {
             .arg("--verbosity=5")
             .arg("--cooldown-time=4") // wait for responses for n secs after sending
             .arg("--probe-module=icmp6_echoscan")
-            .arg("--probe-ttl=128") // Windows value; 64 is RECOMMENDED and should also be safe (if we can't reach it with 64 then it's likely not a super reachable host)
+            .arg(format!("--probe-ttl={}", PROBE_TTL)) // Windows value; 64 is RECOMMENDED and should also be safe (if we can't reach it with 64 then it's likely not a super reachable host)
             .arg("--output-fields=type,code,original_ttl,orig-dest-ip,saddr,classification")
             .arg("--output-module=csv")
             .arg("--disable-syslog")
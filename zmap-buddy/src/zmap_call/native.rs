@@ -0,0 +1,334 @@
+use std::net::Ipv6Addr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use log::{debug, info, trace, warn};
+use prefix_crab::helpers::raw_icmp6::{self, Pacer, Run};
+use prefix_crab::helpers::raw_icmp6::{ICMP6_ECHO_REPLY, ICMP6_ECHO_REQUEST};
+use prefix_crab::helpers::stop;
+use prefix_crab::simulation;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio_util::sync::CancellationToken;
+
+use crate::schedule::ProbeResponse;
+
+use super::targets::TargetCollector;
+use super::{classify, Params, Prober, PROBE_TTL};
+
+/// The target, such that responses from other addresses can be attributed
+const DATA_LEN: usize = 16;
+
+/// Raw ICMPv6 socket shared by all [NativeProber]s, see [raw_icmp6::Session]
+pub type Session = raw_icmp6::Session<EchoRun>;
+
+/// The session is opened on first use and then kept open for the lifetime of the process,
+/// such that chunks don't pay for any setup.
+static SESSION: Mutex<Option<Arc<Session>>> = Mutex::new(None);
+
+/// Opens a new session if there is none yet or if the previous one broke
+pub fn shared_session(params: &Params) -> Result<Arc<Session>> {
+    let mut shared = SESSION.lock().expect("session lock not to be poisoned");
+    if let Some(session) = shared.as_ref().filter(|it| !it.is_broken()) {
+        return Ok(session.clone());
+    }
+    let session = Session::open(&params.source_address, params.interface.as_deref())?;
+    *shared = Some(session.clone());
+    Ok(session)
+}
+
+/// Forwards the responses to the probes of a run, until it is finished, which closes the channel
+#[derive(Debug)]
+pub struct EchoRun {
+    tx: UnboundedSender<ProbeResponse>,
+}
+
+impl Run for EchoRun {
+    type Response = ProbeResponse;
+
+    fn decode(data: &[u8], from: Ipv6Addr, identifier: u16) -> Option<(u32, ProbeResponse)> {
+        decode_response(data, from, identifier)
+    }
+
+    fn register(&mut self, response: ProbeResponse) {
+        if let Err(e) = self.tx.send(response) {
+            warn!(
+                "Unable to send response over channel; maybe the receiver disconnected? {}",
+                e
+            );
+        }
+    }
+}
+
+/// Opens a run that stays open until the returned [ProbeStream] is dropped, such that
+/// targets can be fed as they arrive. Probes are sent at the configured rate by a
+/// background thread, which reports each batch of targets once the last of them was sent.
+pub fn open_stream(
+    session: &Arc<Session>,
+    params: &Params,
+) -> (
+    ProbeStream,
+    UnboundedReceiver<ProbeResponse>,
+    UnboundedReceiver<BatchSent>,
+) {
+    let (response_tx, response_rx) = mpsc::unbounded_channel();
+    let run_id = session.register_run(EchoRun { tx: response_tx });
+    let (stream, mut batches_rx) = ProbeStream::new(cooldown(params));
+    let (sent_tx, sent_rx) = mpsc::unbounded_channel();
+    let mut pacer = Pacer::new(params.rate_pps as u32);
+    let session = session.clone();
+    let cooldown = stream.cooldown;
+    std::thread::spawn(move || {
+        let mut sequence = 0u16;
+        while let Some((batch_id, targets)) = batches_rx.blocking_recv() {
+            for addr in targets {
+                pacer.wait();
+                send_probe(&session, run_id, sequence, &addr);
+                sequence = sequence.wrapping_add(1);
+            }
+            let sent = BatchSent {
+                batch_id,
+                at: Instant::now(),
+            };
+            if sent_tx.send(sent).is_err() {
+                trace!("Nobody is waiting for batch {} anymore", batch_id);
+            }
+        }
+        std::thread::sleep(cooldown);
+        session.finish_run(run_id);
+        debug!("Native probe stream {} closed", run_id);
+    });
+    info!("Opened native probe stream {}", run_id);
+    (stream, response_rx, sent_rx)
+}
+
+fn send_probe(session: &Session, run_id: u32, sequence: u16, target: &Ipv6Addr) {
+    let packet = encode_echo_request(session.identifier(), sequence, run_id, target);
+    if let Err(e) = session.send(&packet, target, PROBE_TTL) {
+        // e.g. no route to host, the target just won't have a response
+        debug!("{:?}", e);
+    }
+}
+
+/// Feeds targets into a long-lived run of the [Session], see [open_stream].
+#[derive(Debug)]
+pub struct ProbeStream {
+    batches_tx: UnboundedSender<(u64, Vec<Ipv6Addr>)>,
+    next_batch_id: u64,
+    cooldown: Duration,
+}
+
+/// Reported by the sender thread of a [ProbeStream] once the last target of a batch was sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchSent {
+    pub batch_id: u64,
+    pub at: Instant,
+}
+
+impl ProbeStream {
+    /// The returned receiver gets each batch that is fed, which is usually the sender thread
+    pub fn new(cooldown: Duration) -> (Self, UnboundedReceiver<(u64, Vec<Ipv6Addr>)>) {
+        let (batches_tx, batches_rx) = mpsc::unbounded_channel();
+        let stream = Self {
+            batches_tx,
+            next_batch_id: 0,
+            cooldown,
+        };
+        (stream, batches_rx)
+    }
+
+    /// Queues targets for probing and returns the id of the batch, which is reported via
+    /// [BatchSent] once all of them have been sent.
+    pub fn feed(&mut self, targets: Vec<Ipv6Addr>) -> Result<u64> {
+        let batch_id = self.next_batch_id;
+        self.next_batch_id += 1;
+        self.batches_tx
+            .send((batch_id, targets))
+            .context("probe stream sender thread exited")?;
+        Ok(batch_id)
+    }
+
+    /// How long to wait for responses after the last probe of a batch was sent
+    pub fn cooldown(&self) -> Duration {
+        self.cooldown
+    }
+}
+
+/// Sends echo probes via a raw socket instead of calling zmap. Since the [Session] stays open
+/// across chunks, there is neither process startup nor shutdown wait, only the cooldown for
+/// responses to the last probes of a run.
+#[derive(Debug)]
+pub struct NativeProber {
+    session: Arc<Session>,
+    rate_pps: u32,
+    cooldown: Duration,
+    response_tx: Option<UnboundedSender<ProbeResponse>>,
+}
+
+impl NativeProber {
+    pub fn new(session: Arc<Session>, params: &Params) -> Self {
+        Self {
+            session,
+            rate_pps: params.rate_pps as u32,
+            cooldown: cooldown(params),
+            response_tx: None,
+        }
+    }
+}
+
+impl Prober for NativeProber {
+    /// The sender will be dropped after the cooldown following the last probe.
+    fn request_responses(&mut self) -> UnboundedReceiver<ProbeResponse> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.response_tx = Some(tx);
+        rx
+    }
+
+    fn consume_run(
+        self: Box<Self>,
+        mut targets: TargetCollector,
+        cancel: CancellationToken,
+    ) -> Result<()> {
+        targets.flush()?;
+        let addrs = simulation::read_targets(&targets.path)?;
+        let Some(tx) = self.response_tx else {
+            warn!("Native prober run without response channel, not sending any probes.");
+            return Ok(());
+        };
+        let run_id = self.session.register_run(EchoRun { tx });
+        debug!("Native probing run {} for {} targets", run_id, addrs.len());
+        let mut pacer = Pacer::new(self.rate_pps);
+        for (sequence, addr) in addrs.iter().enumerate() {
+            if cancel.is_cancelled() {
+                debug!("Native probing run {} cancelled", run_id);
+                break;
+            }
+            pacer.wait();
+            send_probe(&self.session, run_id, sequence as u16, addr);
+        }
+        stop::sleep_unless_cancelled(self.cooldown, &cancel);
+        self.session.finish_run(run_id);
+        Ok(())
+    }
+}
+
+fn cooldown(params: &Params) -> Duration {
+    Duration::from_secs(params.native_cooldown_secs as u64)
+}
+
+fn encode_echo_request(identifier: u16, sequence: u16, run_id: u32, target: &Ipv6Addr) -> Vec<u8> {
+    raw_icmp6::encode_echo_request(identifier, sequence, run_id, &target.octets())
+}
+
+/// Maps an ICMPv6 message received from `from` to the run that caused it, if it was caused
+/// by one of our probes. Error messages are matched by the echo request that they quote.
+fn decode_response(data: &[u8], from: Ipv6Addr, identifier: u16) -> Option<(u32, ProbeResponse)> {
+    let (&icmp_type, rest) = data.split_first()?;
+    let &icmp_code = rest.first()?;
+    let (echo, original_ttl) = match icmp_type {
+        ICMP6_ECHO_REQUEST => return None, // e.g. our own probes on loopback
+        // The hop limit on arrival is only known from quoted packets
+        ICMP6_ECHO_REPLY => (data, 0),
+        t if t < 128 => raw_icmp6::quoted_echo_request(data)?,
+        _ => return None,
+    };
+
+    let (run_id, target) = raw_icmp6::decode_payload(echo, identifier, DATA_LEN)?;
+    let target: [u8; 16] = target.try_into().ok()?;
+    Some((
+        run_id,
+        ProbeResponse {
+            icmp_type,
+            icmp_code,
+            original_ttl,
+            original_dest_ip: target.into(),
+            source_ip: from,
+            classification: classify(icmp_type).to_string(),
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use assertor::*;
+
+    use prefix_crab::helpers::raw_icmp6::{IPV6_HEADER_LEN, NEXT_HEADER_ICMP6};
+
+    use super::*;
+
+    const IDENTIFIER: u16 = 0x1337;
+
+    fn addr(it: &str) -> Ipv6Addr {
+        it.parse().unwrap()
+    }
+
+    fn gen_quoting_error(
+        icmp_type: u8,
+        probe: &[u8],
+        remaining_hops: u8,
+        target: &Ipv6Addr,
+    ) -> Vec<u8> {
+        let mut packet = vec![icmp_type, 0, 0, 0, 0, 0, 0, 0];
+        let mut ip_header = [0u8; IPV6_HEADER_LEN];
+        ip_header[0] = 0x60;
+        ip_header[6] = NEXT_HEADER_ICMP6;
+        ip_header[7] = remaining_hops;
+        ip_header[24..].copy_from_slice(&target.octets());
+        packet.extend_from_slice(&ip_header);
+        packet.extend_from_slice(probe);
+        packet
+    }
+
+    #[test]
+    fn decode_echo_reply() {
+        // given
+        let target = addr("2001:db8:cafe::1");
+        let mut reply = encode_echo_request(IDENTIFIER, 7, 42, &target);
+        reply[0] = ICMP6_ECHO_REPLY;
+
+        // when
+        let decoded = decode_response(&reply, target, IDENTIFIER);
+
+        // then
+        let (run_id, response) = decoded.expect("reply to be decoded");
+        assert_that!(run_id).is_equal_to(42);
+        assert_that!(response.original_dest_ip).is_equal_to(target);
+        assert_that!(response.source_ip).is_equal_to(target);
+        assert_that!(response.classification.as_str()).is_equal_to("echoreply");
+    }
+
+    #[test]
+    fn decode_quoted_time_exceeded() {
+        // given
+        let target = addr("2001:db8:cafe::1");
+        let router = addr("2001:db8:ffff::100");
+        let probe = encode_echo_request(IDENTIFIER, 7, 42, &target);
+        let error = gen_quoting_error(3, &probe, 1, &target);
+
+        // when
+        let decoded = decode_response(&error, router, IDENTIFIER);
+
+        // then
+        let (run_id, response) = decoded.expect("error to be decoded");
+        assert_that!(run_id).is_equal_to(42);
+        assert_that!(response.icmp_type).is_equal_to(3);
+        assert_that!(response.original_ttl).is_equal_to(1);
+        assert_that!(response.original_dest_ip).is_equal_to(target);
+        assert_that!(response.source_ip).is_equal_to(router);
+    }
+
+    #[test]
+    fn ignore_foreign_identifier() {
+        // given
+        let target = addr("2001:db8:cafe::1");
+        let probe = encode_echo_request(IDENTIFIER + 1, 7, 42, &target);
+        let error = gen_quoting_error(1, &probe, 3, &target);
+
+        // when
+        let decoded = decode_response(&error, addr("2001:db8::1"), IDENTIFIER);
+
+        // then
+        assert_that!(decoded.is_none()).is_true();
+    }
+}
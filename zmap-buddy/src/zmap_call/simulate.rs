@@ -0,0 +1,75 @@
+use anyhow::Result;
+use log::{debug, trace, warn};
+use prefix_crab::simulation::{self, SimulatedResponse, Topology};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio_util::sync::CancellationToken;
+
+use crate::schedule::ProbeResponse;
+
+use super::targets::TargetCollector;
+use super::{classify, Prober, PROBE_TTL};
+
+/// Answers probes from a simulated [Topology] instead of calling zmap.
+#[derive(Debug)]
+pub struct Simulator {
+    topology: Topology,
+    response_tx: Option<UnboundedSender<ProbeResponse>>,
+}
+
+impl Simulator {
+    pub fn new(topology: Topology) -> Self {
+        Self {
+            topology,
+            response_tx: None,
+        }
+    }
+}
+
+impl Prober for Simulator {
+    fn request_responses(&mut self) -> UnboundedReceiver<ProbeResponse> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.response_tx = Some(tx);
+        rx
+    }
+
+    fn consume_run(
+        self: Box<Self>,
+        mut targets: TargetCollector,
+        cancel: CancellationToken,
+    ) -> Result<()> {
+        targets.flush()?;
+        let addrs = simulation::read_targets(&targets.path)?;
+        debug!("Simulating zmap call for {} targets", addrs.len());
+        let Some(tx) = self.response_tx else {
+            return Ok(());
+        };
+        let mut run = self.topology.start_run();
+        for addr in addrs {
+            if cancel.is_cancelled() {
+                debug!("Simulated run cancelled");
+                break;
+            }
+            if let Some(response) = run.echo(&addr, PROBE_TTL) {
+                let record = to_record(response);
+                trace!("[[simulated zmap result]] {:?}", record);
+                if let Err(e) = tx.send(record) {
+                    warn!("Unable to send response over channel; maybe the receiver disconnected? {}", e);
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn to_record(response: SimulatedResponse) -> ProbeResponse {
+    ProbeResponse {
+        icmp_type: response.icmp_type,
+        icmp_code: response.icmp_code,
+        original_ttl: response.sent_ttl.saturating_sub(response.responder_hops),
+        original_dest_ip: response.target,
+        source_ip: response.from,
+        classification: classify(response.icmp_type).to_string(),
+    }
+}